@@ -0,0 +1,242 @@
+use log::warn;
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{delete, get, post, State};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::auth::AdminAuth;
+use crate::config::Config;
+use crate::error::Error;
+use crate::generate::{is_bundled_alphabet, ALPHABETS};
+use crate::profile::Profile;
+use crate::query::check_charset;
+use crate::ratelimit::RateLimit;
+use crate::storage::Storage;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct AlphabetsConfig {
+    // registered alphabets are kept under this key of the storage across restarts when set
+    pub path: Option<PathBuf>,
+    // the most alphabets that can be registered
+    pub max: usize,
+}
+
+impl Default for AlphabetsConfig {
+    fn default() -> AlphabetsConfig {
+        AlphabetsConfig {
+            path: None,
+            max: 100,
+        }
+    }
+}
+
+// Alphabets registered by clients, by name, usable wherever a bundled one is.
+pub struct Alphabets {
+    alphabets: Mutex<BTreeMap<String, String>>,
+    config: AlphabetsConfig,
+    storage: Arc<dyn Storage>,
+}
+
+// Whether `name` is 1 to 32 lowercase letters, digits, `-` or `_`, as registered names must be.
+pub fn valid_name(name: &str) -> bool {
+    (1..=32).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+impl Alphabets {
+    pub fn load(config: AlphabetsConfig, storage: Arc<dyn Storage>) -> Alphabets {
+        let alphabets = config
+            .path
+            .as_ref()
+            .and_then(|path| storage.get(&path.to_string_lossy()).ok().flatten())
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default();
+        Alphabets {
+            alphabets: Mutex::new(alphabets),
+            config,
+            storage,
+        }
+    }
+
+    fn save(&self, alphabets: &BTreeMap<String, String>) {
+        if let Some(path) = &self.config.path {
+            let json = serde_json::to_vec(alphabets).unwrap();
+            if let Err(err) = self.storage.put(&path.to_string_lossy(), &json) {
+                warn!("could not save alphabets to {:?}: {}", path, err);
+            }
+        }
+    }
+
+    // Registers `charset`, its repeated characters dropped, as `name`, returning what was stored
+    // and whether it's new. Names are taken until removed, so registering the same characters
+    // again is fine but registering others under a taken name isn't.
+    pub fn register(&self, name: &str, charset: &str) -> Result<(String, bool), Error> {
+        if !valid_name(name) || is_bundled_alphabet(name) {
+            let reason = String::from(
+                "must be 1 to 32 lowercase letters, digits, `-` or `_`, and not a bundled alphabet",
+            );
+            return Err(Error::InvalidValue("name", reason));
+        }
+        let charset: String = check_charset(charset)?.into_iter().collect();
+        let mut alphabets = self.alphabets.lock().unwrap();
+        match alphabets.get(name) {
+            Some(registered) if *registered == charset => return Ok((charset, false)),
+            Some(_) => return Err(Error::AlphabetTaken(String::from(name))),
+            None if alphabets.len() >= self.config.max => {
+                return Err(Status::InsufficientStorage.into())
+            }
+            None => {}
+        }
+        alphabets.insert(String::from(name), charset.clone());
+        self.save(&alphabets);
+        Ok((charset, true))
+    }
+
+    // Unregisters the alphabet `name`, freeing the name for other characters.
+    pub fn remove(&self, name: &str) -> Result<(), Error> {
+        let mut alphabets = self.alphabets.lock().unwrap();
+        if alphabets.remove(name).is_none() {
+            return Err(Error::UnknownAlphabet(String::from(name)));
+        }
+        self.save(&alphabets);
+        Ok(())
+    }
+
+    // Replaces a registered alphabet named in `overrides` with its characters, given as a charset.
+    pub fn expand(&self, overrides: Profile) -> Profile {
+        let charset = match &overrides.alphabet {
+            Some(name) if overrides.charset.is_none() => {
+                self.alphabets.lock().unwrap().get(name).cloned()
+            }
+            _ => None,
+        };
+        match charset {
+            Some(charset) => Profile {
+                alphabet: None,
+                charset: Some(charset),
+                ..overrides
+            },
+            None => overrides,
+        }
+    }
+}
+
+// Manages the `Alphabets`, restoring the persisted ones. Must be attached after the `Storage`
+// fairing.
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Alphabets", |rocket| async {
+        let config = rocket
+            .state::<Config>()
+            .map(|config| config.alphabets.clone())
+            .unwrap_or_default();
+        match rocket.state::<Arc<dyn Storage>>().cloned() {
+            Some(storage) => rocket.manage(Arc::new(Alphabets::load(config, storage))),
+            None => rocket,
+        }
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Alphabet {
+    name: String,
+    charset: String,
+}
+
+#[post("/alphabets", format = "json", data = "<alphabet>")]
+pub fn register_alphabet(
+    _auth: AdminAuth,
+    _limit: RateLimit,
+    alphabet: Json<Alphabet>,
+    alphabets: &State<Arc<Alphabets>>,
+) -> Result<(Status, Json<Alphabet>), Error> {
+    let Alphabet { name, charset } = alphabet.into_inner();
+    let (charset, new) = alphabets.register(&name, &charset)?;
+    let status = if new { Status::Created } else { Status::Ok };
+    Ok((status, Json(Alphabet { name, charset })))
+}
+
+#[delete("/alphabets/<name>")]
+pub fn remove_alphabet(
+    _auth: AdminAuth,
+    name: &str,
+    alphabets: &State<Arc<Alphabets>>,
+) -> Result<Status, Error> {
+    alphabets.remove(name)?;
+    Ok(Status::NoContent)
+}
+
+// What each bundled alphabet suits best, by name.
+const RECOMMENDED: [(&str, &str); 9] = [
+    (
+        "alphabet",
+        "photos, with the most shapes and shades to match",
+    ),
+    ("letters", "photos drawn as text"),
+    ("lowercase", "stylized text, with rounder shapes"),
+    ("uppercase", "stylized text, with blockier shapes"),
+    ("minimal", "line art, of slashes, dots and underscores"),
+    ("symbols", "line art and logos, of punctuation"),
+    (
+        "quadrants",
+        "photos at twice the resolution, of cell quarters",
+    ),
+    ("shades", "smooth gradients, of shading blocks"),
+    ("box", "diagrams and line art, of box-drawing lines"),
+];
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AlphabetInfo {
+    name: String,
+    charset: String,
+    // only given for bundled alphabets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recommended: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AlphabetList {
+    bundled: Vec<AlphabetInfo>,
+    registered: Vec<AlphabetInfo>,
+}
+
+// The bundled alphabets with their characters and what they suit, and the registered ones with
+// their characters, so clients needn't guess valid `alphabet` values.
+#[get("/alphabets")]
+pub fn alphabets(alphabets: &State<Arc<Alphabets>>) -> Json<AlphabetList> {
+    let bundled = ALPHABETS
+        .iter()
+        .map(|&(name, charset)| AlphabetInfo {
+            name: String::from(name),
+            charset: String::from(charset),
+            recommended: RECOMMENDED
+                .iter()
+                .find(|(alphabet, _)| *alphabet == name)
+                .map(|&(_, recommended)| recommended),
+        })
+        .collect();
+    let registered = alphabets
+        .alphabets
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, charset)| AlphabetInfo {
+            name: name.clone(),
+            charset: charset.clone(),
+            recommended: None,
+        })
+        .collect();
+    Json(AlphabetList {
+        bundled,
+        registered,
+    })
+}
@@ -0,0 +1,164 @@
+use log::{error, warn};
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::stats::Stats;
+use crate::storage::Storage;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct AdminConfig {
+    pub token: Option<String>,
+}
+
+// Guards the operator-only routes with `Authorization: Bearer <admin.token>`; those routes don't
+// exist while no token is set.
+pub struct AdminAuth;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminAuth {
+    type Error = &'static str;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let token = match req
+            .rocket()
+            .state::<Config>()
+            .and_then(|config| config.admin.token.as_ref())
+        {
+            Some(token) => token,
+            None => return Outcome::Error((Status::NotFound, "admin endpoints are disabled")),
+        };
+        let header = req.headers().get_one("Authorization");
+        if header.and_then(|header| header.strip_prefix("Bearer ")) == Some(token.as_str()) {
+            Outcome::Success(AdminAuth)
+        } else {
+            Outcome::Error((Status::Unauthorized, "invalid admin token"))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct ApiKeysConfig {
+    // conversions are refused without a valid key when set
+    pub required: bool,
+    // keys by the name of who they're for, which usage is attributed to
+    pub keys: HashMap<String, String>,
+    // more keys are read at launch from this key of the storage, a JSON object of the same shape
+    pub path: Option<PathBuf>,
+}
+
+// Who each API key is for, by key.
+#[derive(Default)]
+pub struct ApiKeys {
+    names: HashMap<String, String>,
+    required: bool,
+}
+
+impl ApiKeys {
+    pub fn load(config: &ApiKeysConfig, storage: &dyn Storage) -> Result<ApiKeys, String> {
+        let mut keys = config.keys.clone();
+        if let Some(path) = &config.path {
+            let stored = storage
+                .get(&path.to_string_lossy())
+                .map_err(|err| err.to_string())?;
+            if let Some(json) = stored {
+                let stored: HashMap<String, String> =
+                    serde_json::from_slice(&json).map_err(|err| err.to_string())?;
+                keys.extend(stored);
+            }
+        }
+        let mut names = HashMap::new();
+        for (name, key) in keys {
+            if key.is_empty() {
+                return Err(format!("the key for {:?} is empty", name));
+            }
+            if let Some(other) = names.insert(key, name.clone()) {
+                return Err(format!("{:?} and {:?} share a key", other, name));
+            }
+        }
+        if config.required && names.is_empty() {
+            warn!("API keys are required but none are configured; conversions are refused");
+        }
+        Ok(ApiKeys {
+            names,
+            required: config.required,
+        })
+    }
+}
+
+// Manages the `ApiKeys`, refusing to launch when they can't be read. Must be attached after the
+// `Storage` fairing.
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("API keys", |rocket| async {
+        let config = rocket
+            .state::<Config>()
+            .map(|config| config.api_keys.clone())
+            .unwrap_or_default();
+        let storage = match rocket.state::<Arc<dyn Storage>>() {
+            Some(storage) => storage.clone(),
+            None => return Ok(rocket),
+        };
+        match ApiKeys::load(&config, storage.as_ref()) {
+            Ok(keys) => Ok(rocket.manage(keys)),
+            Err(err) => {
+                error!("invalid api_keys config: {}", err);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+// The name of the client's API key, given as `X-API-Key` or the `api_key` query parameter, or
+// none when it gave none and keys aren't required. A key that isn't known is always refused.
+pub struct ApiKey(pub Option<String>);
+
+// The outcome of checking the request's key, kept for any other guard that asks.
+struct CheckedKey(Result<Option<String>, &'static str>);
+
+fn check_key(req: &Request<'_>) -> Result<Option<String>, &'static str> {
+    let keys = match req.rocket().state::<ApiKeys>() {
+        Some(keys) => keys,
+        None => return Ok(None),
+    };
+    let given = req
+        .headers()
+        .get_one("X-API-Key")
+        .map(String::from)
+        .or_else(|| req.query_value::<String>("api_key")?.ok());
+    match given {
+        Some(key) => match keys.names.get(&key) {
+            Some(name) => Ok(Some(name.clone())),
+            None => Err("invalid API key"),
+        },
+        None if keys.required => Err("an API key is required"),
+        None => Ok(None),
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKey {
+    type Error = &'static str;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        // checked once per request, however many guards ask, so usage is counted once
+        let CheckedKey(checked) = req.local_cache(|| {
+            let checked = check_key(req);
+            if let (Ok(Some(name)), Some(stats)) = (&checked, req.rocket().state::<Stats>()) {
+                stats.record_key(name);
+            }
+            CheckedKey(checked)
+        });
+        match checked {
+            Ok(name) => Outcome::Success(ApiKey(name.clone())),
+            Err(err) => Outcome::Error((Status::Unauthorized, *err)),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use rocket::http::ContentType;
+use rocket::serde::Deserialize;
+use rocket::{get, State};
+use std::time::Instant;
+
+use crate::cache::Cached;
+use crate::config::Config;
+use crate::convert::char_rows_to_svg;
+use crate::cvd::CVD_KINDS;
+use crate::echo::{resolved_params, WithParams};
+use crate::error::Error;
+use crate::fetch::Fetchers;
+use crate::generate::{color_frame, image_to_char_rows, load_alphabet, load_font, Params};
+use crate::pools::Pools;
+use crate::ratelimit::RateLimit;
+use crate::stats::Stats;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct BadgeConfig {
+    pub width: usize,
+    pub max_width: usize,
+    pub cell_width: f32,
+    pub font: String,
+    pub alphabet: String,
+}
+
+impl Default for BadgeConfig {
+    fn default() -> BadgeConfig {
+        BadgeConfig {
+            width: 40,
+            max_width: 80,
+            cell_width: 6.,
+            font: String::from("bitocra-13"),
+            alphabet: String::from("alphabet"),
+        }
+    }
+}
+
+#[get("/badge?<url>&<width>&<cvd>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn badge(
+    _limit: RateLimit,
+    url: String,
+    width: Option<usize>,
+    cvd: Option<String>,
+    config: &State<Config>,
+    stats: &State<Stats>,
+    pools: &State<Pools>,
+    fetchers: &State<Fetchers>,
+) -> Result<Cached<WithParams<(ContentType, String)>>, Error> {
+    if !fetchers.supports(&url) {
+        return Err(Error::InvalidParameter("url"));
+    }
+    if matches!(cvd.as_deref(), Some(cvd) if !CVD_KINDS.contains(&cvd)) {
+        return Err(Error::InvalidParameter("cvd"));
+    }
+    let badge = config.badge.clone();
+    let width = width.unwrap_or(badge.width).clamp(1, badge.max_width);
+
+    let labels = (badge.font.clone(), badge.alphabet.clone());
+    let t0 = Instant::now();
+    let result = async {
+        let bytes = pools.download(fetchers.fetch(&url)).await?;
+        let (_, image) = pools.decode(bytes).await?;
+        pools
+            .convert(move || {
+                let alphabet = load_alphabet(&badge.alphabet);
+                let font = load_font(&badge.font, &alphabet)?;
+                let args = Params {
+                    font: &badge.font,
+                    alphabet: &badge.alphabet,
+                    cvd: cvd.as_deref(),
+                    ..Params::defaults_for(&url, width)
+                };
+                let char_rows = image_to_char_rows(&image, &font, &args);
+                let colors = color_frame(&image, &args);
+                let svg = char_rows_to_svg(&char_rows, &font, &colors, badge.cell_width);
+                Ok((svg, resolved_params(&args)))
+            })
+            .await?
+    }
+    .await;
+
+    match result {
+        Ok((svg, resolved)) => {
+            stats.record(&labels.0, &labels.1, "grad", "svg", t0.elapsed());
+            Ok(Cached::new(
+                WithParams::new((ContentType::SVG, svg), resolved),
+                &config.cache,
+            ))
+        }
+        Err(err) => {
+            stats.record_failure();
+            Err(err)
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use rocket::http::ContentType;
+use rocket::{get, State};
+
+use crate::cache::Cached;
+use crate::config::Config;
+use crate::convert::text_to_banner;
+use crate::error::Error;
+use crate::generate::{is_available_font, load_alphabet, load_font};
+use crate::pools::Pools;
+use crate::ratelimit::RateLimit;
+
+const MAX_TEXT_LEN: usize = 256;
+
+#[get("/text?<s>&<font>&<fill>")]
+pub async fn text_banner(
+    _limit: RateLimit,
+    s: String,
+    font: Option<String>,
+    fill: Option<String>,
+    config: &State<Config>,
+    pools: &State<Pools>,
+) -> Result<Cached<(ContentType, String)>, Error> {
+    let font = font.unwrap_or_else(|| String::from("bitocra-13"));
+    if !is_available_font(&font) {
+        return Err(Error::UnknownFont(font));
+    }
+    if s.is_empty() || s.chars().count() > MAX_TEXT_LEN {
+        return Err(Error::InvalidParameter("s"));
+    }
+    let fill = match fill {
+        Some(fill) => fill.chars().next().ok_or(Error::InvalidParameter("fill"))?,
+        None => '#',
+    };
+
+    let banner = pools
+        .convert(move || {
+            // the full alphabet keeps Font::new happy for strings made only of blanks
+            let mut alphabet = load_alphabet("alphabet");
+            alphabet.extend(s.chars());
+            let font = load_font(&font, &alphabet)?;
+            Ok::<_, Error>(text_to_banner(&s, &font, fill))
+        })
+        .await??;
+
+    Ok(Cached::new((ContentType::Plain, banner), &config.cache))
+}
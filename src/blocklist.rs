@@ -0,0 +1,185 @@
+use log::warn;
+use reqwest::Url;
+use rocket::fairing::AdHoc;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{get, post, State};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::auth::AdminAuth;
+use crate::cache::DiskCache;
+use crate::config::Config;
+use crate::error::Error;
+use crate::gallery::Gallery;
+use crate::permalink::Permalinks;
+use crate::storage::Storage;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct BlocklistConfig {
+    // blocked sources are kept under this key of the storage across restarts when set
+    pub path: Option<PathBuf>,
+}
+
+// Spells source `url` the way it's blocked and checked: parsed, so a host in another case or a
+// default port spelled out doesn't get around a block, and without a fragment, which is never
+// fetched. Anything that isn't a URL is only trimmed.
+pub fn normalize(url: &str) -> String {
+    match Url::parse(url.trim()) {
+        Ok(mut url) => {
+            url.set_fragment(None);
+            url.into()
+        }
+        Err(_) => url.trim().to_owned(),
+    }
+}
+
+// Source URLs that are refused instead of fetched, once reported as abusive.
+pub struct Blocklist {
+    sources: Mutex<BTreeSet<String>>,
+    key: Option<String>,
+    storage: Arc<dyn Storage>,
+}
+
+impl Blocklist {
+    pub fn load(path: Option<PathBuf>, storage: Arc<dyn Storage>) -> Blocklist {
+        let key = path.map(|path| path.to_string_lossy().into_owned());
+        let sources = key
+            .as_ref()
+            .and_then(|key| storage.get(key).ok().flatten())
+            .and_then(|json| serde_json::from_slice::<BTreeSet<String>>(&json).ok())
+            .unwrap_or_default()
+            .iter()
+            .map(|url| normalize(url))
+            .collect();
+        Blocklist {
+            sources: Mutex::new(sources),
+            key,
+            storage,
+        }
+    }
+
+    fn save(&self, sources: &BTreeSet<String>) {
+        if let Some(key) = &self.key {
+            let json = serde_json::to_vec(sources).unwrap();
+            if let Err(err) = self.storage.put(key, &json) {
+                warn!("could not save blocklist to {:?}: {}", key, err);
+            }
+        }
+    }
+
+    pub fn block(&self, url: &str) {
+        let mut sources = self.sources.lock().unwrap();
+        if sources.insert(normalize(url)) {
+            self.save(&sources);
+        }
+    }
+
+    pub fn check(&self, url: &str) -> Result<(), Error> {
+        if self.sources.lock().unwrap().contains(&normalize(url)) {
+            return Err(Error::BlockedSource);
+        }
+        Ok(())
+    }
+}
+
+// Manages the `Blocklist`, restoring the persisted one. Must be attached after the `Storage` fairing
+// and before the `Fetchers` one, which checks every source against it.
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Blocklist", |rocket| async {
+        let path = rocket
+            .state::<Config>()
+            .and_then(|config| config.blocklist.path.clone());
+        match rocket.state::<Arc<dyn Storage>>().cloned() {
+            Some(storage) => rocket.manage(Arc::new(Blocklist::load(path, storage))),
+            None => rocket,
+        }
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PurgeRequest {
+    // a permalink id, or `/r/<id>` itself
+    id: Option<String>,
+    // a source, blocked from then on and purged from every gallery listing of it
+    url: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Purged {
+    purged: Vec<String>,
+    blocked: Option<String>,
+}
+
+// Takes down a reported conversion: deletes its permalink, gallery listing and the results on disk
+// linking to it, and with a `url` blocks the source and takes down whatever the gallery lists of it
+// too.
+#[post("/purge", format = "json", data = "<request>")]
+pub fn purge(
+    _auth: AdminAuth,
+    request: Json<PurgeRequest>,
+    blocklist: &State<Arc<Blocklist>>,
+    permalinks: &State<Permalinks>,
+    gallery: &State<Arc<Gallery>>,
+    disk_cache: &State<Arc<DiskCache>>,
+) -> Result<Json<Purged>, Error> {
+    let PurgeRequest { id, url } = request.into_inner();
+    if id.is_none() && url.is_none() {
+        return Err(Error::InvalidParameter("id"));
+    }
+    let mut ids = Vec::new();
+    if let Some(id) = id {
+        ids.push(id.trim_start_matches("/r/").to_owned());
+    }
+    if let Some(url) = &url {
+        blocklist.block(url);
+        ids.extend(gallery.ids_of(url));
+    }
+    let mut purged = Vec::new();
+    for id in &ids {
+        gallery.remove(id);
+        if permalinks.delete(id) && !purged.contains(id) {
+            purged.push(id.clone());
+        }
+    }
+    disk_cache.purge(&ids);
+    Ok(Json(Purged {
+        purged,
+        blocked: url,
+    }))
+}
+
+// The blocked sources, in order.
+#[get("/blocklist")]
+pub fn blocked_sources(_auth: AdminAuth, blocklist: &State<Arc<Blocklist>>) -> Json<Vec<String>> {
+    Json(blocklist.sources.lock().unwrap().iter().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Memory;
+
+    #[test]
+    fn blocks_every_spelling_of_a_source() {
+        let blocklist = Blocklist::load(None, Arc::new(Memory::default()));
+        blocklist.block(" https://Example.com:443/a.png#top ");
+        for url in [
+            "https://example.com/a.png",
+            "HTTPS://EXAMPLE.COM/a.png",
+            "https://example.com:443/a.png#other",
+        ] {
+            assert!(
+                matches!(blocklist.check(url), Err(Error::BlockedSource)),
+                "{}",
+                url
+            );
+        }
+        assert!(blocklist.check("https://example.com/A.png").is_ok());
+        assert!(blocklist.check("https://example.com/a.png?v=2").is_ok());
+    }
+}
@@ -0,0 +1,492 @@
+use log::warn;
+use rocket::fairing::AdHoc;
+use rocket::http::{ContentType, Header, Status};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::Request;
+use std::convert::Infallible;
+use std::fs::{self, File, FileTimes};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::config::Config;
+use crate::negotiate::Body;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub max_age: u64,
+    pub public: bool,
+    pub immutable: bool,
+    pub vary: Vec<String>,
+    // strong `ETag`s of the source and parameters, and `304 Not Modified` for `If-None-Match`
+    pub etag: bool,
+    pub disk: DiskCacheConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct DiskCacheConfig {
+    // rendered results are kept in this directory across restarts when set
+    pub dir: Option<PathBuf>,
+    // the least recently used results are evicted once more than this many bytes are kept
+    pub max_bytes: u64,
+    // seconds a result is served for after it's rendered, 0 keeping it until evicted
+    pub ttl: u64,
+}
+
+impl Default for DiskCacheConfig {
+    fn default() -> DiskCacheConfig {
+        DiskCacheConfig {
+            dir: None,
+            max_bytes: 256 * 1024 * 1024,
+            ttl: 24 * 3600,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            enabled: true,
+            max_age: 3600,
+            public: true,
+            immutable: false,
+            vary: vec![String::from("Accept")],
+            etag: true,
+            disk: DiskCacheConfig::default(),
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn cache_control(&self) -> String {
+        if !self.enabled || self.max_age == 0 {
+            return String::from("no-store");
+        }
+        let mut directives = vec![
+            String::from(if self.public { "public" } else { "private" }),
+            format!("max-age={}", self.max_age),
+        ];
+        if self.immutable {
+            directives.push(String::from("immutable"));
+        }
+        directives.join(", ")
+    }
+
+    pub fn expires(&self) -> Option<String> {
+        if !self.enabled || self.max_age == 0 {
+            return None;
+        }
+        let expires = SystemTime::now() + Duration::from_secs(self.max_age);
+        Some(httpdate::fmt_http_date(expires))
+    }
+}
+
+// Conversion parameters all live in the request URI, so they are already part of any cache key;
+// `Vary` only needs to cover the headers that change the representation (Accept by default).
+pub struct Cached<R> {
+    // none for `304 Not Modified`
+    inner: Option<R>,
+    config: CacheConfig,
+    etag: Option<String>,
+}
+
+impl<R> Cached<R> {
+    pub fn new(inner: R, config: &CacheConfig) -> Cached<R> {
+        Cached {
+            inner: Some(inner),
+            config: config.clone(),
+            etag: None,
+        }
+    }
+
+    // Answers with `304 Not Modified` and the caching headers alone, for a client that already has
+    // the response.
+    pub fn not_modified(config: &CacheConfig) -> Cached<R> {
+        Cached {
+            inner: None,
+            config: config.clone(),
+            etag: None,
+        }
+    }
+
+    pub fn with_etag(mut self, etag: Option<String>) -> Cached<R> {
+        self.etag = etag;
+        self
+    }
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Cached<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        let mut response = match self.inner {
+            Some(inner) => inner.respond_to(request)?,
+            None => Response::build().status(Status::NotModified).finalize(),
+        };
+        if response.status().class().is_success() || response.status() == Status::NotModified {
+            if let Some(etag) = self.etag {
+                response.set_header(Header::new("ETag", etag));
+            }
+            response.set_header(Header::new("Cache-Control", self.config.cache_control()));
+            if let Some(expires) = self.config.expires() {
+                response.set_header(Header::new("Expires", expires));
+            }
+            if !self.config.vary.is_empty() {
+                response.set_header(Header::new("Vary", self.config.vary.join(", ")));
+            }
+        }
+        Ok(response)
+    }
+}
+
+// The entity tags of the request's `If-None-Match` header.
+pub struct IfNoneMatch(Option<String>);
+
+impl IfNoneMatch {
+    // Whether the client already has the response tagged `etag`. Tags are compared weakly, as for
+    // any `If-None-Match`, so a `W/` prefix a proxy added doesn't stop the match.
+    pub fn matches(&self, etag: &str) -> bool {
+        self.0.as_deref().is_some_and(|header| {
+            header
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatch {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let header = req.headers().get_one("If-None-Match").map(String::from);
+        Outcome::Success(IfNoneMatch(header))
+    }
+}
+
+// A rendered result as the image URL route responds with it: the body, the resolved parameters,
+// and the source metadata and permalink when there are any.
+pub type CachedResult = (Body, String, Option<String>, Option<String>);
+
+// What's kept of a result besides its body, on the first line of its file.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Entry {
+    content_type: String,
+    resolved: String,
+    metadata: Option<String>,
+    permalink: Option<String>,
+}
+
+// Rendered results kept on disk by the hash of their source and parameters, so they survive
+// restarts. Each is a file of its own named by its key, written when it's rendered and accessed
+// whenever it's served, which is what expiry and eviction go by.
+pub struct DiskCache {
+    config: DiskCacheConfig,
+    // the bytes of every file in the directory, so it's only listed to evict
+    size: Mutex<u64>,
+}
+
+// The files in `dir` with their sizes and when they were last served, unfinished writes ignored.
+fn list_entries(dir: &Path) -> io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && entry.path().extension().is_none() {
+            let accessed = metadata.accessed().or_else(|_| metadata.modified())?;
+            entries.push((entry.path(), metadata.len(), accessed));
+        }
+    }
+    Ok(entries)
+}
+
+impl DiskCache {
+    pub fn open(config: DiskCacheConfig) -> DiskCache {
+        let size = match &config.dir {
+            Some(dir) => fs::create_dir_all(dir)
+                .and_then(|_| list_entries(dir))
+                .map(|entries| entries.iter().map(|(_, len, _)| len).sum())
+                .unwrap_or_else(|err| {
+                    warn!("could not open the disk cache in {:?}: {}", dir, err);
+                    0
+                }),
+            None => 0,
+        };
+        DiskCache {
+            config,
+            size: Mutex::new(size),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.dir.is_some()
+    }
+
+    fn is_expired(&self, written: SystemTime) -> bool {
+        self.config.ttl > 0
+            && written.elapsed().unwrap_or_default() > Duration::from_secs(self.config.ttl)
+    }
+
+    fn remove(&self, path: &Path, len: u64) {
+        if fs::remove_file(path).is_ok() {
+            let mut size = self.size.lock().unwrap();
+            *size = size.saturating_sub(len);
+        }
+    }
+
+    // The result kept under `key`, if it's there and hasn't expired.
+    pub fn get(&self, key: &str) -> Option<CachedResult> {
+        let path = self.config.dir.as_ref()?.join(key);
+        let metadata = fs::metadata(&path).ok()?;
+        if self.is_expired(metadata.modified().ok()?) {
+            self.remove(&path, metadata.len());
+            return None;
+        }
+        let data = fs::read(&path).ok()?;
+        let newline = data.iter().position(|&b| b == b'\n')?;
+        let entry: Entry = serde_json::from_slice(&data[..newline]).ok()?;
+        let content_type = ContentType::parse_flexible(&entry.content_type)?;
+        // served now, so last in line to be evicted
+        let accessed = FileTimes::new().set_accessed(SystemTime::now());
+        if let Err(err) = File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_times(accessed))
+        {
+            warn!("could not touch {:?}: {}", path, err);
+        }
+        let body = (content_type, data[newline + 1..].to_vec());
+        Some((body, entry.resolved, entry.metadata, entry.permalink))
+    }
+
+    // Keeps `result` under `key`, evicting the least recently served results past the size limit.
+    // Results bigger than the whole cache aren't kept.
+    pub fn put(&self, key: &str, result: &CachedResult) {
+        let dir = match &self.config.dir {
+            Some(dir) => dir,
+            None => return,
+        };
+        let ((content_type, body), resolved, metadata, permalink) = result;
+        let entry = Entry {
+            content_type: content_type.to_string(),
+            resolved: resolved.clone(),
+            metadata: metadata.clone(),
+            permalink: permalink.clone(),
+        };
+        let data = [
+            serde_json::to_vec(&entry).unwrap(),
+            vec![b'\n'],
+            body.clone(),
+        ]
+        .concat();
+        if data.len() as u64 > self.config.max_bytes {
+            return;
+        }
+        let (path, temporary) = (dir.join(key), dir.join(format!("{}.tmp", key)));
+        let replaced = fs::metadata(&path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        // written aside first, so a result is never read half written
+        if let Err(err) = fs::write(&temporary, &data).and_then(|_| fs::rename(&temporary, &path)) {
+            warn!("could not cache {:?}: {}", path, err);
+            return;
+        }
+        let mut size = self.size.lock().unwrap();
+        *size = size.saturating_sub(replaced) + data.len() as u64;
+        if *size > self.config.max_bytes {
+            match list_entries(dir) {
+                Ok(entries) => self.evict(&mut size, entries),
+                Err(err) => warn!("could not list the disk cache in {:?}: {}", dir, err),
+            }
+        }
+    }
+
+    // Removes the results that link to any of the permalinks `ids`, so purged conversions aren't
+    // served from disk again. Every file's first line is read, which is fine for takedowns.
+    pub fn purge(&self, ids: &[String]) {
+        let dir = match &self.config.dir {
+            Some(dir) if !ids.is_empty() => dir,
+            _ => return,
+        };
+        let entries = match list_entries(dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("could not list the disk cache in {:?}: {}", dir, err);
+                return;
+            }
+        };
+        let permalinks: Vec<String> = ids.iter().map(|id| format!("/r/{}", id)).collect();
+        for (path, len, _) in entries {
+            let permalink = File::open(&path).ok().and_then(|file| {
+                let mut line = String::new();
+                BufReader::new(file).read_line(&mut line).ok()?;
+                serde_json::from_str::<Entry>(&line).ok()?.permalink
+            });
+            if permalink.is_some_and(|permalink| permalinks.contains(&permalink)) {
+                self.remove(&path, len);
+            }
+        }
+    }
+
+    // Removes expired results, then the least recently served ones until the rest fit.
+    fn evict(&self, size: &mut u64, mut entries: Vec<(PathBuf, u64, SystemTime)>) {
+        entries.sort_by_key(|&(_, _, accessed)| accessed);
+        *size = entries.iter().map(|(_, len, _)| len).sum();
+        for (path, len, _) in entries {
+            let expired = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|written| self.is_expired(written));
+            if (expired || *size > self.config.max_bytes) && fs::remove_file(&path).is_ok() {
+                *size -= len;
+            }
+        }
+    }
+}
+
+// Manages the `DiskCache`, disabled unless `cache.disk.dir` is set.
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Disk cache", |rocket| async {
+        let config = rocket
+            .state::<Config>()
+            .map(|config| config.cache.disk.clone())
+            .unwrap_or_default();
+        rocket.manage(Arc::new(DiskCache::open(config)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{process, thread};
+
+    fn open(name: &str, max_bytes: u64) -> (DiskCache, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("disk-cache-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = DiskCacheConfig {
+            dir: Some(dir.clone()),
+            max_bytes,
+            ttl: 0,
+        };
+        (DiskCache::open(config), dir)
+    }
+
+    fn result(body: &[u8]) -> CachedResult {
+        (
+            (ContentType::Plain, body.to_vec()),
+            String::from("{}"),
+            None,
+            Some(String::from("/r/abc")),
+        )
+    }
+
+    #[test]
+    fn keeps_results() {
+        let (cache, dir) = open("keeps", 1 << 20);
+        assert!(cache.get("a").is_none());
+        cache.put("a", &result(b"hello"));
+        let ((content_type, body), resolved, metadata, permalink) = cache.get("a").unwrap();
+        assert_eq!(content_type, ContentType::Plain);
+        assert_eq!(body, b"hello");
+        assert_eq!(resolved, "{}");
+        assert_eq!(metadata, None);
+        assert_eq!(permalink.as_deref(), Some("/r/abc"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn evicts_the_least_recently_served() {
+        let (cache, dir) = open("evicts", 2500);
+        let body = vec![b'x'; 1000];
+        cache.put("a", &result(&body));
+        thread::sleep(Duration::from_millis(20));
+        cache.put("b", &result(&body));
+        thread::sleep(Duration::from_millis(20));
+        assert!(cache.get("a").is_some());
+        thread::sleep(Duration::from_millis(20));
+        cache.put("c", &result(&body));
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+        assert!(*cache.size.lock().unwrap() <= 2500);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skips_results_bigger_than_the_cache() {
+        let (cache, dir) = open("skips", 100);
+        cache.put("a", &result(&[b'x'; 200]));
+        assert!(cache.get("a").is_none());
+        assert_eq!(*cache.size.lock().unwrap(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn purges_results_linking_to_a_permalink() {
+        let (cache, dir) = open("purges", 1 << 20);
+        cache.put("a", &result(b"hello"));
+        let mut other = result(b"world");
+        other.3 = Some(String::from("/r/other"));
+        cache.put("b", &other);
+        cache.purge(&[String::from("abc")]);
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_some());
+        assert_eq!(
+            *cache.size.lock().unwrap(),
+            fs::metadata(dir.join("b")).unwrap().len()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn drops_expired_results() {
+        let (cache, dir) = open("expires", 1 << 20);
+        let cache = DiskCache {
+            config: DiskCacheConfig {
+                ttl: 60,
+                ..cache.config
+            },
+            ..cache
+        };
+        cache.put("a", &result(b"hello"));
+        assert!(cache.get("a").is_some());
+        let written = SystemTime::now() - Duration::from_secs(120);
+        File::options()
+            .write(true)
+            .open(dir.join("a"))
+            .and_then(|file| file.set_modified(written))
+            .unwrap();
+        assert!(cache.get("a").is_none());
+        assert!(!dir.join("a").exists());
+        assert_eq!(*cache.size.lock().unwrap(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn counts_what_was_kept_before_a_restart() {
+        let (cache, dir) = open("reopens", 1 << 20);
+        cache.put("a", &result(b"hello"));
+        let size = *cache.size.lock().unwrap();
+        // an unfinished write doesn't count
+        fs::write(dir.join("b.tmp"), b"partial").unwrap();
+        let cache = DiskCache::open(cache.config);
+        assert_eq!(*cache.size.lock().unwrap(), size);
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_nothing_without_a_dir() {
+        let cache = DiskCache::open(DiskCacheConfig::default());
+        assert!(!cache.enabled());
+        cache.put("a", &result(b"hello"));
+        assert!(cache.get("a").is_none());
+    }
+}
@@ -0,0 +1,400 @@
+use hmac::{Hmac, Mac};
+use log::warn;
+use reqwest::redirect::Policy;
+use reqwest::{Client, Url};
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::task::block_in_place;
+use rocket::tokio::time::sleep;
+use rocket::{get, State};
+use sha2::Sha256;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::auth::AdminAuth;
+use crate::config::Config;
+use crate::error::Error;
+use crate::fetch::HostPolicy;
+use crate::storage::Storage;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RETRY_DELAY_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct CallbacksConfig {
+    // deliveries are signed with this key, and callbacks refused while it isn't set
+    pub secret: Option<String>,
+    // pending deliveries and dead letters are kept under this key of the storage across restarts
+    // when set
+    pub path: Option<PathBuf>,
+    pub max_attempts: u32,
+    // seconds before the first retry, doubling with every further attempt
+    pub retry_delay: u64,
+    // the most deliveries kept waiting, further callbacks being refused, and the most dead letters
+    // kept, the oldest being dropped
+    pub max_pending: usize,
+    pub max_dead: usize,
+}
+
+impl Default for CallbacksConfig {
+    fn default() -> CallbacksConfig {
+        CallbacksConfig {
+            secret: None,
+            path: None,
+            max_attempts: 8,
+            retry_delay: 10,
+            max_pending: 1000,
+            max_dead: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Delivery {
+    id: String,
+    url: String,
+    body: String,
+    attempts: u32,
+    // unix time in seconds
+    next_attempt: u64,
+    last_error: Option<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+struct Queue {
+    pending: Vec<Delivery>,
+    dead: Vec<Delivery>,
+}
+
+// Callback URLs are held to the same hosts as sources, so they can't be made to probe the network
+// the API runs in either.
+pub struct Callbacks {
+    queue: Mutex<Queue>,
+    config: CallbacksConfig,
+    storage: Arc<dyn Storage>,
+    host_policy: HostPolicy,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn sign(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+impl Callbacks {
+    pub fn load(
+        config: CallbacksConfig,
+        storage: Arc<dyn Storage>,
+        host_policy: HostPolicy,
+    ) -> Callbacks {
+        let queue = config
+            .path
+            .as_ref()
+            .and_then(|path| storage.get(&path.to_string_lossy()).ok().flatten())
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default();
+        Callbacks {
+            queue: Mutex::new(queue),
+            config,
+            storage,
+            host_policy,
+        }
+    }
+
+    fn save(&self, queue: &Queue) {
+        if let Some(path) = &self.config.path {
+            let json = serde_json::to_vec(queue).unwrap();
+            if let Err(err) = self.storage.put(&path.to_string_lossy(), &json) {
+                warn!("could not save callbacks to {:?}: {}", path, err);
+            }
+        }
+    }
+
+    // Blocks on looking up the host.
+    pub fn check(&self, url: &str) -> Result<(), Error> {
+        if self.config.secret.is_none() {
+            return Err(Error::InvalidParameter("callback_url"));
+        }
+        let url = match Url::parse(url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+            _ => return Err(Error::InvalidParameter("callback_url")),
+        };
+        block_in_place(|| self.host_policy.check(&url))?;
+        if self.queue.lock().unwrap().pending.len() >= self.config.max_pending {
+            return Err(Status::ServiceUnavailable.into());
+        }
+        Ok(())
+    }
+
+    // Queues `body` to be POSTed to `url`, which must have passed `check`, and returns the
+    // delivery's id.
+    pub fn enqueue(&self, url: &str, body: String) -> Result<String, Error> {
+        let id = format!("{:016x}", rand::random::<u64>());
+        let mut queue = self.queue.lock().unwrap();
+        if queue.pending.len() >= self.config.max_pending {
+            return Err(Status::ServiceUnavailable.into());
+        }
+        queue.pending.push(Delivery {
+            id: id.clone(),
+            url: url.to_owned(),
+            body,
+            attempts: 0,
+            next_attempt: now(),
+            last_error: None,
+        });
+        self.save(&queue);
+        Ok(id)
+    }
+
+    fn due(&self) -> Vec<Delivery> {
+        let now = now();
+        let queue = self.queue.lock().unwrap();
+        queue
+            .pending
+            .iter()
+            .filter(|delivery| delivery.next_attempt <= now)
+            .cloned()
+            .collect()
+    }
+
+    // Drops a delivered callback, or schedules the next attempt with exponential backoff, moving it
+    // to the dead letters once it has used up `max_attempts`.
+    fn settle(&self, id: &str, error: Option<String>) {
+        let mut queue = self.queue.lock().unwrap();
+        let index = match queue.pending.iter().position(|delivery| delivery.id == id) {
+            Some(index) => index,
+            None => return,
+        };
+        match error {
+            None => {
+                queue.pending.remove(index);
+            }
+            Some(error) => {
+                let delivery = &mut queue.pending[index];
+                delivery.attempts += 1;
+                delivery.last_error = Some(error);
+                if delivery.attempts >= self.config.max_attempts {
+                    let delivery = queue.pending.remove(index);
+                    warn!("giving up on callback {} to {}", delivery.id, delivery.url);
+                    queue.dead.push(delivery);
+                    let excess = queue.dead.len().saturating_sub(self.config.max_dead);
+                    queue.dead.drain(..excess);
+                } else {
+                    let delay = self
+                        .config
+                        .retry_delay
+                        .saturating_mul(1 << (delivery.attempts - 1).min(32))
+                        .min(MAX_RETRY_DELAY_SECS);
+                    delivery.next_attempt = now() + delay;
+                }
+            }
+        }
+        self.save(&queue);
+    }
+
+    // A client connecting to the checked address of the callback's host, when there is one to
+    // keep to, and not following redirects, which could lead anywhere.
+    fn client(pinned: Option<(String, SocketAddr)>) -> Result<Client, String> {
+        let builder = Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .redirect(Policy::none());
+        match pinned {
+            Some((host, addr)) => builder.resolve(&host, addr),
+            None => builder,
+        }
+        .build()
+        .map_err(|err| err.to_string())
+    }
+
+    async fn deliver(&self, delivery: &Delivery) -> Option<String> {
+        let url = match Url::parse(&delivery.url) {
+            Ok(url) => url,
+            Err(err) => return Some(err.to_string()),
+        };
+        // checked again, as where the host resolves may have changed since it was queued
+        let client = match block_in_place(|| self.host_policy.check(&url)) {
+            Ok(pinned) => Self::client(pinned),
+            Err(err) => Err(err.to_string()),
+        };
+        let client = match client {
+            Ok(client) => client,
+            Err(err) => return Some(err),
+        };
+        let secret = self.config.secret.as_deref().unwrap_or_default();
+        let timestamp = now();
+        let response = client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .header("X-Callback-Id", &delivery.id)
+            .header("X-Callback-Timestamp", timestamp)
+            .header(
+                "X-Callback-Signature",
+                sign(secret, timestamp, &delivery.body),
+            )
+            .body(delivery.body.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match response {
+            Ok(response) if response.status().is_redirection() => {
+                Some(format!("redirected with {}", response.status()))
+            }
+            Ok(_) => None,
+            Err(err) => Some(err.to_string()),
+        }
+    }
+
+    async fn run(&self) {
+        loop {
+            for delivery in self.due() {
+                let error = self.deliver(&delivery).await;
+                self.settle(&delivery.id, error);
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+// Manages `Callbacks` (restoring the persisted queue) and delivers them in the background once
+// launched. Must be attached after the `Storage` fairing.
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Callbacks", |rocket| async {
+        let (config, host_policy) = rocket
+            .state::<Config>()
+            .map(|config| (config.callbacks.clone(), HostPolicy::new(&config.fetch)))
+            .unwrap_or_default();
+        let storage = match rocket.state::<Arc<dyn Storage>>() {
+            Some(storage) => storage.clone(),
+            None => return rocket,
+        };
+        rocket
+            .manage(Arc::new(Callbacks::load(config, storage, host_policy)))
+            .attach(AdHoc::on_liftoff("Deliver callbacks", |rocket| {
+                Box::pin(async move {
+                    if let Some(callbacks) = rocket.state::<Arc<Callbacks>>() {
+                        let callbacks = callbacks.clone();
+                        rocket::tokio::spawn(async move { callbacks.run().await });
+                    }
+                })
+            }))
+    })
+}
+
+// Callbacks that used up their attempts, newest last, with the error of the final attempt.
+#[get("/callbacks/dead")]
+pub fn dead_letters(_auth: AdminAuth, callbacks: &State<Arc<Callbacks>>) -> Json<Vec<Delivery>> {
+    Json(callbacks.queue.lock().unwrap().dead.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Memory;
+
+    #[test]
+    fn signs_the_timestamp_and_body() {
+        let signature = sign("whsec", 1700000000, r#"{"id":"abc"}"#);
+        assert_eq!(
+            signature,
+            "sha256=f191adf4630b87508831381399554ba7563de6dec8c6e912b662ac240c2aca13"
+        );
+    }
+
+    #[test]
+    fn signatures_depend_on_every_part() {
+        let signature = sign("whsec", 1700000000, "{}");
+        assert_ne!(signature, sign("other", 1700000000, "{}"));
+        assert_ne!(signature, sign("whsec", 1700000001, "{}"));
+        assert_ne!(signature, sign("whsec", 1700000000, "{ }"));
+    }
+
+    fn open(storage: Arc<dyn Storage>) -> Callbacks {
+        let config = CallbacksConfig {
+            secret: Some(String::from("whsec")),
+            path: Some(PathBuf::from("callbacks.json")),
+            max_attempts: 2,
+            retry_delay: 60,
+            max_pending: 2,
+            max_dead: 1,
+        };
+        Callbacks::load(config, storage, HostPolicy::default())
+    }
+
+    #[test]
+    fn refuses_callbacks_past_the_pending_limit() {
+        let callbacks = open(Arc::new(Memory::default()));
+        callbacks
+            .enqueue("https://example.com/a", String::new())
+            .unwrap();
+        callbacks
+            .enqueue("https://example.com/b", String::new())
+            .unwrap();
+        let refused = callbacks.enqueue("https://example.com/c", String::new());
+        assert!(
+            matches!(refused, Err(Error::Status(status)) if status == Status::ServiceUnavailable)
+        );
+    }
+
+    #[test]
+    fn retries_then_gives_up_keeping_the_latest_dead_letters() {
+        let storage: Arc<dyn Storage> = Arc::new(Memory::default());
+        let callbacks = open(storage.clone());
+        let first = callbacks
+            .enqueue("https://example.com/a", String::new())
+            .unwrap();
+        let second = callbacks
+            .enqueue("https://example.com/b", String::new())
+            .unwrap();
+        assert_eq!(callbacks.due().len(), 2);
+        callbacks.settle(&first, Some(String::from("503")));
+        // not due again until the retry delay is up
+        let due: Vec<String> = callbacks
+            .due()
+            .into_iter()
+            .map(|delivery| delivery.id)
+            .collect();
+        assert_eq!(due, [second.as_str()]);
+        callbacks.settle(&first, Some(String::from("503")));
+        callbacks.settle(&second, Some(String::from("timed out")));
+        callbacks.settle(&second, Some(String::from("timed out")));
+        let queue = callbacks.queue.lock().unwrap();
+        assert!(queue.pending.is_empty());
+        let dead: Vec<&str> = queue
+            .dead
+            .iter()
+            .map(|delivery| delivery.id.as_str())
+            .collect();
+        assert_eq!(dead, [second.as_str()]);
+        assert_eq!(queue.dead[0].last_error.as_deref(), Some("timed out"));
+        drop(queue);
+        // the queue is kept across restarts
+        let restored = open(storage);
+        assert_eq!(restored.queue.lock().unwrap().dead.len(), 1);
+    }
+
+    #[test]
+    fn drops_delivered_callbacks() {
+        let callbacks = open(Arc::new(Memory::default()));
+        let id = callbacks
+            .enqueue("https://example.com/a", String::new())
+            .unwrap();
+        callbacks.settle(&id, None);
+        assert!(callbacks.due().is_empty());
+        assert!(callbacks.queue.lock().unwrap().dead.is_empty());
+    }
+}
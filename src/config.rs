@@ -0,0 +1,84 @@
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::alphabets::AlphabetsConfig;
+use crate::auth::{AdminConfig, ApiKeysConfig};
+use crate::badge::BadgeConfig;
+use crate::blocklist::BlocklistConfig;
+use crate::cache::CacheConfig;
+use crate::callback::CallbacksConfig;
+use crate::fetch::FetchConfig;
+use crate::fonts::FontsConfig;
+use crate::gallery::GalleryConfig;
+use crate::live::LiveConfig;
+use crate::logging::LoggingConfig;
+use crate::permalink::PermalinkConfig;
+use crate::pools::PoolsConfig;
+use crate::profile::Profile;
+use crate::ratelimit::RateLimitConfig;
+use crate::slack::SlackConfig;
+use crate::stats::StatsConfig;
+use crate::storage::StorageConfig;
+use crate::telegram::TelegramConfig;
+use crate::warmup::WarmupConfig;
+
+// Read from Rocket.toml / ROCKET_* environment variables alongside Rocket's own settings.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct Config {
+    pub admin: AdminConfig,
+    pub alphabets: AlphabetsConfig,
+    pub api_keys: ApiKeysConfig,
+    pub badge: BadgeConfig,
+    pub blocklist: BlocklistConfig,
+    pub cache: CacheConfig,
+    pub callbacks: CallbacksConfig,
+    pub conversion_limits: LimitsConfig,
+    // what conversions fall back to where neither the request nor its profile sets a value
+    pub defaults: Profile,
+    pub fetch: FetchConfig,
+    pub fonts: FontsConfig,
+    pub gallery: GalleryConfig,
+    pub live: LiveConfig,
+    pub logging: LoggingConfig,
+    pub permalinks: PermalinkConfig,
+    pub profiles: HashMap<String, Profile>,
+    pub rate_limit: RateLimitConfig,
+    pub slack: SlackConfig,
+    pub stats: StatsConfig,
+    pub storage: StorageConfig,
+    pub telegram: TelegramConfig,
+    pub pools: PoolsConfig,
+    pub warmup: WarmupConfig,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct LimitsConfig {
+    // requested widths are clamped between these
+    pub min_width: usize,
+    pub max_width: usize,
+    pub max_frames: usize,
+    pub max_source_bytes: u64,
+    // the most frames per second `fps`, `sample_fps` and `interpolate_fps` may ask for
+    pub max_fps: f64,
+    // the most threads a single conversion may match glyphs on
+    pub max_threads: usize,
+    // how many times longer than wide, or wider than long, a source may be unless it's tiled, 0
+    // for any
+    pub max_aspect_ratio: f64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        LimitsConfig {
+            min_width: 1,
+            max_width: 500,
+            max_frames: 500,
+            max_source_bytes: 20 * 1024 * 1024,
+            max_fps: 100.,
+            max_threads: 1,
+            max_aspect_ratio: 10.,
+        }
+    }
+}
@@ -1,20 +1,30 @@
-use colored::Colorize;
+use colored::Color;
 use rand::prelude::ThreadRng;
 use rand::{thread_rng, Rng};
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::sync::{mpsc, Arc};
 use std::thread;
 
 use image::imageops::FilterType::{self, Triangle};
-use image::{DynamicImage, GenericImageView, GrayImage, Luma, Rgb, RgbImage};
+use image::{DynamicImage, GenericImageView, GrayImage, Luma, Rgb, RgbImage, Rgba};
 
 use crate::font::Font;
 use crate::metrics::{
-    avg_color_score, dot_score, jaccard_score, movement_toward_clear, occlusion_score, Metric,
+    avg_color_score, dot_score, jaccard_score, movement_toward_clear, occlusion_score, ssim_score,
+    Metric,
 };
+use crate::spans;
 
 pub type Converter = fn(&Font, &[f32], &mut ThreadRng, f32) -> char;
 
+// Dominant colors are picked from a thumbnail of at most this many pixels on a side.
+const THUMBNAIL_SIZE: u32 = 64;
+// How many dominant colors a theme picks its background and foreground from.
+const THEME_COLORS: usize = 8;
+// The least luma difference at which the lightest dominant color still reads against the darkest.
+const THEME_CONTRAST: f32 = 96.;
+
 pub fn score_convert(
     score_fn: Metric,
     font: &Font,
@@ -62,6 +72,10 @@ pub fn clear_convert(font: &Font, chunk: &[f32], rng: &mut ThreadRng, noise_scal
     score_convert(movement_toward_clear, font, chunk, rng, noise_scale)
 }
 
+pub fn ssim_convert(font: &Font, chunk: &[f32], rng: &mut ThreadRng, noise_scale: f32) -> char {
+    score_convert(ssim_score, font, chunk, rng, noise_scale)
+}
+
 pub fn fast_convert(font: &Font, chunk: &[f32], rng: &mut ThreadRng, noise_scale: f32) -> char {
     let intensity = chunk.iter().sum::<f32>();
     let noise = rng.gen::<f32>() * noise_scale;
@@ -69,6 +83,62 @@ pub fn fast_convert(font: &Font, chunk: &[f32], rng: &mut ThreadRng, noise_scale
     font.intensity_chars[index].value
 }
 
+// Like `fast`, for chunks of the CIE lightness `perceptual_lightness` converts images to.
+pub fn luma_convert(font: &Font, chunk: &[f32], rng: &mut ThreadRng, noise_scale: f32) -> char {
+    let intensity = chunk.iter().sum::<f32>();
+    let noise = rng.gen::<f32>() * noise_scale;
+    let index = ((intensity + noise).round().max(0.) as usize).min(font.intensity_chars.len() - 1);
+    font.intensity_chars[index].value
+}
+
+// Every pixel's CIE lightness, from its luminance in linear light with Rec. 709's coefficients.
+// Averaging sRGB values as they are crowds dark tones into a few levels; lightness spaces them
+// as evenly as they're perceived.
+pub fn perceptual_lightness(img: &DynamicImage) -> DynamicImage {
+    let linear: Vec<f32> = (0..=255)
+        .map(|v| {
+            let v = v as f32 / 255.;
+            if v <= 0.04045 {
+                v / 12.92
+            } else {
+                ((v + 0.055) / 1.055).powf(2.4)
+            }
+        })
+        .collect();
+    let rgb = img.to_rgb8();
+    DynamicImage::ImageLuma8(GrayImage::from_fn(rgb.width(), rgb.height(), |x, y| {
+        let Rgb([r, g, b]) = *rgb.get_pixel(x, y);
+        let luminance =
+            0.2126 * linear[r as usize] + 0.7152 * linear[g as usize] + 0.0722 * linear[b as usize];
+        let lightness = if luminance > 216. / 24389. {
+            116. * luminance.cbrt() - 16.
+        } else {
+            luminance * 24389. / 27.
+        };
+        Luma([(lightness / 100. * 255.).round().clamp(0., 255.) as u8])
+    }))
+}
+
+// Stretches every channel's contrast about mid-gray, then applies `gamma` (brightening above 1),
+// and with `invert` takes the negative.
+pub fn adjust_tone(img: &DynamicImage, contrast: f32, gamma: f32, invert: bool) -> DynamicImage {
+    let curve: Vec<u8> = (0..=255)
+        .map(|v| {
+            let v = ((v as f32 / 255. - 0.5) * contrast + 0.5).clamp(0., 1.);
+            let v = v.powf(1. / gamma);
+            let v = if invert { 1. - v } else { v };
+            (v * 255.).round() as u8
+        })
+        .collect();
+    let mut rgba = img.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        for channel in &mut pixel.0[..3] {
+            *channel = curve[*channel as usize];
+        }
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
 pub fn grad_convert(font: &Font, chunk: &[f32], rng: &mut ThreadRng, noise_scale: f32) -> char {
     let max_gradient = (font.width * font.height * 4) as f32; // gradient should never be bigger than this
 
@@ -112,6 +182,18 @@ pub fn grad_convert(font: &Font, chunk: &[f32], rng: &mut ThreadRng, noise_scale
         .value
 }
 
+pub const METRICS: [&str; 9] = [
+    "dot",
+    "jaccard",
+    "occlusion",
+    "color",
+    "clear",
+    "fast",
+    "grad",
+    "ssim",
+    "luma",
+];
+
 pub fn get_converter(metric: &str) -> Converter {
     let convert: Option<Converter> = match &metric[..] {
         "dot" => Some(dot_convert),
@@ -121,6 +203,8 @@ pub fn get_converter(metric: &str) -> Converter {
         "clear" => Some(clear_convert),
         "fast" => Some(fast_convert),
         "grad" => Some(grad_convert),
+        "ssim" => Some(ssim_convert),
+        "luma" => Some(luma_convert),
         _ => None,
     };
     convert.expect(&format!("Unsupported metric {}", metric))
@@ -211,58 +295,289 @@ pub fn chunks_to_chars(
     chars
 }
 
-pub fn img_to_char_rows(
-    font: &Font,
-    img: &DynamicImage,
-    convert: Converter,
+// Output size in characters; the height preserves the image's aspect ratio given the font's cells.
+pub fn char_dimensions(font: &Font, dimensions: (u32, u32), out_width: usize) -> (usize, usize) {
+    grid_dimensions(dimensions, out_width, None, font_aspect(font))
+}
+
+// How many times taller than wide the font's cells are.
+pub fn font_aspect(font: &Font) -> f64 {
+    font.height as f64 / font.width as f64
+}
+
+// The grid `out_width` characters wide that keeps the image's proportions where every cell is shown
+// `aspect` times as tall as it's wide, e.g. in a terminal whose cells aren't the font's. When that
+// comes to more than `out_height` rows, the height constrains the grid instead and it's narrowed.
+pub fn grid_dimensions(
+    (width, height): (u32, u32),
     out_width: usize,
-    brightness_offset: f32,
-    noise_scale: f32,
-    n_threads: usize,
-    edge_detection: bool,
-) -> Vec<Vec<char>> {
-    let (width, height) = img.dimensions();
+    out_height: Option<usize>,
+    aspect: f64,
+) -> (usize, usize) {
+    let rows = |columns: usize| height as f64 * (columns as f64 / width as f64) / aspect;
+    let fitted = (rows(out_width).round() as usize).max(1);
+    match out_height {
+        Some(out_height) if fitted > out_height => {
+            let columns = width as f64 * (out_height as f64 * aspect / height as f64);
+            let columns = (columns.round() as usize).clamp(1, out_width);
+            (columns, out_height)
+        }
+        _ => (out_width, fitted),
+    }
+}
 
-    let out_height = (height as f64
-        * (out_width as f64 / width as f64)
-        * (font.width as f64 / font.height as f64))
-        .round() as usize;
+pub fn resize_to_cells(font: &Font, img: &DynamicImage, out_width: usize) -> DynamicImage {
+    resize_to_grid(
+        font,
+        img,
+        char_dimensions(font, img.dimensions(), out_width),
+    )
+}
 
+// `img` resized to exactly `width` x `height`, left as it is when it's that size already, so a part
+// cut from an image resized for a whole grid converts as it would within it.
+fn resized_exact(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+) -> Cow<'_, DynamicImage> {
+    if img.dimensions() == (width, height) {
+        Cow::Borrowed(img)
+    } else {
+        Cow::Owned(img.resize_exact(width, height, filter))
+    }
+}
+
+// Like `resize_to_cells`, for an explicit grid of `out_width` x `out_height` characters.
+pub fn resize_to_grid(
+    font: &Font,
+    img: &DynamicImage,
+    (out_width, out_height): (usize, usize),
+) -> DynamicImage {
     let (out_img_width, out_img_height) = (out_width * font.width, out_height * font.height);
-    let resized_image = img.resize_exact(
+    resized_exact(
+        img,
         out_img_width as u32,
         out_img_height as u32,
         FilterType::Nearest,
-    );
+    )
+    .into_owned()
+}
+
+pub fn edges_to_cells(font: &Font, img: &DynamicImage, out_width: usize) -> DynamicImage {
+    edges_to_grid(
+        font,
+        img,
+        char_dimensions(font, img.dimensions(), out_width),
+    )
+}
+
+pub fn edges_to_grid(
+    font: &Font,
+    img: &DynamicImage,
+    (out_width, out_height): (usize, usize),
+) -> DynamicImage {
+    let (out_img_width, out_img_height) = (out_width * font.width, out_height * font.height);
+    img.filter3x3(&[0., -1., 0., -1., 4., -1., 0., -1., 0.])
+        .resize_exact(out_img_width as u32, out_img_height as u32, Triangle) // this resize is critical!
+}
+
+// Error diffusion applied to luminance before characters are picked, against the banding of
+// gradients. Atkinson only spreads three quarters of the error, keeping more contrast.
+pub const DITHERS: [&str; 2] = ["floyd-steinberg", "atkinson"];
+
+// Where the error of a pixel goes, as (dx, dy, share) of its neighbors yet to be quantized.
+fn diffusion_kernel(dither: &str) -> &'static [(isize, usize, f32)] {
+    match dither {
+        "floyd-steinberg" => &[
+            (1, 0, 7. / 16.),
+            (-1, 1, 3. / 16.),
+            (0, 1, 5. / 16.),
+            (1, 1, 1. / 16.),
+        ],
+        "atkinson" => &[
+            (1, 0, 1. / 8.),
+            (2, 0, 1. / 8.),
+            (-1, 1, 1. / 8.),
+            (0, 1, 1. / 8.),
+            (1, 1, 1. / 8.),
+            (0, 2, 1. / 8.),
+        ],
+        _ => panic!("Unsupported dither {}", dither),
+    }
+}
+
+// Quantizes `values`, intensities between 0 and 1 laid out row-major `width` wide, to `levels`
+// evenly spaced ones, diffusing each pixel's error onto its neighbors.
+pub fn dither(values: &mut [f32], width: usize, levels: usize, dither: &str) {
+    let kernel = diffusion_kernel(dither);
+    let steps = (levels.max(2) - 1) as f32;
+    let height = values.len() / width;
+    for y in 0..height {
+        for x in 0..width {
+            let old = values[y * width + x].clamp(0., 1.);
+            let new = (old * steps).round() / steps;
+            values[y * width + x] = new;
+            for &(dx, dy, share) in kernel {
+                let (nx, ny) = (x as isize + dx, y + dy);
+                if nx >= 0 && (nx as usize) < width && ny < height {
+                    values[ny * width + nx as usize] += (old - new) * share;
+                }
+            }
+        }
+    }
+}
+
+// The per-pixel intensities the converters actually see, laid out row-major at cell resolution.
+pub fn img_to_pixels(
+    font: &Font,
+    img: &DynamicImage,
+    out_width: usize,
+    brightness_offset: f32,
+    edge_detection: bool,
+) -> Vec<f32> {
+    let grid = char_dimensions(font, img.dimensions(), out_width);
+    grid_to_pixels(font, img, grid, brightness_offset, edge_detection, None)
+}
+
+// With `dither`, intensities are diffused to black and white, which is all glyphs are drawn in.
+pub fn grid_to_pixels(
+    font: &Font,
+    img: &DynamicImage,
+    grid: (usize, usize),
+    brightness_offset: f32,
+    edge_detection: bool,
+    dither: Option<&str>,
+) -> Vec<f32> {
+    let resized_image = {
+        let _span = spans::span("resize");
+        resize_to_grid(font, img, grid)
+    };
+    let edge_detected = edge_detection.then(|| {
+        let _span = spans::span("edges");
+        edges_to_grid(font, img, grid)
+    });
+    let mut pixels = resized_to_pixels(&resized_image, edge_detected.as_ref(), brightness_offset);
+    if let Some(kernel) = dither {
+        self::dither(&mut pixels, grid.0 * font.width, 2, kernel);
+    }
+    pixels
+}
 
-    let pixels: Vec<f32> = if edge_detection {
-        let edge_detected = img
-            .filter3x3(&[0., -1., 0., -1., 4., -1., 0., -1., 0.])
-            .resize_exact(out_img_width as u32, out_img_height as u32, Triangle); // this resize is critical!
-        resized_image
+// The intensities of `resized_image`, a source already resized to a grid's pixels, with the edges
+// detected at the same size added in when there are any.
+pub fn resized_to_pixels(
+    resized_image: &DynamicImage,
+    edge_detected: Option<&DynamicImage>,
+    brightness_offset: f32,
+) -> Vec<f32> {
+    match edge_detected {
+        Some(edge_detected) => resized_image
             .to_luma8()
             .pixels()
             .zip(edge_detected.to_luma8().pixels())
             .map(|(&Luma([a]), &Luma([b]))| {
                 (a as f32 / 4. + b as f32 - brightness_offset) as f32 / 255.
             })
-            .collect()
-    } else {
-        resized_image
+            .collect(),
+        None => resized_image
             .to_luma8()
             .pixels()
             .map(|&Luma([x])| (x as f32 - brightness_offset) / 255.)
-            .collect()
-    };
+            .collect(),
+    }
+}
 
-    let chunks = pixels_to_chunks(
+// How an image is prepared and its cells matched to glyphs, whatever the grid's size.
+#[derive(Debug, Clone, Copy)]
+pub struct MatchOptions<'a> {
+    pub brightness_offset: f32,
+    pub noise_scale: f32,
+    pub n_threads: usize,
+    pub edge_detection: bool,
+    pub dither: Option<&'a str>,
+}
+
+impl Default for MatchOptions<'_> {
+    fn default() -> Self {
+        MatchOptions {
+            brightness_offset: 0.,
+            noise_scale: 0.,
+            n_threads: 1,
+            edge_detection: true,
+            dither: None,
+        }
+    }
+}
+
+pub fn img_to_char_rows(
+    font: &Font,
+    img: &DynamicImage,
+    convert: Converter,
+    out_width: usize,
+    options: MatchOptions,
+) -> Vec<Vec<char>> {
+    let grid = char_dimensions(font, img.dimensions(), out_width);
+    img_to_char_rows_sized(font, img, convert, grid, options)
+}
+
+// Like `img_to_char_rows`, but stretches the image over an explicit grid of characters, so callers
+// can convert horizontal bands of an image separately and still line them up.
+pub fn img_to_char_rows_sized(
+    font: &Font,
+    img: &DynamicImage,
+    convert: Converter,
+    (out_width, out_height): (usize, usize),
+    options: MatchOptions,
+) -> Vec<Vec<char>> {
+    let MatchOptions {
+        brightness_offset,
+        noise_scale,
+        n_threads,
+        edge_detection,
+        dither,
+    } = options;
+    let pixels = grid_to_pixels(
+        font,
+        img,
+        (out_width, out_height),
+        brightness_offset,
+        edge_detection,
+        dither,
+    );
+    pixels_to_char_rows(
+        font,
         &pixels,
+        (out_width, out_height),
+        convert,
+        noise_scale,
+        n_threads,
+    )
+}
+
+// Matches a glyph to every cell of `pixels`, the intensities of a grid of `out_width` x `out_height`
+// characters.
+pub fn pixels_to_char_rows(
+    font: &Font,
+    pixels: &[f32],
+    (out_width, out_height): (usize, usize),
+    convert: Converter,
+    noise_scale: f32,
+    n_threads: usize,
+) -> Vec<Vec<char>> {
+    let (out_img_width, out_img_height) = (out_width * font.width, out_height * font.height);
+    let chunks = pixels_to_chunks(
+        pixels,
         out_img_width as usize,
         out_img_height as usize,
         font.width,
         font.height,
     );
-    let chars = chunks_to_chars(font, &chunks, convert, noise_scale, n_threads);
+    let chars = {
+        let _span = spans::span("match");
+        chunks_to_chars(font, &chunks, convert, noise_scale, n_threads)
+    };
 
     (0..out_height * out_width)
         .step_by(out_width)
@@ -270,6 +585,460 @@ pub fn img_to_char_rows(
         .collect()
 }
 
+// Classic ASCII art: each cell's average luminance picks a character from the alphabet ordered by how
+// much ink its glyph has, ignoring the glyph shapes.
+pub fn img_to_grayscale_rows(
+    font: &Font,
+    img: &DynamicImage,
+    (out_width, out_height): (usize, usize),
+    brightness_offset: f32,
+    dither: Option<&str>,
+) -> Vec<Vec<char>> {
+    let mut chars: Vec<(f32, char)> = font
+        .intensities
+        .iter()
+        .cloned()
+        .zip(font.chars.iter().map(|c| c.value))
+        .collect();
+    chars.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap());
+
+    let resized_image = img
+        .resize_exact(out_width as u32, out_height as u32, Triangle)
+        .to_luma8();
+    let mut values: Vec<f32> = resized_image
+        .pixels()
+        .map(|&Luma([x])| ((x as f32 - brightness_offset) / 255.).clamp(0., 1.))
+        .collect();
+    // diffused between neighboring characters of the ramp rather than snapped to the nearest
+    if let Some(kernel) = dither {
+        self::dither(&mut values, out_width, chars.len(), kernel);
+    }
+    values
+        .chunks(out_width)
+        .map(|row| {
+            row.iter()
+                .map(|value| chars[(value * (chars.len() - 1) as f32).round() as usize].1)
+                .collect()
+        })
+        .collect()
+}
+
+pub const FULL_BLOCK: char = '█';
+
+// Skips glyph matching entirely: every cell brighter than `threshold` becomes a full block, which
+// colored output then paints with the cell's sampled color.
+pub fn img_to_block_rows(
+    img: &DynamicImage,
+    (out_width, out_height): (usize, usize),
+    brightness_offset: f32,
+    threshold: f32,
+) -> Vec<Vec<char>> {
+    let resized_image = img
+        .resize_exact(out_width as u32, out_height as u32, FilterType::Nearest)
+        .to_luma8();
+    resized_image
+        .rows()
+        .map(|row| {
+            row.map(|&Luma([x])| {
+                if (x as f32 - brightness_offset) / 255. > threshold {
+                    FULL_BLOCK
+                } else {
+                    ' '
+                }
+            })
+            .collect()
+        })
+        .collect()
+}
+
+// Block characters by the quadrants of the cell they fill, one bit each for the top left, top
+// right, bottom left and bottom right quadrant, lowest first.
+pub const QUADRANT_BLOCKS: [char; 16] = [
+    ' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛', '▗', '▚', '▐', '▜', '▄', '▙', '▟', FULL_BLOCK,
+];
+// What `halfblocks` picks from: a full block or the top half, either half being the complement of
+// the other.
+const HALF_BLOCK_MASKS: [usize; 2] = [15, 3];
+// Every split of a cell into two colors, the full block first so flat cells stay solid.
+const QUADRANT_MASKS: [usize; 8] = [15, 1, 2, 3, 4, 5, 6, 7];
+
+fn quadrant_mask(chr: char) -> Option<usize> {
+    QUADRANT_BLOCKS.iter().position(|&block| block == chr)
+}
+
+// The colors of a cell's quadrants, in the bit order of `QUADRANT_BLOCKS`, from an image of two
+// by two pixels per cell.
+fn cell_quadrants(quadrants: &RgbImage, i: usize, j: usize) -> [[u8; 3]; 4] {
+    [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(x, y)| {
+        quadrants
+            .get_pixel((i * 2 + x) as u32, (j * 2 + y) as u32)
+            .0
+    })
+}
+
+// The average color of the quadrants that are, or with `inside` false aren't, part of `mask`, or
+// of the whole cell when there are none.
+fn mask_color(colors: &[[u8; 3]; 4], mask: usize, inside: bool) -> [u8; 3] {
+    let chosen: Vec<&[u8; 3]> = (0..4)
+        .filter(|&q| (mask >> q & 1 == 1) == inside)
+        .map(|q| &colors[q])
+        .collect();
+    let chosen = if chosen.is_empty() {
+        colors.iter().collect()
+    } else {
+        chosen
+    };
+    [0, 1, 2].map(|k| {
+        (chosen.iter().map(|color| color[k] as u32).sum::<u32>() / chosen.len() as u32) as u8
+    })
+}
+
+// The block modes: each cell is split into two colors by a block character, the glyph in the
+// foreground color and the rest of the cell in the background color, doubling the vertical
+// resolution with `halves` or both with quadrants. Without color, a part is filled where it's
+// brighter than `threshold`; otherwise the split that keeps each part closest to its average color
+// wins, see `block_colors`.
+pub fn img_to_quadrant_rows(
+    img: &DynamicImage,
+    (out_width, out_height): (usize, usize),
+    brightness_offset: f32,
+    halves: bool,
+    threshold: Option<f32>,
+) -> Vec<Vec<char>> {
+    let quadrants = img
+        .resize_exact(out_width as u32 * 2, out_height as u32 * 2, Triangle)
+        .to_rgb8();
+    let masks: &[usize] = if halves {
+        &HALF_BLOCK_MASKS
+    } else {
+        &QUADRANT_MASKS
+    };
+    (0..out_height)
+        .map(|j| {
+            (0..out_width)
+                .map(|i| {
+                    let colors = cell_quadrants(&quadrants, i, j);
+                    let mask = match threshold {
+                        Some(threshold) => {
+                            let lit = |mask: usize| {
+                                luma(&mask_color(&colors, mask, true)) - brightness_offset
+                                    > threshold * 255.
+                            };
+                            if halves {
+                                [3, 12].into_iter().filter(|&half| lit(half)).sum()
+                            } else {
+                                (0..4).filter(|&q| lit(1 << q)).map(|q| 1 << q).sum()
+                            }
+                        }
+                        None => *masks
+                            .iter()
+                            .min_by(|&&a, &&b| {
+                                split_error(&colors, a).total_cmp(&split_error(&colors, b))
+                            })
+                            .unwrap(),
+                    };
+                    QUADRANT_BLOCKS[mask]
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn split_error(colors: &[[u8; 3]; 4], mask: usize) -> f32 {
+    let (fg, bg) = (
+        mask_color(colors, mask, true),
+        mask_color(colors, mask, false),
+    );
+    (0..4)
+        .map(|q| color_distance(&colors[q], if mask >> q & 1 == 1 { &fg } else { &bg }))
+        .sum()
+}
+
+// The foreground and background color of every cell of the block modes, one pixel per cell: the
+// average colors of the quadrants the cell's block character fills and of the rest. Cells holding
+// anything else get their average color for both.
+pub fn block_colors(char_rows: &[Vec<char>], img: &DynamicImage) -> (DynamicImage, DynamicImage) {
+    let (n_cols, n_rows) = (char_rows[0].len(), char_rows.len());
+    let quadrants = img
+        .resize_exact(n_cols as u32 * 2, n_rows as u32 * 2, Triangle)
+        .to_rgb8();
+    let mut foreground = RgbImage::new(n_cols as u32, n_rows as u32);
+    let mut background = RgbImage::new(n_cols as u32, n_rows as u32);
+    for (j, row) in char_rows.iter().enumerate() {
+        for (i, &c) in row.iter().enumerate() {
+            let colors = cell_quadrants(&quadrants, i, j);
+            let mask = quadrant_mask(c).unwrap_or(15);
+            foreground.put_pixel(i as u32, j as u32, Rgb(mask_color(&colors, mask, true)));
+            background.put_pixel(i as u32, j as u32, Rgb(mask_color(&colors, mask, false)));
+        }
+    }
+    (
+        DynamicImage::ImageRgb8(foreground),
+        DynamicImage::ImageRgb8(background),
+    )
+}
+
+// The braille pattern without any raised dots; the other 255 patterns follow it, one bit per dot.
+pub const BRAILLE_BLANK: char = '\u{2800}';
+// The bit of each dot of a braille cell by column and row, after Unicode's numbering of the dots.
+const BRAILLE_DOTS: [[u32; 4]; 2] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+
+// Renders each cell as a braille pattern of two by four dots, for eight times the resolution of
+// one character per cell. A dot is raised where the image is brighter than `threshold`, or, without
+// one, where it's lit and at least as bright as the average of its cell, leaving the cell's
+// brightness to colored output.
+pub fn img_to_braille_rows(
+    img: &DynamicImage,
+    (out_width, out_height): (usize, usize),
+    brightness_offset: f32,
+    threshold: Option<f32>,
+) -> Vec<Vec<char>> {
+    let resized_image = img
+        .resize_exact(out_width as u32 * 2, out_height as u32 * 4, Triangle)
+        .to_luma8();
+    // brightness out of 255, kept whole so that flat cells compare equal to their average
+    let value = |x: usize, y: usize| {
+        let Luma([v]) = resized_image.get_pixel(x as u32, y as u32);
+        (*v as f32 - brightness_offset).round() as i32
+    };
+    (0..out_height)
+        .map(|j| {
+            (0..out_width)
+                .map(|i| {
+                    let dots: Vec<(usize, usize, i32)> = (0..2)
+                        .flat_map(|x| (0..4).map(move |y| (x, y)))
+                        .map(|(x, y)| (x, y, value(i * 2 + x, j * 4 + y)))
+                        .collect();
+                    let sum: i32 = dots.iter().map(|&(_, _, v)| v).sum();
+                    let bits = dots
+                        .iter()
+                        .filter(|&&(_, _, v)| match threshold {
+                            Some(threshold) => v as f32 / 255. > threshold,
+                            None => v > 0 && v * 8 >= sum,
+                        })
+                        .fold(0, |bits, &(x, y, _)| bits | BRAILLE_DOTS[x][y]);
+                    char::from_u32(BRAILLE_BLANK as u32 + bits).unwrap()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Shade characters by the fraction of the cell they cover, spread evenly over it.
+const SHADES: [(char, f32); 3] = [('░', 0.25), ('▒', 0.5), ('▓', 0.75)];
+
+// Box-drawing lines by the arms they draw out from the middle of the cell, one bit each for up,
+// right, down and left, lowest first.
+const BOX_LINES: [(char, usize); 11] = [
+    ('─', 0b1010),
+    ('│', 0b0101),
+    ('┌', 0b0110),
+    ('┐', 0b1100),
+    ('└', 0b0011),
+    ('┘', 0b1001),
+    ('├', 0b0111),
+    ('┤', 0b1101),
+    ('┬', 0b1110),
+    ('┴', 0b1011),
+    ('┼', 0b1111),
+];
+
+// Diagonal box-drawing lines, by whether they rise to the right and whether they fall to it.
+const BOX_DIAGONALS: [(char, bool, bool); 3] =
+    [('╱', true, false), ('╲', false, true), ('╳', true, true)];
+
+// The stroke of box-drawing lines, about as thick as a font's own strokes.
+fn box_stroke(width: usize, height: usize) -> usize {
+    (width.min(height) / 7).max(1)
+}
+
+// A `width` by `height` bitmap with `on` deciding the coverage of each pixel.
+fn draw_cell(width: usize, height: usize, on: impl Fn(usize, usize) -> f32) -> Vec<f32> {
+    (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| on(x, y))
+        .collect()
+}
+
+// Glyphs drawn at a cell size for characters fonts rarely have: the quadrant blocks of `pixels`
+// mode, the block modes and the `quadrants` alphabet, the shades and box-drawing lines of the
+// `shades` and `box` alphabets, and the braille patterns, each dot taking its eighth of the cell
+// less a one pixel gap where there's room.
+pub fn synthetic_glyph(chr: char, width: usize, height: usize) -> Option<Vec<f32>> {
+    if let Some(mask) = quadrant_mask(chr).filter(|&mask| mask > 0) {
+        let (half_width, half_height) = (width / 2, height / 2);
+        return Some(draw_cell(width, height, |x, y| {
+            let q = (x >= half_width) as usize + 2 * (y >= half_height) as usize;
+            (mask >> q & 1) as f32
+        }));
+    }
+    if let Some(&(_, coverage)) = SHADES.iter().find(|(shade, _)| *shade == chr) {
+        return Some(vec![coverage; width * height]);
+    }
+    let stroke = box_stroke(width, height);
+    if let Some(&(_, arms)) = BOX_LINES.iter().find(|(line, _)| *line == chr) {
+        let (left, top) = ((width - stroke) / 2, (height - stroke) / 2);
+        return Some(draw_cell(width, height, |x, y| {
+            let in_column = x >= left && x < left + stroke;
+            let in_row = y >= top && y < top + stroke;
+            let vertical =
+                in_column && (arms & 1 != 0 && y < top + stroke || arms & 4 != 0 && y >= top);
+            let horizontal =
+                in_row && (arms & 2 != 0 && x >= left || arms & 8 != 0 && x < left + stroke);
+            (vertical || horizontal) as u8 as f32
+        }));
+    }
+    if let Some(&(_, rising, falling)) = BOX_DIAGONALS.iter().find(|(line, _, _)| *line == chr) {
+        let run = (width.max(2) - 1) as f32 / (height.max(2) - 1) as f32;
+        return Some(draw_cell(width, height, |x, y| {
+            let across = y as f32 * run;
+            let near = |center: f32| (x as f32 - center).abs() * 2. < stroke as f32 + 0.5;
+            let rises = rising && near(width.saturating_sub(1) as f32 - across);
+            let falls = falling && near(across);
+            (rises || falls) as u8 as f32
+        }));
+    }
+    let bits = (chr as u32)
+        .checked_sub(BRAILLE_BLANK as u32)
+        .filter(|&bits| bits < 0x100)?;
+    let (dot_width, dot_height) = (width / 2, height / 4);
+    let mut bitmap = vec![0.; width * height];
+    for (x, column) in BRAILLE_DOTS.iter().enumerate() {
+        for (y, &bit) in column.iter().enumerate() {
+            if bits & bit == 0 {
+                continue;
+            }
+            for dy in 0..dot_height.saturating_sub(1).max(1) {
+                for dx in 0..dot_width.saturating_sub(1).max(1) {
+                    bitmap[(y * dot_height + dy) * width + x * dot_width + dx] = 1.;
+                }
+            }
+        }
+    }
+    Some(bitmap)
+}
+
+// Replaces cells with nothing to draw, either fully transparent or no brighter than black once the
+// brightness offset is applied, with `blank_char`.
+pub fn blank_cells(
+    char_rows: &mut [Vec<char>],
+    font: &Font,
+    img: &DynamicImage,
+    brightness_offset: f32,
+    blank_char: char,
+) {
+    let (out_width, out_height) = (char_rows[0].len(), char_rows.len());
+    let (out_img_width, out_img_height) = (out_width * font.width, out_height * font.height);
+    let resized_image = resized_exact(
+        img,
+        out_img_width as u32,
+        out_img_height as u32,
+        FilterType::Nearest,
+    )
+    .to_rgba8();
+    for (j, row) in char_rows.iter_mut().enumerate() {
+        for (i, c) in row.iter_mut().enumerate() {
+            let is_blank = (0..font.height).all(|y| {
+                (0..font.width).all(|x| {
+                    let pixel = resized_image
+                        .get_pixel((i * font.width + x) as u32, (j * font.height + y) as u32);
+                    let Rgba([r, g, b, a]) = *pixel;
+                    let luma = 0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32;
+                    a == 0 || luma - brightness_offset <= 0.
+                })
+            });
+            if is_blank {
+                *c = blank_char;
+            }
+        }
+    }
+}
+
+// The most common colors of `image`, most common first, each the average of the pixels that fall
+// into the same 4-bit-per-channel bucket.
+pub fn dominant_colors(image: &DynamicImage, n: usize) -> Vec<[u8; 3]> {
+    let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8();
+    let mut buckets: HashMap<[u8; 3], (u32, [u32; 3])> = HashMap::new();
+    for pixel in thumbnail.pixels() {
+        let (count, sums) = buckets.entry(pixel.0.map(|c| c >> 4)).or_default();
+        *count += 1;
+        for (sum, c) in sums.iter_mut().zip(pixel.0) {
+            *sum += c as u32;
+        }
+    }
+    let mut buckets: Vec<(u32, [u32; 3])> = buckets.into_values().collect();
+    buckets.sort_by(|(a, _), (b, _)| b.cmp(a));
+    buckets
+        .iter()
+        .take(n)
+        .map(|(count, sums)| sums.map(|sum| (sum / count) as u8))
+        .collect()
+}
+
+fn luma(&[r, g, b]: &[u8; 3]) -> f32 {
+    0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32
+}
+
+// Colors for the parts of rendered outputs that aren't sampled from the image: the background, and
+// the glyphs of uncolored output.
+#[derive(Debug, Clone, Copy)]
+pub struct Theme {
+    pub background: [u8; 3],
+    pub foreground: [u8; 3],
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme {
+            background: [0, 0, 0],
+            foreground: [255, 255, 255],
+        }
+    }
+}
+
+impl Theme {
+    pub fn light() -> Theme {
+        Theme {
+            background: [255, 255, 255],
+            foreground: [0, 0, 0],
+        }
+    }
+
+    // The same colors the other way around, for inverted output.
+    pub fn inverted(self) -> Theme {
+        Theme {
+            background: self.foreground,
+            foreground: self.background,
+        }
+    }
+
+    // The darkest of the image's dominant colors as background, and the lightest as foreground
+    // unless it is too close to tell apart, in which case plain white or black.
+    pub fn from_image(img: &DynamicImage) -> Theme {
+        let mut colors = dominant_colors(img, THEME_COLORS);
+        colors.sort_by(|a, b| luma(a).total_cmp(&luma(b)));
+        let background = colors.first().copied().unwrap_or([0, 0, 0]);
+        let foreground = match colors.last() {
+            Some(&lightest) if luma(&lightest) - luma(&background) >= THEME_CONTRAST => lightest,
+            _ if luma(&background) < 128. => [255, 255, 255],
+            _ => [0, 0, 0],
+        };
+        Theme {
+            background,
+            foreground,
+        }
+    }
+
+    pub fn stylesheet(&self) -> String {
+        let [r, g, b] = self.background;
+        let [fr, fg, fb] = self.foreground;
+        format!(
+            "body{{background-color:#{:02x}{:02x}{:02x};color:#{:02x}{:02x}{:02x}}}",
+            r, g, b, fr, fg, fb
+        )
+    }
+}
+
 pub fn char_rows_to_string(char_rows: &[Vec<char>]) -> String {
     char_rows
         .iter()
@@ -278,24 +1047,147 @@ pub fn char_rows_to_string(char_rows: &[Vec<char>]) -> String {
         .join("\n")
 }
 
-pub fn char_rows_to_terminal_color_string(char_rows: &[Vec<char>], img: &DynamicImage) -> String {
-    let (n_cols, n_rows) = (char_rows[0].len(), char_rows.len());
-    let color_resized_image = img
-        .resize_exact(n_cols as u32, n_rows as u32, FilterType::Nearest)
-        .to_rgb8();
+// How terminal output is colored: not at all, with the 16 standard colors, the xterm 256-color
+// palette, or 24-bit color. Colors outside the palette are snapped to its nearest one.
+pub const COLOR_MODES: [&str; 4] = ["none", "ansi16", "ansi256", "truecolor"];
 
-    let colored_strings: Vec<String> = char_rows
-        .into_iter()
-        .flatten()
-        .zip(color_resized_image.pixels())
-        .map(|(c, Rgb([r, g, b]))| format!("{}", c.to_string().truecolor(*r, *g, *b)))
-        .collect();
+// xterm's defaults for the 16 standard colors, which terminals mostly keep close to.
+const ANSI16_COLORS: [(Color, [u8; 3]); 16] = [
+    (Color::Black, [0, 0, 0]),
+    (Color::Red, [205, 0, 0]),
+    (Color::Green, [0, 205, 0]),
+    (Color::Yellow, [205, 205, 0]),
+    (Color::Blue, [0, 0, 238]),
+    (Color::Magenta, [205, 0, 205]),
+    (Color::Cyan, [0, 205, 205]),
+    (Color::White, [229, 229, 229]),
+    (Color::BrightBlack, [127, 127, 127]),
+    (Color::BrightRed, [255, 0, 0]),
+    (Color::BrightGreen, [0, 255, 0]),
+    (Color::BrightYellow, [255, 255, 0]),
+    (Color::BrightBlue, [92, 92, 255]),
+    (Color::BrightMagenta, [255, 0, 255]),
+    (Color::BrightCyan, [0, 255, 255]),
+    (Color::BrightWhite, [255, 255, 255]),
+];
 
-    (0..n_rows * n_cols)
-        .step_by(n_cols)
-        .map(|i| colored_strings[i..i + n_cols].join(""))
-        .collect::<Vec<String>>()
-        .join("\n")
+// Channel levels of the 6x6x6 color cube at indices 16 to 231 of the 256-color palette.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+// Squared distance between two colors, weighted by the mean red level so it tracks perceived
+// difference closer than plain RGB distance does.
+fn color_distance(&[r1, g1, b1]: &[u8; 3], &[r2, g2, b2]: &[u8; 3]) -> f32 {
+    let mean_r = (r1 as f32 + r2 as f32) / 2.;
+    let (dr, dg, db) = (
+        r1 as f32 - r2 as f32,
+        g1 as f32 - g2 as f32,
+        b1 as f32 - b2 as f32,
+    );
+    (2. + mean_r / 256.) * dr * dr + 4. * dg * dg + (2. + (255. - mean_r) / 256.) * db * db
+}
+
+fn nearest_ansi16(color: &[u8; 3]) -> Color {
+    ANSI16_COLORS
+        .iter()
+        .min_by(|(_, a), (_, b)| color_distance(color, a).total_cmp(&color_distance(color, b)))
+        .unwrap()
+        .0
+}
+
+// The nearest of the color cube and the 24 step gray ramp at indices 232 to 255. The first 16
+// entries are left out, as terminals commonly redefine them.
+fn nearest_ansi256(color: &[u8; 3]) -> u8 {
+    let level = |c: u8| {
+        (0..CUBE_LEVELS.len())
+            .min_by_key(|&i| CUBE_LEVELS[i].abs_diff(c))
+            .unwrap()
+    };
+    let [r, g, b] = color.map(level);
+    let cube = [CUBE_LEVELS[r], CUBE_LEVELS[g], CUBE_LEVELS[b]];
+    let mean = color.iter().map(|&c| c as u32).sum::<u32>() / 3;
+    let gray_index = (mean.saturating_sub(3) / 10).min(23) as u8;
+    let gray = [8 + 10 * gray_index; 3];
+    if color_distance(color, &gray) < color_distance(color, &cube) {
+        232 + gray_index
+    } else {
+        16 + 36 * r as u8 + 6 * g as u8 + b as u8
+    }
+}
+
+// The SGR parameters setting the foreground, or the background, to `color` in `color_mode`.
+fn terminal_color(Rgb(color): Rgb<u8>, color_mode: &str, background: bool) -> String {
+    let [r, g, b] = color;
+    let color = match color_mode {
+        "ansi16" => nearest_ansi16(&color),
+        "ansi256" => {
+            let layer = if background { 48 } else { 38 };
+            return format!("{};5;{}", layer, nearest_ansi256(&color));
+        }
+        "truecolor" => Color::TrueColor { r, g, b },
+        _ => panic!("Unsupported color mode {}", color_mode),
+    };
+    if background {
+        color.to_bg_str().into_owned()
+    } else {
+        color.to_fg_str().into_owned()
+    }
+}
+
+// Consecutive characters share one color escape as long as they map to the same terminal color, or
+// their colors stay within `tolerance` of the run's first color on every channel. With
+// `backgrounds`, each cell is also drawn on its own background color, which runs have to share too.
+pub fn char_rows_to_terminal_color_string(
+    char_rows: &[Vec<char>],
+    img: &DynamicImage,
+    backgrounds: Option<&DynamicImage>,
+    tolerance: u8,
+    color_mode: &str,
+) -> String {
+    if color_mode == "none" {
+        return char_rows_to_string(char_rows);
+    }
+    let (n_cols, n_rows) = (char_rows[0].len(), char_rows.len());
+    let resize = |img: &DynamicImage| {
+        resized_exact(img, n_cols as u32, n_rows as u32, FilterType::Nearest).to_rgb8()
+    };
+    let color_resized_image = resize(img);
+    let background_resized_image = backgrounds.map(resize);
+    let paint = |run: &str, code: &str| format!("\x1b[{}m{}\x1b[0m", code, run);
+
+    let mut rows: Vec<String> = Vec::with_capacity(n_rows);
+    for (j, row) in char_rows.iter().enumerate() {
+        let mut colored_row = String::new();
+        let mut run = String::new();
+        let mut run_color: Option<(Vec<u8>, String)> = None;
+        for (i, &c) in row.iter().enumerate() {
+            let mut color = *color_resized_image.get_pixel(i as u32, j as u32);
+            let mut code = terminal_color(color, color_mode, false);
+            let mut channels = color.0.to_vec();
+            if let Some(backgrounds) = &background_resized_image {
+                color = *backgrounds.get_pixel(i as u32, j as u32);
+                code = format!("{};{}", code, terminal_color(color, color_mode, true));
+                channels.extend(color.0);
+            }
+            let in_run = matches!(&run_color, Some((run_channels, run_code)) if *run_code == code
+                || run_channels
+                    .iter()
+                    .zip(&channels)
+                    .all(|(a, b)| a.abs_diff(*b) <= tolerance));
+            if !in_run {
+                if let Some((_, run_code)) = &run_color {
+                    colored_row.push_str(&paint(&run, run_code));
+                }
+                run.clear();
+                run_color = Some((channels, code));
+            }
+            run.push(c);
+        }
+        if let Some((_, run_code)) = &run_color {
+            colored_row.push_str(&paint(&run, run_code));
+        }
+        rows.push(colored_row);
+    }
+    rows.join("\n")
 }
 
 pub fn char_rows_to_html_color_string(char_rows: &[Vec<char>], img: &DynamicImage) -> String {
@@ -323,16 +1215,212 @@ pub fn char_rows_to_html_color_string(char_rows: &[Vec<char>], img: &DynamicImag
         .join("\n")
 }
 
+// Like `char_rows_to_html_color_string`, with each character on its own background color from
+// `backgrounds`, for the block modes.
+pub fn char_rows_to_html_two_color_string(
+    char_rows: &[Vec<char>],
+    img: &DynamicImage,
+    backgrounds: &DynamicImage,
+) -> String {
+    let (n_cols, n_rows) = (char_rows[0].len(), char_rows.len());
+    let resize = |img: &DynamicImage| {
+        img.resize_exact(n_cols as u32, n_rows as u32, FilterType::Nearest)
+            .to_rgb8()
+    };
+    let (colors, backgrounds) = (resize(img), resize(backgrounds));
+    char_rows
+        .iter()
+        .enumerate()
+        .map(|(j, row)| {
+            row.iter()
+                .enumerate()
+                .map(|(i, c)| {
+                    let Rgb([r, g, b]) = colors.get_pixel(i as u32, j as u32);
+                    let Rgb([br, bg, bb]) = backgrounds.get_pixel(i as u32, j as u32);
+                    format!(
+                        "<span style=\"color: rgb({}, {}, {}); background-color: rgb({}, {}, {})\">{}</span>",
+                        r, g, b, br, bg, bb, c
+                    )
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// Colors are snapped to multiples of this before being assigned a class, which keeps the palette, and
+// so the stylesheet, small at no visible cost.
+const PALETTE_STEP: u8 = 8;
+
+// Colors referenced by class-based HTML output, shared across frames so they can use one stylesheet.
+#[derive(Default)]
+pub struct HtmlPalette {
+    colors: Vec<[u8; 3]>,
+    indices: HashMap<[u8; 3], usize>,
+}
+
+impl HtmlPalette {
+    fn class(&mut self, Rgb(color): &Rgb<u8>) -> usize {
+        let color = color.map(|c| c / PALETTE_STEP * PALETTE_STEP);
+        match self.indices.get(&color) {
+            Some(&index) => index,
+            None => {
+                self.colors.push(color);
+                self.indices.insert(color, self.colors.len() - 1);
+                self.colors.len() - 1
+            }
+        }
+    }
+
+    pub fn stylesheet(&self) -> String {
+        self.colors
+            .iter()
+            .enumerate()
+            .map(|(i, [r, g, b])| format!(".c{}{{color:#{:02x}{:02x}{:02x}}}", i, r, g, b))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+// Like `char_rows_to_html_color_string`, but each run of same-colored characters is a single span
+// whose class indexes into `palette`.
+pub fn char_rows_to_html_class_string(
+    char_rows: &[Vec<char>],
+    img: &DynamicImage,
+    palette: &mut HtmlPalette,
+) -> String {
+    let (n_cols, n_rows) = (char_rows[0].len(), char_rows.len());
+    let color_resized_image = img
+        .resize_exact(n_cols as u32, n_rows as u32, FilterType::Nearest)
+        .to_rgb8();
+
+    let mut rows: Vec<String> = Vec::with_capacity(n_rows);
+    for (j, row) in char_rows.iter().enumerate() {
+        let mut html = String::new();
+        let mut run_class: Option<usize> = None;
+        for (i, &c) in row.iter().enumerate() {
+            let class = palette.class(color_resized_image.get_pixel(i as u32, j as u32));
+            if run_class != Some(class) {
+                if run_class.is_some() {
+                    html.push_str("</span>");
+                }
+                html.push_str(&format!("<span class=\"c{}\">", class));
+                run_class = Some(class);
+            }
+            html.push_str(&escape_xml(c));
+        }
+        if run_class.is_some() {
+            html.push_str("</span>");
+        }
+        rows.push(html);
+    }
+    rows.join("\n")
+}
+
+// Draws `text` with the font's own glyph bitmaps, one output character per glyph pixel, figlet-style.
+pub fn text_to_banner(text: &str, font: &Font, fill: char) -> String {
+    let mut rows: Vec<String> = Vec::new();
+    for line in text.lines() {
+        for y in 0..font.height {
+            let mut row = String::with_capacity(line.len() * font.width);
+            for c in line.chars() {
+                let bitmap = font.char_map.get(&c).map(|c| &c.bitmap);
+                for x in 0..font.width {
+                    match bitmap {
+                        Some(bitmap) if bitmap[y * font.width + x] > 0.5 => row.push(fill),
+                        _ => row.push(' '),
+                    }
+                }
+            }
+            rows.push(row.trim_end().to_owned());
+        }
+    }
+    rows.join("\n")
+}
+
+pub fn escape_xml(c: char) -> String {
+    match c {
+        '&' => String::from("&amp;"),
+        '<' => String::from("&lt;"),
+        '>' => String::from("&gt;"),
+        _ => c.to_string(),
+    }
+}
+
+pub fn char_rows_to_svg(
+    char_rows: &[Vec<char>],
+    font: &Font,
+    img: &DynamicImage,
+    cell_width: f32,
+) -> String {
+    let (n_cols, n_rows) = (char_rows[0].len(), char_rows.len());
+    let color_resized_image = img
+        .resize_exact(n_cols as u32, n_rows as u32, FilterType::Nearest)
+        .to_rgb8();
+
+    // keep the font's cell aspect ratio so the art isn't stretched relative to the conversion
+    let cell_height = cell_width * font.height as f32 / font.width as f32;
+    let (width, height) = (n_cols as f32 * cell_width, n_rows as f32 * cell_height);
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">",
+        w = width,
+        h = height
+    );
+    svg.push_str(&format!(
+        "<rect width=\"100%\" height=\"100%\" fill=\"black\"/><g font-family=\"monospace\" font-size=\"{}\" xml:space=\"preserve\">",
+        cell_height
+    ));
+    for (j, row) in char_rows.iter().enumerate() {
+        svg.push_str(&format!(
+            "<text x=\"0\" y=\"{}\" textLength=\"{}\" lengthAdjust=\"spacingAndGlyphs\">",
+            (j + 1) as f32 * cell_height,
+            width
+        ));
+        // runs of identically colored characters share a single tspan
+        let mut run_color: Option<&Rgb<u8>> = None;
+        for (i, &c) in row.iter().enumerate() {
+            let color = color_resized_image.get_pixel(i as u32, j as u32);
+            if run_color != Some(color) {
+                if run_color.is_some() {
+                    svg.push_str("</tspan>");
+                }
+                let Rgb([r, g, b]) = color;
+                svg.push_str(&format!("<tspan fill=\"rgb({},{},{})\">", r, g, b));
+                run_color = Some(color);
+            }
+            svg.push_str(&escape_xml(c));
+        }
+        if run_color.is_some() {
+            svg.push_str("</tspan>");
+        }
+        svg.push_str("</text>");
+    }
+    svg.push_str("</g></svg>");
+    svg
+}
+
 pub fn char_rows_to_bitmap(char_rows: &[Vec<char>], font: &Font) -> DynamicImage {
     let out_width = (char_rows[0].len() * font.width) as u32;
     let out_height = (char_rows.len() * font.height) as u32;
     let mut image = GrayImage::new(out_width, out_height);
+    let mut synthetic;
 
     for (j, row) in char_rows.iter().enumerate() {
         for (i, chr) in row.iter().enumerate() {
             let x_offset = i * font.width;
             let y_offset = j * font.height;
-            let bitmap = &font.char_map.get(&chr).unwrap().bitmap;
+            // characters without a glyph, such as a custom blank character, are left empty
+            let bitmap = match font.char_map.get(chr) {
+                Some(c) => &c.bitmap,
+                None => match synthetic_glyph(*chr, font.width, font.height) {
+                    Some(bitmap) => {
+                        synthetic = bitmap;
+                        &synthetic
+                    }
+                    None => continue,
+                },
+            };
             for y in 0..font.height {
                 for x in 0..font.width {
                     let pixel = Luma([(255. * bitmap[y * font.width + x]) as u8]);
@@ -349,6 +1437,33 @@ pub fn char_rows_to_color_bitmap(
     char_rows: &[Vec<char>],
     font: &Font,
     img: &DynamicImage,
+    background: [u8; 3],
+) -> DynamicImage {
+    draw_color_bitmap(char_rows, font, img, |_, _| background)
+}
+
+// Like `char_rows_to_color_bitmap`, with every cell drawn on its own color from `backgrounds`, as
+// the block modes are.
+pub fn char_rows_to_two_color_bitmap(
+    char_rows: &[Vec<char>],
+    font: &Font,
+    img: &DynamicImage,
+    backgrounds: &DynamicImage,
+) -> DynamicImage {
+    let (n_cols, n_rows) = (char_rows[0].len(), char_rows.len());
+    let backgrounds = backgrounds
+        .resize_exact(n_cols as u32, n_rows as u32, FilterType::Nearest)
+        .to_rgb8();
+    draw_color_bitmap(char_rows, font, img, |i, j| {
+        backgrounds.get_pixel(i as u32, j as u32).0
+    })
+}
+
+fn draw_color_bitmap(
+    char_rows: &[Vec<char>],
+    font: &Font,
+    img: &DynamicImage,
+    background: impl Fn(usize, usize) -> [u8; 3],
 ) -> DynamicImage {
     let (n_cols, n_rows) = (char_rows[0].len(), char_rows.len());
     let color_resized_image = img
@@ -359,21 +1474,33 @@ pub fn char_rows_to_color_bitmap(
     let out_width = (n_cols * font.width) as u32;
     let out_height = (n_rows * font.height) as u32;
     let mut image = RgbImage::new(out_width, out_height);
+    let blank = vec![0.; font.width * font.height];
+    let mut synthetic;
 
     for (j, row) in char_rows.iter().enumerate() {
         for (i, chr) in row.iter().enumerate() {
             let x_offset = i * font.width;
             let y_offset = j * font.height;
-            let Rgb([r, g, b]) = pixels[j * n_cols as usize + i];
-            let bitmap = &font.char_map.get(&chr).unwrap().bitmap;
+            let Rgb(color) = pixels[j * n_cols + i];
+            let background = background(i, j);
+            let bitmap = match font.char_map.get(chr) {
+                Some(c) => &c.bitmap,
+                None => match synthetic_glyph(*chr, font.width, font.height) {
+                    Some(bitmap) => {
+                        synthetic = bitmap;
+                        &synthetic
+                    }
+                    // left to the background
+                    None => &blank,
+                },
+            };
             for y in 0..font.height {
                 for x in 0..font.width {
                     let intensity = bitmap[y * font.width + x];
-                    let pixel = Rgb([
-                        (*r as f32 * intensity) as u8,
-                        (*g as f32 * intensity) as u8,
-                        (*b as f32 * intensity) as u8,
-                    ]);
+                    let pixel = Rgb([0, 1, 2].map(|k| {
+                        let (ink, paper) = (color[k] as f32, background[k] as f32);
+                        (paper + (ink - paper) * intensity) as u8
+                    }));
                     image.put_pixel((x + x_offset) as u32, (y + y_offset) as u32, pixel);
                 }
             }
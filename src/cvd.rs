@@ -0,0 +1,65 @@
+use image::{DynamicImage, Rgb};
+
+// Machado, Oliveira & Fernandes (2009) simulation matrices at full severity, applied in linear RGB.
+const PROTANOPIA: [[f32; 3]; 3] = [
+    [0.152286, 1.052583, -0.204868],
+    [0.114503, 0.786281, 0.099216],
+    [-0.003882, -0.048116, 1.051998],
+];
+
+const DEUTERANOPIA: [[f32; 3]; 3] = [
+    [0.367322, 0.860646, -0.227968],
+    [0.280085, 0.672501, 0.047413],
+    [-0.011820, 0.042940, 0.968881],
+];
+
+const TRITANOPIA: [[f32; 3]; 3] = [
+    [1.255528, -0.076749, -0.178779],
+    [-0.078411, 0.930809, 0.147602],
+    [0.004733, 0.691367, 0.303900],
+];
+
+pub const CVD_KINDS: [&str; 3] = ["protanopia", "deuteranopia", "tritanopia"];
+
+pub fn get_cvd_matrix(cvd: &str) -> [[f32; 3]; 3] {
+    let matrix = match cvd {
+        "protanopia" => Some(PROTANOPIA),
+        "deuteranopia" => Some(DEUTERANOPIA),
+        "tritanopia" => Some(TRITANOPIA),
+        _ => None,
+    };
+    matrix.unwrap_or_else(|| panic!("Unsupported color vision deficiency {}", cvd))
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let value = value.clamp(0., 1.);
+    let value = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1. / 2.4) - 0.055
+    };
+    (value * 255.).round() as u8
+}
+
+// Only the colors change; glyph selection still works from the untouched source image.
+pub fn simulate_cvd(img: &DynamicImage, cvd: &str) -> DynamicImage {
+    let matrix = get_cvd_matrix(cvd);
+    let mut image = img.to_rgb8();
+    for Rgb(pixel) in image.pixels_mut() {
+        let linear = pixel.map(srgb_to_linear);
+        for (channel, row) in pixel.iter_mut().zip(matrix.iter()) {
+            let value: f32 = row.iter().zip(linear.iter()).map(|(m, c)| m * c).sum();
+            *channel = linear_to_srgb(value);
+        }
+    }
+    DynamicImage::ImageRgb8(image)
+}
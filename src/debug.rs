@@ -0,0 +1,82 @@
+use image::{DynamicImage, GenericImageView, GrayImage, ImageOutputFormat, Luma};
+use rocket::http::ContentType;
+use rocket::{get, State};
+use std::io::Cursor;
+
+use crate::auth::AdminAuth;
+use crate::config::Config;
+use crate::convert::{char_dimensions, edges_to_cells, img_to_pixels, resize_to_cells};
+use crate::error::Error;
+use crate::fetch::Fetchers;
+use crate::font::Font;
+use crate::generate::{is_available_font, load_alphabet, load_font};
+use crate::pools::Pools;
+
+// The converter's input with each cell outlined, so it's obvious what a single glyph is matched
+// against.
+fn cells_image(
+    font: &Font,
+    img: &DynamicImage,
+    width: usize,
+    brightness_offset: f32,
+) -> DynamicImage {
+    let (out_width, out_height) = char_dimensions(font, img.dimensions(), width);
+    let (image_width, image_height) = (out_width * font.width, out_height * font.height);
+    let pixels = img_to_pixels(font, img, width, brightness_offset, true);
+    let mut image = GrayImage::new(image_width as u32, image_height as u32);
+    for (i, value) in pixels.iter().enumerate() {
+        let (x, y) = (i % image_width, i / image_width);
+        let pixel = if x % font.width == 0 || y % font.height == 0 {
+            Luma([64])
+        } else {
+            Luma([(value.clamp(0., 1.) * 255.) as u8])
+        };
+        image.put_pixel(x as u32, y as u32, pixel);
+    }
+    DynamicImage::ImageLuma8(image)
+}
+
+#[get("/debug?<url>&<stage>&<width>&<font>&<brightness_offset>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn debug(
+    _auth: AdminAuth,
+    url: String,
+    stage: String,
+    width: Option<usize>,
+    font: Option<String>,
+    brightness_offset: Option<f32>,
+    config: &State<Config>,
+    pools: &State<Pools>,
+    fetchers: &State<Fetchers>,
+) -> Result<(ContentType, Vec<u8>), Error> {
+    if !["resized", "edges", "cells"].contains(&stage.as_str()) {
+        return Err(Error::InvalidParameter("stage"));
+    }
+    let font = font.unwrap_or_else(|| String::from("bitocra-13"));
+    if !is_available_font(&font) {
+        return Err(Error::UnknownFont(font));
+    }
+    let width = width
+        .unwrap_or(150)
+        .clamp(1, config.conversion_limits.max_width);
+    let brightness_offset = brightness_offset.unwrap_or(0.);
+
+    let bytes = pools.download(fetchers.fetch(&url)).await?;
+    let (_, image) = pools.decode(bytes).await?;
+
+    pools
+        .convert(move || {
+            let alphabet = load_alphabet("alphabet");
+            let font = load_font(&font, &alphabet)?;
+            let out = match stage.as_str() {
+                "resized" => resize_to_cells(&font, &image, width),
+                "edges" => edges_to_cells(&font, &image, width),
+                _ => cells_image(&font, &image, width, brightness_offset),
+            };
+            let mut png = Vec::new();
+            out.write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+                .map_err(|_| Error::ConversionFailed)?;
+            Ok((ContentType::PNG, png))
+        })
+        .await?
+}
@@ -0,0 +1,64 @@
+use rocket::http::Header;
+use rocket::response::{self, Responder};
+use rocket::Request;
+
+use crate::generate::Params;
+use crate::metadata::SourceMetadata;
+
+// Sent with every conversion so a given output can be reproduced exactly, whatever defaults or
+// clamping the request went through.
+pub const RESOLVED_PARAMS_HEADER: &str = "X-Resolved-Params";
+// Only sent when the request asked for it with `metadata`.
+pub const SOURCE_METADATA_HEADER: &str = "X-Source-Metadata";
+// Only sent when permalinks are enabled.
+pub const PERMALINK_HEADER: &str = "X-Permalink";
+
+pub fn resolved_params(params: &Params) -> String {
+    serde_json::to_string(params).unwrap()
+}
+
+pub fn source_metadata(metadata: &SourceMetadata) -> String {
+    serde_json::to_string(metadata).unwrap()
+}
+
+pub struct WithParams<R> {
+    inner: R,
+    resolved: String,
+    metadata: Option<String>,
+    permalink: Option<String>,
+}
+
+impl<R> WithParams<R> {
+    pub fn new(inner: R, resolved: String) -> WithParams<R> {
+        WithParams {
+            inner,
+            resolved,
+            metadata: None,
+            permalink: None,
+        }
+    }
+
+    pub fn with_metadata(mut self, metadata: Option<String>) -> WithParams<R> {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn with_permalink(mut self, permalink: Option<String>) -> WithParams<R> {
+        self.permalink = permalink;
+        self
+    }
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for WithParams<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        let mut response = self.inner.respond_to(request)?;
+        response.set_header(Header::new(RESOLVED_PARAMS_HEADER, self.resolved));
+        if let Some(metadata) = self.metadata {
+            response.set_header(Header::new(SOURCE_METADATA_HEADER, metadata));
+        }
+        if let Some(permalink) = self.permalink {
+            response.set_header(Header::new(PERMALINK_HEADER, permalink));
+        }
+        Ok(response)
+    }
+}
@@ -0,0 +1,104 @@
+use rocket::http::ContentType;
+use rocket::{get, State};
+use std::time::Instant;
+
+use crate::cache::Cached;
+use crate::config::Config;
+use crate::convert::Theme;
+use crate::echo::WithParams;
+use crate::error::Error;
+use crate::fetch::Fetchers;
+use crate::pools::Pools;
+use crate::query::ValidatedParams;
+use crate::ratelimit::RateLimit;
+use crate::stats::Stats;
+
+fn hex([r, g, b]: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+fn embed_script(html: &str, theme: &Theme, target: Option<&str>) -> String {
+    let style = format!(
+        "background-color:{};color:{};line-height:1;display:inline-block;padding:0.5em",
+        hex(theme.background),
+        hex(theme.foreground)
+    );
+    let json = |value: &str| serde_json::to_string(value).unwrap();
+    let target = target.map(json).unwrap_or_else(|| String::from("null"));
+    // a `<pre>` appended to the element `target` selects, or in place of the script tag itself
+    // when there's none
+    format!(
+        "(function () {{
+    var script = document.currentScript;
+    var target = {};
+    var pre = document.createElement(\"pre\");
+    pre.className = \"image-to-ascii\";
+    pre.style.cssText = {};
+    pre.innerHTML = {};
+    var element = target && document.querySelector(target);
+    if (element) {{
+        element.appendChild(pre);
+    }} else if (script) {{
+        script.parentNode.insertBefore(pre, script);
+    }}
+}})();
+",
+        target,
+        json(&style),
+        json(html)
+    )
+}
+
+// A script rendering `url` into the page that includes it, for embedding with a single script tag:
+// `<script src=".../embed.js?url=...&width=80"></script>`. Takes the conversion parameters of
+// `GET /<url>`; animated sources show their first frame.
+#[get("/embed.js?<url>&<target>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn embed_js(
+    _limit: RateLimit,
+    url: String,
+    target: Option<String>,
+    params: Result<ValidatedParams, Error>,
+    config: &State<Config>,
+    stats: &State<Stats>,
+    pools: &State<Pools>,
+    fetchers: &State<Fetchers>,
+) -> Result<Cached<WithParams<(ContentType, String)>>, Error> {
+    if !fetchers.supports(&url) {
+        return Err(Error::InvalidParameter("url"));
+    }
+    let ValidatedParams { conversion, .. } = params?;
+
+    let labels = (
+        conversion.font.clone(),
+        conversion.alphabet.clone(),
+        conversion.metric.clone(),
+    );
+    let t0 = Instant::now();
+    let result = async {
+        let bytes = pools.download(fetchers.fetch(&url)).await?;
+        let (_, image) = pools.decode(bytes).await?;
+        pools
+            .convert(move || {
+                let (html, theme, resolved) = conversion.render_html_frame(&image, &url)?;
+                Ok((embed_script(&html, &theme, target.as_deref()), resolved))
+            })
+            .await?
+    }
+    .await;
+
+    match result {
+        Ok((script, resolved)) => {
+            let (font, alphabet, metric) = labels;
+            stats.record(&font, &alphabet, &metric, "js", t0.elapsed());
+            Ok(Cached::new(
+                WithParams::new((ContentType::JavaScript, script), resolved),
+                &config.cache,
+            ))
+        }
+        Err(err) => {
+            stats.record_failure();
+            Err(err)
+        }
+    }
+}
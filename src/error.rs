@@ -0,0 +1,367 @@
+use image::ImageError;
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder};
+use rocket::serde::json::{json, Value};
+use rocket::{catch, Request};
+use std::fmt;
+use std::time::Duration;
+
+use crate::convert::escape_xml;
+use crate::generate::ConvertError;
+use crate::ratelimit::refusal;
+
+// Every error code is described in this section of the README.
+pub const DOCS_URL: &str = "https://github.com/leomosley/image-to-ascii-api#errors";
+
+// An error rendered as JSON, HTML, or plain text depending on the request's `Accept` header, with a
+// stable machine-readable `code` next to the human-readable message.
+#[derive(Debug)]
+pub struct ApiError {
+    status: Status,
+    code: &'static str,
+    message: String,
+    details: Option<Value>,
+    // sent as `Retry-After`, in whole seconds
+    retry_after: Option<Duration>,
+}
+
+impl ApiError {
+    pub fn new(status: Status, code: &'static str, message: impl Into<String>) -> ApiError {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            details: None,
+            retry_after: None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut error = json!({
+            "code": self.code,
+            "message": self.message,
+            "docs": DOCS_URL,
+        });
+        if let (Some(Value::Object(details)), Value::Object(error)) = (&self.details, &mut error) {
+            error.extend(details.clone());
+        }
+        json!({ "error": error }).to_string()
+    }
+
+    fn to_html(&self) -> String {
+        let message: String = self.message.chars().map(escape_xml).collect();
+        format!(
+            "<!DOCTYPE html>\n<html>\n<head><title>{status}</title></head>\n<body>\n<h1>{status}</h1>\n<p>{message}</p>\n<p><code>{code}</code> &middot; <a href=\"{docs}\">documentation</a></p>\n</body>\n</html>\n",
+            status = self.status,
+            message = message,
+            code = self.code,
+            docs = DOCS_URL
+        )
+    }
+
+    fn to_text(&self) -> String {
+        format!("{}\n({}, see {})\n", self.message, self.code, DOCS_URL)
+    }
+}
+
+// Bare statuses get a code derived from their reason phrase, e.g. `not_found`.
+impl From<Status> for ApiError {
+    fn from(status: Status) -> ApiError {
+        let reason = status.reason().unwrap_or("Error");
+        let code = match status.code {
+            400 => "bad_request",
+            401 => "unauthorized",
+            404 => "not_found",
+            413 => "payload_too_large",
+            415 => "unsupported_media_type",
+            422 => "unprocessable_entity",
+            426 => "upgrade_required",
+            429 => "too_many_requests",
+            502 => "bad_gateway",
+            503 => "service_unavailable",
+            507 => "insufficient_storage",
+            _ if status.class().is_client_error() => "client_error",
+            _ => "server_error",
+        };
+        ApiError::new(status, code, reason)
+    }
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        let preferred = request
+            .accept()
+            .map(|accept| accept.preferred().media_type());
+        let (content_type, body) = match preferred {
+            Some(media_type) if media_type.is_json() => (ContentType::JSON, self.to_json()),
+            Some(media_type) if media_type.is_html() => (ContentType::HTML, self.to_html()),
+            _ => (ContentType::Plain, self.to_text()),
+        };
+        let mut response = (self.status, (content_type, body)).respond_to(request)?;
+        if let Some(wait) = self.retry_after {
+            response.set_raw_header("Retry-After", whole_secs(wait).to_string());
+        }
+        Ok(response)
+    }
+}
+
+// A wait rounded up to the whole seconds clients are told of, a second at least.
+fn whole_secs(wait: Duration) -> u64 {
+    wait.as_secs_f64().ceil().max(1.) as u64
+}
+
+// Everything a request can fail with, each with its own status and code.
+#[derive(Debug)]
+pub enum Error {
+    InvalidParameter(&'static str),
+    // a parameter along with why its value was rejected
+    InvalidValue(&'static str, String),
+    UnknownProfile(String),
+    UnknownFont(String),
+    UnknownAlphabet(String),
+    // another alphabet is already registered under the name
+    AlphabetTaken(String),
+    SourceTooLarge(u64),
+    TooManyFrames(usize),
+    DownloadFailed(String),
+    UnsupportedFormat(String),
+    // the source is something else, such as a web page
+    NotAnImage(String),
+    InvalidImage(String),
+    MissingGlyphs(Vec<char>),
+    ConversionFailed,
+    // the source is on the blocklist
+    BlockedSource,
+    // the source's host is on a private network or not allowed by the fetch config
+    ForbiddenHost(String),
+    // the client has made too many requests, and may retry after this long
+    RateLimited(Duration),
+    // the client has used up its quota, which resets after this long
+    QuotaExceeded(Duration),
+    // the source's host kept failing, and won't be fetched from for this long
+    HostUnavailable(String, Duration),
+    // the source is more elongated than `max_aspect_ratio` and wasn't tiled
+    ExtremeAspectRatio(f64, f64),
+    // the `fetch`, `decode` or `convert` stage ran over its budget
+    Timeout(&'static str, Duration),
+    // the request's API key was refused, and why
+    Unauthorized(&'static str),
+    Status(Status),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidParameter(name) => write!(f, "Invalid {}", name),
+            Error::InvalidValue(name, reason) => write!(f, "Invalid {}: {}", name, reason),
+            Error::UnknownProfile(name) => write!(f, "Unknown profile {:?}", name),
+            Error::UnknownFont(name) => write!(f, "Unknown font {:?}", name),
+            Error::UnknownAlphabet(name) => write!(f, "Unknown alphabet {:?}", name),
+            Error::AlphabetTaken(name) => {
+                write!(f, "The alphabet {:?} is already registered", name)
+            }
+            Error::SourceTooLarge(max) => write!(f, "The image is larger than {} bytes", max),
+            Error::TooManyFrames(max) => write!(f, "The animation has more than {} frames", max),
+            Error::DownloadFailed(err) => write!(f, "Error downloading image: {}", err),
+            Error::UnsupportedFormat(err) => write!(f, "Unsupported image format: {}", err),
+            Error::NotAnImage(kind) => write!(f, "URL returned {}, not an image", kind),
+            Error::ExtremeAspectRatio(ratio, max) => write!(
+                f,
+                "The image's aspect ratio of {:.1}:1 is over the maximum {}:1; set tile to \
+                 convert it in parts",
+                ratio, max
+            ),
+            Error::InvalidImage(err) => write!(f, "Error decoding image: {}", err),
+            Error::MissingGlyphs(missing) => write!(
+                f,
+                "The alphabet has characters without a glyph in the font: {}",
+                missing.iter().collect::<String>()
+            ),
+            Error::ConversionFailed => write!(f, "Conversion failed"),
+            Error::BlockedSource => write!(f, "The source has been blocked"),
+            Error::ForbiddenHost(host) => {
+                write!(f, "Fetching sources from {} is not allowed", host)
+            }
+            Error::RateLimited(wait) => {
+                write!(f, "Too many requests, retry in {}s", whole_secs(*wait))
+            }
+            Error::QuotaExceeded(reset) => {
+                write!(f, "Quota used up, it resets in {}s", whole_secs(*reset))
+            }
+            Error::HostUnavailable(host, wait) => write!(
+                f,
+                "{} keeps failing, so it won't be fetched from for {}s",
+                host,
+                whole_secs(*wait)
+            ),
+            Error::Timeout(stage, budget) => write!(
+                f,
+                "The {} stage took longer than {}ms",
+                stage,
+                budget.as_millis()
+            ),
+            Error::Unauthorized(reason) => write!(f, "Unauthorized: {}", reason),
+            Error::Status(status) => write!(f, "{}", status.reason().unwrap_or("Error")),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Status> for Error {
+    fn from(status: Status) -> Error {
+        Error::Status(status)
+    }
+}
+
+impl From<ImageError> for Error {
+    fn from(err: ImageError) -> Error {
+        match err {
+            ImageError::Unsupported(err) => Error::UnsupportedFormat(err.to_string()),
+            err => Error::InvalidImage(err.to_string()),
+        }
+    }
+}
+
+impl From<ConvertError> for Error {
+    fn from(err: ConvertError) -> Error {
+        match err {
+            ConvertError::UnknownFormat(_) => Error::InvalidParameter("format"),
+            ConvertError::Render(err) => err,
+            ConvertError::Write(_) => Error::ConversionFailed,
+        }
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(err: Error) -> ApiError {
+        let message = err.to_string();
+        let retry_after = match err {
+            Error::RateLimited(wait)
+            | Error::QuotaExceeded(wait)
+            | Error::HostUnavailable(_, wait) => Some(wait),
+            _ => None,
+        };
+        let (status, code, details) = match err {
+            Error::InvalidParameter(name) => (
+                Status::BadRequest,
+                "invalid_parameter",
+                Some(json!({ "parameter": name })),
+            ),
+            Error::InvalidValue(name, reason) => (
+                Status::BadRequest,
+                "invalid_parameter",
+                Some(json!({ "parameter": name, "reason": reason })),
+            ),
+            Error::UnknownProfile(name) => (
+                Status::BadRequest,
+                "unknown_profile",
+                Some(json!({ "profile": name })),
+            ),
+            Error::UnknownFont(name) => (
+                Status::NotFound,
+                "unknown_font",
+                Some(json!({ "font": name })),
+            ),
+            Error::UnknownAlphabet(name) => (
+                Status::NotFound,
+                "unknown_alphabet",
+                Some(json!({ "alphabet": name })),
+            ),
+            Error::AlphabetTaken(name) => (
+                Status::Conflict,
+                "alphabet_taken",
+                Some(json!({ "alphabet": name })),
+            ),
+            Error::SourceTooLarge(max) => (
+                Status::PayloadTooLarge,
+                "payload_too_large",
+                Some(json!({ "max_bytes": max })),
+            ),
+            Error::TooManyFrames(max) => (
+                Status::PayloadTooLarge,
+                "payload_too_large",
+                Some(json!({ "max_frames": max })),
+            ),
+            Error::DownloadFailed(_) => (Status::BadGateway, "download_failed", None),
+            Error::UnsupportedFormat(_) => {
+                (Status::UnsupportedMediaType, "unsupported_format", None)
+            }
+            Error::NotAnImage(kind) => (
+                Status::UnprocessableEntity,
+                "not_an_image",
+                Some(json!({ "returned": kind })),
+            ),
+            Error::InvalidImage(_) => (Status::UnprocessableEntity, "invalid_image", None),
+            Error::ExtremeAspectRatio(ratio, max) => (
+                Status::UnprocessableEntity,
+                "extreme_aspect_ratio",
+                Some(
+                    json!({ "aspect_ratio": (ratio * 10.).round() / 10., "max_aspect_ratio": max }),
+                ),
+            ),
+            Error::MissingGlyphs(missing) => (
+                Status::UnprocessableEntity,
+                "missing_glyphs",
+                Some(json!({ "missing": missing.iter().collect::<String>() })),
+            ),
+            Error::ConversionFailed => (Status::InternalServerError, "conversion_failed", None),
+            Error::BlockedSource => (Status::Forbidden, "blocked_source", None),
+            Error::ForbiddenHost(host) => (
+                Status::Forbidden,
+                "forbidden_host",
+                Some(json!({ "host": host })),
+            ),
+            Error::RateLimited(wait) => (
+                Status::TooManyRequests,
+                "rate_limited",
+                Some(json!({ "retry_after_secs": whole_secs(wait) })),
+            ),
+            Error::QuotaExceeded(reset) => (
+                Status::TooManyRequests,
+                "quota_exceeded",
+                Some(json!({ "retry_after_secs": whole_secs(reset) })),
+            ),
+            Error::HostUnavailable(host, wait) => (
+                Status::BadGateway,
+                "host_unavailable",
+                Some(json!({ "host": host, "retry_after_secs": whole_secs(wait) })),
+            ),
+            Error::Timeout(stage, budget) => {
+                let (status, code) = match stage {
+                    "fetch" => (Status::GatewayTimeout, "fetch_timeout"),
+                    "decode" => (Status::ServiceUnavailable, "decode_timeout"),
+                    _ => (Status::ServiceUnavailable, "conversion_timeout"),
+                };
+                let details = json!({ "stage": stage, "timeout_secs": budget.as_secs_f64() });
+                (status, code, Some(details))
+            }
+            Error::Unauthorized(_) => (Status::Unauthorized, "unauthorized", None),
+            Error::Status(status) => return ApiError::from(status),
+        };
+        ApiError {
+            status,
+            code,
+            message,
+            details,
+            retry_after,
+        }
+    }
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for Error {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        ApiError::from(self).respond_to(request)
+    }
+}
+
+// Errors raised as a bare `Status`, by handlers or by Rocket itself, go through the same rendering.
+#[catch(default)]
+pub fn default_catcher(status: Status, request: &Request) -> ApiError {
+    match refusal(request) {
+        Some(err) if status == Status::TooManyRequests || status == Status::Unauthorized => {
+            err.into()
+        }
+        _ => ApiError::from(status),
+    }
+}
@@ -0,0 +1,250 @@
+use image::codecs::gif::GifDecoder;
+use image::io::Reader;
+use image::{AnimationDecoder, ImageFormat};
+use reqwest::blocking::Client;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE};
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{post, State};
+use std::error::Error as _;
+use std::io::{Cursor, Read};
+
+use crate::config::{Config, LimitsConfig};
+use crate::convert::char_dimensions;
+use crate::error::Error;
+use crate::fetch::{Fetchers, HostPolicy, MAX_REDIRECTS};
+use crate::generate::{is_available_font, is_bundled_alphabet, load_alphabet, load_font};
+use crate::pools::Pools;
+use crate::ratelimit::RateLimit;
+
+// Enough for the header of any supported format and the first few frames of most GIFs.
+const PREFIX_BYTES: u64 = 64 * 1024;
+
+// Rough single-core costs, used only to give clients an order of magnitude.
+const NS_PER_PIXEL: f64 = 25.;
+const NS_PER_GLYPH_PIXEL: f64 = 1.5;
+const NS_PER_GLYPH: f64 = 10.;
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct EstimateRequest {
+    url: String,
+    width: Option<usize>,
+    font: Option<String>,
+    alphabet: Option<String>,
+    metric: Option<String>,
+    threads: Option<usize>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Estimate {
+    content_type: Option<String>,
+    source_bytes: Option<u64>,
+    format: Option<String>,
+    source_width: u32,
+    source_height: u32,
+    width: usize,
+    height: usize,
+    frames: usize,
+    frames_exact: bool,
+    estimated_ms: f64,
+    exceeds_limits: Vec<&'static str>,
+    limits: LimitsConfig,
+}
+
+struct Prefix {
+    bytes: Vec<u8>,
+    content_type: Option<String>,
+    total_bytes: Option<u64>,
+}
+
+// Fetches the start of `url` from a host the policy allows, as are those of its redirects.
+fn fetch_prefix(url: &str, host_policy: &HostPolicy) -> Result<Prefix, Error> {
+    // a redirect the policy refused comes back wrapped in reqwest's error
+    let failed = |err: reqwest::Error| {
+        let mut source = err.source();
+        while let Some(cause) = source {
+            if let Some(Error::ForbiddenHost(host)) = cause.downcast_ref::<Error>() {
+                return Error::ForbiddenHost(host.clone());
+            }
+            source = cause.source();
+        }
+        Error::DownloadFailed(err.to_string())
+    };
+    let pinned =
+        host_policy.check(&Url::parse(url).map_err(|_| Error::InvalidParameter("url"))?)?;
+    let redirects = host_policy.clone();
+    let policy = Policy::custom(move |attempt| {
+        if attempt.previous().len() > MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+        match redirects.check(attempt.url()) {
+            Ok(_) => attempt.follow(),
+            Err(err) => attempt.error(err),
+        }
+    });
+    let builder = Client::builder().redirect(policy);
+    let client = match pinned {
+        Some((host, addr)) => builder.resolve(&host, addr),
+        None => builder,
+    }
+    .build()
+    .map_err(failed)?;
+    let response = client
+        .get(url)
+        .header(RANGE, format!("bytes=0-{}", PREFIX_BYTES - 1))
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(failed)?;
+    let headers = response.headers();
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    // a 206 reports the full size after the slash in Content-Range; servers that ignore ranges
+    // send the whole body with a Content-Length
+    let total_bytes = headers
+        .get(CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit('/').next())
+        .and_then(|total| total.parse().ok())
+        .or_else(|| {
+            headers
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|length| length.parse().ok())
+        });
+
+    let mut bytes = Vec::new();
+    // never read past the prefix, even when the range was ignored
+    let _ = response.take(PREFIX_BYTES).read_to_end(&mut bytes);
+    Ok(Prefix {
+        bytes,
+        content_type,
+        total_bytes,
+    })
+}
+
+// Counts the frames that fully decode from the prefix and extrapolates by size when the prefix is
+// only part of the file.
+fn count_frames(prefix: &Prefix) -> (usize, bool) {
+    let decoded = match GifDecoder::new(Cursor::new(&prefix.bytes)) {
+        Ok(decoder) => decoder
+            .into_frames()
+            .take_while(|frame| frame.is_ok())
+            .count(),
+        Err(_) => return (1, false),
+    };
+    match prefix.total_bytes {
+        Some(total) if total <= prefix.bytes.len() as u64 => (decoded.max(1), true),
+        Some(total) => {
+            let scale = total as f64 / prefix.bytes.len() as f64;
+            (((decoded.max(1)) as f64 * scale).round() as usize, false)
+        }
+        None => (decoded.max(1), false),
+    }
+}
+
+fn estimate_ms(
+    frames: usize,
+    cells: usize,
+    cell_pixels: usize,
+    glyphs: usize,
+    metric: &str,
+    threads: usize,
+) -> f64 {
+    let pixels = (cells * cell_pixels) as f64;
+    let matching = match metric {
+        "fast" => 0.,
+        "grad" => glyphs as f64 * NS_PER_GLYPH * cells as f64,
+        _ => glyphs as f64 * pixels * NS_PER_GLYPH_PIXEL,
+    };
+    let per_frame = pixels * NS_PER_PIXEL + matching / threads.max(1) as f64;
+    frames as f64 * per_frame / 1e6
+}
+
+#[post("/estimate", format = "json", data = "<request>")]
+pub async fn estimate(
+    _limit: RateLimit,
+    request: Json<EstimateRequest>,
+    config: &State<Config>,
+    pools: &State<Pools>,
+    fetchers: &State<Fetchers>,
+) -> Result<Json<Estimate>, Error> {
+    let request = request.into_inner();
+    if !request.url.starts_with("http://") && !request.url.starts_with("https://") {
+        return Err(Error::InvalidParameter("url"));
+    }
+    let font = request.font.unwrap_or_else(|| String::from("bitocra-13"));
+    let alphabet = request.alphabet.unwrap_or_else(|| String::from("alphabet"));
+    if !is_available_font(&font) {
+        return Err(Error::UnknownFont(font));
+    }
+    if !is_bundled_alphabet(&alphabet) {
+        return Err(Error::UnknownAlphabet(alphabet));
+    }
+    let limits = config.conversion_limits.clone();
+    let width = request.width.unwrap_or(150);
+    let metric = request.metric.unwrap_or_else(|| String::from("grad"));
+    let threads = request.threads.unwrap_or(1);
+    let host_policy = fetchers.host_policy().clone();
+
+    pools
+        .fetch(move || {
+            let prefix = fetch_prefix(&request.url, &host_policy)?;
+            let reader = Reader::new(Cursor::new(&prefix.bytes))
+                .with_guessed_format()
+                .map_err(|err| Error::InvalidImage(err.to_string()))?;
+            let format = reader.format();
+            let (source_width, source_height) = reader.into_dimensions()?;
+
+            let (frames, frames_exact) = if format == Some(ImageFormat::Gif) {
+                count_frames(&prefix)
+            } else {
+                (1, true)
+            };
+
+            let alphabet = load_alphabet(&alphabet);
+            let font = load_font(&font, &alphabet)?;
+            let (width, height) = char_dimensions(&font, (source_width, source_height), width);
+            let estimated_ms = estimate_ms(
+                frames,
+                width * height,
+                font.width * font.height,
+                font.chars.len(),
+                &metric,
+                threads,
+            );
+
+            let mut exceeds_limits = Vec::new();
+            if width > limits.max_width {
+                exceeds_limits.push("max_width");
+            }
+            if frames > limits.max_frames {
+                exceeds_limits.push("max_frames");
+            }
+            if matches!(prefix.total_bytes, Some(total) if total > limits.max_source_bytes) {
+                exceeds_limits.push("max_source_bytes");
+            }
+
+            Ok(Json(Estimate {
+                content_type: prefix.content_type,
+                source_bytes: prefix.total_bytes,
+                format: format.map(|format| format!("{:?}", format).to_lowercase()),
+                source_width,
+                source_height,
+                width,
+                height,
+                frames,
+                frames_exact,
+                estimated_ms,
+                exceeds_limits,
+                limits,
+            }))
+        })
+        .await
+        .map_err(|_| Error::ConversionFailed)?
+}
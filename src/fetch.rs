@@ -0,0 +1,953 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use image::ImageFormat;
+use log::error;
+use reqwest::header::{HeaderMap, CONTENT_TYPE, LOCATION};
+use reqwest::redirect::Policy;
+use reqwest::{Client, Url};
+use rocket::fairing::AdHoc;
+use rocket::serde::Deserialize;
+use rocket::tokio::fs;
+use rocket::tokio::io::AsyncReadExt;
+use rocket::tokio::task::block_in_place;
+use rocket::tokio::time::timeout;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::blocklist::Blocklist;
+use crate::config::Config;
+use crate::error::Error;
+use crate::storage::{S3Config, S3};
+
+// Downloads a source image's bytes from a URL of one scheme.
+#[rocket::async_trait]
+pub trait Fetcher: Send + Sync {
+    async fn fetch(&self, url: &str) -> Result<Vec<u8>, Error>;
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct GcsConfig {
+    // an HMAC key of a service account, as Cloud Storage's XML API takes S3-style signatures
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub endpoint: String,
+}
+
+impl Default for GcsConfig {
+    fn default() -> GcsConfig {
+        GcsConfig {
+            access_key_id: String::new(),
+            secret_access_key: String::new(),
+            endpoint: String::from("https://storage.googleapis.com"),
+        }
+    }
+}
+
+impl GcsConfig {
+    fn s3_config(&self) -> S3Config {
+        S3Config {
+            bucket: String::new(),
+            region: String::from("auto"),
+            endpoint: Some(self.endpoint.clone()),
+            access_key_id: self.access_key_id.clone(),
+            secret_access_key: self.secret_access_key.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct FetchConfig {
+    // seconds an HTTP source may take to connect, and then to send each part of its response; the
+    // whole download is also bounded by `pools.fetch_timeout`
+    pub connect_timeout: u64,
+    pub read_timeout: u64,
+    // http(s) sources on loopback, private, link-local and other non-public addresses are refused
+    // unless this is set, however their hosts resolve
+    pub allow_private: bool,
+    // when any are given, only sources on these domains and their subdomains are fetched
+    pub allowed_domains: Vec<String>,
+    // sources on these domains and their subdomains are never fetched
+    pub denied_domains: Vec<String>,
+    // a host that fails this many downloads in a row, by timing out, not connecting or erroring
+    // with a 5xx, isn't fetched from for `breaker_cooldown` seconds; 0 never stops fetching
+    pub breaker_failures: u32,
+    pub breaker_cooldown: u64,
+    // seconds a host's addresses are kept once resolved, and idle connections kept open, and the
+    // most of those that are kept to each host
+    pub dns_ttl: u64,
+    pub pool_idle_timeout: u64,
+    pub pool_max_idle_per_host: usize,
+    // `file:` URLs and absolute paths, read from the server's own filesystem
+    pub file: bool,
+    // when set, only files within this directory can be read
+    pub file_root: Option<PathBuf>,
+    // `s3://<bucket>/<key>` URLs, fetched with the credentials and endpoint of `storage.s3`
+    pub s3: bool,
+    // `gs://<bucket>/<object>` URLs, enabled by setting Cloud Storage credentials
+    pub gcs: Option<GcsConfig>,
+}
+
+impl Default for FetchConfig {
+    fn default() -> FetchConfig {
+        FetchConfig {
+            connect_timeout: 10,
+            read_timeout: 30,
+            allow_private: false,
+            allowed_domains: Vec::new(),
+            denied_domains: Vec::new(),
+            breaker_failures: 5,
+            breaker_cooldown: 30,
+            dns_ttl: 60,
+            pool_idle_timeout: 90,
+            pool_max_idle_per_host: 8,
+            file: false,
+            file_root: None,
+            s3: false,
+            gcs: None,
+        }
+    }
+}
+
+// What a response of a type that isn't an image is instead, for telling the client.
+fn kind_of_type(essence: &str) -> String {
+    match essence {
+        "text/html" | "application/xhtml+xml" => String::from("HTML"),
+        "text/plain" => String::from("text"),
+        _ if essence.ends_with("json") => String::from("JSON"),
+        _ if essence.ends_with("xml") => String::from("XML"),
+        _ => String::from(essence),
+    }
+}
+
+// Responses of these types may be images; one without a `Content-Type` is given the benefit of
+// the doubt. Returns the format a response claims to be in, when it names a supported one.
+fn may_be_image(headers: &HeaderMap) -> Result<Option<ImageFormat>, Error> {
+    let content_type = match headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(content_type) => content_type,
+        None => return Ok(None),
+    };
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    let essence = essence.to_ascii_lowercase();
+    if essence.starts_with("image/") {
+        Ok(ImageFormat::from_mime_type(&essence))
+    } else if essence == "application/octet-stream" || essence == "binary/octet-stream" {
+        Ok(None)
+    } else {
+        Err(Error::NotAnImage(kind_of_type(&essence)))
+    }
+}
+
+// What a source that isn't in any image format looks like it is instead.
+fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    let head = &bytes[..bytes.len().min(SNIFF_BYTES)];
+    let head = String::from_utf8_lossy(head);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    let lowercase = head.to_ascii_lowercase();
+    if lowercase.starts_with("<!doctype html")
+        || ["<html", "<head", "<body"]
+            .iter()
+            .any(|tag| lowercase.contains(tag))
+    {
+        Some("HTML")
+    } else if lowercase.starts_with("<?xml") {
+        Some("XML")
+    } else if head.starts_with('{') || head.starts_with('[') {
+        Some("JSON")
+    } else if !head.is_empty() && !head.chars().any(|c| c.is_control() && !c.is_whitespace()) {
+        Some("text")
+    } else {
+        None
+    }
+}
+
+// Checks a fetched source is in a supported image format, the one it was `claimed` to be in when
+// it was, before anything decodes or caches it.
+pub fn verify(bytes: &[u8], claimed: Option<ImageFormat>) -> Result<(), Error> {
+    let format = match image::guess_format(bytes) {
+        Ok(format) => format,
+        Err(_) => {
+            return Err(match sniff(bytes) {
+                Some(kind) => Error::NotAnImage(String::from(kind)),
+                None => Error::UnsupportedFormat(String::from("the response is not an image")),
+            })
+        }
+    };
+    match claimed {
+        Some(claimed) if claimed != format => Err(Error::UnsupportedFormat(format!(
+            "the response claims to be {} but is {}",
+            format!("{:?}", claimed).to_uppercase(),
+            format!("{:?}", format).to_uppercase()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// How much of a source is looked at to tell what it is when it isn't an image.
+const SNIFF_BYTES: usize = 512;
+
+// Redirects an HTTP source may go through, each checked like the source itself.
+pub const MAX_REDIRECTS: usize = 10;
+
+// Whether `ip` is reachable on the public internet, rather than on the server itself, its
+// network, or a range reserved for something else.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // shared address space, benchmarking, IETF protocol assignments and reserved
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 198 && (18..20).contains(&b))
+                || (a == 192 && b == 0 && ip.octets()[2] == 0)
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let [first, second, ..] = ip.segments();
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local and link-local
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80
+                    // NAT64's local-use prefix, translating to wherever the network's set up to
+                    || (first == 0x64 && second == 0xff9b)
+                    // documentation
+                    || (first == 0x2001 && second == 0xdb8))
+            }
+        },
+    }
+}
+
+// The IPv4 address an IPv6 one leads to, for those that embed one: IPv4-mapped and -compatible
+// addresses, NAT64's well-known prefix and 6to4.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let [a, b, c, d, e, f, g, h] = ip.segments();
+    let ipv4 = |high: u16, low: u16| Ipv4Addr::from((high as u32) << 16 | low as u32);
+    match [a, b, c, d, e, f] {
+        [0, 0, 0, 0, 0, 0xffff] | [0x64, 0xff9b, 0, 0, 0, 0] => Some(ipv4(g, h)),
+        // `::` and `::1` are the unspecified and loopback addresses themselves
+        [0, 0, 0, 0, 0, 0] if (g, h) > (0, 1) => Some(ipv4(g, h)),
+        [0x2002, ..] => Some(ipv4(b, c)),
+        _ => None,
+    }
+}
+
+fn on_domain(host: &str, domains: &[String]) -> bool {
+    domains.iter().any(|domain| {
+        let domain = domain.trim_start_matches('.').to_ascii_lowercase();
+        host == domain || host.ends_with(&format!(".{}", domain))
+    })
+}
+
+// Once this many hosts' addresses or clients are kept, they're all let go and looked up again.
+const MAX_HOSTS: usize = 1000;
+
+// Hosts' addresses by host and port, along with when they were looked up.
+type DnsCache = HashMap<(String, u16), (Vec<SocketAddr>, Instant)>;
+
+// Which hosts HTTP sources may be fetched from, so the API can't be made to probe the network it
+// runs in. Clones share the addresses they've looked up.
+#[derive(Debug, Clone, Default)]
+pub struct HostPolicy {
+    allow_private: bool,
+    allowed_domains: Vec<String>,
+    denied_domains: Vec<String>,
+    dns_ttl: Duration,
+    dns_cache: Arc<Mutex<DnsCache>>,
+}
+
+impl HostPolicy {
+    pub fn new(config: &FetchConfig) -> HostPolicy {
+        HostPolicy {
+            allow_private: config.allow_private,
+            allowed_domains: config.allowed_domains.clone(),
+            denied_domains: config.denied_domains.clone(),
+            dns_ttl: Duration::from_secs(config.dns_ttl),
+            dns_cache: Arc::default(),
+        }
+    }
+
+    // The addresses of `host`, looked up again once they're older than `dns_ttl`.
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+        let key = (String::from(host), port);
+        if let Some((addrs, at)) = self.dns_cache.lock().unwrap().get(&key) {
+            if at.elapsed() < self.dns_ttl {
+                return Ok(addrs.clone());
+            }
+        }
+        let addrs: Vec<SocketAddr> = (host, port)
+            .to_socket_addrs()
+            .map_err(|err| Error::DownloadFailed(err.to_string()))?
+            .collect();
+        let mut cache = self.dns_cache.lock().unwrap();
+        if cache.len() >= MAX_HOSTS {
+            cache.clear();
+        }
+        cache.insert(key, (addrs.clone(), Instant::now()));
+        Ok(addrs)
+    }
+
+    // Checks the host of `url` against the domain lists and, unless private addresses are
+    // allowed, every address it resolves to. Returns the checked address to connect to for a
+    // host name, so the request can't be sent wherever a second lookup leads. Blocks on the lookup.
+    pub fn check(&self, url: &Url) -> Result<Option<(String, SocketAddr)>, Error> {
+        let host = url
+            .host_str()
+            .ok_or(Error::InvalidParameter("url"))?
+            .to_ascii_lowercase();
+        let forbidden = || Error::ForbiddenHost(host.clone());
+        if !self.allowed_domains.is_empty() && !on_domain(&host, &self.allowed_domains)
+            || on_domain(&host, &self.denied_domains)
+        {
+            return Err(forbidden());
+        }
+        if self.allow_private {
+            return Ok(None);
+        }
+        if let Ok(ip) = host
+            .trim_matches(|c| c == '[' || c == ']')
+            .parse::<IpAddr>()
+        {
+            return match is_public(ip) {
+                true => Ok(None),
+                false => Err(forbidden()),
+            };
+        }
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addrs = self.resolve(&host, port)?;
+        match addrs.first() {
+            Some(_) if addrs.iter().any(|addr| !is_public(addr.ip())) => Err(forbidden()),
+            Some(&addr) => Ok(Some((host, addr))),
+            None => Err(Error::DownloadFailed(format!("{} has no addresses", host))),
+        }
+    }
+}
+
+// Once this many hosts are tracked, those whose circuits aren't open are forgotten.
+const MAX_CIRCUITS: usize = 10_000;
+
+#[derive(Default)]
+struct Circuit {
+    // downloads failed in a row
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+// Stops fetching from hosts that keep failing for a while, so dead hosts don't tie up workers
+// waiting on their timeouts. Once the while is over a single download is let through to try the
+// host again, and the circuit closes if it succeeds or opens again if it fails.
+pub struct Breaker {
+    circuits: Mutex<HashMap<String, Circuit>>,
+    failures: u32,
+    cooldown: Duration,
+}
+
+impl Breaker {
+    fn new(config: &FetchConfig) -> Breaker {
+        Breaker {
+            circuits: Mutex::new(HashMap::new()),
+            failures: config.breaker_failures,
+            cooldown: Duration::from_secs(config.breaker_cooldown),
+        }
+    }
+
+    fn admit(&self, host: &str) -> Result<(), Error> {
+        if self.failures == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = match circuits.get_mut(host) {
+            Some(circuit) => circuit,
+            None => return Ok(()),
+        };
+        match circuit.open_until {
+            Some(open_until) if open_until > now => {
+                Err(Error::HostUnavailable(String::from(host), open_until - now))
+            }
+            // the trial download; the others keep waiting until it's done
+            Some(_) => {
+                circuit.open_until = Some(now + self.cooldown);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn record(&self, host: &str, failed: bool) {
+        if self.failures == 0 {
+            return;
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        if !failed {
+            circuits.remove(host);
+            return;
+        }
+        if circuits.len() >= MAX_CIRCUITS {
+            circuits.retain(|_, circuit| circuit.open_until.is_some());
+        }
+        let circuit = circuits.entry(String::from(host)).or_default();
+        circuit.failures += 1;
+        if circuit.failures >= self.failures {
+            circuit.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+// A download from a host, recorded with the breaker once it's over. One dropped before it's over,
+// as when `pools.fetch_timeout` runs out, counts as failed.
+struct Attempt {
+    breaker: Arc<Breaker>,
+    host: String,
+    failed: bool,
+    over: bool,
+}
+
+impl Attempt {
+    // Whether `err` says the host is down or struggling, rather than that the request was wrong.
+    fn fail(&mut self, err: reqwest::Error) -> Error {
+        if err.is_timeout()
+            || err.is_connect()
+            || err.is_body()
+            || err.status().is_some_and(|status| status.is_server_error())
+        {
+            self.failed = true;
+        }
+        // URLs can carry credentials, as Telegram's file URLs do the bot's token
+        Error::DownloadFailed(err.without_url().to_string())
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        self.breaker.record(&self.host, self.failed || !self.over);
+    }
+}
+
+// Downloads sources of up to `max_bytes`, refusing larger ones as soon as they're known to be,
+// and anything that isn't an image before it's decoded.
+// Redirects are followed here rather than by the client, so every hop's host is checked.
+#[derive(Clone)]
+pub struct Http {
+    clients: Arc<Clients>,
+    read_timeout: Duration,
+    max_bytes: u64,
+    host_policy: HostPolicy,
+    breaker: Arc<Breaker>,
+}
+
+// The clients downloads are sent with, kept so their connections are pooled: one for the hosts
+// that needn't be pinned, and one for each pinned host and address, made anew only once the host
+// resolves elsewhere.
+struct Clients {
+    shared: Client,
+    pinned: Mutex<HashMap<(String, SocketAddr), Client>>,
+    connect_timeout: Duration,
+    idle_timeout: Duration,
+    max_idle: usize,
+}
+
+impl Clients {
+    fn new(config: &FetchConfig) -> Result<Clients, String> {
+        let mut clients = Clients {
+            shared: Client::new(),
+            pinned: Mutex::new(HashMap::new()),
+            connect_timeout: Duration::from_secs(config.connect_timeout),
+            idle_timeout: Duration::from_secs(config.pool_idle_timeout),
+            max_idle: config.pool_max_idle_per_host,
+        };
+        clients.shared = clients
+            .build(None)
+            .map_err(|err| format!("could not build the HTTP client: {}", err))?;
+        Ok(clients)
+    }
+
+    // A client connecting to the checked address of a host, when there is one to keep to.
+    fn build(&self, pinned: Option<&(String, SocketAddr)>) -> reqwest::Result<Client> {
+        let builder = Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .pool_idle_timeout(self.idle_timeout)
+            .pool_max_idle_per_host(self.max_idle)
+            .http2_adaptive_window(true)
+            .redirect(Policy::none());
+        match pinned {
+            Some((host, addr)) => builder.resolve(host, *addr),
+            None => builder,
+        }
+        .build()
+    }
+
+    fn get(&self, pinned: Option<(String, SocketAddr)>) -> Result<Client, Error> {
+        let pinned = match pinned {
+            Some(pinned) => pinned,
+            None => return Ok(self.shared.clone()),
+        };
+        let mut clients = self.pinned.lock().unwrap();
+        if let Some(client) = clients.get(&pinned) {
+            return Ok(client.clone());
+        }
+        if clients.len() >= MAX_HOSTS {
+            clients.clear();
+        }
+        let client = self
+            .build(Some(&pinned))
+            .map_err(|err| Error::DownloadFailed(err.to_string()))?;
+        clients.insert(pinned, client.clone());
+        Ok(client)
+    }
+}
+
+impl Http {
+    pub fn new(
+        config: &FetchConfig,
+        max_bytes: u64,
+        host_policy: HostPolicy,
+    ) -> Result<Http, String> {
+        Ok(Http {
+            clients: Arc::new(Clients::new(config)?),
+            read_timeout: Duration::from_secs(config.read_timeout),
+            max_bytes,
+            host_policy,
+            breaker: Arc::new(Breaker::new(config)),
+        })
+    }
+
+    async fn send(&self, mut url: Url, attempt: &mut Attempt) -> Result<reqwest::Response, Error> {
+        for _ in 0..=MAX_REDIRECTS {
+            let client = self
+                .clients
+                .get(block_in_place(|| self.host_policy.check(&url))?)?;
+            let response = client
+                .get(url.clone())
+                .send()
+                .await
+                .map_err(|err| attempt.fail(err))?;
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok());
+            match location {
+                Some(location) if response.status().is_redirection() => {
+                    url = url.join(location).map_err(|_| {
+                        Error::DownloadFailed(format!("invalid redirect to {:?}", location))
+                    })?;
+                }
+                _ => return response.error_for_status().map_err(|err| attempt.fail(err)),
+            }
+        }
+        Err(Error::DownloadFailed(String::from("too many redirects")))
+    }
+
+    async fn download(&self, url: Url, attempt: &mut Attempt) -> Result<Vec<u8>, Error> {
+        let mut response = self.send(url, attempt).await?;
+        let claimed = may_be_image(response.headers())?;
+        if response
+            .content_length()
+            .is_some_and(|length| length > self.max_bytes)
+        {
+            return Err(Error::SourceTooLarge(self.max_bytes));
+        }
+        // the length may be missing or wrong, so it's counted as the body arrives too
+        let mut bytes = Vec::new();
+        loop {
+            let chunk = match timeout(self.read_timeout, response.chunk()).await {
+                Ok(chunk) => chunk.map_err(|err| attempt.fail(err))?,
+                Err(_) => {
+                    attempt.failed = true;
+                    let secs = self.read_timeout.as_secs();
+                    let err = format!("the source sent nothing for {}s", secs);
+                    return Err(Error::DownloadFailed(err));
+                }
+            };
+            match chunk {
+                Some(chunk) if (bytes.len() + chunk.len()) as u64 > self.max_bytes => {
+                    return Err(Error::SourceTooLarge(self.max_bytes))
+                }
+                Some(chunk) => bytes.extend_from_slice(&chunk),
+                None => break,
+            }
+        }
+        verify(&bytes, claimed)?;
+        Ok(bytes)
+    }
+}
+
+#[rocket::async_trait]
+impl Fetcher for Http {
+    async fn fetch(&self, url: &str) -> Result<Vec<u8>, Error> {
+        let url = Url::parse(url).map_err(|_| Error::InvalidParameter("url"))?;
+        let host = match (url.host_str(), url.port_or_known_default()) {
+            (Some(host), Some(port)) => format!("{}:{}", host.to_ascii_lowercase(), port),
+            _ => return Err(Error::InvalidParameter("url")),
+        };
+        self.breaker.admit(&host)?;
+        let mut attempt = Attempt {
+            breaker: self.breaker.clone(),
+            host,
+            failed: false,
+            over: false,
+        };
+        let bytes = self.download(url, &mut attempt).await;
+        attempt.over = true;
+        bytes
+    }
+}
+
+fn percent_decode(data: &str) -> Vec<u8> {
+    let bytes = data.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| {
+            let hex = std::str::from_utf8(hex).ok()?;
+            u8::from_str_radix(hex, 16).ok()
+        });
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+// `data:[<media type>][;base64],<data>` URLs, which carry the image themselves.
+pub struct Data {
+    max_bytes: u64,
+}
+
+#[rocket::async_trait]
+impl Fetcher for Data {
+    async fn fetch(&self, url: &str) -> Result<Vec<u8>, Error> {
+        let (header, data) = url
+            .strip_prefix("data:")
+            .and_then(|url| url.split_once(','))
+            .ok_or(Error::InvalidParameter("url"))?;
+        let bytes = if header.ends_with(";base64") {
+            let data: String = percent_decode(data)
+                .into_iter()
+                .map(char::from)
+                .filter(|c| !c.is_ascii_whitespace())
+                .collect();
+            // four characters decode to three bytes, so larger data is refused before decoding
+            if data.len() as u64 / 4 * 3 > self.max_bytes {
+                return Err(Error::SourceTooLarge(self.max_bytes));
+            }
+            STANDARD
+                .decode(data)
+                .map_err(|_| Error::InvalidParameter("url"))?
+        } else {
+            percent_decode(data)
+        };
+        if bytes.len() as u64 > self.max_bytes {
+            return Err(Error::SourceTooLarge(self.max_bytes));
+        }
+        Ok(bytes)
+    }
+}
+
+pub struct File {
+    root: Option<PathBuf>,
+    max_bytes: u64,
+}
+
+#[rocket::async_trait]
+impl Fetcher for File {
+    async fn fetch(&self, url: &str) -> Result<Vec<u8>, Error> {
+        let path = if url.starts_with('/') {
+            Some(PathBuf::from(url))
+        } else {
+            Url::parse(url).ok().and_then(|url| url.to_file_path().ok())
+        };
+        let path = path.ok_or(Error::InvalidParameter("url"))?;
+        let io_error = |err: std::io::Error| Error::DownloadFailed(err.to_string());
+        // resolved first, so neither `..` nor symlinks lead out of the root
+        let path = fs::canonicalize(path).await.map_err(io_error)?;
+        if let Some(root) = &self.root {
+            let root = fs::canonicalize(root).await.map_err(io_error)?;
+            if !path.starts_with(root) {
+                return Err(Error::InvalidParameter("url"));
+            }
+        }
+        // read no further than the limit, however large the file is or grows to be
+        let file = fs::File::open(path).await.map_err(io_error)?;
+        let mut bytes = Vec::new();
+        file.take(self.max_bytes + 1)
+            .read_to_end(&mut bytes)
+            .await
+            .map_err(io_error)?;
+        if bytes.len() as u64 > self.max_bytes {
+            return Err(Error::SourceTooLarge(self.max_bytes));
+        }
+        Ok(bytes)
+    }
+}
+
+// Objects of any bucket the credentials can read, of S3 or another service signing requests alike.
+pub struct S3Objects {
+    config: S3Config,
+    max_bytes: u64,
+}
+
+#[rocket::async_trait]
+impl Fetcher for S3Objects {
+    async fn fetch(&self, url: &str) -> Result<Vec<u8>, Error> {
+        let url = Url::parse(url).map_err(|_| Error::InvalidParameter("url"))?;
+        let bucket = url.host_str().ok_or(Error::InvalidParameter("url"))?;
+        let bucket = S3::new(S3Config {
+            bucket: bucket.to_owned(),
+            ..self.config.clone()
+        })
+        .map_err(Error::DownloadFailed)?;
+        let key = String::from_utf8_lossy(&percent_decode(url.path())).into_owned();
+        match bucket.get_prefix(&key, self.max_bytes + 1) {
+            Ok(Some(bytes)) if bytes.len() as u64 > self.max_bytes => {
+                Err(Error::SourceTooLarge(self.max_bytes))
+            }
+            Ok(Some(bytes)) => Ok(bytes),
+            Ok(None) => Err(Error::DownloadFailed(String::from("no such object"))),
+            Err(err) => Err(Error::DownloadFailed(err.to_string())),
+        }
+    }
+}
+
+// The fetchers of every scheme sources may be given in, by scheme.
+#[derive(Default, Clone)]
+pub struct Fetchers {
+    fetchers: HashMap<String, Arc<dyn Fetcher>>,
+    blocklist: Option<Arc<Blocklist>>,
+    host_policy: HostPolicy,
+}
+
+impl Fetchers {
+    pub fn from_config(config: &Config) -> Result<Fetchers, String> {
+        let mut fetchers = Fetchers {
+            host_policy: HostPolicy::new(&config.fetch),
+            ..Fetchers::default()
+        };
+        let max_bytes = config.conversion_limits.max_source_bytes;
+        let http = Http::new(&config.fetch, max_bytes, fetchers.host_policy.clone())?;
+        fetchers.register("http", http.clone());
+        fetchers.register("https", http);
+        fetchers.register("data", Data { max_bytes });
+        if config.fetch.file {
+            let root = config.fetch.file_root.clone();
+            fetchers.register("file", File { root, max_bytes });
+        }
+        if config.fetch.s3 {
+            let config = config.storage.s3.clone();
+            fetchers.register("s3", S3Objects { config, max_bytes });
+        }
+        if let Some(gcs) = &config.fetch.gcs {
+            let config = gcs.s3_config();
+            fetchers.register("gs", S3Objects { config, max_bytes });
+        }
+        Ok(fetchers)
+    }
+
+    // Refuses the sources on `blocklist` from then on.
+    pub fn with_blocklist(mut self, blocklist: Arc<Blocklist>) -> Fetchers {
+        self.blocklist = Some(blocklist);
+        self
+    }
+
+    // Replaces whatever fetched `scheme` before, e.g. with fixtures in tests.
+    pub fn register(&mut self, scheme: &str, fetcher: impl Fetcher + 'static) {
+        self.fetchers.insert(scheme.to_owned(), Arc::new(fetcher));
+    }
+
+    // Absolute paths are read like `file:` URLs.
+    fn fetcher(&self, url: &str) -> Option<&Arc<dyn Fetcher>> {
+        if url.starts_with('/') {
+            return self.fetchers.get("file");
+        }
+        let (scheme, _) = url.split_once(':')?;
+        self.fetchers.get(&scheme.to_ascii_lowercase())
+    }
+
+    // The hosts HTTP sources may be fetched from, for the endpoints that fetch them their own way.
+    pub fn host_policy(&self) -> &HostPolicy {
+        &self.host_policy
+    }
+
+    pub fn supports(&self, url: &str) -> bool {
+        self.fetcher(url).is_some()
+    }
+
+    pub async fn fetch(&self, url: &str) -> Result<Vec<u8>, Error> {
+        if let Some(blocklist) = &self.blocklist {
+            blocklist.check(url)?;
+        }
+        let bytes = match self.fetcher(url) {
+            Some(fetcher) => fetcher.fetch(url).await?,
+            None => return Err(Error::InvalidParameter("url")),
+        };
+        // sources of other schemes come with no type to check first
+        verify(&bytes, None)?;
+        Ok(bytes)
+    }
+}
+
+// Manages the `Fetchers` enabled by the config, refusing to launch when they can't be set up; must
+// be attached after the `Config` and `Blocklist` fairings.
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Fetchers", |rocket| async {
+        let config = rocket.state::<Config>().cloned().unwrap_or_default();
+        let fetchers = match Fetchers::from_config(&config) {
+            Ok(fetchers) => fetchers,
+            Err(err) => {
+                error!("invalid fetch config: {}", err);
+                return Err(rocket);
+            }
+        };
+        let fetchers = match rocket.state::<Arc<Blocklist>>() {
+            Some(blocklist) => fetchers.with_blocklist(blocklist.clone()),
+            None => fetchers,
+        };
+        Ok(rocket.manage(fetchers))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn public_ipv4() {
+        assert!(public("93.184.216.34"));
+        assert!(public("8.8.8.8"));
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "0.1.2.3",
+            "255.255.255.255",
+            "100.64.0.1",
+            "198.18.0.1",
+            "192.0.0.8",
+            "192.0.2.1",
+            "224.0.0.1",
+            "240.0.0.1",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn public_ipv6() {
+        assert!(public("2606:2800:220:1:248:1893:25c8:1946"));
+        for ip in [
+            "::",
+            "::1",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "ff02::1",
+            "64:ff9b:1::1",
+            "2001:db8::1",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn ipv4_embedded_in_ipv6() {
+        for ip in [
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "::127.0.0.1",
+            "::192.168.0.1",
+            "2002:7f00:1::",
+            "2002:c0a8:101::1",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+        assert!(public("::ffff:8.8.8.8"));
+        assert!(public("64:ff9b::808:808"));
+        assert!(public("2002:808:808::1"));
+        assert_eq!(
+            embedded_ipv4("2002:c0a8:101::1".parse().unwrap()),
+            Some(Ipv4Addr::new(192, 168, 1, 1))
+        );
+        assert_eq!(embedded_ipv4("2001:4860::8888".parse().unwrap()), None);
+    }
+
+    fn check(config: FetchConfig, url: &str) -> Result<Option<(String, SocketAddr)>, Error> {
+        HostPolicy::new(&config).check(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn refuses_hosts_off_the_domain_lists() {
+        let config = FetchConfig {
+            allow_private: true,
+            allowed_domains: vec![String::from(".Example.com")],
+            denied_domains: vec![String::from("private.example.com")],
+            ..FetchConfig::default()
+        };
+        for url in ["https://example.com/a.png", "https://cdn.EXAMPLE.com/a.png"] {
+            assert!(check(config.clone(), url).is_ok(), "{}", url);
+        }
+        for url in [
+            "https://badexample.com/a.png",
+            "https://example.org/a.png",
+            "https://private.example.com/a.png",
+            "https://a.private.example.com/a.png",
+        ] {
+            let refused = check(config.clone(), url);
+            assert!(matches!(refused, Err(Error::ForbiddenHost(_))), "{}", url);
+        }
+    }
+
+    #[test]
+    fn refuses_private_addresses_however_they_are_spelled() {
+        for url in [
+            "http://127.0.0.1/",
+            "http://[::1]/",
+            "http://[::ffff:169.254.169.254]/",
+            "http://localhost:8080/",
+        ] {
+            let refused = check(FetchConfig::default(), url);
+            assert!(matches!(refused, Err(Error::ForbiddenHost(_))), "{}", url);
+        }
+        // addresses aren't pinned, as there's nothing to look up
+        assert!(matches!(
+            check(FetchConfig::default(), "http://8.8.8.8/"),
+            Ok(None)
+        ));
+        let config = FetchConfig {
+            allow_private: true,
+            ..FetchConfig::default()
+        };
+        assert!(matches!(check(config, "http://localhost/"), Ok(None)));
+    }
+}
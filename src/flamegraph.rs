@@ -0,0 +1,58 @@
+use rocket::{get, State};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::auth::AdminAuth;
+use crate::error::Error;
+use crate::fetch::Fetchers;
+use crate::negotiate::Representation;
+use crate::permalink::{PermalinkConfig, Permalinks};
+use crate::pools::Pools;
+use crate::query::ValidatedParams;
+use crate::spans;
+use crate::storage::Memory;
+
+// Converts `https://<IMAGE_URL>` as `GET /<IMAGE_URL>` would, and answers with where the time went
+// instead of the result: the download, then the decoding, conversion and encoding stages as folded
+// stacks of microseconds, for `flamegraph.pl`, inferno or speedscope to draw.
+#[get("/flamegraph/<image_url..>")]
+pub async fn flamegraph(
+    _auth: AdminAuth,
+    image_url: PathBuf,
+    params: Result<ValidatedParams, Error>,
+    representation: Representation,
+    pools: &State<Pools>,
+    fetchers: &State<Fetchers>,
+) -> Result<String, Error> {
+    let string_url = image_url.to_str().ok_or(Error::InvalidParameter("URL"))?;
+    let ValidatedParams { conversion, format } = params?;
+    let representation = representation.or_format(format.as_deref())?;
+    let image_url = format!("{}{}", "https://", string_url);
+
+    let t0 = Instant::now();
+    let bytes = pools.download(fetchers.fetch(&image_url)).await?;
+    let download = t0.elapsed();
+    // nothing profiled is stored behind permalinks
+    let permalinks = Permalinks::new(&PermalinkConfig::default(), Arc::new(Memory::default()));
+    let (rendered, mut folded) = pools
+        .convert(move || {
+            spans::record(|| {
+                let image = {
+                    let _span = spans::span("decode");
+                    image::load_from_memory(&bytes)?
+                };
+                conversion.render_negotiated(
+                    &bytes,
+                    &image,
+                    &image_url,
+                    representation,
+                    &permalinks,
+                )
+            })
+        })
+        .await?;
+    rendered?;
+    folded.add("download", download);
+    Ok(folded.to_text())
+}
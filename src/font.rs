@@ -1,8 +1,11 @@
 use std::collections::{HashMap, HashSet};
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Read;
 use std::path::Path;
 
+use crate::convert::synthetic_glyph;
+use crate::truetype::TrueType;
+
 #[derive(Clone)]
 pub struct Character {
     pub value: char,
@@ -46,6 +49,18 @@ impl Font {
             .filter(|c| char_set.contains(&c.value))
             .cloned()
             .collect();
+        // none of the alphabet is in the font, which `missing_glyphs` reports
+        if chars.is_empty() {
+            return Font {
+                width: 0,
+                height: 0,
+                chars,
+                intensities: Vec::new(),
+                grads: Vec::new(),
+                char_map: HashMap::new(),
+                intensity_chars: Vec::new(),
+            };
+        }
 
         let min_height = chars.iter().map(|c| c.height).min().unwrap();
         let max_height = chars.iter().map(|c| c.height).max().unwrap();
@@ -67,12 +82,23 @@ impl Font {
 
         let (width, height) = (min_width, min_height);
 
+        // block, shade, box-drawing and braille characters the font lacks are drawn to its cells
+        let mut chars = chars;
+        for &c in alphabet {
+            if !chars.iter().any(|chr| chr.value == c) {
+                if let Some(bitmap) = synthetic_glyph(c, width, height) {
+                    chars.push(Character::new(c, bitmap, width, height));
+                }
+            }
+        }
+
         let intensity_indexes: Vec<i32> = chars
             .iter()
             .cloned()
             .map(|c| c.bitmap.iter().sum::<f32>() as i32)
             .collect();
-        let max_intensity = *intensity_indexes.iter().max().unwrap_or(&0);
+        // at least 1, for alphabets of blank glyphs alone
+        let max_intensity = intensity_indexes.iter().copied().max().unwrap_or(0).max(1);
         let max_possible_intensity = (width * height) as i32;
         let intensities: Vec<i32> = intensity_indexes
             .iter()
@@ -89,7 +115,7 @@ impl Font {
             Vec::with_capacity(max_possible_intensity as usize + 1);
         let mut index = 0;
         for i in 0..=max_possible_intensity {
-            while i > char_intensities[index].0 {
+            while i > char_intensities[index].0 && index + 1 < char_intensities.len() {
                 index += 1;
             }
             intensity_chars.push(char_intensities[index].1.clone());
@@ -138,6 +164,18 @@ impl Font {
         }
     }
 
+    // Characters of `alphabet` that `Font::new` had to drop because the font has no glyph for them.
+    pub fn missing_glyphs(&self, alphabet: &[char]) -> Vec<char> {
+        let mut missing: Vec<char> = alphabet
+            .iter()
+            .filter(|c| !self.char_map.contains_key(c))
+            .cloned()
+            .collect();
+        missing.sort();
+        missing.dedup();
+        missing
+    }
+
     pub fn from_bdf_stream<R: Read>(stream: R, alphabet: &[char]) -> Font {
         let font: bdf::Font = bdf::read(stream).unwrap();
         let mut chars: Vec<Character> = font
@@ -165,6 +203,30 @@ impl Font {
         Font::from_bdf_stream(File::open(path).unwrap(), alphabet)
     }
 
+    // Rasterizes the alphabet's glyphs from TrueType font data at `size` pixels per em, into cells
+    // as wide as the widest of them and as tall as the font's ascender to its descender.
+    pub fn from_truetype(data: &[u8], size: f32, alphabet: &[char]) -> Result<Font, String> {
+        let font = TrueType::parse(data)?;
+        let glyphs: Vec<(char, u16)> = alphabet
+            .iter()
+            .filter_map(|&c| font.glyph_index(c).map(|glyph| (c, glyph)))
+            .collect();
+        let indexes: Vec<u16> = glyphs.iter().map(|&(_, glyph)| glyph).collect();
+        let (width, height) = font.cell(&indexes, size);
+        let mut chars = Vec::with_capacity(glyphs.len());
+        for (value, glyph) in glyphs {
+            let bitmap = font.rasterize(glyph, size, (width, height))?;
+            chars.push(Character::new(value, bitmap, width, height));
+        }
+        Ok(Font::new(&chars, alphabet))
+    }
+
+    pub fn from_truetype_file(path: &Path, size: f32, alphabet: &[char]) -> Font {
+        let data = fs::read(path).unwrap();
+        Font::from_truetype(&data, size, alphabet)
+            .unwrap_or_else(|err| panic!("could not load font {:?}: {}", path, err))
+    }
+
     pub fn _print(&self) {
         for c in &self.chars {
             println!("{}", c.value);
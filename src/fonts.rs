@@ -0,0 +1,328 @@
+use log::warn;
+use rocket::fairing::AdHoc;
+use rocket::form::Form;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{get, post, FromForm, State};
+use std::collections::BTreeMap;
+use std::panic;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::alphabets::valid_name;
+use crate::auth::AdminAuth;
+use crate::config::Config;
+use crate::error::Error;
+use crate::font::Font;
+use crate::generate::{
+    is_bundled_font, load_alphabet, load_font, register_font, ALPHABETS, DEFAULT_FONT_SIZE, FONTS,
+};
+use crate::storage::Storage;
+use crate::truetype;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct FontsConfig {
+    // uploaded fonts are kept under this key of the storage across restarts when set, each file
+    // under `<path>/<name>` and the list of them under `<path>/index.json`
+    pub path: Option<PathBuf>,
+    // the most fonts that can be uploaded
+    pub max: usize,
+}
+
+impl Default for FontsConfig {
+    fn default() -> FontsConfig {
+        FontsConfig {
+            path: None,
+            max: 20,
+        }
+    }
+}
+
+// Fonts uploaded by operators, by name, usable wherever a bundled one is. Only the listing of each
+// is kept here, the files themselves are registered with `generate`.
+pub struct Fonts {
+    fonts: Mutex<BTreeMap<String, FontInfo>>,
+    // the listing of the bundled fonts, which never changes
+    bundled: Vec<FontInfo>,
+    config: FontsConfig,
+    storage: Arc<dyn Storage>,
+}
+
+// The characters every alphabet is drawn from, which an uploaded font must have glyphs for some
+// of.
+fn printable() -> Vec<char> {
+    (' '..='~').collect()
+}
+
+// The format of font file `data`, `bdf` or `truetype`, if it can be drawn with.
+fn check_font(data: &[u8]) -> Result<&'static str, String> {
+    if truetype::is_font(data) {
+        let font = Font::from_truetype(data, DEFAULT_FONT_SIZE, &printable())?;
+        if font.chars.is_empty() {
+            return Err(String::from("has no glyphs for printable ASCII"));
+        }
+        return Ok("truetype");
+    }
+    let not_a_font = || String::from("is neither a BDF nor a TrueType font");
+    if !data.starts_with(b"STARTFONT") {
+        return Err(not_a_font());
+    }
+    // the BDF reader panics on some malformed properties rather than erroring
+    let font = panic::catch_unwind(|| bdf::read(data))
+        .map_err(|_| not_a_font())?
+        .map_err(|_| not_a_font())?;
+    let cells: Vec<(u32, u32)> = font
+        .glyphs()
+        .iter()
+        .filter(|(c, _)| printable().contains(c))
+        .map(|(_, glyph)| (glyph.width(), glyph.height()))
+        .collect();
+    match cells.first() {
+        None => Err(String::from("has no glyphs for printable ASCII")),
+        Some(cell) if cells.iter().any(|other| other != cell) => {
+            Err(String::from("must be monospace"))
+        }
+        Some(_) => Ok("bdf"),
+    }
+}
+
+// Draws the characters `chars` in font file `data`, which `check_font` accepted.
+fn draw(data: &[u8], chars: &[char]) -> Result<Font, Error> {
+    if truetype::is_font(data) {
+        Font::from_truetype(data, DEFAULT_FONT_SIZE, chars)
+            .map_err(|reason| Error::InvalidValue("font", reason))
+    } else {
+        Ok(Font::from_bdf_stream(data, chars))
+    }
+}
+
+impl Fonts {
+    // Registers the fonts the storage kept, skipping any that's gone missing.
+    pub fn load(config: FontsConfig, storage: Arc<dyn Storage>) -> Fonts {
+        let mut fonts = BTreeMap::new();
+        if let Some(path) = &config.path {
+            let index: BTreeMap<String, String> = storage
+                .get(&path.join("index.json").to_string_lossy())
+                .ok()
+                .flatten()
+                .and_then(|json| serde_json::from_slice(&json).ok())
+                .unwrap_or_default();
+            for (name, format) in index {
+                match storage.get(&path.join(&name).to_string_lossy()) {
+                    Ok(Some(data)) => {
+                        let info = check_font(&data).and_then(|_| {
+                            font_info(&name, &format, |chars| draw(&data, chars))
+                                .map_err(|err| err.to_string())
+                        });
+                        match info {
+                            Ok(info) => {
+                                register_font(&name, data);
+                                fonts.insert(name, info);
+                            }
+                            Err(reason) => {
+                                warn!("could not load font {:?} from {:?}: {}", name, path, reason)
+                            }
+                        }
+                    }
+                    _ => warn!("could not load font {:?} from {:?}", name, path),
+                }
+            }
+        }
+        let bundled = FONTS
+            .iter()
+            .filter_map(|(name, _)| font_info(name, "bdf", |chars| load_font(name, chars)).ok())
+            .collect();
+        Fonts {
+            fonts: Mutex::new(fonts),
+            bundled,
+            config,
+            storage,
+        }
+    }
+
+    fn save(&self, fonts: &BTreeMap<String, FontInfo>, name: &str, data: &[u8]) {
+        if let Some(path) = &self.config.path {
+            let index: BTreeMap<&String, &String> = fonts
+                .iter()
+                .map(|(name, info)| (name, &info.format))
+                .collect();
+            let json = serde_json::to_vec(&index).unwrap();
+            let saved = self
+                .storage
+                .put(&path.join(name).to_string_lossy(), data)
+                .and_then(|_| {
+                    let index = path.join("index.json");
+                    self.storage.put(&index.to_string_lossy(), &json)
+                });
+            if let Err(err) = saved {
+                warn!("could not save font {:?} to {:?}: {}", name, path, err);
+            }
+        }
+    }
+
+    // Validates font file `data` and registers it as `name`, returning its format and whether the
+    // name is new. Unlike alphabets, uploading again under a name replaces the font.
+    pub fn register(&self, name: &str, data: &[u8]) -> Result<(&'static str, bool), Error> {
+        if !valid_name(name) || is_bundled_font(name) {
+            let reason = String::from(
+                "must be 1 to 32 lowercase letters, digits, `-` or `_`, and not a bundled font",
+            );
+            return Err(Error::InvalidValue("name", reason));
+        }
+        let format = check_font(data).map_err(|reason| Error::InvalidValue("font", reason))?;
+        let info = font_info(name, format, |chars| draw(data, chars))?;
+        let mut fonts = self.fonts.lock().unwrap();
+        let new = !fonts.contains_key(name);
+        if new && fonts.len() >= self.config.max {
+            return Err(Status::InsufficientStorage.into());
+        }
+        fonts.insert(String::from(name), info);
+        register_font(name, data.to_vec());
+        self.save(&fonts, name, data);
+        Ok((format, new))
+    }
+}
+
+// Manages the `Fonts`, restoring the persisted ones. Must be attached after the `Storage` fairing
+// and before anything that loads fonts.
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Fonts", |rocket| async {
+        let config = rocket
+            .state::<Config>()
+            .map(|config| config.fonts.clone())
+            .unwrap_or_default();
+        match rocket.state::<Arc<dyn Storage>>().cloned() {
+            Some(storage) => rocket.manage(Arc::new(Fonts::load(config, storage))),
+            None => rocket,
+        }
+    })
+}
+
+// Rocket caps the `font` field with its own `limits.bytes` and `limits.data-form` settings.
+#[derive(FromForm)]
+pub struct FontUpload<'r> {
+    name: String,
+    font: &'r [u8],
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct UploadedFont {
+    name: String,
+    format: &'static str,
+}
+
+// Uploads a .bdf, .ttf, .otf or .ttc font as the `font` field of a multipart form, making it
+// available by `name` to conversions.
+#[post("/fonts", format = "multipart/form-data", data = "<upload>")]
+pub fn upload_font(
+    _auth: AdminAuth,
+    upload: Form<FontUpload<'_>>,
+    fonts: &State<Arc<Fonts>>,
+) -> Result<(Status, Json<UploadedFont>), Error> {
+    let (format, new) = fonts.register(&upload.name, upload.font)?;
+    let status = if new { Status::Created } else { Status::Ok };
+    let name = upload.into_inner().name;
+    Ok((status, Json(UploadedFont { name, format })))
+}
+
+#[derive(Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Coverage {
+    characters: usize,
+    // the characters the font has no glyph for, in order
+    missing: String,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct FontInfo {
+    name: String,
+    format: String,
+    // the size in pixels of the font's cells for the default alphabet; TrueType fonts are measured
+    // at the default font size, and their cells fit the widest glyph of the alphabet used
+    width: usize,
+    height: usize,
+    // how much of each bundled alphabet the font has glyphs for
+    coverage: BTreeMap<&'static str, Coverage>,
+}
+
+// The listing of font `name`, drawing each bundled alphabet with `draw`. Done once as the font is
+// registered, since it draws the font many times over.
+fn font_info(
+    name: &str,
+    format: &str,
+    draw: impl Fn(&[char]) -> Result<Font, Error>,
+) -> Result<FontInfo, Error> {
+    let coverage = ALPHABETS
+        .iter()
+        .map(|&(alphabet, _)| {
+            let chars = load_alphabet(alphabet);
+            let missing = draw(&chars)?.missing_glyphs(&chars);
+            let coverage = Coverage {
+                characters: chars.len(),
+                missing: missing.into_iter().collect(),
+            };
+            Ok((alphabet, coverage))
+        })
+        .collect::<Result<_, Error>>()?;
+    let font = draw(&load_alphabet("alphabet"))?;
+    Ok(FontInfo {
+        name: String::from(name),
+        format: String::from(format),
+        width: font.width,
+        height: font.height,
+        coverage,
+    })
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct FontList {
+    bundled: Vec<FontInfo>,
+    uploaded: Vec<FontInfo>,
+}
+
+// The bundled fonts and the uploaded ones, with their formats, cell sizes and how much of each
+// bundled alphabet they cover, for clients to offer a choice of fonts from.
+#[get("/fonts")]
+pub fn fonts(fonts: &State<Arc<Fonts>>) -> Json<FontList> {
+    let uploaded = fonts.fonts.lock().unwrap().values().cloned().collect();
+    Json(FontList {
+        bundled: fonts.bundled.clone(),
+        uploaded,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Memory;
+
+    fn config() -> FontsConfig {
+        FontsConfig {
+            path: Some(PathBuf::from("fonts")),
+            ..FontsConfig::default()
+        }
+    }
+
+    #[test]
+    fn lists_uploaded_fonts_as_they_are_registered_and_restored() {
+        let storage: Arc<dyn Storage> = Arc::new(Memory::default());
+        let fonts = Fonts::load(config(), storage.clone());
+        assert_eq!(fonts.bundled.len(), FONTS.len());
+        let courier = FONTS[0].1.as_bytes();
+        assert_eq!(fonts.register("listed", courier).unwrap(), ("bdf", true));
+        let listed = |fonts: &Fonts| {
+            let fonts = fonts.fonts.lock().unwrap();
+            let info = &fonts["listed"];
+            (info.format.clone(), info.width, info.height)
+        };
+        let bundled = &fonts.bundled[0];
+        let expected = (String::from("bdf"), bundled.width, bundled.height);
+        assert_eq!(listed(&fonts), expected);
+        assert_eq!(listed(&Fonts::load(config(), storage)), expected);
+    }
+}
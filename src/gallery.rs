@@ -0,0 +1,308 @@
+use log::warn;
+use reqwest::Url;
+use rocket::fairing::AdHoc;
+use rocket::http::{ContentType, Status};
+use rocket::serde::json::{json, Value};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{get, State};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::blocklist::normalize;
+use crate::config::Config;
+use crate::convert::Theme;
+use crate::error::Error;
+use crate::storage::Storage;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct GalleryConfig {
+    // conversions stored behind a permalink are listed publicly under `/gallery` when set
+    pub enabled: bool,
+    // how many of the most recent ones are listed
+    pub size: usize,
+    pub title: String,
+    // prefixed to the links of the feeds, which readers want absolute, e.g.
+    // `https://ascii.example.com`
+    pub base_url: String,
+    // the listed conversions are kept under this key of the storage across restarts when set
+    pub path: Option<PathBuf>,
+}
+
+impl Default for GalleryConfig {
+    fn default() -> GalleryConfig {
+        GalleryConfig {
+            enabled: false,
+            size: 50,
+            title: String::from("image-to-ascii-api gallery"),
+            base_url: String::new(),
+            path: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Entry {
+    id: String,
+    source: String,
+    font: String,
+    width: u64,
+    // unix time in seconds
+    added: u64,
+}
+
+impl Entry {
+    fn title(&self) -> String {
+        format!("{} characters wide in {}", self.width, self.font)
+    }
+}
+
+pub struct Gallery {
+    entries: Mutex<VecDeque<Entry>>,
+    config: GalleryConfig,
+    storage: Arc<dyn Storage>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// Source `url` as the gallery lists it, without a query or fragment, which may carry tokens that
+// grant access to it, such as those of presigned URLs. Uploads' sources, which aren't URLs, are kept
+// as they are.
+fn without_query(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut url) => {
+            url.set_query(None);
+            url.set_fragment(None);
+            url.into()
+        }
+        Err(_) => url.to_owned(),
+    }
+}
+
+// Escaped for XML text and attribute values alike.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl Gallery {
+    pub fn load(config: GalleryConfig, storage: Arc<dyn Storage>) -> Gallery {
+        let entries = config
+            .path
+            .as_ref()
+            .and_then(|path| storage.get(&path.to_string_lossy()).ok().flatten())
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default();
+        Gallery {
+            entries: Mutex::new(entries),
+            config,
+            storage,
+        }
+    }
+
+    fn save(&self, entries: &VecDeque<Entry>) {
+        if let Some(path) = &self.config.path {
+            let json = serde_json::to_vec(entries).unwrap();
+            if let Err(err) = self.storage.put(&path.to_string_lossy(), &json) {
+                warn!("could not save gallery to {:?}: {}", path, err);
+            }
+        }
+    }
+
+    // Lists the conversion just stored as permalink `id` first, moving it up when the same one was
+    // already listed. `params` are its resolved parameters.
+    pub fn add(&self, id: &str, params: &Value) {
+        if !self.config.enabled || self.config.size == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| entry.id != id);
+        entries.push_front(Entry {
+            id: id.to_owned(),
+            source: without_query(params["image_url"].as_str().unwrap_or_default()),
+            font: String::from(params["font"].as_str().unwrap_or_default()),
+            width: params["width"].as_u64().unwrap_or(0),
+            added: now(),
+        });
+        entries.truncate(self.config.size);
+        self.save(&entries);
+    }
+
+    pub fn remove(&self, id: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        let len = entries.len();
+        entries.retain(|entry| entry.id != id);
+        if entries.len() != len {
+            self.save(&entries);
+        }
+    }
+
+    // The permalinks listed of `source`, whatever its query.
+    pub fn ids_of(&self, source: &str) -> Vec<String> {
+        let source = normalize(&without_query(source.trim()));
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .filter(|entry| normalize(&entry.source) == source)
+            .map(|entry| entry.id.clone())
+            .collect()
+    }
+
+    fn recent(&self) -> Result<Vec<Entry>, Error> {
+        if !self.config.enabled {
+            return Err(Status::NotFound.into());
+        }
+        Ok(self.entries.lock().unwrap().iter().cloned().collect())
+    }
+
+    fn link(&self, path: &str) -> String {
+        format!("{}{}", self.config.base_url.trim_end_matches('/'), path)
+    }
+
+    fn image_html(&self, entry: &Entry) -> String {
+        format!(
+            "<a href=\"{}\"><img src=\"{}\" alt=\"{}\"></a>",
+            escape(&self.link(&format!("/r/{}?format=html", entry.id))),
+            escape(&self.link(&format!("/r/{}?format=png", entry.id))),
+            escape(&entry.title())
+        )
+    }
+}
+
+// Manages the `Gallery`, restoring the persisted one. Must be attached after the `Storage` fairing
+// and before the `Permalinks` one, which lists what it stores in it.
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Gallery", |rocket| async {
+        let config = rocket
+            .state::<Config>()
+            .map(|config| config.gallery.clone())
+            .unwrap_or_default();
+        match rocket.state::<Arc<dyn Storage>>().cloned() {
+            Some(storage) => rocket.manage(Arc::new(Gallery::load(config, storage))),
+            None => rocket,
+        }
+    })
+}
+
+// The recent conversions as a page of their PNG renders, each linking to its HTML one.
+#[get("/gallery")]
+pub fn gallery(gallery: &State<Arc<Gallery>>) -> Result<(ContentType, String), Error> {
+    let title = escape(&gallery.config.title);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+         <link rel=\"alternate\" type=\"application/feed+json\" href=\"/gallery/feed.json\">\n\
+         <link rel=\"alternate\" type=\"application/rss+xml\" href=\"/gallery/rss.xml\">\n\
+         <style>\n{}\nimg{{max-width:100%}}\n</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+        title,
+        Theme::default().stylesheet(),
+        title
+    );
+    for entry in gallery.recent()? {
+        html += &format!(
+            "<figure>\n{}\n<figcaption>{}</figcaption>\n</figure>\n",
+            gallery.image_html(&entry),
+            escape(&entry.title())
+        );
+    }
+    Ok((ContentType::HTML, html + "</body>\n</html>\n"))
+}
+
+// The recent conversions as a JSON Feed, https://www.jsonfeed.org/version/1.1/.
+#[get("/gallery/feed.json")]
+pub fn json_feed(gallery: &State<Arc<Gallery>>) -> Result<(ContentType, String), Error> {
+    let items: Vec<Value> = gallery
+        .recent()?
+        .iter()
+        .map(|entry| {
+            let mut item = json!({
+                "id": entry.id,
+                "url": gallery.link(&format!("/r/{}?format=html", entry.id)),
+                "title": entry.title(),
+                "content_html": gallery.image_html(entry),
+                "image": gallery.link(&format!("/r/{}?format=png", entry.id)),
+            });
+            // uploads have no source to link to
+            if entry.source.starts_with("http") {
+                item["external_url"] = json!(without_query(&entry.source));
+            }
+            item
+        })
+        .collect();
+    let feed = json!({
+        "version": "https://jsonfeed.org/version/1.1",
+        "title": gallery.config.title,
+        "home_page_url": gallery.link("/gallery"),
+        "feed_url": gallery.link("/gallery/feed.json"),
+        "items": items,
+    });
+    let content_type = ContentType::new("application", "feed+json");
+    Ok((content_type, feed.to_string()))
+}
+
+// The recent conversions as an RSS 2.0 feed.
+#[get("/gallery/rss.xml")]
+pub fn rss_feed(gallery: &State<Arc<Gallery>>) -> Result<(ContentType, String), Error> {
+    let mut rss = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<rss version=\"2.0\">\n<channel>\n\
+         <title>{}</title>\n<link>{}</link>\n<description>{}</description>\n",
+        escape(&gallery.config.title),
+        escape(&gallery.link("/gallery")),
+        escape(&gallery.config.title)
+    );
+    for entry in gallery.recent()? {
+        let added = UNIX_EPOCH + Duration::from_secs(entry.added);
+        rss += &format!(
+            "<item>\n<title>{}</title>\n<link>{}</link>\n<guid isPermaLink=\"false\">{}</guid>\n\
+             <pubDate>{}</pubDate>\n<description>{}</description>\n</item>\n",
+            escape(&entry.title()),
+            escape(&gallery.link(&format!("/r/{}?format=html", entry.id))),
+            entry.id,
+            httpdate::fmt_http_date(added),
+            escape(&gallery.image_html(&entry))
+        );
+    }
+    let content_type = ContentType::new("application", "rss+xml");
+    Ok((content_type, rss + "</channel>\n</rss>\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Memory;
+
+    #[test]
+    fn lists_sources_without_their_query() {
+        let config = GalleryConfig {
+            enabled: true,
+            ..GalleryConfig::default()
+        };
+        let gallery = Gallery::load(config, Arc::new(Memory::default()));
+        let url = "https://bucket.example.com/a.png?X-Amz-Signature=secret#top";
+        gallery.add(
+            "abc",
+            &json!({ "image_url": url, "font": "courier", "width": 80 }),
+        );
+        gallery.add(
+            "def",
+            &json!({ "image_url": "upload", "font": "courier", "width": 80 }),
+        );
+        let sources: Vec<String> = gallery
+            .recent()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.source)
+            .collect();
+        assert_eq!(sources, ["upload", "https://bucket.example.com/a.png"]);
+        assert_eq!(gallery.ids_of(url), ["abc"]);
+    }
+}
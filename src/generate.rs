@@ -1,250 +1,851 @@
-use crate::convert::get_converter;
-use crate::convert::{
-    char_rows_to_bitmap, char_rows_to_color_bitmap, char_rows_to_html_color_string,
-    char_rows_to_string, char_rows_to_terminal_color_string,
-};
+use crate::convert::{blank_cells, char_rows_to_string, char_rows_to_terminal_color_string, Theme};
+use crate::convert::{get_converter, MatchOptions};
+use crate::cvd::simulate_cvd;
 use crate::font::Font;
-use crate::gif::write_gif;
-use crate::progress::default_progress_bar;
+use crate::metadata::{Quality, WidthAdvice};
+use crate::spans;
 
-use image::DynamicImage;
-use indicatif::ProgressIterator;
-use rocket::http::hyper::Uri;
+use image::imageops::FilterType::Nearest;
+use image::{DynamicImage, GenericImageView};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread::sleep;
 use std::time::{Duration, Instant};
-use reqwest::blocking::get;
 
-use log::info;
+use log::{debug, info};
+use rocket::serde::Serialize;
+use sha2::{Digest, Sha256};
 
 use crate::convert;
-use crate::font;
-use crate::gif;
-use crate::progress;
+use crate::error::Error;
+use crate::gif::collapse_runs;
+use crate::negotiate::{self, Representation};
+use crate::truetype;
 
-
-const ALPHABETS: [(&str, &str); 6] = [
+// The quadrant, shade and box-drawing presets are drawn from how much of the cell each character
+// covers when the font has no glyphs for them.
+pub const ALPHABETS: [(&str, &str); 9] = [
     ("alphabet", include_str!("../alphabets/alphabet.txt")),
+    ("box", include_str!("../alphabets/box.txt")),
     ("letters", include_str!("../alphabets/letters.txt")),
     ("lowercase", include_str!("../alphabets/lowercase.txt")),
     ("minimal", include_str!("../alphabets/minimal.txt")),
+    ("quadrants", include_str!("../alphabets/quadrants.txt")),
+    ("shades", include_str!("../alphabets/shades.txt")),
     ("symbols", include_str!("../alphabets/symbols.txt")),
     ("uppercase", include_str!("../alphabets/uppercase.txt")),
 ];
 
-const FONTS: [(&str, &str); 2] = [
+// Rows of characters converted at once when streaming output band by band.
+pub const BAND_ROWS: usize = 32;
+// Text grids with more cells than this are converted band by band.
+const BANDED_CELLS: usize = 40_000;
+
+pub const FONTS: [(&str, &str); 2] = [
     ("courier", include_str!("../fonts/courier.bdf")),
     ("bitocra-13", include_str!("../fonts/bitocra-13.bdf")),
 ];
 
-#[derive(Debug)]
+// `glyphs` matches glyphs against each cell's structure, `pixels` emits one colored block per cell,
+// `braille` a pattern of two by four dots per cell, `halfblocks` and `quadrants` split each cell
+// into a foreground and a background color, and `grayscale` maps brightness straight to the
+// alphabet ordered by glyph density, without color.
+pub const MODES: [&str; 6] = [
+    "glyphs",
+    "pixels",
+    "braille",
+    "halfblocks",
+    "quadrants",
+    "grayscale",
+];
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(crate = "rocket::serde")]
 pub struct Params<'a> {
     pub image_url: &'a str,
     pub font: &'a str,
+    // pixels per em TrueType fonts are drawn at
+    pub font_size: Option<f32>,
     pub alphabet: &'a str,
+    // the alphabet's characters given literally, used instead of `alphabet`
+    pub charset: Option<&'a str>,
     pub width: usize,
+    // rows the grid may not exceed, narrowing it to keep the image's proportions
+    pub height: Option<usize>,
     pub metric: &'a str,
     pub threads: usize,
     pub no_color: bool,
     pub brightness_offset: f32,
+    // applied to the image characters are picked from, 1 leaving it as is
+    pub contrast: f32,
+    pub gamma: f32,
     pub noise_scale: f32,
     pub out_path: Option<&'a str>,
     pub fps: f64,
     pub no_edge_detection: bool,
+    pub cvd: Option<&'a str>,
+    pub playback: &'a str,
+    pub speed: f64,
+    pub sample_fps: Option<f64>,
+    pub interpolate_fps: Option<f64>,
+    pub blank_char: Option<char>,
+    pub mode: &'a str,
+    pub color_tolerance: u8,
+    pub color_mode: &'a str,
+    pub themed_background: bool,
+    pub light_background: bool,
+    // dense characters for dark areas rather than bright ones, for light backgrounds
+    pub invert: bool,
+    pub dither: Option<&'a str>,
+    // cells' height over their width as shown; the font's own when unset
+    pub aspect: Option<f64>,
+    // whether colored HTML pages style characters by classes from a stylesheet, not inline
+    pub html_classes: bool,
+}
+
+impl<'a> Params<'a> {
+    // The parameters a conversion of `image_url` `width` characters wide has when a request sets
+    // nothing else, so callers that only pick a few spell out just those.
+    pub fn defaults_for(image_url: &'a str, width: usize) -> Params<'a> {
+        Params {
+            image_url,
+            font: "bitocra-13",
+            font_size: None,
+            alphabet: "alphabet",
+            charset: None,
+            width,
+            height: None,
+            metric: "grad",
+            threads: 1,
+            no_color: false,
+            brightness_offset: 0.0,
+            contrast: 1.0,
+            gamma: 1.0,
+            noise_scale: 0.0,
+            out_path: None,
+            fps: 30.0,
+            no_edge_detection: false,
+            cvd: None,
+            playback: "forward",
+            speed: 1.0,
+            sample_fps: None,
+            interpolate_fps: None,
+            blank_char: None,
+            mode: "glyphs",
+            color_tolerance: 0,
+            color_mode: "truecolor",
+            themed_background: false,
+            light_background: false,
+            invert: false,
+            dither: None,
+            aspect: None,
+            html_classes: false,
+        }
+    }
+}
+
+pub fn is_bundled_alphabet(alphabet_str: &str) -> bool {
+    ALPHABETS.iter().any(|(name, _)| *name == alphabet_str)
+}
+
+pub fn is_bundled_font(font_str: &str) -> bool {
+    FONTS.iter().any(|(name, _)| *name == font_str)
+}
+
+// Whether a client may name the font: a bundled one or one uploaded with `POST /fonts`.
+pub fn is_available_font(font_str: &str) -> bool {
+    is_bundled_font(font_str) || registered_font(font_str).is_some()
+}
+
+// A font uploaded with `POST /fonts`: its file, and a hash of the file that tells results drawn in
+// it from ones drawn in another file uploaded under the same name.
+#[derive(Clone)]
+struct RegisteredFont {
+    data: Arc<Vec<u8>>,
+    digest: String,
+}
+
+type FontRegistry = Mutex<HashMap<String, RegisteredFont>>;
+
+// The fonts uploaded with `POST /fonts`, by name.
+fn font_registry() -> &'static FontRegistry {
+    static FONT_REGISTRY: OnceLock<FontRegistry> = OnceLock::new();
+    FONT_REGISTRY.get_or_init(Default::default)
 }
 
-pub fn download_image(url: &str) -> Result<DynamicImage, Box<dyn std::error::Error>> {
-    let body = get(url)?.bytes()?;
-    let image = image::load_from_memory(&body)
-        .map_err(|err| Box::new(err) as Box<dyn std::error::Error>)?;
-    Ok(image)
-}
-
-pub fn generate(args: Params) {
-    env_logger::init();
-
-    if args.image_url.starts_with("http://") || args.image_url.starts_with("https://") {
-        info!("Downloading image from URL: {:?}", args.image_url);
-        match download_image(args.image_url) {
-            Ok(image) => {
-                let in_extension = Path::new(args.image_url).extension().unwrap();
-
-                let alphabet_str = &args.alphabet;
-                let alphabet_map: HashMap<&str, &str> = ALPHABETS.iter().cloned().collect();
-                let alphabet: Vec<char> = if alphabet_map.contains_key(&alphabet_str.as_ref()) {
-                    info!("alphabet name  {:?}", alphabet_str);
-                    alphabet_map
-                        .get(&alphabet_str.as_ref())
-                        .unwrap()
-                        .chars()
-                        .collect()
-                } else {
-                    let alphabet_path = Path::new(alphabet_str);
-                    info!("alphabet path  {:?}", alphabet_path);
-                    fs::read(&alphabet_path)
-                        .unwrap()
-                        .iter()
-                        .map(|&b| b as char)
-                        .collect()
-                };
-                info!("alphabet       [{}]", alphabet.iter().collect::<String>());
-
-                let width = args.width;
-                info!("width          {}", width);
-
-                let font_str = &args.font;
-                let font_map: HashMap<&str, &str> = FONTS.iter().cloned().collect();
-                let font: font::Font = if font_map.contains_key(&font_str.as_ref()) {
-                    info!("font name      {:?}", font_str);
-                    let font_data = font_map.get(&font_str.as_ref()).unwrap();
-                    Font::from_bdf_stream(font_data.as_bytes(), &alphabet)
-                } else {
-                    let font_path = Path::new(font_str);
-                    info!("font path      {:?}", font_path);
-                    Font::from_bdf(font_path, &alphabet)
-                };
-
-                let metric = args.metric;
-                info!("metric         {}", metric);
-
-                let out_path = args.out_path.as_ref().map(|name| Path::new(name));
-                info!("out path       {:?}", out_path);
-
-                let fps = args.fps;
-                info!("fps            {}", fps);
-
-                let color = !args.no_color;
-                info!("color          {}", color);
-
-                let brightness_offset = args.brightness_offset;
-                info!("brightness     {}", brightness_offset);
-
-                let noise_scale = args.noise_scale;
-                info!("noise scale    {}", noise_scale);
-
-                let threads = args.threads;
-                info!("threads        {}", threads);
-
-                let edge_detection = !args.no_edge_detection;
-                info!("edge detection {}", edge_detection);
-
-                let convert = get_converter(&metric);
-                info!("converting frames to ascii...");
-
-                info!("converting frames to ascii...");
-                let frames: Vec<DynamicImage> = if in_extension == "gif" {
-                    vec![image.into()]
-                } else {
-                    vec![image]
-                };                
-
-                let mut frame_char_rows: Vec<Vec<Vec<char>>> = Vec::new();
-                let progress = default_progress_bar("Frames", frames.len());
-                for img in frames.iter().progress_with(progress) {
-                    let ascii = convert::img_to_char_rows(
-                        &font,
-                        &img,
-                        convert,
-                        width,
-                        brightness_offset,
-                        noise_scale,
-                        threads,
-                        edge_detection,
-                    );
-                    frame_char_rows.push(ascii);
+// Makes the BDF or TrueType font file `data` loadable as `name`, replacing any font registered as
+// it before.
+pub fn register_font(name: &str, data: Vec<u8>) {
+    let digest = hex::encode(Sha256::digest(&data));
+    let font = RegisteredFont {
+        data: Arc::new(data),
+        digest,
+    };
+    font_registry()
+        .lock()
+        .unwrap()
+        .insert(name.to_owned(), font);
+}
+
+fn registered_font(font_str: &str) -> Option<Arc<Vec<u8>>> {
+    let registry = font_registry().lock().unwrap();
+    registry.get(font_str).map(|font| font.data.clone())
+}
+
+// The hash of the file registered as `font_str`, none for a bundled font, which never changes.
+pub fn registered_font_digest(font_str: &str) -> Option<String> {
+    let registry = font_registry().lock().unwrap();
+    registry.get(font_str).map(|font| font.digest.clone())
+}
+
+// The characters of the bundled alphabet named `alphabet_str`, or of the default one for any other
+// name, so callers check names with `is_bundled_alphabet` first.
+pub fn load_alphabet(alphabet_str: &str) -> Vec<char> {
+    let chars = match ALPHABETS.iter().find(|(name, _)| *name == alphabet_str) {
+        Some((_, chars)) => chars,
+        None => ALPHABETS[0].1,
+    };
+    debug!("alphabet name  {:?}", alphabet_str);
+    chars.chars().collect()
+}
+
+// The characters of `charset` in order, once each, or else those of the alphabet named `alphabet`.
+pub fn alphabet_chars(alphabet: &str, charset: Option<&str>) -> Vec<char> {
+    match charset {
+        Some(charset) => {
+            let mut chars: Vec<char> = Vec::new();
+            for c in charset.chars() {
+                if !chars.contains(&c) {
+                    chars.push(c);
                 }
+            }
+            chars
+        }
+        None => load_alphabet(alphabet),
+    }
+}
 
-                if let Some(path) = out_path {
-                    let out_extension = path.extension().unwrap();
-
-                    if out_extension == "json" {
-                        let out_frames: Vec<String> = if color {
-                            frame_char_rows
-                                .iter()
-                                .zip(frames)
-                                .map(|(char_rows, frame)| {
-                                    char_rows_to_html_color_string(char_rows, &frame)
-                                })
-                                .collect()
-                        } else {
-                            frame_char_rows
-                                .iter()
-                                .map(|char_rows| char_rows_to_string(char_rows))
-                                .collect()
-                        };
-                        let json = serde_json::to_string(&out_frames).unwrap();
-                        fs::write(path, json).unwrap();
-                    } else if out_extension == "gif" {
-                        info!("converting ascii strings to bitmaps...");
-                        let progress = default_progress_bar("Frames", frame_char_rows.len());
-                        let out_frames: Vec<DynamicImage> = if color {
-                            frame_char_rows
-                                .iter()
-                                .zip(frames)
-                                .progress_with(progress)
-                                .map(|(char_rows, frame)| {
-                                    char_rows_to_color_bitmap(&char_rows, &font, &frame)
-                                })
-                                .collect()
-                        } else {
-                            frame_char_rows
-                                .iter()
-                                .progress_with(progress)
-                                .map(|char_rows| char_rows_to_bitmap(&char_rows, &font))
-                                .collect()
-                        };
-                        write_gif(path, &out_frames, fps);
-                    } else {
-                        let img = if color {
-                            char_rows_to_color_bitmap(&frame_char_rows[0], &font, &frames[0])
-                        } else {
-                            char_rows_to_bitmap(&frame_char_rows[0], &font)
-                        };
-                        img.save(path).unwrap();
-                    }
-                } else {
-                    let out_frames: Vec<String> = if color {
-                        frame_char_rows
-                            .iter()
-                            .zip(frames)
-                            .map(|(char_rows, frame)| {
-                                char_rows_to_terminal_color_string(char_rows, &frame)
-                            })
-                            .collect()
-                    } else {
-                        frame_char_rows
-                            .iter()
-                            .map(|char_rows| char_rows_to_string(char_rows))
-                            .collect()
-                    };
-                    
-                    // OUTPUT
-                    if in_extension == "gif" {
-                        loop {
-                            for frame in &out_frames {
-                                let t0 = Instant::now();
-                                println!("{}[2J{}", 27 as char, frame);
-                                let elapsed = t0.elapsed().as_secs_f64();
-                                let delay = (1.0 / fps) - elapsed;
-                                if delay > 0.0 {
-                                    sleep(Duration::from_secs_f64(delay));
-                                }
-                            }
-                        }
-                    } else {
-                        println!("{}", out_frames[0]);
+// Fonts parsed for a bundled font and alphabet, kept for the life of the process as they never
+// change. Fonts from paths, and banners' own alphabets, are parsed every time.
+type FontCache = Mutex<HashMap<(String, Vec<char>), Font>>;
+
+fn font_cache() -> &'static FontCache {
+    static FONT_CACHE: OnceLock<FontCache> = OnceLock::new();
+    FONT_CACHE.get_or_init(Default::default)
+}
+
+// Pixels per em TrueType fonts are drawn at unless told otherwise.
+pub const DEFAULT_FONT_SIZE: f32 = 16.;
+
+fn is_truetype(path: &Path) -> bool {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    matches!(extension.as_deref(), Some("ttf" | "otf" | "ttc"))
+}
+
+pub fn load_font(font_str: &str, alphabet: &[char]) -> Result<Font, Error> {
+    load_sized_font(font_str, None, alphabet)
+}
+
+// Like `load_font`, drawing a TrueType font at `font_size` pixels per em; BDF fonts have one size.
+pub fn load_sized_font(
+    font_str: &str,
+    font_size: Option<f32>,
+    alphabet: &[char],
+) -> Result<Font, Error> {
+    let bundled = is_bundled_font(font_str)
+        && ALPHABETS
+            .iter()
+            .any(|(_, chars)| chars.chars().eq(alphabet.iter().cloned()));
+    if !bundled {
+        return parse_font(font_str, font_size, alphabet);
+    }
+    let key = (font_str.to_owned(), alphabet.to_vec());
+    if let Some(font) = font_cache().lock().unwrap().get(&key) {
+        return Ok(font.clone());
+    }
+    let font = parse_font(font_str, font_size, alphabet)?;
+    font_cache().lock().unwrap().insert(key, font.clone());
+    Ok(font)
+}
+
+// Uploaded TrueType fonts are checked when they're registered, but can still fail to draw at a
+// size or alphabet they weren't checked with.
+fn parse_font(font_str: &str, font_size: Option<f32>, alphabet: &[char]) -> Result<Font, Error> {
+    let font_map: HashMap<&str, &str> = FONTS.iter().cloned().collect();
+    if font_map.contains_key(font_str) {
+        debug!("font name      {:?}", font_str);
+        let font_data = font_map.get(font_str).unwrap();
+        Ok(Font::from_bdf_stream(font_data.as_bytes(), alphabet))
+    } else if let Some(font_data) = registered_font(font_str) {
+        debug!("font name      {:?} (registered)", font_str);
+        if truetype::is_font(&font_data) {
+            let font_size = font_size.unwrap_or(DEFAULT_FONT_SIZE);
+            debug!("font size      {}", font_size);
+            Font::from_truetype(&font_data, font_size, alphabet)
+                .map_err(|reason| Error::InvalidValue("font", reason))
+        } else {
+            Ok(Font::from_bdf_stream(&font_data[..], alphabet))
+        }
+    } else {
+        let font_path = Path::new(font_str);
+        debug!("font path      {:?}", font_path);
+        if is_truetype(font_path) {
+            let font_size = font_size.unwrap_or(DEFAULT_FONT_SIZE);
+            debug!("font size      {}", font_size);
+            Ok(Font::from_truetype_file(font_path, font_size, alphabet))
+        } else {
+            Ok(Font::from_bdf(font_path, alphabet))
+        }
+    }
+}
+
+pub fn image_to_char_rows(image: &DynamicImage, font: &Font, args: &Params) -> Vec<Vec<char>> {
+    image_to_char_grid(image, font, args, grid_dimensions(image, font, args))
+}
+
+fn grid_dimensions(image: &DynamicImage, font: &Font, args: &Params) -> (usize, usize) {
+    let aspect = args.aspect.unwrap_or_else(|| convert::font_aspect(font));
+    convert::grid_dimensions(image.dimensions(), args.width, args.height, aspect)
+}
+
+// Characters are picked from the tone-adjusted image, and from its negative with `invert`, which
+// turns the density ramp around.
+fn picked_from<'a>(image: &'a DynamicImage, args: &Params) -> Cow<'a, DynamicImage> {
+    if args.contrast != 1. || args.gamma != 1. || args.invert {
+        let _span = spans::span("tone");
+        Cow::Owned(convert::adjust_tone(
+            image,
+            args.contrast,
+            args.gamma,
+            args.invert,
+        ))
+    } else {
+        Cow::Borrowed(image)
+    }
+}
+
+// How well the glyphs of a `glyphs` or `grayscale` grid converted from `image` match it, measured
+// on the same intensities glyphs are matched to; the other modes don't draw glyphs.
+pub fn match_quality(
+    image: &DynamicImage,
+    font: &Font,
+    args: &Params,
+    char_rows: &[Vec<char>],
+) -> Option<Quality> {
+    if !matches!(args.mode, "glyphs" | "grayscale") {
+        return None;
+    }
+    let picked = picked_from(image, args);
+    let lightness;
+    let image = if args.metric == "luma" {
+        lightness = convert::perceptual_lightness(&picked);
+        &lightness
+    } else {
+        &*picked
+    };
+    let grid = (char_rows[0].len(), char_rows.len());
+    let edge_detection = args.mode == "glyphs" && !args.no_edge_detection;
+    let pixels = convert::grid_to_pixels(
+        font,
+        image,
+        grid,
+        args.brightness_offset,
+        edge_detection,
+        None,
+    );
+    let width_advice = WidthAdvice::check(font, image.width(), grid.0);
+    Some(Quality::measure(font, &pixels, char_rows).with_width_advice(width_advice))
+}
+
+fn image_to_char_grid(
+    image: &DynamicImage,
+    font: &Font,
+    args: &Params,
+    grid: (usize, usize),
+) -> Vec<Vec<char>> {
+    let _span = spans::span("convert");
+    let picked = picked_from(image, args);
+    let image = &*picked;
+    let mut char_rows = match args.mode {
+        "pixels" => {
+            // without color, blocks alone have to carry the image
+            let threshold = if args.no_color { 0.5 } else { 0. };
+            convert::img_to_block_rows(image, grid, args.brightness_offset, threshold)
+        }
+        "braille" => {
+            // likewise, colored dots only outline what's brighter than the rest of their cell
+            let threshold = if args.no_color { Some(0.5) } else { None };
+            convert::img_to_braille_rows(image, grid, args.brightness_offset, threshold)
+        }
+        "halfblocks" | "quadrants" => {
+            let threshold = if args.no_color { Some(0.5) } else { None };
+            let halves = args.mode == "halfblocks";
+            convert::img_to_quadrant_rows(image, grid, args.brightness_offset, halves, threshold)
+        }
+        "grayscale" => {
+            convert::img_to_grayscale_rows(font, image, grid, args.brightness_offset, args.dither)
+        }
+        _ => {
+            // `luma` maps perceived lightness rather than gamma-encoded values to the ramp
+            let lightness;
+            let image = if args.metric == "luma" {
+                lightness = convert::perceptual_lightness(image);
+                &lightness
+            } else {
+                image
+            };
+            convert::img_to_char_rows_sized(
+                font,
+                image,
+                get_converter(args.metric),
+                grid,
+                MatchOptions {
+                    brightness_offset: args.brightness_offset,
+                    noise_scale: args.noise_scale,
+                    n_threads: args.threads,
+                    edge_detection: !args.no_edge_detection,
+                    dither: args.dither,
+                },
+            )
+        }
+    };
+    if let Some(blank_char) = args.blank_char {
+        blank_cells(
+            &mut char_rows,
+            font,
+            image,
+            args.brightness_offset,
+            blank_char,
+        );
+    }
+    char_rows
+}
+
+pub fn use_color(args: &Params) -> bool {
+    !args.no_color && args.mode != "grayscale"
+}
+
+pub fn is_block_mode(args: &Params) -> bool {
+    matches!(args.mode, "halfblocks" | "quadrants")
+}
+
+// What colored output is painted with: the frame glyph colors are sampled from, and for the block
+// modes, which have already sampled both colors of every cell, the cells' background colors.
+pub fn output_colors(
+    char_rows: &[Vec<char>],
+    image: &DynamicImage,
+    args: &Params,
+) -> (DynamicImage, Option<DynamicImage>) {
+    let image = color_frame(image, args);
+    if is_block_mode(args) {
+        let (foreground, background) = convert::block_colors(char_rows, &image);
+        (foreground, Some(background))
+    } else {
+        (image, None)
+    }
+}
+
+pub fn char_rows_to_text(char_rows: &[Vec<char>], image: &DynamicImage, args: &Params) -> String {
+    if !use_color(args) {
+        char_rows_to_string(char_rows)
+    } else {
+        let (colors, backgrounds) = output_colors(char_rows, image, args);
+        char_rows_to_terminal_color_string(
+            char_rows,
+            &colors,
+            backgrounds.as_ref(),
+            args.color_tolerance,
+            args.color_mode,
+        )
+    }
+}
+
+pub fn image_to_text(image: &DynamicImage, font: &Font, args: &Params) -> String {
+    char_rows_to_text(&image_to_char_rows(image, font, args), image, args)
+}
+
+// Whether the text grid converted from `image` is large enough to be converted band by band.
+pub fn is_banded(image: &DynamicImage, font: &Font, args: &Params) -> bool {
+    let (out_width, out_height) = grid_dimensions(image, font, args);
+    out_width * out_height > BANDED_CELLS
+}
+
+// The text of `image` `band_rows` rows of characters at a time, joining up into exactly the text of
+// `image_to_text`. Glyphs are matched a band at a time against one resize of the whole source, so
+// edges and colors come out as they would unbanded, while only a band's intensities, grid and text
+// are held at once. The other modes, and dithering, whose error crosses band seams, convert the
+// grid whole and hand its text out in bands.
+pub fn image_to_text_bands<'a>(
+    image: &'a DynamicImage,
+    font: &'a Font,
+    args: &'a Params,
+    band_rows: usize,
+) -> Box<dyn Iterator<Item = String> + 'a> {
+    let grid @ (out_width, out_height) = grid_dimensions(image, font, args);
+    if args.mode != "glyphs" || args.dither.is_some() {
+        let text = image_to_text(image, font, args);
+        let lines: Vec<&str> = text.split('\n').collect();
+        let bands: Vec<String> = lines
+            .chunks(band_rows)
+            .map(|band| band.join("\n"))
+            .collect();
+        return Box::new(bands.into_iter());
+    }
+    let _span = spans::span("convert");
+    let picked = picked_from(image, args);
+    let lightness = (args.metric == "luma").then(|| convert::perceptual_lightness(&picked));
+    let matched = lightness.as_ref().unwrap_or(&picked);
+    let resized = convert::resize_to_grid(font, matched, grid);
+    let edges = (!args.no_edge_detection).then(|| convert::edges_to_grid(font, matched, grid));
+    let blanks = args
+        .blank_char
+        .map(|_| convert::resize_to_grid(font, &picked, grid));
+    let colors = use_color(args).then(|| {
+        color_frame(image, args).resize_exact(out_width as u32, out_height as u32, Nearest)
+    });
+    Box::new((0..out_height).step_by(band_rows).map(move |start| {
+        let _span = spans::span("convert");
+        let rows = band_rows.min(out_height - start);
+        let crop = |img: &DynamicImage, row_height: usize| {
+            img.crop_imm(
+                0,
+                (start * row_height) as u32,
+                img.width(),
+                (rows * row_height) as u32,
+            )
+        };
+        let band_edges = edges.as_ref().map(|edges| crop(edges, font.height));
+        let pixels = convert::resized_to_pixels(
+            &crop(&resized, font.height),
+            band_edges.as_ref(),
+            args.brightness_offset,
+        );
+        let mut char_rows = convert::pixels_to_char_rows(
+            font,
+            &pixels,
+            (out_width, rows),
+            get_converter(args.metric),
+            args.noise_scale,
+            args.threads,
+        );
+        if let (Some(blank_char), Some(blanks)) = (args.blank_char, &blanks) {
+            let band = crop(blanks, font.height);
+            blank_cells(
+                &mut char_rows,
+                font,
+                &band,
+                args.brightness_offset,
+                blank_char,
+            );
+        }
+        match &colors {
+            Some(colors) => char_rows_to_terminal_color_string(
+                &char_rows,
+                &crop(colors, 1),
+                None,
+                args.color_tolerance,
+                args.color_mode,
+            ),
+            None => char_rows_to_string(&char_rows),
+        }
+    }))
+}
+
+// Like `image_to_text`, converting grids of more than `BANDED_CELLS` cells `BAND_ROWS` rows at a
+// time so that their whole grid is never held at once.
+pub fn image_to_banded_text(image: &DynamicImage, font: &Font, args: &Params) -> String {
+    if !is_banded(image, font, args) {
+        return image_to_text(image, font, args);
+    }
+    let mut text = String::new();
+    for (i, band) in image_to_text_bands(image, font, args, BAND_ROWS).enumerate() {
+        if i > 0 {
+            text.push('\n');
+        }
+        text.push_str(&band);
+    }
+    text
+}
+
+// The theme rendered outputs are drawn in: drawn from the image with `themed_background`, else
+// black on white with `light_background`, else white on black, its colors swapped with `invert`.
+pub fn output_theme(image: &DynamicImage, args: &Params) -> Theme {
+    let theme = if args.themed_background {
+        Theme::from_image(image)
+    } else if args.light_background {
+        Theme::light()
+    } else {
+        Theme::default()
+    };
+    if args.invert {
+        theme.inverted()
+    } else {
+        theme
+    }
+}
+
+// The frame output colors are sampled from, after any color vision deficiency simulation.
+pub fn color_frame(image: &DynamicImage, args: &Params) -> DynamicImage {
+    match args.cvd {
+        Some(cvd) => simulate_cvd(image, cvd),
+        None => image.clone(),
+    }
+}
+
+#[derive(Debug)]
+pub enum ConvertError {
+    // the extension of the output path names no format output is written in
+    UnknownFormat(String),
+    Render(Error),
+    Write(io::Error),
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConvertError::UnknownFormat(path) => write!(f, "Unknown output format: {:?}", path),
+            ConvertError::Render(err) => write!(f, "Error rendering output: {}", err),
+            ConvertError::Write(err) => write!(f, "Error writing output: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ConvertError {}
+
+impl From<Error> for ConvertError {
+    fn from(err: Error) -> ConvertError {
+        ConvertError::Render(err)
+    }
+}
+
+// What a conversion produced, left to the caller to show.
+#[derive(Debug)]
+pub enum ConversionOutput {
+    Text(String),
+    // every frame's text, and the runs it plays back as: a frame and how many frames it lasts
+    Animation {
+        frames: Vec<String>,
+        runs: Vec<(usize, u32)>,
+        fps: f64,
+    },
+    Written(PathBuf),
+}
+
+impl ConversionOutput {
+    // Shows the output on the terminal, playing animations back forever, for library users; the API
+    // answers with the text instead.
+    #[allow(dead_code)]
+    pub fn print(&self) {
+        match self {
+            ConversionOutput::Text(text) => println!("{}", text),
+            ConversionOutput::Animation { frames, runs, fps } => loop {
+                for &(i, count) in runs {
+                    let t0 = Instant::now();
+                    println!("{}[2J{}", 27 as char, frames[i]);
+                    let delay = count as f64 / fps - t0.elapsed().as_secs_f64();
+                    if delay > 0.0 {
+                        sleep(Duration::from_secs_f64(delay));
                     }
                 }
-            }
-            Err(err) => {
-                eprintln!("Error downloading image: {}", err);
-                return;
-            }
+            },
+            ConversionOutput::Written(path) => info!("wrote {:?}", path),
         }
+    }
+}
+
+// The representation output written to `path` is rendered as, by its extension.
+fn out_representation(path: &Path) -> Result<Representation, ConvertError> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("txt") => Ok(Representation::Text),
+        Some("html" | "htm") => Ok(Representation::Html),
+        Some("json") => Ok(Representation::Json),
+        Some("png") => Ok(Representation::Png),
+        Some("jpg" | "jpeg") => Ok(Representation::Jpeg),
+        Some("gif") => Ok(Representation::Gif),
+        _ => Err(ConvertError::UnknownFormat(path.display().to_string())),
+    }
+}
+
+// Converts `frames`, a single one for a still image, as `args` asks: written to `out_path` in the
+// format its extension names, or else returned as terminal text, an animation's as every frame's
+// text played back at its rate. Nothing is printed; failures are returned to the caller.
+pub fn generate(
+    args: &Params,
+    font: &Font,
+    frames: Vec<DynamicImage>,
+) -> Result<ConversionOutput, ConvertError> {
+    let animated = frames.len() > 1;
+    let out_path = match args.out_path {
+        Some(out_path) => Path::new(out_path),
+        None if animated => {
+            let (frames, fps) = negotiate::prepare_frames(frames, args, usize::MAX)?;
+            let texts: Vec<String> = frames
+                .iter()
+                .map(|frame| image_to_text(frame, font, args))
+                .collect();
+            let runs = collapse_runs(&texts, |a, b| a == b);
+            return Ok(ConversionOutput::Animation {
+                frames: texts,
+                runs,
+                fps: fps * args.speed,
+            });
+        }
+        None => {
+            let text = image_to_banded_text(&frames[0], font, args);
+            return Ok(ConversionOutput::Text(text));
+        }
+    };
+    let representation = out_representation(out_path)?;
+    let (_, body) = if animated && representation.is_animated() {
+        let (frames, fps) = negotiate::prepare_frames(frames, args, usize::MAX)?;
+        negotiate::render_frames(representation, &frames, font, args, fps)?.0
     } else {
-        eprintln!("Invalid URL format: {:?}", args.image_url);
-        return;
+        let char_rows = image_to_char_rows(&frames[0], font, args);
+        negotiate::render(representation, &char_rows, font, &frames[0], args)?
+    };
+    fs::write(out_path, body).map_err(ConvertError::Write)?;
+    Ok(ConversionOutput::Written(out_path.to_path_buf()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+    use std::process;
+
+    // A diagonal gradient with a bright square in it, so there are edges to detect.
+    fn sample(width: u32, height: u32, shift: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let square =
+                (width / 4..width / 2).contains(&x) && (height / 4..height / 2).contains(&y);
+            let level = if square {
+                255
+            } else {
+                ((x + y + shift) % 256) as u8
+            };
+            Rgb([level, level / 2, 255 - level])
+        }))
+    }
+
+    fn sample_font() -> Font {
+        load_font("bitocra-13", &load_alphabet("alphabet")).unwrap()
+    }
+
+    #[test]
+    fn replacing_an_uploaded_font_changes_its_digest() {
+        assert_eq!(registered_font_digest("courier"), None);
+        register_font("replaced", FONTS[0].1.as_bytes().to_vec());
+        let digest = registered_font_digest("replaced").unwrap();
+        register_font("replaced", FONTS[1].1.as_bytes().to_vec());
+        assert_ne!(registered_font_digest("replaced").unwrap(), digest);
+    }
+
+    #[test]
+    fn generate_returns_the_text_of_a_still() {
+        let (image, font) = (sample(64, 48, 0), sample_font());
+        let args = Params::defaults_for("sample.png", 16);
+        match generate(&args, &font, vec![image.clone()]).unwrap() {
+            ConversionOutput::Text(text) => assert_eq!(text, image_to_text(&image, &font, &args)),
+            output => panic!("expected text, got {:?}", output),
+        }
+    }
+
+    #[test]
+    fn bands_join_up_into_the_unbanded_text() {
+        let (image, font) = (sample(331, 517, 7), sample_font());
+        let args = Params::defaults_for("sample.png", 40);
+        let variants = [
+            args,
+            Params {
+                no_color: true,
+                ..args
+            },
+            Params {
+                no_edge_detection: true,
+                ..args
+            },
+            Params {
+                metric: "luma",
+                blank_char: Some('.'),
+                contrast: 1.5,
+                ..args
+            },
+            Params {
+                mode: "pixels",
+                ..args
+            },
+            Params {
+                dither: Some("atkinson"),
+                ..args
+            },
+        ];
+        for args in variants {
+            let bands: Vec<String> = image_to_text_bands(&image, &font, &args, 7).collect();
+            let text = image_to_text(&image, &font, &args);
+            assert_eq!(bands.join("\n"), text, "{:?}", args);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn only_large_grids_are_banded() {
+        let (image, font) = (sample(600, 900, 0), sample_font());
+        assert!(is_banded(&image, &font, &Params::defaults_for("", 300)));
+        assert!(!is_banded(&image, &font, &Params::defaults_for("", 80)));
+    }
+
+    #[test]
+    fn generate_holds_repeated_frames_of_an_animation() {
+        let font = sample_font();
+        let args = Params::defaults_for("sample.gif", 16);
+        let frames = vec![sample(64, 48, 0), sample(64, 48, 0), sample(64, 48, 100)];
+        match generate(&args, &font, frames).unwrap() {
+            ConversionOutput::Animation { frames, runs, fps } => {
+                assert_eq!(frames.len(), 3);
+                assert_eq!(runs, [(0, 2), (2, 1)]);
+                assert_eq!(fps, 30.0);
+            }
+            output => panic!("expected an animation, got {:?}", output),
+        }
+    }
+
+    #[test]
+    fn generate_writes_the_format_the_extension_names() {
+        let font = sample_font();
+        let path = std::env::temp_dir().join(format!("generate-{}.json", process::id()));
+        let path_str = path.to_str().unwrap();
+        let args = Params {
+            out_path: Some(path_str),
+            ..Params::defaults_for("sample.png", 16)
+        };
+        match generate(&args, &font, vec![sample(64, 48, 0)]).unwrap() {
+            ConversionOutput::Written(written) => assert_eq!(written, path),
+            output => panic!("expected a written file, got {:?}", output),
+        }
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert!(json.is_array());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn generate_refuses_unknown_formats() {
+        let font = sample_font();
+        let args = Params {
+            out_path: Some("out.xyz"),
+            ..Params::defaults_for("sample.png", 16)
+        };
+        let result = generate(&args, &font, vec![sample(64, 48, 0)]);
+        assert!(matches!(result, Err(ConvertError::UnknownFormat(_))));
+    }
+}
@@ -1,7 +1,8 @@
 use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
-use image::{AnimationDecoder, Delay, DynamicImage, Frame};
-use indicatif::ProgressIterator;
-use log::info;
+use image::{AnimationDecoder, Delay, DynamicImage, Frame, GenericImageView, ImageResult};
+use indicatif::{ProgressBar, ProgressIterator};
+use log::{debug, info};
+use std::borrow::Borrow;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::Path;
@@ -13,8 +14,8 @@ pub fn read_gif_from_stream<R: Read>(stream: R) -> Vec<DynamicImage> {
     let frames = decoder.into_frames();
     let frames = frames.collect_frames().expect("error decoding gif");
     frames
-        .iter()
-        .map(|frame| DynamicImage::ImageRgba8(frame.buffer().clone()))
+        .into_iter()
+        .map(|frame| DynamicImage::ImageRgba8(frame.into_buffer()))
         .collect()
 }
 
@@ -23,27 +24,201 @@ pub fn read_gif(path: &Path) -> Vec<DynamicImage> {
     read_gif_from_stream(fp)
 }
 
-pub fn write_gif_to_stream<W: Write>(stream: W, frames: &[DynamicImage], fps: f64) {
-    let mut encoder = GifEncoder::new(stream);
-    encoder.set_repeat(Repeat::Infinite).unwrap();
-    let delay = Delay::from_numer_denom_ms(1000, fps as u32);
+// Like `read_gif_from_stream`, for a GIF already held in memory, reporting decoding errors instead
+// of panicking. Decoding stops after `limit` frames and one more, so that a caller can tell a GIF
+// with more frames than it takes without the rest being decoded.
+pub fn decode_gif(bytes: &[u8], limit: usize) -> ImageResult<Vec<DynamicImage>> {
+    GifDecoder::new(bytes)?
+        .into_frames()
+        .take(limit.saturating_add(1))
+        .map(|frame| Ok(DynamicImage::ImageRgba8(frame?.into_buffer())))
+        .collect()
+}
 
-    info!("converting bitmaps to gif frames...");
-    let progress = default_progress_bar("Frames", frames.len());
-    let frames: Vec<Frame> = frames
+pub const PLAYBACK_MODES: [&str; 3] = ["forward", "reverse", "boomerang"];
+
+// Indices of the source frames in the order they're played back. Boomerang doesn't repeat the
+// first and last frames at the turn-around points, so the loop doesn't stutter.
+pub fn playback_order(frame_count: usize, playback: &str) -> Vec<usize> {
+    match playback {
+        "forward" => (0..frame_count).collect(),
+        "reverse" => (0..frame_count).rev().collect(),
+        "boomerang" => (0..frame_count)
+            .chain((1..frame_count.saturating_sub(1)).rev())
+            .collect(),
+        _ => panic!("Unsupported playback mode {}", playback),
+    }
+}
+
+// Indices of the frames to keep when dropping a `fps` source down to at most `sample_fps`.
+pub fn sample_frames(frame_count: usize, fps: f64, sample_fps: f64) -> Vec<usize> {
+    if sample_fps >= fps {
+        return (0..frame_count).collect();
+    }
+    let step = fps / sample_fps;
+    (0..)
+        .map(|i| (i as f64 * step) as usize)
+        .take_while(|&i| i < frame_count)
+        .collect()
+}
+
+// The `frames` at `order`, indices as `sample_frames` and `playback_order` give. Each frame is
+// moved to the last place it's played at rather than copied, so only frames played more than once
+// are, and the ones never played are dropped.
+pub fn arrange_frames<T: Clone>(frames: Vec<T>, order: &[usize]) -> Vec<T> {
+    let mut last_played = vec![None; frames.len()];
+    for (position, &i) in order.iter().enumerate() {
+        last_played[i] = Some(position);
+    }
+    let mut frames: Vec<Option<T>> = frames.into_iter().map(Some).collect();
+    order
         .iter()
-        .progress_with(progress)
-        .map(|f| Frame::from_parts(f.to_rgba8(), 0, 0, delay))
-        .collect();
+        .enumerate()
+        .map(|(position, &i)| {
+            if last_played[i] == Some(position) {
+                frames[i].take().unwrap()
+            } else {
+                frames[i].clone().unwrap()
+            }
+        })
+        .collect()
+}
+
+// Most frames a single source frame is stretched into by interpolation.
+const MAX_INTERPOLATED_FRAMES: usize = 16;
+
+// How many frames `interpolate_frames` stretches each of `frame_count` frames of a `fps` source
+// into.
+pub fn interpolation_steps(frame_count: usize, fps: f64, interpolate_fps: f64) -> usize {
+    if frame_count < 2 {
+        return 1;
+    }
+    ((interpolate_fps / fps).round() as usize).clamp(1, MAX_INTERPOLATED_FRAMES)
+}
+
+// Cross-fades between consecutive frames of a `fps` source to play it back at up to
+// `interpolate_fps`, returning the frames along with their new rate. The last frame is held rather
+// than blended back into the first, and frames of different sizes are held instead of blended.
+pub fn interpolate_frames(
+    frames: Vec<DynamicImage>,
+    fps: f64,
+    interpolate_fps: f64,
+) -> (Vec<DynamicImage>, f64) {
+    let steps = interpolation_steps(frames.len(), fps, interpolate_fps);
+    if steps == 1 {
+        return (frames, fps);
+    }
+    let mut out = Vec::with_capacity(frames.len() * steps);
+    let mut frames = frames.into_iter().peekable();
+    while let Some(frame) = frames.next() {
+        let next = frames
+            .peek()
+            .filter(|next| next.dimensions() == frame.dimensions());
+        let between: Vec<DynamicImage> = (1..steps)
+            .map(|step| match next {
+                Some(next) => blend(&frame, next, step as f32 / steps as f32),
+                None => frame.clone(),
+            })
+            .collect();
+        out.push(frame);
+        out.extend(between);
+    }
+    (out, fps * steps as f64)
+}
+
+fn blend(a: &DynamicImage, b: &DynamicImage, t: f32) -> DynamicImage {
+    let mut out = a.to_rgba8();
+    for (pixel, other) in out.pixels_mut().zip(b.to_rgba8().pixels()) {
+        for (channel, &target) in pixel.0.iter_mut().zip(&other.0) {
+            *channel = (*channel as f32 * (1. - t) + target as f32 * t).round() as u8;
+        }
+    }
+    DynamicImage::ImageRgba8(out)
+}
+
+// Fraction of pixels allowed to differ for two rendered frames to still count as the same frame.
+const MAX_CHANGED_PIXELS: f64 = 0.001;
+
+// Groups consecutive frames that `same` considers equal to the first frame of their run, returning
+// the index of each run's first frame and how many frames it stands for.
+pub fn collapse_runs<T>(frames: &[T], same: impl Fn(&T, &T) -> bool) -> Vec<(usize, u32)> {
+    let mut runs: Vec<(usize, u32)> = Vec::new();
+    for (i, frame) in frames.iter().enumerate() {
+        match runs.last_mut() {
+            Some((first, count)) if same(&frames[*first], frame) => *count += 1,
+            _ => runs.push((i, 1)),
+        }
+    }
+    runs
+}
+
+pub fn frames_match(a: &DynamicImage, b: &DynamicImage) -> bool {
+    let (a, b) = (a.to_rgba8(), b.to_rgba8());
+    if a.dimensions() != b.dimensions() {
+        return false;
+    }
+    let changed = a.pixels().zip(b.pixels()).filter(|(a, b)| a != b).count();
+    changed as f64 <= MAX_CHANGED_PIXELS * (a.width() * a.height()) as f64
+}
+
+// Frames may be given by reference, so ones played more than once needn't be copied. Each is only
+// converted to a GIF frame as it's encoded, so no more than one copy is held at a time. `progress`
+// is told of each frame encoded.
+pub fn write_gif_to_stream<W: Write, F: Borrow<DynamicImage>>(
+    stream: W,
+    frames: &[F],
+    fps: f64,
+    progress: ProgressBar,
+) -> ImageResult<()> {
+    let mut encoder = GifEncoder::new(stream);
+    encoder.set_repeat(Repeat::Infinite)?;
+
+    let runs = collapse_runs(frames, |a, b| frames_match(a.borrow(), b.borrow()));
+    debug!("collapsed {} frames into {}", frames.len(), runs.len());
+
+    progress.set_length(runs.len() as u64);
+    let frames = runs.iter().progress_with(progress).map(|&(i, count)| {
+        Frame::from_parts(frames[i].borrow().to_rgba8(), 0, 0, frame_delay(count, fps))
+    });
+    encoder.encode_frames(frames)
+}
+
+// Encodes frames each already held for a number of frames at `fps`, as permalinks store them, so
+// none are compared or copied again.
+pub fn encode_held_gif<F: Borrow<DynamicImage>>(
+    frames: &[(F, u32)],
+    fps: f64,
+) -> ImageResult<Vec<u8>> {
+    let mut gif = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut gif);
+        encoder.set_repeat(Repeat::Infinite)?;
+        let frames = frames.iter().map(|(frame, ticks)| {
+            Frame::from_parts(frame.borrow().to_rgba8(), 0, 0, frame_delay(*ticks, fps))
+        });
+        encoder.encode_frames(frames)?;
+    }
+    Ok(gif)
+}
+
+// How long a frame held for `count` frames at `fps` is shown, to the nearest millisecond and at
+// least one, so fractional and sub-1 rates neither drift nor divide by zero.
+pub fn frame_delay(count: u32, fps: f64) -> Delay {
+    let ms = (1000. * count as f64 / fps).round().max(1.);
+    Delay::from_numer_denom_ms(ms as u32, 1)
+}
 
+pub fn write_gif<F: Borrow<DynamicImage>>(path: &Path, frames: &[F], fps: f64) -> ImageResult<()> {
+    let fp = File::create(path)?;
     info!("encoding gif frames...");
     let progress = default_progress_bar("Frames", frames.len());
-    encoder
-        .encode_frames(frames.into_iter().progress_with(progress))
-        .unwrap();
+    write_gif_to_stream(fp, frames, fps, progress)
 }
 
-pub fn write_gif(path: &Path, frames: &[DynamicImage], fps: f64) {
-    let fp = File::create(path).unwrap();
-    write_gif_to_stream(fp, frames, fps)
+// Encodes the GIF in memory instead, for answering HTTP requests with, where there's no terminal to
+// show progress on.
+pub fn encode_gif<F: Borrow<DynamicImage>>(frames: &[F], fps: f64) -> ImageResult<Vec<u8>> {
+    let mut gif = Vec::new();
+    write_gif_to_stream(&mut gif, frames, fps, ProgressBar::hidden())?;
+    Ok(gif)
 }
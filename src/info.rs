@@ -0,0 +1,62 @@
+use image::ImageFormat;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{get, State};
+
+use crate::config::{Config, LimitsConfig};
+
+// The formats enabled through the image crate's default features.
+const IMAGE_FORMATS: [ImageFormat; 12] = [
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::Gif,
+    ImageFormat::WebP,
+    ImageFormat::Pnm,
+    ImageFormat::Tiff,
+    ImageFormat::Tga,
+    ImageFormat::Bmp,
+    ImageFormat::Ico,
+    ImageFormat::Hdr,
+    ImageFormat::OpenExr,
+    ImageFormat::Farbfeld,
+];
+
+// Outputs produced by the pipeline itself rather than by encoding a rendered bitmap.
+const TEXT_OUTPUTS: [&str; 4] = ["text", "ansi", "html", "json"];
+
+const FEATURES: &[&str] = &[];
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Info {
+    name: &'static str,
+    version: &'static str,
+    git_hash: Option<&'static str>,
+    features: &'static [&'static str],
+    input_formats: Vec<&'static str>,
+    output_formats: Vec<&'static str>,
+    limits: LimitsConfig,
+}
+
+fn extensions(can: fn(&ImageFormat) -> bool) -> Vec<&'static str> {
+    IMAGE_FORMATS
+        .iter()
+        .filter(|format| can(format))
+        .map(|format| format.extensions_str()[0])
+        .collect()
+}
+
+#[get("/info")]
+pub fn info(config: &State<Config>) -> Json<Info> {
+    let mut output_formats = TEXT_OUTPUTS.to_vec();
+    output_formats.extend(extensions(ImageFormat::can_write));
+    Json(Info {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: option_env!("GIT_HASH"),
+        features: FEATURES,
+        input_formats: extensions(ImageFormat::can_read),
+        output_formats,
+        limits: config.conversion_limits.clone(),
+    })
+}
@@ -1,5 +1,8 @@
 pub mod convert;
+pub mod cvd;
 pub mod font;
 pub mod gif;
 pub mod metrics;
-pub mod progress;
\ No newline at end of file
+pub mod progress;
+pub mod spans;
+pub mod truetype;
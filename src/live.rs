@@ -0,0 +1,577 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rocket::data::{IoHandler, IoStream};
+use rocket::http::{Header, Status};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::Deserialize;
+use rocket::tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ErrorKind};
+use rocket::tokio::select;
+use rocket::tokio::sync::{Mutex as AsyncMutex, Notify};
+use rocket::tokio::task::block_in_place;
+use rocket::tokio::time::timeout;
+use rocket::{get, State};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::error::{ApiError, Error};
+use crate::negotiate::Representation;
+use crate::permalink::{PermalinkConfig, Permalinks};
+use crate::pools::Pools;
+use crate::query::{Conversion, ValidatedParams};
+use crate::ratelimit::Limiter;
+use crate::stats::Stats;
+use crate::storage::Memory;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct LiveConfig {
+    // images kept waiting while one is converted or its reply is still being taken by the client
+    pub backlog: usize,
+    // once the backlog is full, an image that arrives replaces the oldest waiting when set, so the
+    // latest are converted, and is dropped otherwise
+    pub coalesce: bool,
+    // seconds a reply may take to be sent before the session is given up on
+    pub send_timeout: u64,
+}
+
+impl Default for LiveConfig {
+    fn default() -> LiveConfig {
+        LiveConfig {
+            backlog: 1,
+            coalesce: true,
+            send_timeout: 10,
+        }
+    }
+}
+
+// Appended to the client's key to answer a WebSocket handshake, as RFC 6455 defines it.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// A session ends once the client has sent nothing for this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+// Close codes: a normal closure, a frame breaking the protocol, and a message bigger than the
+// source size limit.
+const NORMAL_CLOSURE: u16 = 1000;
+const PROTOCOL_ERROR: u16 = 1002;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+// The SHA-1 WebSocket handshakes are answered with, for nothing else.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0_u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, x) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(x);
+        }
+    }
+    let mut digest = [0; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+// The `Sec-WebSocket-Key` of a request to open a WebSocket; anything else is told to upgrade.
+pub struct WebSocketKey(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WebSocketKey {
+    type Error = Error;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let headers = req.headers();
+        let upgrade = headers.get("Upgrade").any(|protocols| {
+            protocols
+                .split(',')
+                .any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket"))
+        });
+        match headers.get_one("Sec-WebSocket-Key") {
+            Some(key) if upgrade && headers.get_one("Sec-WebSocket-Version") == Some("13") => {
+                Outcome::Success(WebSocketKey(String::from(key)))
+            }
+            _ => {
+                let status = Status::UpgradeRequired;
+                Outcome::Error((status, Error::Status(status)))
+            }
+        }
+    }
+}
+
+// Reads a whole message of up to `max_bytes`, joining its fragments, with its opcode. Control
+// frames may come between the fragments, and are returned as they come, the fragments read so far
+// being kept in `message` for the next call. Clients must mask every frame they send.
+async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    message: &mut Option<(u8, Vec<u8>)>,
+    max_bytes: u64,
+) -> io::Result<Result<(u8, Vec<u8>), u16>> {
+    loop {
+        let mut head = [0; 2];
+        reader.read_exact(&mut head).await?;
+        let (fin, opcode, masked) = (head[0] & 0x80 != 0, head[0] & 0x0f, head[1] & 0x80 != 0);
+        if !masked {
+            return Ok(Err(PROTOCOL_ERROR));
+        }
+        let length = match head[1] & 0x7f {
+            126 => reader.read_u16().await? as u64,
+            127 => reader.read_u64().await?,
+            length => length as u64,
+        };
+        let buffered = message
+            .as_ref()
+            .map_or(0, |(_, payload)| payload.len() as u64);
+        if length.saturating_add(buffered) > max_bytes {
+            return Ok(Err(MESSAGE_TOO_BIG));
+        }
+        let mut mask = [0; 4];
+        reader.read_exact(&mut mask).await?;
+        let mut payload = vec![0; length as usize];
+        reader.read_exact(&mut payload).await?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        match (opcode, &mut *message) {
+            (CLOSE | PING | PONG, _) => return Ok(Ok((opcode, payload))),
+            (CONTINUATION, Some((_, buffered))) => buffered.extend_from_slice(&payload),
+            (CONTINUATION, None) => return Err(io::Error::other("continuation of nothing")),
+            (opcode, _) => *message = Some((opcode, payload)),
+        }
+        if fin {
+            return message
+                .take()
+                .map(Ok)
+                .ok_or_else(|| io::Error::other("no message"));
+        }
+    }
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length @ 0..=125 => frame.push(length as u8),
+        length @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+async fn close<W: AsyncWrite + Unpin>(writer: &mut W, code: u16) -> io::Result<()> {
+    write_frame(writer, CLOSE, &code.to_be_bytes()).await
+}
+
+// The images a session has yet to convert, so a client sending faster than its replies are
+// converted or taken has no more than `max` of them kept for it.
+struct Backlog {
+    images: Mutex<VecDeque<Vec<u8>>>,
+    arrived: Notify,
+    max: usize,
+    coalesce: bool,
+}
+
+impl Backlog {
+    fn new(config: &LiveConfig) -> Backlog {
+        Backlog {
+            images: Mutex::default(),
+            arrived: Notify::new(),
+            max: config.backlog.max(1),
+            coalesce: config.coalesce,
+        }
+    }
+
+    // Queues `image`, making room by dropping the oldest waiting image or `image` itself.
+    fn push(&self, image: Vec<u8>) {
+        let mut images = self.images.lock().unwrap();
+        if images.len() >= self.max {
+            if !self.coalesce {
+                return;
+            }
+            images.pop_front();
+        }
+        images.push_back(image);
+        self.arrived.notify_one();
+    }
+
+    async fn pop(&self) -> Vec<u8> {
+        loop {
+            if let Some(image) = self.images.lock().unwrap().pop_front() {
+                return image;
+            }
+            self.arrived.notified().await;
+        }
+    }
+}
+
+// One client's session, converting each image it sends with the same parameters.
+pub struct LiveSession<'r> {
+    key: String,
+    conversion: Arc<Conversion>,
+    representation: Representation,
+    max_bytes: u64,
+    config: LiveConfig,
+    limiter: Limiter,
+    pools: &'r Pools,
+    stats: &'r Stats,
+}
+
+impl LiveSession<'_> {
+    async fn convert(&self, bytes: Vec<u8>) -> Result<(bool, Vec<u8>), Error> {
+        let (bytes, image) = self.pools.decode(bytes).await?;
+        let (conversion, representation) = (self.conversion.clone(), self.representation);
+        // frames aren't stored behind permalinks
+        let permalinks = Permalinks::new(&PermalinkConfig::default(), Arc::new(Memory::default()));
+        let ((content_type, body), _, _, _) = self
+            .pools
+            .convert(move || {
+                conversion.render_negotiated(&bytes, &image, "live", representation, &permalinks)
+            })
+            .await
+            .and_then(|result| result)?;
+        let text = content_type.top() == "text" || content_type.is_json();
+        Ok((text, body))
+    }
+
+    // Sends a frame, giving up on a client that doesn't take it within `send_timeout`.
+    async fn send<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &AsyncMutex<W>,
+        opcode: u8,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let mut writer = writer.lock().await;
+        let within = Duration::from_secs(self.config.send_timeout);
+        match timeout(within, write_frame(&mut *writer, opcode, payload)).await {
+            Ok(sent) => sent,
+            Err(_) => Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "client stopped taking frames",
+            )),
+        }
+    }
+
+    // Reads the client's messages until the session is to close, returning the code to close it
+    // with. Images are left in the backlog for `answer`, so pings are answered and idle clients
+    // noticed however long conversions take.
+    async fn receive<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+        &self,
+        reader: &mut R,
+        writer: &AsyncMutex<W>,
+        backlog: &Backlog,
+    ) -> io::Result<u16> {
+        let mut fragments = None;
+        loop {
+            let read = read_message(reader, &mut fragments, self.max_bytes);
+            let message = match timeout(IDLE_TIMEOUT, read).await {
+                Ok(message) => message?,
+                Err(_) => return Ok(NORMAL_CLOSURE),
+            };
+            let (opcode, payload) = match message {
+                Ok(message) => message,
+                Err(code) => return Ok(code),
+            };
+            match opcode {
+                BINARY => backlog.push(payload),
+                PING => self.send(writer, PONG, &payload).await?,
+                CLOSE => return Ok(NORMAL_CLOSURE),
+                // text messages and pongs have nothing to answer
+                _ => {}
+            }
+        }
+    }
+
+    // Converts the images in the backlog one at a time, in order, answering each.
+    async fn answer<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &AsyncMutex<W>,
+        backlog: &Backlog,
+    ) -> io::Result<()> {
+        loop {
+            let image = backlog.pop().await;
+            // every image counts against the client's bucket, not just the session
+            if let Err(err) = block_in_place(|| self.limiter.take()) {
+                let error = ApiError::from(err).to_json();
+                self.send(writer, TEXT, error.as_bytes()).await?;
+                continue;
+            }
+            let t0 = Instant::now();
+            match self.convert(image).await {
+                Ok((text, body)) => {
+                    let conversion = &self.conversion;
+                    let (font, alphabet) = (&conversion.font, &conversion.alphabet);
+                    let took = t0.elapsed();
+                    self.stats
+                        .record(font, alphabet, &conversion.metric, "live", took);
+                    let opcode = if text { TEXT } else { BINARY };
+                    self.send(writer, opcode, &body).await?;
+                }
+                Err(err) => {
+                    self.stats.record_failure();
+                    let error = ApiError::from(err).to_json();
+                    self.send(writer, TEXT, error.as_bytes()).await?;
+                }
+            }
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl IoHandler for LiveSession<'_> {
+    async fn io(self: Pin<Box<Self>>, io: IoStream) -> io::Result<()> {
+        let (mut reader, writer) = io::split(io);
+        let writer = AsyncMutex::new(writer);
+        let backlog = Backlog::new(&self.config);
+        let code = select! {
+            code = self.receive(&mut reader, &writer, &backlog) => code?,
+            answered = self.answer(&writer, &backlog) => return answered,
+        };
+        // the client may have stopped taking frames altogether, so this is given up on too
+        let mut writer = writer.lock().await;
+        let within = Duration::from_secs(self.config.send_timeout);
+        timeout(within, close(&mut *writer, code))
+            .await
+            .unwrap_or(Ok(()))
+    }
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for LiveSession<'o> {
+    fn respond_to(self, _request: &'r Request<'_>) -> response::Result<'o> {
+        let accept = STANDARD.encode(sha1([&self.key, HANDSHAKE_GUID].concat().as_bytes()));
+        Response::build()
+            .header(Header::new("Sec-WebSocket-Accept", accept))
+            .upgrade("websocket", self)
+            .ok()
+    }
+}
+
+// A WebSocket the client sends images to, each as a binary message such as a canvas capture in
+// any supported format, and gets each back converted with the query's parameters, one at a time
+// and in order. Text representations come back as text messages, and the rest as binary ones.
+#[get("/live")]
+pub fn live<'r>(
+    limiter: Limiter,
+    key: WebSocketKey,
+    params: Result<ValidatedParams, Error>,
+    config: &State<Config>,
+    pools: &'r State<Pools>,
+    stats: &'r State<Stats>,
+) -> Result<LiveSession<'r>, Error> {
+    let ValidatedParams { conversion, format } = params?;
+    let representation = Representation::Text.or_format(format.as_deref())?;
+    Ok(LiveSession {
+        key: key.0,
+        conversion: Arc::new(conversion),
+        representation,
+        max_bytes: config.conversion_limits.max_source_bytes,
+        config: config.live.clone(),
+        limiter,
+        pools,
+        stats,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A frame as clients send it, masked.
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+        match payload.len() {
+            length @ 0..=125 => frame.push(0x80 | length as u8),
+            length => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+        }
+        let mask = [1, 2, 3, 4];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    async fn read(bytes: &[u8], max_bytes: u64) -> io::Result<Result<(u8, Vec<u8>), u16>> {
+        let mut reader = bytes;
+        read_message(&mut reader, &mut None, max_bytes).await
+    }
+
+    #[rocket::async_test]
+    async fn reads_masked_messages() {
+        let message = read(&frame(true, BINARY, b"image"), 100).await.unwrap();
+        assert_eq!(message, Ok((BINARY, b"image".to_vec())));
+        let long = vec![7; 300];
+        let message = read(&frame(true, BINARY, &long), 1000).await.unwrap();
+        assert_eq!(message, Ok((BINARY, long)));
+    }
+
+    #[rocket::async_test]
+    async fn joins_fragments_around_control_frames() {
+        let bytes = [
+            frame(false, TEXT, b"ab"),
+            frame(true, PING, b"p"),
+            frame(false, CONTINUATION, b"cd"),
+            frame(true, CONTINUATION, b"ef"),
+        ]
+        .concat();
+        let (mut reader, mut fragments) = (&bytes[..], None);
+        let ping = read_message(&mut reader, &mut fragments, 100).await;
+        assert_eq!(ping.unwrap(), Ok((PING, b"p".to_vec())));
+        let message = read_message(&mut reader, &mut fragments, 100)
+            .await
+            .unwrap();
+        assert_eq!(message, Ok((TEXT, b"abcdef".to_vec())));
+    }
+
+    #[rocket::async_test]
+    async fn refuses_unmasked_frames() {
+        let bytes = [0x80 | BINARY, 1, b'x'];
+        assert_eq!(read(&bytes, 100).await.unwrap(), Err(PROTOCOL_ERROR));
+    }
+
+    #[rocket::async_test]
+    async fn refuses_messages_over_the_limit() {
+        let bytes = frame(true, BINARY, &[0; 11]);
+        assert_eq!(read(&bytes, 10).await.unwrap(), Err(MESSAGE_TOO_BIG));
+        let bytes = [
+            frame(false, BINARY, &[0; 6]),
+            frame(true, CONTINUATION, &[0; 6]),
+        ]
+        .concat();
+        assert_eq!(read(&bytes, 10).await.unwrap(), Err(MESSAGE_TOO_BIG));
+        // a length that would overflow once added to what's buffered
+        let mut bytes = frame(false, BINARY, &[0; 6]);
+        bytes.extend_from_slice(&[0x80 | CONTINUATION, 0x80 | 127]);
+        bytes.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(read(&bytes, 10).await.unwrap(), Err(MESSAGE_TOO_BIG));
+    }
+
+    #[rocket::async_test]
+    async fn fails_on_a_continuation_of_nothing() {
+        assert!(read(&frame(true, CONTINUATION, b"x"), 100).await.is_err());
+    }
+
+    #[test]
+    fn accepts_handshakes_as_the_rfc_does() {
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let accept = STANDARD.encode(sha1([key, HANDSHAKE_GUID].concat().as_bytes()));
+        assert_eq!(accept, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[rocket::async_test]
+    async fn writes_unmasked_frames_with_the_shortest_length() {
+        for (length, head) in [
+            (5, vec![0x80 | TEXT, 5]),
+            (300, vec![0x80 | TEXT, 126, 1, 44]),
+            (
+                70_000,
+                [vec![0x80 | TEXT, 127], 70_000_u64.to_be_bytes().to_vec()].concat(),
+            ),
+        ] {
+            let mut written = Vec::new();
+            write_frame(&mut written, TEXT, &vec![b'x'; length])
+                .await
+                .unwrap();
+            assert_eq!(written[..head.len()], head[..]);
+            assert_eq!(written.len(), head.len() + length);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn closes_with_the_code_as_payload() {
+        let mut written = Vec::new();
+        close(&mut written, MESSAGE_TOO_BIG).await.unwrap();
+        assert_eq!(written, [0x80 | CLOSE, 2, 0x03, 0xf1]);
+    }
+
+    fn backlog(backlog: usize, coalesce: bool) -> Backlog {
+        Backlog::new(&LiveConfig {
+            backlog,
+            coalesce,
+            ..LiveConfig::default()
+        })
+    }
+
+    #[rocket::async_test]
+    async fn coalesces_images_into_the_latest() {
+        let backlog = backlog(2, true);
+        for image in [b"a", b"b", b"c"] {
+            backlog.push(image.to_vec());
+        }
+        assert_eq!(backlog.pop().await, b"b");
+        assert_eq!(backlog.pop().await, b"c");
+        assert!(backlog.images.lock().unwrap().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn drops_images_past_the_backlog() {
+        let backlog = backlog(0, false);
+        backlog.push(b"a".to_vec());
+        backlog.push(b"b".to_vec());
+        assert_eq!(backlog.pop().await, b"a");
+        assert!(backlog.images.lock().unwrap().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn waits_for_an_image_to_arrive() {
+        let backlog = Arc::new(backlog(1, true));
+        let pushed = backlog.clone();
+        rocket::tokio::spawn(async move { pushed.push(b"a".to_vec()) });
+        let image = timeout(Duration::from_secs(5), backlog.pop()).await;
+        assert_eq!(image.unwrap(), b"a");
+    }
+}
@@ -0,0 +1,146 @@
+use log::info;
+use rocket::fairing::AdHoc;
+use rocket::http::Header;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::Response;
+use rocket::serde::Deserialize;
+use std::convert::Infallible;
+use std::time::Instant;
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct LoggingConfig {
+    // whether every request is logged once it's answered
+    pub requests: bool,
+    // milliseconds a conversion may take before it's logged as slow, 0 for never
+    pub slow_conversion_ms: u64,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig {
+            requests: true,
+            slow_conversion_ms: 1000,
+        }
+    }
+}
+
+// Longer IDs given in `X-Request-Id` are replaced, as are ones with other characters than these.
+const MAX_ID_LEN: usize = 64;
+const ID_PUNCTUATION: &[u8] = b"-_.:";
+
+// Query parameters whose values are left out of the log.
+const SECRET_PARAMS: [&str; 1] = ["api_key"];
+
+// The ID a request is logged under along with everything done for it, taken from its
+// `X-Request-Id` when a proxy in front of the API already gave it one, and sent back in the same
+// header.
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+// When the request came in.
+struct Started(Instant);
+
+fn usable(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || ID_PUNCTUATION.contains(&b))
+}
+
+impl RequestId {
+    pub fn of(req: &Request<'_>) -> RequestId {
+        req.local_cache(|| {
+            let id = match req.headers().get_one("X-Request-Id") {
+                Some(id) if usable(id) => String::from(id),
+                _ => format!("{:016x}", rand::random::<u64>()),
+            };
+            RequestId(id)
+        })
+        .clone()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(RequestId::of(req))
+    }
+}
+
+// A logfmt value, quoted when it has to be for the line to stay parseable.
+pub fn field(value: &str) -> String {
+    let plain = !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == '"' || c == '=');
+    match plain {
+        true => String::from(value),
+        false => format!("{:?}", value),
+    }
+}
+
+// The query string with the values of secret parameters replaced.
+fn redacted(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if SECRET_PARAMS.contains(&name) => format!("{}=redacted", name),
+            _ => String::from(pair),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn log_request(req: &Request<'_>, res: &Response<'_>, id: &str) {
+    let Started(t0) = req.local_cache(|| Started(Instant::now()));
+    let route = req
+        .route()
+        .and_then(|route| route.name.as_deref())
+        .unwrap_or("unmatched");
+    let params = req.uri().query().map(|query| redacted(query.as_str()));
+    info!(
+        "request id={} method={} path={} params={} route={} status={} duration_ms={:.1}",
+        field(id),
+        req.method(),
+        field(req.uri().path().as_str()),
+        field(params.as_deref().unwrap_or("")),
+        route,
+        res.status().code,
+        t0.elapsed().as_secs_f64() * 1000.
+    );
+}
+
+// Gives every request an ID, sent back in `X-Request-Id`, and with `logging.requests` logs each
+// one as it's answered, as a line of logfmt fields.
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Request log", |rocket| async {
+        let config = rocket
+            .state::<Config>()
+            .map(|config| config.logging.clone())
+            .unwrap_or_default();
+        let requests = config.requests;
+        rocket
+            .attach(AdHoc::on_request("Request IDs", |req, _| {
+                Box::pin(async move {
+                    req.local_cache(|| Started(Instant::now()));
+                    RequestId::of(req);
+                })
+            }))
+            .attach(AdHoc::on_response("Log requests", move |req, res| {
+                Box::pin(async move {
+                    let RequestId(id) = RequestId::of(req);
+                    if requests {
+                        log_request(req, res, &id);
+                    }
+                    res.set_header(Header::new("X-Request-Id", id));
+                })
+            }))
+    })
+}
@@ -1,13 +1,73 @@
+use cache::{Cached, DiskCache, IfNoneMatch};
+use config::Config;
+use echo::{source_metadata, WithParams};
+use error::Error;
+use fetch::Fetchers;
+use metadata::SourceMetadata;
+use negotiate::{Body, Representation};
+use permalink::Permalinks;
+use pools::Pools;
+use query::{TextBands, ValidatedParams};
+use ratelimit::RateLimit;
+use rocket::fairing::AdHoc;
+use rocket::response::stream::TextStream;
+use rocket::tokio::sync::mpsc;
+use rocket::tokio::task::block_in_place;
+use rocket::{catchers, get, launch, routes, Either, State};
+use stats::Stats;
 use std::path::PathBuf;
-use rocket::{get, routes};
-use generate::{generate, Params};
+use std::sync::Arc;
+use std::time::Instant;
 
-mod generate;
+mod alphabets;
+mod auth;
+mod badge;
+mod banner;
+mod blocklist;
+mod cache;
+mod callback;
+mod config;
 mod convert;
+mod cvd;
+mod debug;
+mod echo;
+mod embed;
+mod error;
+mod estimate;
+mod fetch;
+mod flamegraph;
 mod font;
+mod fonts;
+mod gallery;
+mod generate;
 mod gif;
-mod progress;
+mod info;
+mod live;
+mod logging;
+mod metadata;
 mod metrics;
+mod negotiate;
+mod palette;
+mod permalink;
+mod pngtext;
+mod pools;
+mod profile;
+mod progress;
+mod qr;
+mod query;
+mod ratelimit;
+mod selftest;
+mod slack;
+mod spans;
+mod stats;
+mod storage;
+mod sweep;
+mod telegram;
+mod telemetry;
+mod tilemap;
+mod truetype;
+mod upload;
+mod warmup;
 
 #[get("/")]
 fn index() -> &'static str {
@@ -15,36 +75,185 @@ fn index() -> &'static str {
 }
 
 #[get("/<image_url..>")]
-fn get_image_url(image_url: PathBuf) -> String {
-    let string_url = match image_url.to_str() {
-        Some(url) => url,
-        None => {
-            return String::from("Invalid URL");
+#[allow(clippy::too_many_arguments)]
+async fn get_image_url(
+    _limit: RateLimit,
+    image_url: PathBuf,
+    params: Result<ValidatedParams, Error>,
+    representation: Representation,
+    if_none_match: IfNoneMatch,
+    config: &State<Config>,
+    stats: &State<Stats>,
+    pools: &State<Pools>,
+    fetchers: &State<Fetchers>,
+    permalinks: &State<Permalinks>,
+    disk_cache: &State<Arc<DiskCache>>,
+) -> Result<Cached<WithParams<Either<Body, TextStream![String]>>>, Error> {
+    let string_url = image_url.to_str().ok_or(Error::InvalidParameter("URL"))?;
+    let ValidatedParams { conversion, format } = params?;
+    let representation = representation.or_format(format.as_deref())?;
+
+    let image_url = format!("{}{}", "https://", string_url);
+    let labels = (
+        conversion.font.clone(),
+        conversion.alphabet.clone(),
+        conversion.metric.clone(),
+    );
+    let permalinks = permalinks.inner().clone();
+    let t0 = Instant::now();
+    let result = async {
+        let bytes = pools.download(fetchers.fetch(&image_url)).await?;
+        // a client that already has the result is answered before anything is converted
+        let tag = conversion.etag(&bytes, representation);
+        let etag = tag.clone().filter(|_| config.cache.etag);
+        if etag
+            .as_ref()
+            .is_some_and(|etag| if_none_match.matches(etag))
+        {
+            return Ok((None, etag));
+        }
+        // results on disk are kept by the same hash as their entity tag
+        let cache_key = tag
+            .filter(|_| disk_cache.enabled())
+            .map(|tag| String::from(tag.trim_matches('"')));
+        if let Some(key) = &cache_key {
+            let cached = block_in_place(|| disk_cache.get(key));
+            stats.record_cache(cached.is_some());
+            if cached.is_some() {
+                return Ok((cached.map(Either::Left), etag));
+            }
+        }
+        let (bytes, image) = pools.decode(bytes).await?;
+        let streamed = cache_key.is_none();
+        let rendered = pools
+            .convert(move || {
+                if streamed && conversion.streams(&image, representation, &permalinks)? {
+                    return TextBands::new(conversion, image, image_url).map(Either::Right);
+                }
+                let (body, resolved, permalink, quality) = conversion.render_negotiated(
+                    &bytes,
+                    &image,
+                    &image_url,
+                    representation,
+                    &permalinks,
+                )?;
+                let metadata = conversion.metadata.then(|| {
+                    source_metadata(&SourceMetadata::new(&bytes, &image).with_quality(quality))
+                });
+                Ok(Either::Left((body, resolved, metadata, permalink)))
+            })
+            .await
+            .and_then(|result| result)?;
+        if let (Some(key), Either::Left(rendered)) = (&cache_key, &rendered) {
+            block_in_place(|| disk_cache.put(key, rendered));
+        }
+        Ok((Some(rendered), etag))
+    }
+    .await;
+
+    match result {
+        Ok((None, etag)) => Ok(Cached::not_modified(&config.cache).with_etag(etag)),
+        Ok((Some(rendered), etag)) => {
+            let (font, alphabet, metric) = labels;
+            let format = representation.name();
+            stats.record(&font, &alphabet, &metric, format, t0.elapsed());
+            let response = match rendered {
+                Either::Left((body, resolved, metadata, permalink)) => {
+                    WithParams::new(Either::Left(body), resolved)
+                        .with_metadata(metadata)
+                        .with_permalink(permalink)
+                }
+                Either::Right(bands) => {
+                    let resolved = bands.resolved();
+                    WithParams::new(Either::Right(stream_bands(pools, bands).await), resolved)
+                }
+            };
+            Ok(Cached::new(response, &config.cache).with_etag(etag))
+        }
+        Err(err) => {
+            stats.record_failure();
+            Err(err)
+        }
+    }
+}
+
+// Bands of streamed text waiting to be sent; a client reading slower than they're converted holds
+// the conversion up instead of letting them pile up.
+const STREAMED_BANDS: usize = 4;
+
+// Sends the bands of `bands` as they're converted, stopping the conversion if the client goes away.
+async fn stream_bands(pools: &Pools, bands: TextBands) -> TextStream![String] {
+    let (sender, mut receiver) = mpsc::channel(STREAMED_BANDS);
+    pools
+        .spawn_convert(move || bands.convert(|band| sender.blocking_send(band).is_ok()))
+        .await;
+    TextStream! {
+        while let Some(band) = receiver.recv().await {
+            yield band;
         }
-    };
-    let args = Params {
-        image_url: format!("{}{}", "https://", string_url).as_str(),
-        font: "bitocra-13",
-        alphabet: "alphabet",
-        width: 150,
-        metric: "grad",
-        threads: 1,
-        no_color: false,
-        brightness_offset: 0.0,
-        noise_scale: 0.0,
-        out_path: None,
-        fps: 30.0,
-        no_edge_detection: false,
-    };
-
-    format!("URL: {}", string_url)
+    }
 }
 
-#[tokio::main]
-async fn main() {
+#[launch]
+fn rocket() -> _ {
+    // Colored output goes to HTTP clients, not the server's own stdout, so don't let whether that
+    // is a terminal decide.
+    colored::control::set_override(true);
     rocket::build()
-        .mount("/", routes![index, get_image_url])
-        .launch()
-        .await
-        .expect("Rocket failed to launch");
+        .mount(
+            "/",
+            routes![
+                index,
+                get_image_url,
+                alphabets::alphabets,
+                alphabets::register_alphabet,
+                alphabets::remove_alphabet,
+                badge::badge,
+                banner::text_banner,
+                blocklist::blocked_sources,
+                blocklist::purge,
+                callback::dead_letters,
+                qr::qr,
+                debug::debug,
+                embed::embed_js,
+                estimate::estimate,
+                flamegraph::flamegraph,
+                fonts::fonts,
+                fonts::upload_font,
+                gallery::gallery,
+                gallery::json_feed,
+                gallery::rss_feed,
+                info::info,
+                live::live,
+                palette::palette,
+                permalink::permalink,
+                permalink::job_result,
+                selftest::selftest,
+                stats::stats,
+                sweep::sweep,
+                slack::slack_command,
+                telegram::telegram_webhook,
+                telemetry::metrics,
+                upload::convert,
+                upload::convert_json
+            ],
+        )
+        .register("/", catchers![error::default_catcher])
+        .attach(AdHoc::config::<Config>())
+        .attach(logging::stage())
+        .attach(storage::stage())
+        .attach(auth::stage())
+        .attach(ratelimit::stage())
+        .attach(alphabets::stage())
+        .attach(fonts::stage())
+        .attach(blocklist::stage())
+        .attach(fetch::stage())
+        .attach(gallery::stage())
+        .attach(permalink::stage())
+        .attach(stats::stage())
+        .attach(pools::stage())
+        .attach(callback::stage())
+        .attach(warmup::stage())
+        .attach(cache::stage())
+        .attach(telemetry::stage())
 }
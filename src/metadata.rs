@@ -0,0 +1,237 @@
+use image::{DynamicImage, GenericImageView};
+use rocket::serde::Serialize;
+
+use crate::convert::dominant_colors;
+use crate::font::Font;
+
+const DOMINANT_COLORS: usize = 5;
+// The quality breakdown divides the grid into this many regions across and down.
+const QUALITY_REGIONS: usize = 3;
+
+#[derive(Debug, Default, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Exif {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub orientation: Option<u16>,
+    pub date_time: Option<String>,
+}
+
+// Facts about the source image itself rather than the conversion, so clients don't need a second
+// request to learn them.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SourceMetadata {
+    pub width: u32,
+    pub height: u32,
+    pub format: Option<String>,
+    pub bytes: usize,
+    pub exif: Option<Exif>,
+    pub dominant_colors: Vec<String>,
+    // only for the glyph modes, which match glyph bitmaps to the image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<Quality>,
+}
+
+// How closely the characters picked match the cells of the image they stand for, to compare
+// parameter sets by. Errors are the mean absolute difference between a cell's intensities and its
+// glyph's bitmap, from 0 to 1; the score is one minus their mean, so higher is better.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Quality {
+    pub score: f32,
+    pub mean_error: f32,
+    // the mean error of each region of the grid, row by row from the top left
+    pub regions: Vec<Vec<f32>>,
+    // only when the grid is too wide for the source to fill its glyphs with detail
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width_advice: Option<WidthAdvice>,
+}
+
+// Cells are matched to the source scaled to the grid's size in glyph pixels, so a grid wider than
+// the source has glyph pixels to spare only blurs it: the glyphs are picked from smooth upscaled
+// cells rather than from the source's own edges.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct WidthAdvice {
+    pub warning: String,
+    // up to this many source pixels per glyph pixel across
+    pub suggested_min_width: usize,
+    // one source pixel per glyph pixel across
+    pub suggested_max_width: usize,
+}
+
+// The most source pixels across each glyph pixel stands for at the suggested minimum width; any
+// fewer columns and the source's detail averages away within glyph pixels.
+const MAX_PIXELS_PER_GLYPH_PIXEL: usize = 4;
+
+impl WidthAdvice {
+    // Advice for a grid of `columns` matched to a source `source_width` pixels wide, none when
+    // every glyph pixel has at least one source pixel to go by.
+    pub fn check(font: &Font, source_width: u32, columns: usize) -> Option<WidthAdvice> {
+        let max_width = (source_width as usize / font.width).max(1);
+        if columns <= max_width {
+            return None;
+        }
+        Some(WidthAdvice {
+            warning: format!(
+                "{} columns of {}px wide glyphs upscale the {}px wide source, blurring it",
+                columns, font.width, source_width
+            ),
+            suggested_min_width: (max_width / MAX_PIXELS_PER_GLYPH_PIXEL).max(1),
+            suggested_max_width: max_width,
+        })
+    }
+}
+
+impl Quality {
+    // `pixels` are the cells' intensities as the glyphs were matched to them; characters the font
+    // has no glyph for, such as a blank character, aren't counted.
+    pub fn measure(font: &Font, pixels: &[f32], char_rows: &[Vec<char>]) -> Quality {
+        let (n_cols, n_rows) = (char_rows[0].len(), char_rows.len());
+        let (regions_x, regions_y) = (QUALITY_REGIONS.min(n_cols), QUALITY_REGIONS.min(n_rows));
+        let mut sums = vec![(0_f32, 0_usize); regions_x * regions_y];
+        let row_length = n_cols * font.width;
+        for (j, row) in char_rows.iter().enumerate() {
+            for (i, c) in row.iter().enumerate() {
+                let glyph = match font.char_map.get(c) {
+                    Some(glyph) => glyph,
+                    None => continue,
+                };
+                let mut error = 0.;
+                for y in 0..font.height {
+                    let offset = (j * font.height + y) * row_length + i * font.width;
+                    for x in 0..font.width {
+                        let value = pixels[offset + x].clamp(0., 1.);
+                        error += (value - glyph.bitmap[y * font.width + x]).abs();
+                    }
+                }
+                let region = (j * regions_y / n_rows) * regions_x + i * regions_x / n_cols;
+                sums[region].0 += error / (font.width * font.height) as f32;
+                sums[region].1 += 1;
+            }
+        }
+        let (total, count) = sums
+            .iter()
+            .fold((0., 0), |(total, count), (sum, n)| (total + sum, count + n));
+        let mean = |sum: f32, n: usize| if n > 0 { sum / n as f32 } else { 0. };
+        let mean_error = mean(total, count);
+        Quality {
+            score: 1. - mean_error,
+            mean_error,
+            regions: sums
+                .chunks(regions_x)
+                .map(|row| row.iter().map(|&(sum, n)| mean(sum, n)).collect())
+                .collect(),
+            width_advice: None,
+        }
+    }
+
+    pub fn with_width_advice(mut self, width_advice: Option<WidthAdvice>) -> Quality {
+        self.width_advice = width_advice;
+        self
+    }
+}
+
+impl SourceMetadata {
+    pub fn new(bytes: &[u8], image: &DynamicImage) -> SourceMetadata {
+        let (width, height) = image.dimensions();
+        SourceMetadata {
+            width,
+            height,
+            format: image::guess_format(bytes)
+                .ok()
+                .and_then(|format| format.extensions_str().first())
+                .map(|extension| String::from(*extension)),
+            bytes: bytes.len(),
+            exif: read_exif(bytes),
+            dominant_colors: dominant_colors(image, DOMINANT_COLORS)
+                .iter()
+                .map(hex_color)
+                .collect(),
+            quality: None,
+        }
+    }
+
+    pub fn with_quality(mut self, quality: Option<Quality>) -> SourceMetadata {
+        self.quality = quality;
+        self
+    }
+}
+
+pub fn hex_color([r, g, b]: &[u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+// Reads a few IFD0 tags from a JPEG's APP1 segment; other formats and anything malformed yield
+// `None`.
+fn read_exif(bytes: &[u8]) -> Option<Exif> {
+    if bytes.get(..2)? != [0xff, 0xd8] {
+        return None;
+    }
+    let mut offset = 2;
+    loop {
+        let (marker, length) = (*bytes.get(offset + 1)?, read_u16(bytes, offset + 2, false)?);
+        let segment = bytes.get(offset + 4..offset + 2 + length as usize)?;
+        if marker == 0xe1 && segment.starts_with(b"Exif\0\0") {
+            return read_tiff(&segment[6..]);
+        }
+        // image data follows start of scan, there are no more metadata segments
+        if marker == 0xda {
+            return None;
+        }
+        offset += 2 + length as usize;
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize, little_endian: bool) -> Option<u16> {
+    let value: [u8; 2] = bytes.get(offset..offset + 2)?.try_into().ok()?;
+    Some(if little_endian {
+        u16::from_le_bytes(value)
+    } else {
+        u16::from_be_bytes(value)
+    })
+}
+
+fn read_u32(bytes: &[u8], offset: usize, little_endian: bool) -> Option<u32> {
+    let value: [u8; 4] = bytes.get(offset..offset + 4)?.try_into().ok()?;
+    Some(if little_endian {
+        u32::from_le_bytes(value)
+    } else {
+        u32::from_be_bytes(value)
+    })
+}
+
+fn read_tiff(tiff: &[u8]) -> Option<Exif> {
+    let little_endian = match tiff.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let ifd = read_u32(tiff, 4, little_endian)? as usize;
+    let mut exif = Exif::default();
+    for i in 0..read_u16(tiff, ifd, little_endian)? as usize {
+        let entry = ifd + 2 + i * 12;
+        let tag = read_u16(tiff, entry, little_endian)?;
+        let count = read_u32(tiff, entry + 4, little_endian)? as usize;
+        // values of up to four bytes are stored in the entry itself
+        let ascii = || {
+            let start = if count <= 4 {
+                entry + 8
+            } else {
+                read_u32(tiff, entry + 8, little_endian)? as usize
+            };
+            let value = tiff.get(start..start + count)?;
+            let value = String::from_utf8_lossy(value);
+            Some(String::from(value.trim_end_matches('\0').trim()))
+        };
+        match tag {
+            0x010f => exif.make = ascii(),
+            0x0110 => exif.model = ascii(),
+            0x0112 => exif.orientation = read_u16(tiff, entry + 8, little_endian),
+            0x0132 => exif.date_time = ascii(),
+            _ => {}
+        }
+    }
+    Some(exif)
+}
@@ -26,3 +26,24 @@ pub fn movement_toward_clear(xs: &[f32], ys: &[f32]) -> f32 {
         .map(|(&x, &y)| if y > 0. { 0. } else { x })
         .sum::<f32>()
 }
+
+// Stabilize the ratios of `ssim_score` for flat cells, as in the original paper for a range of 1.
+const SSIM_C1: f32 = 0.01 * 0.01;
+const SSIM_C2: f32 = 0.03 * 0.03;
+
+// Structural similarity over the whole chunk: how alike the mean, contrast and structure of the
+// pixel values are to those of the bitmap, between -1 and 1.
+pub fn ssim_score(xs: &[f32], ys: &[f32]) -> f32 {
+    let n = xs.len() as f32;
+    let mean_x = xs.iter().sum::<f32>() / n;
+    let mean_y = ys.iter().sum::<f32>() / n;
+    let (mut var_x, mut var_y, mut covariance) = (0., 0., 0.);
+    for (x, y) in xs.iter().zip(ys) {
+        var_x += (x - mean_x) * (x - mean_x);
+        var_y += (y - mean_y) * (y - mean_y);
+        covariance += (x - mean_x) * (y - mean_y);
+    }
+    let (var_x, var_y, covariance) = (var_x / n, var_y / n, covariance / n);
+    ((2. * mean_x * mean_y + SSIM_C1) * (2. * covariance + SSIM_C2))
+        / ((mean_x * mean_x + mean_y * mean_y + SSIM_C1) * (var_x + var_y + SSIM_C2))
+}
@@ -0,0 +1,673 @@
+use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+use rocket::http::{ContentType, MediaType};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::Serialize;
+use std::convert::Infallible;
+use std::io::Cursor;
+use std::{mem, thread};
+
+use crate::convert::{
+    char_rows_to_bitmap, char_rows_to_color_bitmap, char_rows_to_html_class_string,
+    char_rows_to_html_color_string, char_rows_to_html_two_color_string, char_rows_to_string,
+    char_rows_to_two_color_bitmap, escape_xml, HtmlPalette, Theme,
+};
+use crate::error::Error;
+use crate::font::Font;
+use crate::generate::{
+    char_rows_to_text, image_to_char_rows, output_colors, output_theme, use_color, Params,
+};
+use crate::gif::{
+    arrange_frames, collapse_runs, encode_gif, interpolate_frames, interpolation_steps,
+    playback_order, sample_frames,
+};
+use crate::spans;
+use crate::tilemap::{compact_cells, Tilemap};
+
+// Quality rendered bitmaps are encoded at as `image/jpeg`.
+pub const JPEG_QUALITY: u8 = 90;
+
+// The media type of `Representation::Cells`.
+const CELLS_MEDIA_TYPE: MediaType =
+    MediaType::const_new("application", "vnd.image-to-ascii.cells", &[]);
+
+// What a conversion is answered with, picked by the media type the request's `Accept` header
+// prefers, or named with `format`. Anything else, `*/*` included, keeps the terminal text the API
+// has always returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Representation {
+    Terminal,
+    Text,
+    Html,
+    Json,
+    Png,
+    Jpeg,
+    Gif,
+    // a shell script printing the widest of several renders that fits the terminal, only named
+    // with `format`
+    AutoTerm,
+    // the grid as tile and palette indices, as JSON or packed binary, only named with `format`
+    Tilemap,
+    TilemapBinary,
+    // tile indices and RGB565 colors packed cell by cell, for bandwidth-sensitive clients
+    Cells,
+}
+
+const REPRESENTATIONS: [Representation; 11] = [
+    Representation::Terminal,
+    Representation::Text,
+    Representation::Html,
+    Representation::Json,
+    Representation::Png,
+    Representation::Jpeg,
+    Representation::Gif,
+    Representation::AutoTerm,
+    Representation::Tilemap,
+    Representation::TilemapBinary,
+    Representation::Cells,
+];
+
+impl Representation {
+    // Named like the formats of `/r/<id>`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Representation::Terminal => "ansi",
+            Representation::Text => "text",
+            Representation::Html => "html",
+            Representation::Json => "json",
+            Representation::Png => "png",
+            Representation::Jpeg => "jpeg",
+            Representation::Gif => "gif",
+            Representation::AutoTerm => "autoterm",
+            Representation::Tilemap => "tilemap",
+            Representation::TilemapBinary => "tilemap-bin",
+            Representation::Cells => "cells",
+        }
+    }
+
+    // Whether the representation can hold every frame of an animated source.
+    pub fn is_animated(&self) -> bool {
+        matches!(
+            self,
+            Representation::Html | Representation::Json | Representation::Gif
+        )
+    }
+
+    // An explicit `format` wins over the negotiated representation, for clients such as `<img>`
+    // tags that can't choose their `Accept` header.
+    pub fn or_format(self, format: Option<&str>) -> Result<Representation, Error> {
+        match format {
+            Some(format) => REPRESENTATIONS
+                .into_iter()
+                .find(|representation| representation.name() == format)
+                .ok_or(Error::InvalidParameter("format")),
+            None => Ok(self),
+        }
+    }
+}
+
+// A rendered response body along with its content type.
+pub type Body = (ContentType, Vec<u8>);
+
+// A rendered animation's body along with the grid converted from each frame.
+pub type Frames = (Body, Vec<Vec<Vec<char>>>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Representation {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let preferred = req.accept().map(|accept| accept.preferred().media_type());
+        Outcome::Success(match preferred {
+            Some(media_type) if *media_type == MediaType::Plain => Representation::Text,
+            Some(media_type) if media_type.is_html() => Representation::Html,
+            Some(media_type) if media_type.is_json() => Representation::Json,
+            Some(media_type) if media_type.is_png() => Representation::Png,
+            Some(media_type) if media_type.is_jpeg() => Representation::Jpeg,
+            Some(media_type) if media_type.is_gif() => Representation::Gif,
+            Some(media_type) if *media_type == CELLS_MEDIA_TYPE => Representation::Cells,
+            _ => Representation::Terminal,
+        })
+    }
+}
+
+// A frame as HTML color strings, with the cell backgrounds of the block modes.
+fn html_color_string(char_rows: &[Vec<char>], image: &DynamicImage, args: &Params) -> String {
+    match output_colors(char_rows, image, args) {
+        (colors, Some(backgrounds)) => {
+            char_rows_to_html_two_color_string(char_rows, &colors, &backgrounds)
+        }
+        (colors, None) => char_rows_to_html_color_string(char_rows, &colors),
+    }
+}
+
+// The body of a frame in an HTML page: inline-styled spans when colored, escaped text otherwise.
+pub fn html_frame(char_rows: &[Vec<char>], image: &DynamicImage, args: &Params) -> String {
+    if use_color(args) {
+        html_color_string(char_rows, image, args)
+    } else {
+        char_rows_to_string(char_rows)
+            .chars()
+            .map(escape_xml)
+            .collect()
+    }
+}
+
+// Like `html_frame`, with each run of a color a span classed by its color's entry in `palette`
+// rather than styled inline, for `html_classes`. The block modes' backgrounds stay inline.
+fn classed_html_frame(
+    char_rows: &[Vec<char>],
+    image: &DynamicImage,
+    args: &Params,
+    palette: &mut HtmlPalette,
+) -> String {
+    if !use_color(args) {
+        return html_frame(char_rows, image, args);
+    }
+    match output_colors(char_rows, image, args) {
+        (colors, Some(backgrounds)) => {
+            char_rows_to_html_two_color_string(char_rows, &colors, &backgrounds)
+        }
+        (colors, None) => char_rows_to_html_class_string(char_rows, &colors, palette),
+    }
+}
+
+// Identical consecutive frames as one, along with how many frames at the animation's rate each is
+// shown for.
+fn hold_repeats(mut frames: Vec<String>) -> Vec<(String, u32)> {
+    collapse_runs(&frames, |a, b| a == b)
+        .into_iter()
+        .map(|(i, count)| (mem::take(&mut frames[i]), count))
+        .collect()
+}
+
+// A frame of an animation's JSON, shown for `ticks` frames at its rate.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct HeldFrame {
+    frame: String,
+    ticks: u32,
+}
+
+// Cycles the frames of an animated page, each shown for `data-ticks` frames at the page's rate.
+const PLAYER_SCRIPT: &str = "<script>
+const frames = document.querySelectorAll(\"pre\");
+let frame = 0;
+function next() {
+    frames[frame].hidden = true;
+    frame = (frame + 1) % frames.length;
+    frames[frame].hidden = false;
+    setTimeout(next, frames[frame].dataset.ticks * DELAY);
+}
+setTimeout(next, frames[0].dataset.ticks * DELAY);
+</script>
+";
+
+// A complete, self-contained HTML document showing `frames`, the HTML bodies of each frame with the
+// number of frames at `fps` it's shown for, styled by the classes of `palette` if they use any.
+// More than one frame get a small player script that cycles through them.
+pub fn html_page(
+    theme: &Theme,
+    palette: &HtmlPalette,
+    frames: &[(String, u32)],
+    fps: f64,
+) -> String {
+    let mut html = [
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<style>\n",
+        &theme.stylesheet(),
+        "\n",
+        &palette.stylesheet(),
+        "\n</style>\n</head>\n<body>\n",
+    ]
+    .concat();
+    for (i, (body, ticks)) in frames.iter().enumerate() {
+        let hidden = if i > 0 { " hidden" } else { "" };
+        html += &format!(
+            "<pre data-ticks=\"{}\"{}>\n{}\n</pre>\n",
+            ticks, hidden, body
+        );
+    }
+    if frames.len() > 1 {
+        html += &PLAYER_SCRIPT.replace("DELAY", &(1000. / fps).to_string());
+    }
+    html + "</body>\n</html>\n"
+}
+
+// The rendered bitmap, in the theme's colors; uncolored glyphs are drawn in its foreground.
+fn bitmap(
+    char_rows: &[Vec<char>],
+    font: &Font,
+    image: &DynamicImage,
+    args: &Params,
+    theme: &Theme,
+) -> DynamicImage {
+    if use_color(args) {
+        match output_colors(char_rows, image, args) {
+            (colors, Some(backgrounds)) => {
+                char_rows_to_two_color_bitmap(char_rows, font, &colors, &backgrounds)
+            }
+            (colors, None) => char_rows_to_color_bitmap(char_rows, font, &colors, theme.background),
+        }
+    } else if args.themed_background || args.light_background || args.invert {
+        let ink = DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, Rgb(theme.foreground)));
+        char_rows_to_color_bitmap(char_rows, font, &ink, theme.background)
+    } else {
+        char_rows_to_bitmap(char_rows, font)
+    }
+}
+
+// Widths `format=autoterm` renders besides the requested one, those narrower than it at least.
+const AUTOTERM_WIDTHS: [usize; 6] = [40, 60, 80, 100, 120, 160];
+
+// A shell script for `curl ... | sh` that reads the terminal's width and prints the widest render
+// of `image` that fits, `char_rows` being the grid at the requested width. The narrower variants
+// keep the aspect ratio rather than any requested height; without a terminal to ask, one $COLUMNS
+// wide or else 80 is assumed.
+fn autoterm_script(
+    char_rows: &[Vec<char>],
+    font: &Font,
+    image: &DynamicImage,
+    args: &Params,
+) -> String {
+    let mut variants = vec![(args.width, char_rows_to_text(char_rows, image, args))];
+    for &width in AUTOTERM_WIDTHS.iter().rev() {
+        if width < args.width {
+            let variant_args = Params {
+                width,
+                height: None,
+                ..*args
+            };
+            let char_rows = image_to_char_rows(image, font, &variant_args);
+            variants.push((width, char_rows_to_text(&char_rows, image, &variant_args)));
+        }
+    }
+    let mut script = String::from(
+        "#!/bin/sh\n\
+         size=$(stty size 2>/dev/null </dev/tty)\n\
+         cols=${size#* }\n\
+         [ -n \"$cols\" ] || cols=${COLUMNS:-80}\n",
+    );
+    let count = variants.len();
+    for (i, (width, text)) in variants.iter().enumerate() {
+        if i + 1 < count {
+            let keyword = if i == 0 { "if" } else { "elif" };
+            script.push_str(&format!("{} [ \"$cols\" -ge {} ]; then\n", keyword, width));
+        } else if count > 1 {
+            script.push_str("else\n");
+        }
+        // a delimiter no line of the render could end the here-document early with
+        let mut delimiter = format!("ASCII_{}", width);
+        while text.lines().any(|line| line == delimiter) {
+            delimiter.push('_');
+        }
+        script.push_str(&format!("cat <<'{0}'\n{1}\n{0}\n", delimiter, text));
+    }
+    if count > 1 {
+        script.push_str("fi\n");
+    }
+    script
+}
+
+// Renders the grid converted from `image` as `representation`. JSON is the array of held frames
+// animations are rendered as, HTML color strings when colored and plain text otherwise, here always a
+// single one shown for one tick.
+pub fn render(
+    representation: Representation,
+    char_rows: &[Vec<char>],
+    font: &Font,
+    image: &DynamicImage,
+    args: &Params,
+) -> Result<Body, Error> {
+    let _span = spans::span("encode");
+    let color = use_color(args);
+    let body = match representation {
+        Representation::Terminal => (
+            ContentType::Plain,
+            char_rows_to_text(char_rows, image, args).into_bytes(),
+        ),
+        Representation::Text => (
+            ContentType::Plain,
+            char_rows_to_string(char_rows).into_bytes(),
+        ),
+        Representation::Html => {
+            let mut palette = HtmlPalette::default();
+            let frame = match args.html_classes {
+                true => classed_html_frame(char_rows, image, args, &mut palette),
+                false => html_frame(char_rows, image, args),
+            };
+            let html = html_page(
+                &output_theme(image, args),
+                &palette,
+                &[(frame, 1)],
+                args.fps,
+            );
+            (ContentType::HTML, html.into_bytes())
+        }
+        Representation::Json => {
+            let frame = if color {
+                html_color_string(char_rows, image, args)
+            } else {
+                char_rows_to_string(char_rows)
+            };
+            let held = [HeldFrame { frame, ticks: 1 }];
+            (ContentType::JSON, serde_json::to_vec(&held).unwrap())
+        }
+        Representation::Png => {
+            let mut png = Vec::new();
+            bitmap(char_rows, font, image, args, &output_theme(image, args))
+                .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+                .map_err(|_| Error::ConversionFailed)?;
+            (ContentType::PNG, png)
+        }
+        Representation::Jpeg => {
+            let mut jpeg = Vec::new();
+            let bitmap = DynamicImage::ImageRgb8(
+                bitmap(char_rows, font, image, args, &output_theme(image, args)).to_rgb8(),
+            );
+            bitmap
+                .write_to(
+                    &mut Cursor::new(&mut jpeg),
+                    ImageOutputFormat::Jpeg(JPEG_QUALITY),
+                )
+                .map_err(|_| Error::ConversionFailed)?;
+            (ContentType::JPEG, jpeg)
+        }
+        Representation::Gif => {
+            let frames = [bitmap(
+                char_rows,
+                font,
+                image,
+                args,
+                &output_theme(image, args),
+            )];
+            let gif =
+                encode_gif(&frames, args.fps * args.speed).map_err(|_| Error::ConversionFailed)?;
+            (ContentType::GIF, gif)
+        }
+        Representation::AutoTerm => (
+            ContentType::Plain,
+            autoterm_script(char_rows, font, image, args).into_bytes(),
+        ),
+        Representation::Tilemap => (
+            ContentType::JSON,
+            Tilemap::new(char_rows, font, image, args).to_json(),
+        ),
+        Representation::TilemapBinary => (
+            ContentType::Binary,
+            Tilemap::new(char_rows, font, image, args).to_binary(),
+        ),
+        Representation::Cells => (
+            ContentType(CELLS_MEDIA_TYPE),
+            compact_cells(char_rows, font, image, args),
+        ),
+    };
+    Ok(body)
+}
+
+// The frames of an animated source as they're played back, after sampling, interpolation and
+// playback order are applied as for the CLI's animated output, along with their rate. Frames are
+// moved rather than copied through each step, except the ones boomerang plays twice.
+pub fn prepare_frames(
+    frames: Vec<DynamicImage>,
+    args: &Params,
+    max_frames: usize,
+) -> Result<(Vec<DynamicImage>, f64), Error> {
+    if played_frames(frames.len(), args) > max_frames {
+        return Err(Error::TooManyFrames(max_frames));
+    }
+    let (frames, fps) = match args.sample_fps {
+        Some(sample_fps) => {
+            let order = sample_frames(frames.len(), args.fps, sample_fps);
+            (arrange_frames(frames, &order), args.fps.min(sample_fps))
+        }
+        None => (frames, args.fps),
+    };
+    let (frames, fps) = match args.interpolate_fps {
+        Some(interpolate_fps) => interpolate_frames(frames, fps, interpolate_fps),
+        None => (frames, fps),
+    };
+    let order = playback_order(frames.len(), args.playback);
+    Ok((arrange_frames(frames, &order), fps))
+}
+
+// How many frames `prepare_frames` plays `frame_count` source frames back as, worked out before
+// any are sampled or interpolated.
+fn played_frames(frame_count: usize, args: &Params) -> usize {
+    let (sampled, fps) = match args.sample_fps {
+        Some(sample_fps) => (
+            sample_frames(frame_count, args.fps, sample_fps).len(),
+            args.fps.min(sample_fps),
+        ),
+        None => (frame_count, args.fps),
+    };
+    let interpolated = match args.interpolate_fps {
+        Some(interpolate_fps) => sampled * interpolation_steps(sampled, fps, interpolate_fps),
+        None => sampled,
+    };
+    match args.playback {
+        "boomerang" => interpolated + interpolated.saturating_sub(2),
+        _ => interpolated,
+    }
+}
+
+// The most source frames an animation may have to play back as no more than `max_frames`, before
+// sampling drops any, for capping how many are decoded.
+pub fn max_source_frames(args: &Params, max_frames: usize) -> usize {
+    match args.sample_fps {
+        Some(sample_fps) if sample_fps < args.fps => {
+            (max_frames as f64 * args.fps / sample_fps).ceil() as usize
+        }
+        _ => max_frames,
+    }
+}
+
+// Runs `job` on every item, in order, over up to `threads` scoped threads that each take a run of
+// consecutive items.
+pub fn map_parallel<T, U, F>(items: &[T], threads: usize, job: F) -> Vec<U>
+where
+    T: Sync,
+    U: Send,
+    F: Fn(&T) -> U + Sync,
+{
+    let threads = threads.clamp(1, items.len().max(1));
+    if threads == 1 {
+        return items.iter().map(job).collect();
+    }
+    let job = &job;
+    thread::scope(|scope| {
+        let workers: Vec<_> = items
+            .chunks(items.len().div_ceil(threads))
+            .map(|chunk| scope.spawn(move || chunk.iter().map(job).collect::<Vec<U>>()))
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    })
+}
+
+// Converts every frame into its grid and the string of it JSON holds, spread over `args.threads`
+// threads.
+fn json_frames(
+    frames: &[DynamicImage],
+    font: &Font,
+    args: &Params,
+) -> (Vec<Vec<Vec<char>>>, Vec<String>) {
+    let frame_args = Params {
+        threads: 1,
+        ..*args
+    };
+    map_parallel(frames, args.threads, |frame| {
+        let char_rows = image_to_char_rows(frame, font, &frame_args);
+        let out_frame = if use_color(args) {
+            html_color_string(&char_rows, frame, args)
+        } else {
+            char_rows_to_string(&char_rows)
+        };
+        (char_rows, out_frame)
+    })
+    .into_iter()
+    .unzip()
+}
+
+// Renders the tiles cut from a still as JSON, each a frame of its own shown for one tick. Unlike
+// an animation's frames, identical neighbouring tiles are parts of the image, so they're kept.
+pub fn render_json_tiles(tiles: &[DynamicImage], font: &Font, args: &Params) -> Body {
+    let (_, out_frames) = json_frames(tiles, font, args);
+    let held: Vec<HeldFrame> = out_frames
+        .into_iter()
+        .map(|frame| HeldFrame { frame, ticks: 1 })
+        .collect();
+    (ContentType::JSON, serde_json::to_vec(&held).unwrap())
+}
+
+// Converts every frame and renders the grids as an HTML page playing them, a JSON array of frames
+// or an animated GIF, returning the grids along with the body. Each frame is encoded right after
+// it's converted, frames being spread over `args.threads` threads that match glyphs on one each, so
+// only putting the GIF together runs over every frame in turn. As for the CLI, one theme is used
+// for every frame so animations don't flicker between backgrounds.
+pub fn render_frames(
+    representation: Representation,
+    frames: &[DynamicImage],
+    font: &Font,
+    args: &Params,
+    fps: f64,
+) -> Result<Frames, Error> {
+    let theme = output_theme(&frames[0], args);
+    let frame_args = Params {
+        threads: 1,
+        ..*args
+    };
+    let convert = |frame: &DynamicImage| image_to_char_rows(frame, font, &frame_args);
+    let (body, frame_char_rows) = match representation {
+        Representation::Html => {
+            let mut palette = HtmlPalette::default();
+            let (frame_char_rows, bodies): (Vec<_>, Vec<_>) = if args.html_classes {
+                // the classes are numbered as frames add colors, so they're built in order
+                let frame_char_rows = map_parallel(frames, args.threads, convert);
+                let bodies = frame_char_rows
+                    .iter()
+                    .zip(frames)
+                    .map(|(char_rows, frame)| {
+                        classed_html_frame(char_rows, frame, args, &mut palette)
+                    })
+                    .collect();
+                (frame_char_rows, bodies)
+            } else {
+                map_parallel(frames, args.threads, |frame| {
+                    let char_rows = convert(frame);
+                    let body = html_frame(&char_rows, frame, args);
+                    (char_rows, body)
+                })
+                .into_iter()
+                .unzip()
+            };
+            let html = html_page(&theme, &palette, &hold_repeats(bodies), fps * args.speed);
+            ((ContentType::HTML, html.into_bytes()), frame_char_rows)
+        }
+        Representation::Json => {
+            let (frame_char_rows, out_frames) = json_frames(frames, font, args);
+            let held: Vec<HeldFrame> = hold_repeats(out_frames)
+                .into_iter()
+                .map(|(frame, ticks)| HeldFrame { frame, ticks })
+                .collect();
+            let json = serde_json::to_vec(&held).unwrap();
+            ((ContentType::JSON, json), frame_char_rows)
+        }
+        Representation::Gif => {
+            let (frame_char_rows, bitmaps): (Vec<_>, Vec<_>) =
+                map_parallel(frames, args.threads, |frame| {
+                    let char_rows = convert(frame);
+                    let bitmap = bitmap(&char_rows, font, frame, args, &theme);
+                    (char_rows, bitmap)
+                })
+                .into_iter()
+                .unzip();
+            let _span = spans::span("encode");
+            let gif =
+                encode_gif(&bitmaps, fps * args.speed).map_err(|_| Error::ConversionFailed)?;
+            ((ContentType::GIF, gif), frame_char_rows)
+        }
+        _ => return Err(Error::InvalidParameter("format")),
+    };
+    Ok((body, frame_char_rows))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::{load_alphabet, load_font};
+    use crate::gif::decode_gif;
+
+    fn frames(count: usize) -> Vec<DynamicImage> {
+        (0..count)
+            .map(|i| DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([i as u8 * 10; 3]))))
+            .collect()
+    }
+
+    #[test]
+    fn counts_played_frames_before_preparing_them() {
+        let args = Params::defaults_for("sample.gif", 16);
+        let variants = [
+            args,
+            Params {
+                sample_fps: Some(12.),
+                ..args
+            },
+            Params {
+                interpolate_fps: Some(120.),
+                ..args
+            },
+            Params {
+                sample_fps: Some(10.),
+                interpolate_fps: Some(60.),
+                playback: "boomerang",
+                ..args
+            },
+        ];
+        for args in variants {
+            let played = played_frames(7, &args);
+            let (prepared, _) = prepare_frames(frames(7), &args, usize::MAX).unwrap();
+            assert_eq!(played, prepared.len(), "{:?}", args);
+            let refused = prepare_frames(frames(7), &args, played - 1);
+            assert!(matches!(refused, Err(Error::TooManyFrames(_))));
+        }
+    }
+
+    #[test]
+    fn decodes_no_more_frames_than_asked_for() {
+        let gif = encode_gif(&frames(6), 10.).unwrap();
+        assert_eq!(decode_gif(&gif, 10).unwrap().len(), 6);
+        assert_eq!(decode_gif(&gif, 3).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn stills_and_animations_share_a_json_schema() {
+        let args = Params {
+            no_color: true,
+            ..Params::defaults_for("sample.gif", 4)
+        };
+        let font = load_font(args.font, &load_alphabet(args.alphabet)).unwrap();
+        let frames = frames(1);
+        let char_rows = image_to_char_rows(&frames[0], &font, &args);
+        let (_, still) =
+            render(Representation::Json, &char_rows, &font, &frames[0], &args).unwrap();
+        let ((_, animation), _) =
+            render_frames(Representation::Json, &frames, &font, &args, args.fps).unwrap();
+        assert_eq!(still, animation);
+        let json: serde_json::Value = serde_json::from_slice(&still).unwrap();
+        assert_eq!(json[0]["ticks"], 1);
+        assert!(json[0]["frame"].is_string());
+    }
+
+    #[test]
+    fn identical_tiles_are_kept_apart() {
+        let args = Params::defaults_for("sample.png", 4);
+        let font = load_font(args.font, &load_alphabet(args.alphabet)).unwrap();
+        let tiles = [frames(1), frames(1)].concat();
+        let (_, json) = render_json_tiles(&tiles, &font, &args);
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), 2);
+        assert_eq!(json[0], json[1]);
+    }
+}
@@ -0,0 +1,48 @@
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{get, State};
+
+use crate::cache::Cached;
+use crate::config::Config;
+use crate::convert::dominant_colors;
+use crate::error::Error;
+use crate::fetch::Fetchers;
+use crate::metadata::hex_color;
+use crate::pools::Pools;
+use crate::ratelimit::RateLimit;
+
+const MAX_COLORS: usize = 32;
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Palette {
+    colors: Vec<String>,
+}
+
+// The dominant colors of a source image, most common first, for theming whatever displays its
+// conversion.
+#[get("/palette?<url>&<n>")]
+pub async fn palette(
+    _limit: RateLimit,
+    url: String,
+    n: Option<usize>,
+    config: &State<Config>,
+    pools: &State<Pools>,
+    fetchers: &State<Fetchers>,
+) -> Result<Cached<Json<Palette>>, Error> {
+    if !fetchers.supports(&url) {
+        return Err(Error::InvalidParameter("url"));
+    }
+    let n = n.unwrap_or(8);
+    if n == 0 || n > MAX_COLORS {
+        return Err(Error::InvalidParameter("n"));
+    }
+
+    let bytes = pools.download(fetchers.fetch(&url)).await?;
+    let (_, image) = pools.decode(bytes).await?;
+    let colors = pools.convert(move || dominant_colors(&image, n)).await?;
+    let palette = Palette {
+        colors: colors.iter().map(hex_color).collect(),
+    };
+    Ok(Cached::new(Json(palette), &config.cache))
+}
@@ -0,0 +1,498 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+use log::warn;
+use rocket::fairing::AdHoc;
+use rocket::http::{ContentType, Status};
+use rocket::serde::json::Value;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{get, State};
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::cache::Cached;
+use crate::config::Config;
+use crate::convert::{
+    char_rows_to_bitmap, char_rows_to_color_bitmap, char_rows_to_html_color_string,
+    char_rows_to_html_two_color_string, char_rows_to_string, char_rows_to_svg,
+    char_rows_to_terminal_color_string, char_rows_to_two_color_bitmap, escape_xml, HtmlPalette,
+    Theme,
+};
+use crate::error::Error;
+use crate::font::Font;
+use crate::gallery::Gallery;
+use crate::generate::{alphabet_chars, load_sized_font, output_colors, use_color, Params};
+use crate::gif::{collapse_runs, encode_held_gif};
+use crate::negotiate::{html_page, map_parallel, JPEG_QUALITY};
+use crate::pools::Pools;
+use crate::ratelimit::RateLimit;
+use crate::storage::Storage;
+
+// Hex digits of the content hash kept in a permalink.
+const ID_LENGTH: usize = 12;
+const SVG_CELL_WIDTH: f32 = 6.;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct PermalinkConfig {
+    // results are stored under this directory of the storage, and conversions answered with a
+    // permalink, when set
+    pub dir: Option<PathBuf>,
+}
+
+// One frame of a stored conversion, with the color of every cell as hex RGB, row by row, left
+// empty when uncolored, and likewise the background of every cell for the block modes.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct StoredFrame {
+    rows: Vec<String>,
+    colors: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    backgrounds: String,
+    // how many frames at `fps` this one stands for, so a held frame is only stored once
+    ticks: u32,
+}
+
+impl StoredFrame {
+    fn char_rows(&self) -> Vec<Vec<char>> {
+        self.rows.iter().map(|row| row.chars().collect()).collect()
+    }
+
+    fn hex_image(&self, hex: &str) -> DynamicImage {
+        let n_cols = self.rows.first().map_or(0, |row| row.chars().count());
+        let colors = hex::decode(hex).unwrap_or_default();
+        let mut image = RgbImage::new(n_cols as u32, self.rows.len() as u32);
+        for (pixel, color) in image.pixels_mut().zip(colors.chunks_exact(3)) {
+            *pixel = Rgb([color[0], color[1], color[2]]);
+        }
+        DynamicImage::ImageRgb8(image)
+    }
+
+    fn color_image(&self) -> DynamicImage {
+        self.hex_image(&self.colors)
+    }
+
+    fn background_image(&self) -> Option<DynamicImage> {
+        (!self.backgrounds.is_empty()).then(|| self.hex_image(&self.backgrounds))
+    }
+}
+
+// A conversion as a permalink keeps it: the character grid of every frame with the color of every
+// cell and how long it's shown for, which every output format can be rendered from without
+// converting again. Served as is as the `json` format, for diffing and client-side rendering.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct StoredResult {
+    font: String,
+    alphabet: String,
+    color: bool,
+    fps: f64,
+    frames: Vec<StoredFrame>,
+    params: Value,
+}
+
+impl StoredResult {
+    // `frames` are the source frames the grids were converted from, and `fps` the rate they're
+    // played back at.
+    pub fn new(
+        frame_char_rows: &[Vec<Vec<char>>],
+        frames: &[DynamicImage],
+        fps: f64,
+        args: &Params,
+    ) -> StoredResult {
+        let color = use_color(args);
+        let frames: Vec<StoredFrame> = frame_char_rows
+            .iter()
+            .zip(frames)
+            .map(|(char_rows, frame)| {
+                let (n_cols, n_rows) = (char_rows[0].len(), char_rows.len());
+                let hex = |image: &DynamicImage| {
+                    let image =
+                        image.resize_exact(n_cols as u32, n_rows as u32, FilterType::Nearest);
+                    hex::encode(image.to_rgb8().into_raw())
+                };
+                let (colors, backgrounds) = if color {
+                    let (colors, backgrounds) = output_colors(char_rows, frame, args);
+                    (
+                        hex(&colors),
+                        backgrounds.as_ref().map(hex).unwrap_or_default(),
+                    )
+                } else {
+                    (String::new(), String::new())
+                };
+                StoredFrame {
+                    rows: char_rows.iter().map(|row| row.iter().collect()).collect(),
+                    colors,
+                    backgrounds,
+                    ticks: 1,
+                }
+            })
+            .collect();
+        let runs = collapse_runs(&frames, |a, b| {
+            a.rows == b.rows && a.colors == b.colors && a.backgrounds == b.backgrounds
+        });
+        StoredResult {
+            font: String::from(args.font),
+            alphabet: String::from(args.alphabet),
+            color,
+            fps,
+            frames: runs
+                .iter()
+                .map(|&(i, ticks)| StoredFrame {
+                    ticks,
+                    ..frames[i].clone()
+                })
+                .collect(),
+            params: serde_json::to_value(args).unwrap(),
+        }
+    }
+
+    fn colors(&self, frame: &StoredFrame) -> DynamicImage {
+        if self.color {
+            frame.color_image()
+        } else {
+            let foreground = Rgb(Theme::default().foreground);
+            DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, foreground))
+        }
+    }
+
+    fn load_font(&self) -> Result<Font, Error> {
+        let charset = self.params["charset"].as_str();
+        let font_size = self.params["font_size"].as_f64().map(|size| size as f32);
+        load_sized_font(
+            &self.font,
+            font_size,
+            &alphabet_chars(&self.alphabet, charset),
+        )
+    }
+
+    // The page theme the result was converted with; a themed background is drawn from the cell
+    // colors, which is all that's left of the source.
+    fn theme(&self) -> Theme {
+        let theme = if self.params["themed_background"].as_bool() == Some(true) {
+            Theme::from_image(&self.frames[0].color_image())
+        } else if self.params["light_background"].as_bool() == Some(true) {
+            Theme::light()
+        } else {
+            Theme::default()
+        };
+        if self.params["invert"].as_bool() == Some(true) {
+            theme.inverted()
+        } else {
+            theme
+        }
+    }
+
+    // Drawn as the live render is, uncolored glyphs and the background in the result's `theme`.
+    fn bitmap(&self, font: &Font, frame: &StoredFrame, theme: &Theme) -> DynamicImage {
+        let char_rows = frame.char_rows();
+        let themed = ["themed_background", "light_background", "invert"]
+            .iter()
+            .any(|&flag| self.params[flag].as_bool() == Some(true));
+        if let Some(backgrounds) = frame.background_image() {
+            char_rows_to_two_color_bitmap(&char_rows, font, &self.colors(frame), &backgrounds)
+        } else if self.color {
+            char_rows_to_color_bitmap(&char_rows, font, &self.colors(frame), theme.background)
+        } else if themed {
+            let ink = DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, Rgb(theme.foreground)));
+            char_rows_to_color_bitmap(&char_rows, font, &ink, theme.background)
+        } else {
+            char_rows_to_bitmap(&char_rows, font)
+        }
+    }
+
+    // Still formats show the first frame, `html` and `gif` the whole animation.
+    fn render(&self, format: &str) -> Result<(ContentType, Vec<u8>), Error> {
+        let first = &self.frames[0];
+        let char_rows = first.char_rows();
+        let colors = self.colors(first);
+        let body = match format {
+            "ansi" => {
+                let tolerance = self.params["color_tolerance"].as_u64().unwrap_or(0) as u8;
+                let color_mode = self.params["color_mode"].as_str().unwrap_or("truecolor");
+                let text = char_rows_to_terminal_color_string(
+                    &char_rows,
+                    &colors,
+                    first.background_image().as_ref(),
+                    tolerance,
+                    color_mode,
+                );
+                (ContentType::Plain, text.into_bytes())
+            }
+            "text" => (
+                ContentType::Plain,
+                char_rows_to_string(&char_rows).into_bytes(),
+            ),
+            "html" => {
+                let frames: Vec<(String, u32)> = self
+                    .frames
+                    .iter()
+                    .map(|frame| {
+                        let char_rows = frame.char_rows();
+                        let body = match frame.background_image() {
+                            Some(backgrounds) => char_rows_to_html_two_color_string(
+                                &char_rows,
+                                &self.colors(frame),
+                                &backgrounds,
+                            ),
+                            None if self.color => {
+                                char_rows_to_html_color_string(&char_rows, &self.colors(frame))
+                            }
+                            None => char_rows_to_string(&char_rows)
+                                .chars()
+                                .map(escape_xml)
+                                .collect(),
+                        };
+                        (body, frame.ticks)
+                    })
+                    .collect();
+                let html = html_page(&self.theme(), &HtmlPalette::default(), &frames, self.fps);
+                (ContentType::HTML, html.into_bytes())
+            }
+            "svg" => {
+                let font = self.load_font()?;
+                let svg = char_rows_to_svg(&char_rows, &font, &colors, SVG_CELL_WIDTH);
+                (ContentType::SVG, svg.into_bytes())
+            }
+            "png" | "jpeg" => {
+                let font = self.load_font()?;
+                let bitmap = self.bitmap(&font, first, &self.theme());
+                let mut body = Vec::new();
+                let content_type = if format == "png" {
+                    bitmap
+                        .write_to(&mut Cursor::new(&mut body), ImageOutputFormat::Png)
+                        .map(|_| ContentType::PNG)
+                } else {
+                    DynamicImage::ImageRgb8(bitmap.to_rgb8())
+                        .write_to(
+                            &mut Cursor::new(&mut body),
+                            ImageOutputFormat::Jpeg(JPEG_QUALITY),
+                        )
+                        .map(|_| ContentType::JPEG)
+                };
+                (content_type.map_err(|_| Error::ConversionFailed)?, body)
+            }
+            "gif" => {
+                let font = self.load_font()?;
+                // drawn over as many threads as the conversion was allowed
+                let threads = self.params["threads"].as_u64().unwrap_or(1) as usize;
+                let theme = self.theme();
+                let drawn = map_parallel(&self.frames, threads, |frame| {
+                    self.bitmap(&font, frame, &theme)
+                });
+                // a frame held for several ticks is encoded once, shown for all of them
+                let held: Vec<(&DynamicImage, u32)> = drawn
+                    .iter()
+                    .zip(&self.frames)
+                    .map(|(bitmap, frame)| (bitmap, frame.ticks))
+                    .collect();
+                let gif = encode_held_gif(&held, self.fps).map_err(|_| Error::ConversionFailed)?;
+                (ContentType::GIF, gif)
+            }
+            "json" => (ContentType::JSON, serde_json::to_vec(self).unwrap()),
+            _ => return Err(Error::InvalidParameter("format")),
+        };
+        Ok(body)
+    }
+}
+
+fn is_id(id: &str) -> bool {
+    id.len() == ID_LENGTH && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// Stores results behind permalinks, under `permalinks.dir` in the configured storage.
+#[derive(Clone)]
+pub struct Permalinks {
+    dir: Option<PathBuf>,
+    storage: Arc<dyn Storage>,
+    gallery: Option<Arc<Gallery>>,
+}
+
+impl Permalinks {
+    pub fn new(config: &PermalinkConfig, storage: Arc<dyn Storage>) -> Permalinks {
+        Permalinks {
+            dir: config.dir.clone(),
+            storage,
+            gallery: None,
+        }
+    }
+
+    // Also lists every stored result in `gallery`.
+    pub fn with_gallery(mut self, gallery: Arc<Gallery>) -> Permalinks {
+        self.gallery = Some(gallery);
+        self
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    fn key(dir: &Path, id: &str) -> String {
+        dir.join(format!("{}.json", id))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // Stores `result` under its content hash, so identical conversions share a permalink, and
+    // returns the permalink. Failing to store only loses the permalink, never the conversion.
+    pub fn save(&self, result: &StoredResult) -> Option<String> {
+        let dir = self.dir.as_ref()?;
+        let json = serde_json::to_vec(result).unwrap();
+        let id = hex::encode(Sha256::digest(&json))[..ID_LENGTH].to_owned();
+        let key = Permalinks::key(dir, &id);
+        if let Err(err) = self.storage.put(&key, &json) {
+            warn!("could not store permalink {:?}: {}", key, err);
+            return None;
+        }
+        if let Some(gallery) = &self.gallery {
+            gallery.add(&id, &result.params);
+        }
+        Some(format!("/r/{}", id))
+    }
+
+    // Deletes the result stored as `id`, and whether there was one; failing to delete it leaves it
+    // served.
+    pub fn delete(&self, id: &str) -> bool {
+        let dir = match &self.dir {
+            Some(dir) if is_id(id) => dir,
+            _ => return false,
+        };
+        let key = Permalinks::key(dir, id);
+        if !matches!(self.storage.get(&key), Ok(Some(_))) {
+            return false;
+        }
+        match self.storage.delete(&key) {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("could not delete permalink {:?}: {}", key, err);
+                false
+            }
+        }
+    }
+
+    fn load(&self, id: &str) -> Option<StoredResult> {
+        let json = self
+            .storage
+            .get(&Permalinks::key(self.dir.as_ref()?, id))
+            .ok()??;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+// Manages `Permalinks`; must be attached after the `Storage` and `Gallery` fairings.
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Permalinks", |rocket| async {
+        let config = rocket
+            .state::<Config>()
+            .map(|config| config.permalinks.clone())
+            .unwrap_or_default();
+        let permalinks = match rocket.state::<Arc<dyn Storage>>().cloned() {
+            Some(storage) => Permalinks::new(&config, storage),
+            None => return rocket,
+        };
+        let permalinks = match rocket.state::<Arc<Gallery>>() {
+            Some(gallery) => permalinks.with_gallery(gallery.clone()),
+            None => permalinks,
+        };
+        rocket.manage(permalinks)
+    })
+}
+
+// Serves a stored conversion as `ansi` or `text` (defaulting to whichever it was produced as),
+// `html`, `svg`, `png`, `jpeg`, `gif` or `json`.
+#[get("/r/<id>?<format>")]
+pub async fn permalink(
+    _limit: RateLimit,
+    id: &str,
+    format: Option<&str>,
+    config: &State<Config>,
+    permalinks: &State<Permalinks>,
+    pools: &State<Pools>,
+) -> Result<Cached<(ContentType, Vec<u8>)>, Error> {
+    serve(id, format, config, permalinks, pools).await
+}
+
+// The same as `permalink`, under the name clients that treat a conversion as a job look for: the
+// permalink's id is the job's, and its result re-encoded in any format without converting again.
+#[get("/jobs/<id>/result?<format>")]
+pub async fn job_result(
+    _limit: RateLimit,
+    id: &str,
+    format: Option<&str>,
+    config: &State<Config>,
+    permalinks: &State<Permalinks>,
+    pools: &State<Pools>,
+) -> Result<Cached<(ContentType, Vec<u8>)>, Error> {
+    serve(id, format, config, permalinks, pools).await
+}
+
+async fn serve(
+    id: &str,
+    format: Option<&str>,
+    config: &State<Config>,
+    permalinks: &State<Permalinks>,
+    pools: &State<Pools>,
+) -> Result<Cached<(ContentType, Vec<u8>)>, Error> {
+    if !permalinks.enabled() {
+        return Err(Status::NotFound.into());
+    }
+    if !is_id(id) {
+        return Err(Status::NotFound.into());
+    }
+    let (id, format) = (id.to_owned(), format.map(String::from));
+    let permalinks = permalinks.inner().clone();
+    let body = pools
+        .convert(move || {
+            let result = permalinks
+                .load(&id)
+                .ok_or(Error::Status(Status::NotFound))?;
+            let default = if result.color { "ansi" } else { "text" };
+            result.render(format.as_deref().unwrap_or(default))
+        })
+        .await??;
+    Ok(Cached::new(body, &config.cache))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::FONTS;
+    use rocket::serde::json::json;
+
+    fn stored(color: bool, params: Value) -> StoredResult {
+        StoredResult {
+            font: String::from(FONTS[0].0),
+            alphabet: String::from("alphabet"),
+            color,
+            fps: 10.,
+            frames: vec![StoredFrame {
+                rows: vec![String::from("  ")],
+                colors: if color {
+                    String::from("ff0000ff0000")
+                } else {
+                    String::new()
+                },
+                backgrounds: String::new(),
+                ticks: 1,
+            }],
+            params,
+        }
+    }
+
+    fn corner(result: &StoredResult) -> [u8; 3] {
+        let font = result.load_font().unwrap();
+        let bitmap = result.bitmap(&font, &result.frames[0], &result.theme());
+        bitmap.to_rgb8().get_pixel(0, 0).0
+    }
+
+    #[test]
+    fn draws_bitmaps_on_the_stored_theme() {
+        assert_eq!(corner(&stored(false, json!({}))), [0, 0, 0]);
+        let light = json!({ "light_background": true });
+        assert_eq!(corner(&stored(false, light.clone())), [255, 255, 255]);
+        assert_eq!(corner(&stored(true, light)), [255, 255, 255]);
+        let inverted = json!({ "invert": true });
+        assert_eq!(corner(&stored(true, inverted)), Theme::default().foreground);
+    }
+}
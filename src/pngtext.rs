@@ -0,0 +1,61 @@
+// Text chunks inserted into an encoded PNG, which the image crate's encoder doesn't write.
+
+const SIGNATURE_LEN: usize = 8;
+
+// The CRC-32 each PNG chunk ends with, over its type and data.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffffffff_u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    let crc = crc32(&chunk[4..]);
+    chunk.extend_from_slice(&crc.to_be_bytes());
+    chunk
+}
+
+// A `tEXt` chunk when the text is ASCII, and otherwise an uncompressed `iTXt` one, as `tEXt` is
+// Latin-1 and art drawn with Unicode characters isn't.
+fn text_chunk(keyword: &str, text: &str) -> Vec<u8> {
+    let mut data = keyword.as_bytes().to_vec();
+    data.push(0);
+    if text.is_ascii() {
+        data.extend_from_slice(text.as_bytes());
+        chunk(b"tEXt", &data)
+    } else {
+        // no compression, and empty language tag and translated keyword
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(text.as_bytes());
+        chunk(b"iTXt", &data)
+    }
+}
+
+// `png` with a text chunk for each keyword and text right after its header, where readers look
+// first.
+pub fn with_text(png: Vec<u8>, texts: &[(&str, &str)]) -> Vec<u8> {
+    let header_len = match png.get(SIGNATURE_LEN..SIGNATURE_LEN + 4) {
+        Some(len) => u32::from_be_bytes(len.try_into().unwrap()) as usize,
+        None => return png,
+    };
+    // length, type, data and CRC
+    let end = (SIGNATURE_LEN + 12 + header_len).min(png.len());
+    let mut bytes = png[..end].to_vec();
+    for (keyword, text) in texts {
+        bytes.extend(text_chunk(keyword, text));
+    }
+    bytes.extend_from_slice(&png[end..]);
+    bytes
+}
@@ -0,0 +1,159 @@
+use image::DynamicImage;
+use rocket::fairing::AdHoc;
+use rocket::serde::Deserialize;
+use rocket::tokio::sync::Semaphore;
+use rocket::tokio::task::{spawn_blocking, JoinError};
+use rocket::tokio::time::timeout;
+use std::future::Future;
+use std::sync::Arc;
+use std::thread::available_parallelism;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::telemetry;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct PoolsConfig {
+    pub fetch: usize,
+    pub convert: usize,
+    // seconds each stage of a conversion may take, 0 for no limit
+    pub fetch_timeout: u64,
+    pub decode_timeout: u64,
+    pub convert_timeout: u64,
+}
+
+impl Default for PoolsConfig {
+    fn default() -> PoolsConfig {
+        PoolsConfig {
+            fetch: 64,
+            convert: available_parallelism().map_or(1, |n| n.get()),
+            fetch_timeout: 60,
+            decode_timeout: 30,
+            convert_timeout: 120,
+        }
+    }
+}
+
+// Blocking work never runs on Rocket's async workers. Blocking I/O shares a generous pool with
+// async downloads, which count against the same limit, while CPU-bound decoding and conversion is
+// capped at roughly one job per core, so that a burst of requests queues up instead of thrashing.
+// Clones share the same pools, for work that outlives the request it was started by.
+#[derive(Clone)]
+pub struct Pools {
+    fetch: Arc<Semaphore>,
+    convert: Arc<Semaphore>,
+    fetch_timeout: Option<Duration>,
+    decode_timeout: Option<Duration>,
+    convert_timeout: Option<Duration>,
+}
+
+fn budget(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+impl Pools {
+    pub fn new(config: &PoolsConfig) -> Pools {
+        Pools {
+            fetch: Arc::new(Semaphore::new(config.fetch.max(1))),
+            convert: Arc::new(Semaphore::new(config.convert.max(1))),
+            fetch_timeout: budget(config.fetch_timeout),
+            decode_timeout: budget(config.decode_timeout),
+            convert_timeout: budget(config.convert_timeout),
+        }
+    }
+
+    pub async fn fetch<F, T>(&self, job: F) -> Result<T, JoinError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let _permit = self.fetch.acquire().await.unwrap();
+        spawn_blocking(job).await
+    }
+
+    // Fails with a `fetch` timeout once the download takes longer than `fetch_timeout`.
+    pub async fn download<F, T>(&self, download: F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        let _permit = self.fetch.acquire().await.unwrap();
+        let t0 = Instant::now();
+        let downloaded = match self.fetch_timeout {
+            Some(budget) => timeout(budget, download)
+                .await
+                .unwrap_or_else(|_| Err(Error::Timeout("fetch", budget))),
+            None => download.await,
+        };
+        // downloads that failed or ran out of time are timed too
+        telemetry::observe_download(t0.elapsed());
+        downloaded
+    }
+
+    // A blocking job can't be interrupted, so one over its budget is answered with a timeout right
+    // away but keeps its permit until it's done, leaving the pool's cap on running jobs intact.
+    async fn run<F, T>(
+        &self,
+        stage: &'static str,
+        budget: Option<Duration>,
+        job: F,
+    ) -> Result<T, Error>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = self.convert.clone().acquire_owned().await.unwrap();
+        let job = spawn_blocking(move || {
+            let _permit = permit;
+            job()
+        });
+        let result = match budget {
+            Some(budget) => timeout(budget, job)
+                .await
+                .map_err(|_| Error::Timeout(stage, budget))?,
+            None => job.await,
+        };
+        result.map_err(|_| Error::ConversionFailed)
+    }
+
+    // Decodes a downloaded or uploaded source within `decode_timeout`, handing its bytes back along
+    // with the image.
+    pub async fn decode(&self, bytes: Vec<u8>) -> Result<(Vec<u8>, DynamicImage), Error> {
+        let job = move || image::load_from_memory(&bytes).map(|image| (bytes, image));
+        Ok(self.run("decode", self.decode_timeout, job).await??)
+    }
+
+    // Starts `job` on a blocking thread once there's room in the pool, without waiting for it, for
+    // conversions streamed to the client as they go. It keeps its permit until it's done; as its
+    // response has already begun, it isn't timed out.
+    pub async fn spawn_convert<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let permit = self.convert.clone().acquire_owned().await.unwrap();
+        spawn_blocking(move || {
+            let _permit = permit;
+            job()
+        });
+    }
+
+    pub async fn convert<F, T>(&self, job: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run("convert", self.convert_timeout, job).await
+    }
+}
+
+// Manages `Pools` sized from the config; must be attached after the `Config` fairing.
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Pools", |rocket| async {
+        let config = rocket
+            .state::<Config>()
+            .map(|config| config.pools.clone())
+            .unwrap_or_default();
+        rocket.manage(Pools::new(&config))
+    })
+}
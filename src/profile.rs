@@ -0,0 +1,89 @@
+use rocket::serde::Deserialize;
+
+// A named set of conversion defaults maintained server-side, selected with `profile=<name>`.
+// Anything left unset falls back to the usual defaults, and explicit query parameters still win.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct Profile {
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub max_width: Option<usize>,
+    pub max_height: Option<usize>,
+    pub font: Option<String>,
+    pub font_size: Option<f32>,
+    pub alphabet: Option<String>,
+    pub charset: Option<String>,
+    pub metric: Option<String>,
+    pub no_color: Option<bool>,
+    pub brightness_offset: Option<f32>,
+    pub contrast: Option<f32>,
+    pub gamma: Option<f32>,
+    pub noise_scale: Option<f32>,
+    pub fps: Option<f64>,
+    pub no_edge_detection: Option<bool>,
+    pub cvd: Option<String>,
+    pub playback: Option<String>,
+    pub speed: Option<f64>,
+    pub sample_fps: Option<f64>,
+    pub interpolate_fps: Option<f64>,
+    pub blank_char: Option<char>,
+    pub mode: Option<String>,
+    pub color_tolerance: Option<u8>,
+    pub color_mode: Option<String>,
+    pub themed_background: Option<bool>,
+    pub light_background: Option<bool>,
+    pub invert: Option<bool>,
+    pub dither: Option<String>,
+    pub threads: Option<usize>,
+    pub aspect: Option<f64>,
+    pub tile: Option<bool>,
+    pub embed_text: Option<bool>,
+    pub html_classes: Option<bool>,
+}
+
+impl Profile {
+    // The values set here, and the ones that aren't from `fallback`. An alphabet and a charset
+    // replace each other, so they're taken together from whichever sets either.
+    pub fn or(self, fallback: &Profile) -> Profile {
+        let (alphabet, charset) = match self.alphabet.is_some() || self.charset.is_some() {
+            true => (self.alphabet, self.charset),
+            false => (fallback.alphabet.clone(), fallback.charset.clone()),
+        };
+        Profile {
+            alphabet,
+            charset,
+            width: self.width.or(fallback.width),
+            height: self.height.or(fallback.height),
+            max_width: self.max_width.or(fallback.max_width),
+            max_height: self.max_height.or(fallback.max_height),
+            font: self.font.or_else(|| fallback.font.clone()),
+            font_size: self.font_size.or(fallback.font_size),
+            metric: self.metric.or_else(|| fallback.metric.clone()),
+            no_color: self.no_color.or(fallback.no_color),
+            brightness_offset: self.brightness_offset.or(fallback.brightness_offset),
+            contrast: self.contrast.or(fallback.contrast),
+            gamma: self.gamma.or(fallback.gamma),
+            noise_scale: self.noise_scale.or(fallback.noise_scale),
+            fps: self.fps.or(fallback.fps),
+            no_edge_detection: self.no_edge_detection.or(fallback.no_edge_detection),
+            cvd: self.cvd.or_else(|| fallback.cvd.clone()),
+            playback: self.playback.or_else(|| fallback.playback.clone()),
+            speed: self.speed.or(fallback.speed),
+            sample_fps: self.sample_fps.or(fallback.sample_fps),
+            interpolate_fps: self.interpolate_fps.or(fallback.interpolate_fps),
+            blank_char: self.blank_char.or(fallback.blank_char),
+            mode: self.mode.or_else(|| fallback.mode.clone()),
+            color_mode: self.color_mode.or_else(|| fallback.color_mode.clone()),
+            color_tolerance: self.color_tolerance.or(fallback.color_tolerance),
+            themed_background: self.themed_background.or(fallback.themed_background),
+            light_background: self.light_background.or(fallback.light_background),
+            invert: self.invert.or(fallback.invert),
+            dither: self.dither.or_else(|| fallback.dither.clone()),
+            threads: self.threads.or(fallback.threads),
+            aspect: self.aspect.or(fallback.aspect),
+            tile: self.tile.or(fallback.tile),
+            embed_text: self.embed_text.or(fallback.embed_text),
+            html_classes: self.html_classes.or(fallback.html_classes),
+        }
+    }
+}
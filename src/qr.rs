@@ -0,0 +1,83 @@
+use image::{DynamicImage, GrayImage, Luma};
+use qrcode::{Color, QrCode};
+use rocket::http::ContentType;
+use rocket::{get, State};
+
+use crate::cache::Cached;
+use crate::config::Config;
+use crate::convert::{char_rows_to_string, fast_convert, img_to_char_rows, MatchOptions};
+use crate::error::Error;
+use crate::generate::{is_available_font, is_bundled_alphabet, load_alphabet, load_font};
+use crate::pools::Pools;
+use crate::ratelimit::RateLimit;
+
+const MAX_DATA_LEN: usize = 1024;
+// Scanners need a light margin around the code; the spec asks for four modules, two is plenty on
+// a terminal background.
+const QUIET_ZONE: usize = 2;
+
+// Rasterizes each module as a `module_width` x `module_height` block of pixels.
+fn qr_to_image(code: &QrCode, module_width: usize, module_height: usize) -> DynamicImage {
+    let width = code.width();
+    let colors = code.to_colors();
+    let size = width + 2 * QUIET_ZONE;
+    let (image_width, image_height) = (size * module_width, size * module_height);
+    let image = GrayImage::from_fn(image_width as u32, image_height as u32, |x, y| {
+        let (x, y) = (x as usize / module_width, y as usize / module_height);
+        let in_code = (QUIET_ZONE..QUIET_ZONE + width).contains(&x)
+            && (QUIET_ZONE..QUIET_ZONE + width).contains(&y);
+        if in_code && colors[(y - QUIET_ZONE) * width + x - QUIET_ZONE] == Color::Dark {
+            Luma([0])
+        } else {
+            Luma([255])
+        }
+    });
+    DynamicImage::ImageLuma8(image)
+}
+
+// Each module becomes exactly one row by two characters, which with the usual ~1:2 cell aspect ratio
+// keeps the code square enough to scan. Brightness-only matching without edge detection maps light
+// modules to the densest glyph and dark ones to blanks.
+#[get("/qr?<data>&<font>&<alphabet>")]
+pub async fn qr(
+    _limit: RateLimit,
+    data: String,
+    font: Option<String>,
+    alphabet: Option<String>,
+    config: &State<Config>,
+    pools: &State<Pools>,
+) -> Result<Cached<(ContentType, String)>, Error> {
+    if data.is_empty() || data.len() > MAX_DATA_LEN {
+        return Err(Error::InvalidParameter("data"));
+    }
+    let font = font.unwrap_or_else(|| String::from("bitocra-13"));
+    let alphabet = alphabet.unwrap_or_else(|| String::from("alphabet"));
+    if !is_available_font(&font) {
+        return Err(Error::UnknownFont(font));
+    }
+    if !is_bundled_alphabet(&alphabet) {
+        return Err(Error::UnknownAlphabet(alphabet));
+    }
+
+    let ascii = pools
+        .convert(move || {
+            let code = QrCode::new(data.as_bytes()).map_err(|_| Error::InvalidParameter("data"))?;
+            let alphabet = load_alphabet(&alphabet);
+            let font = load_font(&font, &alphabet)?;
+            let missing = font.missing_glyphs(&alphabet);
+            if !missing.is_empty() {
+                return Err(Error::MissingGlyphs(missing));
+            }
+            let image = qr_to_image(&code, 2 * font.width, font.height);
+            let width = 2 * (code.width() + 2 * QUIET_ZONE);
+            let options = MatchOptions {
+                edge_detection: false,
+                ..MatchOptions::default()
+            };
+            let char_rows = img_to_char_rows(&font, &image, fast_convert, width, options);
+            Ok(char_rows_to_string(&char_rows))
+        })
+        .await??;
+
+    Ok(Cached::new((ContentType::Plain, ascii), &config.cache))
+}
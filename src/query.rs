@@ -0,0 +1,894 @@
+use image::{DynamicImage, ImageFormat};
+use log::{debug, warn};
+use rocket::form::Form;
+use rocket::http::{ContentType, Status};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::FromForm;
+use sha2::{Digest, Sha256};
+use std::slice;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::alphabets::Alphabets;
+use crate::config::Config;
+use crate::convert::{char_rows_to_string, Theme, COLOR_MODES, DITHERS, METRICS};
+use crate::cvd::CVD_KINDS;
+use crate::echo::resolved_params;
+use crate::error::Error;
+use crate::font::Font;
+use crate::generate::{
+    alphabet_chars, generate, image_to_char_rows, image_to_text_bands, is_available_font,
+    is_banded, is_bundled_alphabet, load_sized_font, match_quality, output_theme,
+    registered_font_digest, ConversionOutput, Params, BAND_ROWS, MODES,
+};
+use crate::gif::{decode_gif, PLAYBACK_MODES};
+use crate::logging::{field, RequestId};
+use crate::metadata::Quality;
+use crate::negotiate::{self, Body, Representation};
+use crate::permalink::{Permalinks, StoredResult};
+use crate::pngtext;
+use crate::profile::Profile;
+use crate::spans;
+use crate::telemetry;
+
+// The most distinct characters a client's charset may have, each a glyph matched against every cell.
+const MAX_CHARSET_LEN: usize = 256;
+
+// Conversion parameters as they arrive in the query string. Values are kept as strings so a
+// malformed one is reported instead of being silently ignored.
+#[derive(Debug, Default, FromForm)]
+pub struct ConversionQuery<'r> {
+    pub profile: Option<&'r str>,
+    pub width: Option<&'r str>,
+    pub height: Option<&'r str>,
+    pub max_width: Option<&'r str>,
+    pub max_height: Option<&'r str>,
+    pub font: Option<&'r str>,
+    pub font_size: Option<&'r str>,
+    pub alphabet: Option<&'r str>,
+    pub charset: Option<&'r str>,
+    pub metric: Option<&'r str>,
+    pub no_color: Option<&'r str>,
+    pub brightness_offset: Option<&'r str>,
+    pub contrast: Option<&'r str>,
+    pub gamma: Option<&'r str>,
+    pub noise_scale: Option<&'r str>,
+    pub fps: Option<&'r str>,
+    pub no_edge_detection: Option<&'r str>,
+    pub cvd: Option<&'r str>,
+    pub playback: Option<&'r str>,
+    pub speed: Option<&'r str>,
+    pub sample_fps: Option<&'r str>,
+    pub interpolate_fps: Option<&'r str>,
+    pub blank_char: Option<&'r str>,
+    pub mode: Option<&'r str>,
+    pub color_tolerance: Option<&'r str>,
+    pub color_mode: Option<&'r str>,
+    pub themed_background: Option<&'r str>,
+    pub light_background: Option<&'r str>,
+    pub invert: Option<&'r str>,
+    pub dither: Option<&'r str>,
+    pub threads: Option<&'r str>,
+    pub aspect: Option<&'r str>,
+    pub metadata: Option<&'r str>,
+    pub tile: Option<&'r str>,
+    pub embed_text: Option<&'r str>,
+    pub html_classes: Option<&'r str>,
+    pub format: Option<&'r str>,
+}
+
+// Parts a source may be tiled into, more being refused as too elongated.
+const MAX_TILES: usize = 16;
+
+fn untileable(what: &str) -> Error {
+    let reason = format!("can't be applied to {}, only to text, ansi and json", what);
+    Error::InvalidValue("tile", reason)
+}
+
+// A rendered result, with the resolved parameters, its permalink and its match quality.
+pub type Rendered<T> = (T, String, Option<String>, Option<Quality>);
+
+// A fully resolved and validated conversion, owned so it can be moved into a blocking job.
+#[derive(Debug, Clone)]
+pub struct Conversion {
+    pub width: usize,
+    pub height: Option<usize>,
+    pub font: String,
+    pub font_size: Option<f32>,
+    pub alphabet: String,
+    pub charset: Option<String>,
+    pub metric: String,
+    pub no_color: bool,
+    pub brightness_offset: f32,
+    pub contrast: f32,
+    pub gamma: f32,
+    pub noise_scale: f32,
+    pub fps: f64,
+    pub no_edge_detection: bool,
+    pub cvd: Option<String>,
+    pub playback: String,
+    pub speed: f64,
+    pub sample_fps: Option<f64>,
+    pub interpolate_fps: Option<f64>,
+    pub blank_char: Option<char>,
+    pub mode: String,
+    pub color_tolerance: u8,
+    pub color_mode: String,
+    pub themed_background: bool,
+    pub light_background: bool,
+    pub invert: bool,
+    pub dither: Option<String>,
+    pub threads: usize,
+    pub aspect: Option<f64>,
+    pub metadata: bool,
+    // whether a source too elongated for `max_aspect_ratio` is converted in parts
+    pub tile: bool,
+    pub max_aspect_ratio: f64,
+    // whether PNG output carries the plain text and the parameters in text chunks
+    pub embed_text: bool,
+    // whether colored HTML pages style their spans by classes instead of inline
+    pub html_classes: bool,
+    pub max_frames: usize,
+    // the request converted for, which the conversion is logged under
+    pub request_id: Option<String>,
+    // conversions taking longer are logged as slow
+    pub slow_after: Option<Duration>,
+}
+
+fn parse<T: FromStr>(name: &'static str, value: Option<&str>) -> Result<Option<T>, Error> {
+    value
+        .map(|value| {
+            value
+                .parse::<T>()
+                .map_err(|_| Error::InvalidValue(name, format!("can't parse {:?}", value)))
+        })
+        .transpose()
+}
+
+// Flags are set by their mere presence (`?no_color`), as well as by `=true`/`=false`.
+fn parse_flag(name: &'static str, value: Option<&str>) -> Result<Option<bool>, Error> {
+    match value {
+        Some("") => Ok(Some(true)),
+        value => parse(name, value),
+    }
+}
+
+// A single character that can be shown, such as `blank_char`.
+fn parse_char(name: &'static str, value: Option<&str>) -> Result<Option<char>, Error> {
+    value
+        .map(|value| {
+            let mut chars = value.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if !c.is_control() => Ok(c),
+                _ => Err(Error::InvalidValue(
+                    name,
+                    format!("must be a single printable character, not {:?}", value),
+                )),
+            }
+        })
+        .transpose()
+}
+
+fn check(name: &'static str, valid: bool, reason: impl FnOnce() -> String) -> Result<(), Error> {
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidValue(name, reason()))
+    }
+}
+
+fn one_of(values: &[&str]) -> String {
+    format!("must be one of {}", values.join(", "))
+}
+
+impl<'r> ConversionQuery<'r> {
+    // The explicitly given values, parsed but not yet validated.
+    fn overrides(&self) -> Result<Profile, Error> {
+        Ok(Profile {
+            width: parse("width", self.width)?,
+            height: parse("height", self.height)?,
+            max_width: parse("max_width", self.max_width)?,
+            max_height: parse("max_height", self.max_height)?,
+            font: self.font.map(String::from),
+            font_size: parse("font_size", self.font_size)?,
+            alphabet: self.alphabet.map(String::from),
+            charset: self.charset.map(String::from),
+            metric: self.metric.map(String::from),
+            no_color: parse_flag("no_color", self.no_color)?,
+            brightness_offset: parse("brightness_offset", self.brightness_offset)?,
+            contrast: parse("contrast", self.contrast)?,
+            gamma: parse("gamma", self.gamma)?,
+            noise_scale: parse("noise_scale", self.noise_scale)?,
+            fps: parse("fps", self.fps)?,
+            no_edge_detection: parse_flag("no_edge_detection", self.no_edge_detection)?,
+            cvd: self.cvd.map(String::from),
+            playback: self.playback.map(String::from),
+            speed: parse("speed", self.speed)?,
+            sample_fps: parse("sample_fps", self.sample_fps)?,
+            interpolate_fps: parse("interpolate_fps", self.interpolate_fps)?,
+            blank_char: parse_char("blank_char", self.blank_char)?,
+            mode: self.mode.map(String::from),
+            color_tolerance: parse("color_tolerance", self.color_tolerance)?,
+            color_mode: self.color_mode.map(String::from),
+            themed_background: parse_flag("themed_background", self.themed_background)?,
+            light_background: parse_flag("light_background", self.light_background)?,
+            invert: parse_flag("invert", self.invert)?,
+            dither: self.dither.map(String::from),
+            threads: parse("threads", self.threads)?,
+            aspect: parse("aspect", self.aspect)?,
+            tile: parse_flag("tile", self.tile)?,
+            embed_text: parse_flag("embed_text", self.embed_text)?,
+            html_classes: parse_flag("html_classes", self.html_classes)?,
+        })
+    }
+
+    pub fn resolve(
+        &self,
+        config: &Config,
+        alphabets: Option<&Alphabets>,
+    ) -> Result<Conversion, Error> {
+        let metadata = parse_flag("metadata", self.metadata)?.unwrap_or(false);
+        let overrides = match alphabets {
+            Some(alphabets) => alphabets.expand(self.overrides()?),
+            None => self.overrides()?,
+        };
+        resolve(config, self.profile, &overrides, metadata)
+    }
+}
+
+// A conversion resolved from the request's query string and validated against the config before
+// the handler runs, along with the `format` asked for. Handlers take it as a `Result`, which keeps
+// the error along with the offending parameter for the response instead of a bare status.
+pub struct ValidatedParams {
+    pub conversion: Conversion,
+    pub format: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ValidatedParams {
+    type Error = Error;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let config = match req.rocket().state::<Config>() {
+            Some(config) => config,
+            None => return Outcome::Error((Status::InternalServerError, Error::ConversionFailed)),
+        };
+        let alphabets = req.rocket().state::<Arc<Alphabets>>().map(Arc::as_ref);
+        let validated = Form::<ConversionQuery>::parse_iter(req.query_fields())
+            .map_err(|_| Error::Status(Status::BadRequest))
+            .and_then(|query| {
+                let mut conversion = query.resolve(config, alphabets)?;
+                conversion.request_id = Some(RequestId::of(req).0);
+                Ok(ValidatedParams {
+                    conversion,
+                    format: query.format.map(String::from),
+                })
+            });
+        match validated {
+            Ok(validated) => Outcome::Success(validated),
+            Err(err) => Outcome::Error((Status::BadRequest, err)),
+        }
+    }
+}
+
+// The distinct characters of a client's charset, checked to be usable as an alphabet.
+pub fn check_charset(charset: &str) -> Result<Vec<char>, Error> {
+    let chars = alphabet_chars("", Some(charset));
+    check("charset", !chars.is_empty(), || {
+        String::from("must not be empty")
+    })?;
+    check("charset", chars.len() <= MAX_CHARSET_LEN, || {
+        format!("must have at most {} distinct characters", MAX_CHARSET_LEN)
+    })?;
+    check("charset", !chars.iter().any(|c| c.is_control()), || {
+        String::from("must not contain control characters")
+    })?;
+    Ok(chars)
+}
+
+// Explicit values win over the selected profile, which wins over the defaults.
+pub fn resolve(
+    config: &Config,
+    profile: Option<&str>,
+    overrides: &Profile,
+    metadata: bool,
+) -> Result<Conversion, Error> {
+    let profile = match profile {
+        Some(name) => match config.profiles.get(name) {
+            Some(profile) => profile.clone(),
+            None => return Err(Error::UnknownProfile(String::from(name))),
+        },
+        None => Profile::default(),
+    }
+    .or(&config.defaults);
+
+    // fonts from profiles are trusted, clients only get the bundled and uploaded ones; alphabets
+    // are bundled ones wherever they're named
+    if let Some(font) = &overrides.font {
+        if !is_available_font(font) {
+            return Err(Error::UnknownFont(font.clone()));
+        }
+    }
+    for alphabet in [&overrides.alphabet, &profile.alphabet]
+        .into_iter()
+        .flatten()
+    {
+        if !is_bundled_alphabet(alphabet) {
+            return Err(Error::UnknownAlphabet(alphabet.clone()));
+        }
+    }
+
+    // an alphabet named by the client replaces a profile's charset, a charset replaces any alphabet
+    check(
+        "charset",
+        overrides.charset.is_none() || overrides.alphabet.is_none(),
+        || String::from("must not be given along with alphabet"),
+    )?;
+    let charset = match &overrides.alphabet {
+        Some(_) => None,
+        None => overrides.charset.as_deref().or(profile.charset.as_deref()),
+    };
+    if let Some(charset) = charset {
+        check_charset(charset)?;
+    }
+
+    let font_size = overrides.font_size.or(profile.font_size);
+    check(
+        "font_size",
+        !matches!(font_size, Some(size) if !(4.0..=256.0).contains(&size)),
+        || String::from("must be between 4 and 256"),
+    )?;
+
+    let limits = &config.conversion_limits;
+    let positive = || String::from("must be positive");
+    let height = overrides.height.or(profile.height);
+    check("height", height != Some(0), positive)?;
+    let max_width = overrides.max_width.or(profile.max_width);
+    check("max_width", max_width != Some(0), positive)?;
+    let max_height = overrides.max_height.or(profile.max_height);
+    check("max_height", max_height != Some(0), positive)?;
+    // a height alone sizes the output, within the widest it can be
+    let default_width = if height.is_some() {
+        limits.max_width
+    } else {
+        150
+    };
+    let width = overrides.width.or(profile.width).unwrap_or(default_width);
+    check("width", width > 0, positive)?;
+    let width = width.min(max_width.unwrap_or(usize::MAX));
+    let height = match (height, max_height) {
+        (Some(height), Some(max_height)) => Some(height.min(max_height)),
+        (height, max_height) => height.or(max_height),
+    };
+    let metric = overrides
+        .metric
+        .as_deref()
+        .or(profile.metric.as_deref())
+        .unwrap_or("grad");
+    check("metric", METRICS.contains(&metric), || one_of(&METRICS))?;
+    let brightness_offset = overrides
+        .brightness_offset
+        .or(profile.brightness_offset)
+        .unwrap_or(0.0);
+    check(
+        "brightness_offset",
+        (0.0..=255.0).contains(&brightness_offset),
+        || String::from("must be between 0 and 255"),
+    )?;
+    let contrast = overrides.contrast.or(profile.contrast).unwrap_or(1.0);
+    check("contrast", (0.0..=10.0).contains(&contrast), || {
+        String::from("must be between 0 and 10")
+    })?;
+    let gamma = overrides.gamma.or(profile.gamma).unwrap_or(1.0);
+    check("gamma", (0.1..=10.0).contains(&gamma), || {
+        String::from("must be between 0.1 and 10")
+    })?;
+    let noise_scale = overrides.noise_scale.or(profile.noise_scale).unwrap_or(0.0);
+    check(
+        "noise_scale",
+        noise_scale.is_finite() && noise_scale >= 0.0,
+        || String::from("must not be negative"),
+    )?;
+    let fps_range = || format!("must be above 0 and at most {}", limits.max_fps);
+    let valid_fps = |fps: f64| fps.is_finite() && fps > 0.0 && fps <= limits.max_fps;
+    let fps = overrides.fps.or(profile.fps).unwrap_or(30.0);
+    check("fps", valid_fps(fps), fps_range)?;
+    let cvd = overrides.cvd.as_deref().or(profile.cvd.as_deref());
+    check(
+        "cvd",
+        !matches!(cvd, Some(cvd) if !CVD_KINDS.contains(&cvd)),
+        || one_of(&CVD_KINDS),
+    )?;
+    let playback = overrides
+        .playback
+        .as_deref()
+        .or(profile.playback.as_deref())
+        .unwrap_or("forward");
+    check("playback", PLAYBACK_MODES.contains(&playback), || {
+        one_of(&PLAYBACK_MODES)
+    })?;
+    let speed = overrides.speed.or(profile.speed).unwrap_or(1.0);
+    check("speed", speed.is_finite() && speed > 0.0, || {
+        String::from("must be positive")
+    })?;
+    let sample_fps = overrides.sample_fps.or(profile.sample_fps);
+    check(
+        "sample_fps",
+        !matches!(sample_fps, Some(fps) if !valid_fps(fps)),
+        fps_range,
+    )?;
+    let interpolate_fps = overrides.interpolate_fps.or(profile.interpolate_fps);
+    check(
+        "interpolate_fps",
+        !matches!(interpolate_fps, Some(fps) if !valid_fps(fps)),
+        fps_range,
+    )?;
+    let mode = overrides
+        .mode
+        .as_deref()
+        .or(profile.mode.as_deref())
+        .unwrap_or("glyphs");
+    check("mode", MODES.contains(&mode), || one_of(&MODES))?;
+    let color_mode = overrides
+        .color_mode
+        .as_deref()
+        .or(profile.color_mode.as_deref())
+        .unwrap_or("truecolor");
+    check("color_mode", COLOR_MODES.contains(&color_mode), || {
+        one_of(&COLOR_MODES)
+    })?;
+    let dither = overrides.dither.as_deref().or(profile.dither.as_deref());
+    check(
+        "dither",
+        !matches!(dither, Some(dither) if !DITHERS.contains(&dither)),
+        || one_of(&DITHERS),
+    )?;
+    check(
+        "dither",
+        dither.is_none() || matches!(mode, "glyphs" | "grayscale"),
+        || String::from("only applies to the glyphs and grayscale modes"),
+    )?;
+    let threads = overrides.threads.or(profile.threads).unwrap_or(1);
+    check(
+        "threads",
+        (1..=limits.max_threads).contains(&threads),
+        || format!("must be between 1 and {}", limits.max_threads),
+    )?;
+    let aspect = overrides.aspect.or(profile.aspect);
+    check(
+        "aspect",
+        !matches!(aspect, Some(aspect) if !(0.25..=4.0).contains(&aspect)),
+        || String::from("must be between 0.25 and 4"),
+    )?;
+    let themed_background = overrides
+        .themed_background
+        .or(profile.themed_background)
+        .unwrap_or(false);
+    let light_background = overrides
+        .light_background
+        .or(profile.light_background)
+        .unwrap_or(false);
+    check(
+        "light_background",
+        !(themed_background && light_background),
+        || String::from("can't be combined with themed_background"),
+    )?;
+
+    Ok(Conversion {
+        width: width.clamp(limits.min_width, limits.max_width.max(limits.min_width)),
+        height,
+        font: String::from(
+            overrides
+                .font
+                .as_deref()
+                .or(profile.font.as_deref())
+                .unwrap_or("bitocra-13"),
+        ),
+        font_size,
+        alphabet: String::from(
+            overrides
+                .alphabet
+                .as_deref()
+                .or(profile.alphabet.as_deref())
+                .unwrap_or("alphabet"),
+        ),
+        charset: charset.map(String::from),
+        metric: String::from(metric),
+        no_color: overrides.no_color.or(profile.no_color).unwrap_or(false),
+        brightness_offset,
+        contrast,
+        gamma,
+        noise_scale,
+        fps,
+        no_edge_detection: overrides
+            .no_edge_detection
+            .or(profile.no_edge_detection)
+            .unwrap_or(false),
+        cvd: cvd.map(String::from),
+        playback: String::from(playback),
+        speed,
+        sample_fps,
+        interpolate_fps,
+        blank_char: overrides.blank_char.or(profile.blank_char),
+        mode: String::from(mode),
+        color_tolerance: overrides
+            .color_tolerance
+            .or(profile.color_tolerance)
+            .unwrap_or(0),
+        color_mode: String::from(color_mode),
+        themed_background,
+        light_background,
+        invert: overrides.invert.or(profile.invert).unwrap_or(false),
+        dither: dither.map(String::from),
+        threads,
+        aspect,
+        metadata,
+        tile: overrides.tile.or(profile.tile).unwrap_or(false),
+        max_aspect_ratio: limits.max_aspect_ratio,
+        embed_text: overrides.embed_text.or(profile.embed_text).unwrap_or(false),
+        html_classes: overrides
+            .html_classes
+            .or(profile.html_classes)
+            .unwrap_or(false),
+        max_frames: limits.max_frames,
+        request_id: None,
+        slow_after: (config.logging.slow_conversion_ms > 0)
+            .then(|| Duration::from_millis(config.logging.slow_conversion_ms)),
+    })
+}
+
+impl Conversion {
+    // Observes the frames converted, and logs them under the request's ID, as a warning when they
+    // took longer than `slow_after`.
+    fn log_frames(&self, source: &str, frames: usize, took: Duration) {
+        telemetry::observe_frames(took, frames);
+        let line = format!(
+            "conversion request={} source={} frames={} width={} font={} mode={} took_ms={:.1}",
+            field(self.request_id.as_deref().unwrap_or("-")),
+            field(source),
+            frames,
+            self.width,
+            field(&self.font),
+            self.mode,
+            took.as_secs_f64() * 1000.
+        );
+        match self.slow_after {
+            Some(slow_after) if took > slow_after => warn!("slow {}", line),
+            _ => debug!("{}", line),
+        }
+    }
+
+    // Runs the conversion on an already decoded image, returning the text along with the resolved
+    // parameters for `WithParams`. `source` only labels where the image came from.
+    pub fn render_text(
+        &self,
+        image: &DynamicImage,
+        source: &str,
+    ) -> Result<(String, String), Error> {
+        let args = self.params(source);
+        let font = self.load_font()?;
+        let tiles = self.tiles(image)?.unwrap_or_else(|| vec![image.clone()]);
+        let count = tiles.len();
+        let t0 = Instant::now();
+        let texts = tiles
+            .into_iter()
+            .map(|tile| match generate(&args, &font, vec![tile])? {
+                ConversionOutput::Text(text) => Ok(text),
+                _ => Err(Error::ConversionFailed),
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        self.log_frames(source, count, t0.elapsed());
+        Ok((texts.join("\n\n"), resolved_params(&args)))
+    }
+
+    // Whether `image` is answered as `representation` a band at a time, sending each band as soon as
+    // it's converted: only terminal text of a still large enough to be banded is, when there are
+    // no tiles, permalink or match quality that need the whole grid.
+    pub fn streams(
+        &self,
+        image: &DynamicImage,
+        representation: Representation,
+        permalinks: &Permalinks,
+    ) -> Result<bool, Error> {
+        if representation != Representation::Terminal || permalinks.enabled() || self.metadata {
+            return Ok(false);
+        }
+        if self.tiles(image)?.is_some() {
+            return Ok(false);
+        }
+        Ok(is_banded(image, &self.load_font()?, &self.params("")))
+    }
+
+    // Like `render_text`, as the body of a `<pre>` on a page other than the API's own, along with
+    // the theme it's shown in.
+    pub fn render_html_frame(
+        &self,
+        image: &DynamicImage,
+        source: &str,
+    ) -> Result<(String, Theme, String), Error> {
+        if self.tiles(image)?.is_some() {
+            return Err(untileable("this output"));
+        }
+        let args = self.params(source);
+        let font = self.load_font()?;
+        let char_rows = image_to_char_rows(image, &font, &args);
+        let html = negotiate::html_frame(&char_rows, image, &args);
+        Ok((html, output_theme(image, &args), resolved_params(&args)))
+    }
+
+    // Like `render_text`, also storing the grid the text was rendered from and returning its
+    // permalink when permalinks are enabled, and with `metadata` its match quality.
+    pub fn render_permalinked(
+        &self,
+        image: &DynamicImage,
+        source: &str,
+        permalinks: &Permalinks,
+    ) -> Result<Rendered<String>, Error> {
+        if !permalinks.enabled() && !self.metadata {
+            let (text, resolved) = self.render_text(image, source)?;
+            return Ok((text, resolved, None, None));
+        }
+        let ((_, text), resolved, permalink, quality) =
+            self.render_negotiated(&[], image, source, Representation::Terminal, permalinks)?;
+        Ok((
+            String::from_utf8(text).unwrap(),
+            resolved,
+            permalink,
+            quality,
+        ))
+    }
+
+    // Like `render_permalinked`, rendering the grid as the negotiated `representation`. Animated
+    // GIFs, `bytes` being the source `image` was decoded from, keep all their frames in
+    // representations that can hold them, and behind their permalink; their quality is the first
+    // frame's.
+    pub fn render_negotiated(
+        &self,
+        bytes: &[u8],
+        image: &DynamicImage,
+        source: &str,
+        representation: Representation,
+        permalinks: &Permalinks,
+    ) -> Result<Rendered<Body>, Error> {
+        let args = self.params(source);
+        let font = self.load_font()?;
+        if representation.is_animated() && image::guess_format(bytes).ok() == Some(ImageFormat::Gif)
+        {
+            let limit = negotiate::max_source_frames(&args, self.max_frames);
+            let frames = {
+                let _span = spans::span("decode_frames");
+                decode_gif(bytes, limit)?
+            };
+            if frames.len() > limit {
+                return Err(Error::TooManyFrames(self.max_frames));
+            }
+            if frames.len() > 1 {
+                if self.tiles(&frames[0])?.is_some() {
+                    return Err(untileable("animations"));
+                }
+                let (frames, fps) = negotiate::prepare_frames(frames, &args, self.max_frames)?;
+                let t0 = Instant::now();
+                let (body, frame_char_rows) =
+                    negotiate::render_frames(representation, &frames, &font, &args, fps)?;
+                self.log_frames(source, frames.len(), t0.elapsed());
+                let permalink = permalinks
+                    .enabled()
+                    .then(|| {
+                        let stored =
+                            StoredResult::new(&frame_char_rows, &frames, fps * args.speed, &args);
+                        permalinks.save(&stored)
+                    })
+                    .flatten();
+                let quality = self
+                    .metadata
+                    .then(|| match_quality(&frames[0], &font, &args, &frame_char_rows[0]))
+                    .flatten();
+                return Ok((body, resolved_params(&args), permalink, quality));
+            }
+        }
+        if let Some(tiles) = self.tiles(image)? {
+            return self.render_tiles(&tiles, source, representation, &font, &args);
+        }
+        let t0 = Instant::now();
+        let char_rows = image_to_char_rows(image, &font, &args);
+        self.log_frames(source, 1, t0.elapsed());
+        let permalink = permalinks
+            .enabled()
+            .then(|| {
+                let stored = StoredResult::new(
+                    slice::from_ref(&char_rows),
+                    slice::from_ref(image),
+                    args.fps * args.speed,
+                    &args,
+                );
+                permalinks.save(&stored)
+            })
+            .flatten();
+        let quality = self
+            .metadata
+            .then(|| match_quality(image, &font, &args, &char_rows))
+            .flatten();
+        let (content_type, mut body) =
+            negotiate::render(representation, &char_rows, &font, image, &args)?;
+        let resolved = resolved_params(&args);
+        if self.embed_text && representation == Representation::Png {
+            let text = char_rows_to_string(&char_rows);
+            let texts = [
+                ("Description", text.as_str()),
+                ("Parameters", resolved.as_str()),
+                ("Software", "image-to-ascii-api"),
+            ];
+            body = pngtext::with_text(body, &texts);
+        }
+        Ok(((content_type, body), resolved, permalink, quality))
+    }
+
+    // The source in parts along its longer side, none more elongated than `max_aspect_ratio`, or
+    // none when the source itself isn't. One that is is refused unless `tile` is set.
+    fn tiles(&self, image: &DynamicImage) -> Result<Option<Vec<DynamicImage>>, Error> {
+        let (width, height) = (image.width().max(1), image.height().max(1));
+        let ratio = width.max(height) as f64 / width.min(height) as f64;
+        if self.max_aspect_ratio <= 0. || ratio <= self.max_aspect_ratio {
+            return Ok(None);
+        }
+        let count = (ratio / self.max_aspect_ratio).ceil() as u32;
+        if !self.tile || count as usize > MAX_TILES {
+            return Err(Error::ExtremeAspectRatio(ratio, self.max_aspect_ratio));
+        }
+        let long = width.max(height);
+        let tiles = (0..count)
+            .map(|i| {
+                let (start, end) = (long * i / count, long * (i + 1) / count);
+                match width > height {
+                    true => image.crop_imm(start, 0, end - start, height),
+                    false => image.crop_imm(0, start, width, end - start),
+                }
+            })
+            .collect();
+        Ok(Some(tiles))
+    }
+
+    // Tiles one after another, as text separated by blank lines or as the JSON array animations
+    // come as. They aren't stored behind permalinks.
+    fn render_tiles(
+        &self,
+        tiles: &[DynamicImage],
+        source: &str,
+        representation: Representation,
+        font: &Font,
+        args: &Params,
+    ) -> Result<Rendered<Body>, Error> {
+        let t0 = Instant::now();
+        let body = match representation {
+            Representation::Json => negotiate::render_json_tiles(tiles, font, args),
+            Representation::Text | Representation::Terminal => {
+                let mut text = Vec::new();
+                for (i, tile) in tiles.iter().enumerate() {
+                    if i > 0 {
+                        text.extend_from_slice(b"\n\n");
+                    }
+                    let char_rows = image_to_char_rows(tile, font, args);
+                    let (_, body) =
+                        negotiate::render(representation, &char_rows, font, tile, args)?;
+                    text.extend(body);
+                }
+                (ContentType::Plain, text)
+            }
+            _ => return Err(untileable("this output")),
+        };
+        self.log_frames(source, tiles.len(), t0.elapsed());
+        Ok((body, resolved_params(args), None, None))
+    }
+
+    // A strong entity tag of the response to converting the source `bytes` as `representation`,
+    // none when the conversion adds noise and so differs every time.
+    pub fn etag(&self, bytes: &[u8], representation: Representation) -> Option<String> {
+        if self.noise_scale > 0. {
+            return None;
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(Sha256::digest(bytes));
+        hasher.update(resolved_params(&self.params("")));
+        // an uploaded font can be replaced under the same name
+        if let Some(digest) = registered_font_digest(&self.font) {
+            hasher.update(digest);
+        }
+        hasher.update(representation.name());
+        hasher.update([self.metadata as u8]);
+        if self.tile {
+            hasher.update(b"tile");
+        }
+        if self.embed_text {
+            hasher.update(b"embed_text");
+        }
+        Some(format!("\"{}\"", &hex::encode(hasher.finalize())[..32]))
+    }
+
+    pub fn load_font(&self) -> Result<Font, Error> {
+        let alphabet = alphabet_chars(&self.alphabet, self.charset.as_deref());
+        let font = load_sized_font(&self.font, self.font_size, &alphabet)?;
+        let missing = font.missing_glyphs(&alphabet);
+        if !missing.is_empty() {
+            return Err(Error::MissingGlyphs(missing));
+        }
+        Ok(font)
+    }
+
+    pub fn params<'a>(&'a self, image_url: &'a str) -> Params<'a> {
+        Params {
+            image_url,
+            font: &self.font,
+            font_size: self.font_size,
+            alphabet: &self.alphabet,
+            charset: self.charset.as_deref(),
+            width: self.width,
+            height: self.height,
+            metric: &self.metric,
+            threads: self.threads,
+            no_color: self.no_color,
+            brightness_offset: self.brightness_offset,
+            contrast: self.contrast,
+            gamma: self.gamma,
+            noise_scale: self.noise_scale,
+            out_path: None,
+            fps: self.fps,
+            no_edge_detection: self.no_edge_detection,
+            cvd: self.cvd.as_deref(),
+            playback: &self.playback,
+            speed: self.speed,
+            sample_fps: self.sample_fps,
+            interpolate_fps: self.interpolate_fps,
+            blank_char: self.blank_char,
+            mode: &self.mode,
+            color_tolerance: self.color_tolerance,
+            color_mode: &self.color_mode,
+            themed_background: self.themed_background,
+            light_background: self.light_background,
+            invert: self.invert,
+            dither: self.dither.as_deref(),
+            aspect: self.aspect,
+            html_classes: self.html_classes,
+        }
+    }
+}
+
+// The terminal text of a still converted a band at a time, for `Conversion::streams`.
+pub struct TextBands {
+    conversion: Conversion,
+    image: DynamicImage,
+    source: String,
+    font: Font,
+}
+
+impl TextBands {
+    pub fn new(conversion: Conversion, image: DynamicImage, source: String) -> Result<Self, Error> {
+        let font = conversion.load_font()?;
+        Ok(TextBands {
+            conversion,
+            image,
+            source,
+            font,
+        })
+    }
+
+    pub fn resolved(&self) -> String {
+        resolved_params(&self.conversion.params(&self.source))
+    }
+
+    // Converts the bands in order, handing `send` each one with the line break that joins it to the
+    // last, and gives up as soon as `send` returns false.
+    pub fn convert(&self, mut send: impl FnMut(String) -> bool) {
+        let args = self.conversion.params(&self.source);
+        let t0 = Instant::now();
+        let bands = image_to_text_bands(&self.image, &self.font, &args, BAND_ROWS);
+        for (i, band) in bands.enumerate() {
+            let band = if i > 0 { format!("\n{}", band) } else { band };
+            if !send(band) {
+                return;
+            }
+        }
+        self.conversion.log_frames(&self.source, 1, t0.elapsed());
+    }
+}
@@ -0,0 +1,688 @@
+use log::{error, warn};
+use reqwest::Url;
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::Deserialize;
+use rocket::tokio::task::block_in_place;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::auth::ApiKey;
+use crate::config::Config;
+use crate::error::Error;
+
+// Where rate limits and quotas are counted, by key. Backends are blocking, like `Storage`'s, and
+// every replica pointed at the same shared one enforces the same limits.
+pub trait LimitStore: Send + Sync {
+    // Takes `cost` tokens from bucket `key`, which holds up to `capacity` and refills at `per_sec`,
+    // or when there aren't enough, takes none and returns how long until there would be.
+    fn take(
+        &self,
+        key: &str,
+        capacity: f64,
+        per_sec: f64,
+        cost: f64,
+    ) -> io::Result<Option<Duration>>;
+    // Adds `amount` to counter `key`, which resets `window` after it was started, returning its
+    // total and how long until it resets.
+    fn count(&self, key: &str, amount: u64, window: Duration) -> io::Result<(u64, Duration)>;
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct RateLimitConfig {
+    // conversions are limited per client IP when set
+    pub enabled: bool,
+    // the requests a client can make in a minute once it's used up its burst
+    pub per_minute: f64,
+    // the requests a client can make at once, after being idle
+    pub burst: f64,
+    // the most requests a client can make in `quota_window` seconds on top of that, 0 for no limit
+    pub quota: u64,
+    pub quota_window: u64,
+    // `memory`, counting for this instance only, or `redis`, shared across replicas
+    pub backend: String,
+    // `redis://[:password@]host[:port][/db]`
+    pub redis_url: String,
+    // prefixed to every key, so deployments can share a Redis
+    pub key_prefix: String,
+    // clients are told apart by Rocket's `ip_header` when set, which only a trusted proxy in front
+    // may be left to set, and otherwise by the address they connect from
+    pub trust_ip_header: bool,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> RateLimitConfig {
+        RateLimitConfig {
+            enabled: false,
+            per_minute: 30.,
+            burst: 10.,
+            quota: 0,
+            quota_window: 24 * 60 * 60,
+            backend: String::from("memory"),
+            redis_url: String::from("redis://127.0.0.1:6379"),
+            key_prefix: String::from("image-to-ascii:"),
+            trust_ip_header: false,
+        }
+    }
+}
+
+// Once this many buckets or counters are kept in memory, those that are full or over are dropped.
+const MAX_MEMORY_KEYS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    at: Instant,
+    // how long it takes to fill up again, after which it's as good as gone
+    refill: Duration,
+}
+
+// Kept for the life of the process only, so each replica counts on its own.
+#[derive(Default)]
+pub struct MemoryLimits {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    counters: Mutex<HashMap<String, (u64, Instant, Duration)>>,
+}
+
+impl LimitStore for MemoryLimits {
+    fn take(
+        &self,
+        key: &str,
+        capacity: f64,
+        per_sec: f64,
+        cost: f64,
+    ) -> io::Result<Option<Duration>> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_MEMORY_KEYS {
+            buckets.retain(|_, bucket| now.duration_since(bucket.at) < bucket.refill);
+        }
+        let refill = Duration::from_secs_f64(capacity / per_sec);
+        let bucket = buckets.entry(String::from(key)).or_insert(Bucket {
+            tokens: capacity,
+            at: now,
+            refill,
+        });
+        let elapsed = now.duration_since(bucket.at).as_secs_f64();
+        let tokens = capacity.min(bucket.tokens + elapsed * per_sec);
+        *bucket = Bucket {
+            tokens,
+            at: now,
+            refill,
+        };
+        if tokens < cost {
+            return Ok(Some(Duration::from_secs_f64((cost - tokens) / per_sec)));
+        }
+        bucket.tokens -= cost;
+        Ok(None)
+    }
+
+    fn count(&self, key: &str, amount: u64, window: Duration) -> io::Result<(u64, Duration)> {
+        let now = Instant::now();
+        let mut counters = self.counters.lock().unwrap();
+        if counters.len() >= MAX_MEMORY_KEYS {
+            counters.retain(|_, (_, started, window)| now.duration_since(*started) < *window);
+        }
+        let counter = counters
+            .entry(String::from(key))
+            .or_insert((0, now, window));
+        if now.duration_since(counter.1) >= counter.2 {
+            *counter = (0, now, window);
+        }
+        counter.0 += amount;
+        Ok((
+            counter.0,
+            (counter.1 + counter.2).saturating_duration_since(now),
+        ))
+    }
+}
+
+// Refills bucket KEYS[1] by Redis's own clock, so replicas' clocks needn't agree, and takes
+// ARGV[3] tokens from it, returning "0" or the seconds to wait as a string, since Lua numbers
+// would come back truncated to integers.
+const TAKE_SCRIPT: &str = "
+local capacity, per_sec, cost = tonumber(ARGV[1]), tonumber(ARGV[2]), tonumber(ARGV[3])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'at')
+local tokens = tonumber(bucket[1]) or capacity
+local at = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - at) * per_sec)
+local wait = 0
+if tokens >= cost then tokens = tokens - cost else wait = (cost - tokens) / per_sec end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'at', tostring(now))
+redis.call('PEXPIRE', KEYS[1], math.ceil(capacity / per_sec * 1000))
+return tostring(wait)
+";
+
+// Adds ARGV[1] to counter KEYS[1], starting its window of ARGV[2] milliseconds when it's new, and
+// returns its total and the milliseconds left of it.
+const COUNT_SCRIPT: &str = "
+local total = redis.call('INCRBY', KEYS[1], ARGV[1])
+if redis.call('PTTL', KEYS[1]) < 0 then redis.call('PEXPIRE', KEYS[1], ARGV[2]) end
+return {total, redis.call('PTTL', KEYS[1])}
+";
+
+const REDIS_TIMEOUT: Duration = Duration::from_secs(1);
+// Connections left open for later calls beyond this many are closed.
+const MAX_IDLE_CONNECTIONS: usize = 16;
+
+enum Reply {
+    Status,
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+fn protocol_error() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "unexpected reply from Redis")
+}
+
+fn read_reply<R: BufRead>(reader: &mut R) -> io::Result<Reply> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let line = line.trim_end_matches("\r\n");
+    if line.is_empty() {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "Redis hung up"));
+    }
+    let (kind, rest) = match line.get(1..) {
+        Some(rest) => (line.as_bytes()[0], rest),
+        None => return Err(protocol_error()),
+    };
+    let number = || rest.parse::<i64>().map_err(|_| protocol_error());
+    match kind {
+        b'+' => Ok(Reply::Status),
+        b'-' => Err(io::Error::other(format!("Redis: {}", rest))),
+        b':' => Ok(Reply::Integer(number()?)),
+        b'$' => match number()? {
+            length if length < 0 => Ok(Reply::Bulk(None)),
+            length => {
+                let mut bulk = vec![0; length as usize + 2];
+                reader.read_exact(&mut bulk)?;
+                bulk.truncate(length as usize);
+                Ok(Reply::Bulk(Some(bulk)))
+            }
+        },
+        b'*' => {
+            let length = number()?.max(0);
+            (0..length)
+                .map(|_| read_reply(reader))
+                .collect::<io::Result<_>>()
+                .map(Reply::Array)
+        }
+        _ => Err(protocol_error()),
+    }
+}
+
+// A Redis server, spoken to over a pool of connections, so one waiting on a slow reply doesn't
+// hold up the others. Each call takes an idle connection or opens one, and hands it back after,
+// unless it failed.
+pub struct RedisLimits {
+    url: Url,
+    key_prefix: String,
+    idle: Mutex<Vec<BufReader<TcpStream>>>,
+}
+
+impl RedisLimits {
+    pub fn new(config: &RateLimitConfig) -> Result<RedisLimits, String> {
+        let url = Url::parse(&config.redis_url).map_err(|err| err.to_string())?;
+        if url.scheme() != "redis" || url.host_str().is_none() {
+            return Err(format!("{:?} isn't a redis:// URL", config.redis_url));
+        }
+        Ok(RedisLimits {
+            url,
+            key_prefix: config.key_prefix.clone(),
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn connect(&self) -> io::Result<BufReader<TcpStream>> {
+        let port = self.url.port().unwrap_or(6379);
+        let addr = (self.url.host_str().unwrap_or_default(), port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "Redis host has no address"))?;
+        let stream = TcpStream::connect_timeout(&addr, REDIS_TIMEOUT)?;
+        stream.set_read_timeout(Some(REDIS_TIMEOUT))?;
+        stream.set_write_timeout(Some(REDIS_TIMEOUT))?;
+        let mut connection = BufReader::new(stream);
+        if let Some(password) = self.url.password() {
+            let user = self.url.username();
+            match user.is_empty() {
+                true => send(&mut connection, &["AUTH", password])?,
+                false => send(&mut connection, &["AUTH", user, password])?,
+            };
+        }
+        let db = self.url.path().trim_start_matches('/');
+        if !db.is_empty() {
+            send(&mut connection, &["SELECT", db])?;
+        }
+        Ok(connection)
+    }
+
+    fn call(&self, args: &[&str]) -> io::Result<Reply> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut connection = match idle {
+            Some(connection) => connection,
+            None => self.connect()?,
+        };
+        let reply = send(&mut connection, args);
+        // a connection that failed mid-reply may have more of it waiting, so it isn't reused
+        if reply.is_ok() {
+            let mut idle = self.idle.lock().unwrap();
+            if idle.len() < MAX_IDLE_CONNECTIONS {
+                idle.push(connection);
+            }
+        }
+        reply
+    }
+}
+
+fn send(connection: &mut BufReader<TcpStream>, args: &[&str]) -> io::Result<Reply> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+    }
+    connection.get_mut().write_all(&command)?;
+    read_reply(connection)
+}
+
+impl LimitStore for RedisLimits {
+    fn take(
+        &self,
+        key: &str,
+        capacity: f64,
+        per_sec: f64,
+        cost: f64,
+    ) -> io::Result<Option<Duration>> {
+        let key = [&self.key_prefix, key].concat();
+        let args = [capacity, per_sec, cost].map(|n| n.to_string());
+        let reply = self.call(&["EVAL", TAKE_SCRIPT, "1", &key, &args[0], &args[1], &args[2]])?;
+        let wait: f64 = match reply {
+            Reply::Bulk(Some(wait)) => String::from_utf8_lossy(&wait)
+                .parse()
+                .map_err(|_| protocol_error())?,
+            _ => return Err(protocol_error()),
+        };
+        Ok(Some(Duration::from_secs_f64(wait)).filter(|_| wait > 0.))
+    }
+
+    fn count(&self, key: &str, amount: u64, window: Duration) -> io::Result<(u64, Duration)> {
+        let key = [&self.key_prefix, key].concat();
+        let (amount, window) = (amount.to_string(), window.as_millis().max(1).to_string());
+        match self.call(&["EVAL", COUNT_SCRIPT, "1", &key, &amount, &window])? {
+            Reply::Array(reply) => match reply[..] {
+                [Reply::Integer(total), Reply::Integer(left)] => {
+                    Ok((total as u64, Duration::from_millis(left.max(0) as u64)))
+                }
+                _ => Err(protocol_error()),
+            },
+            _ => Err(protocol_error()),
+        }
+    }
+}
+
+// Why a client was refused, and how long it was told to wait, for the catcher to answer with.
+#[derive(Clone, Copy)]
+enum Refusal {
+    None,
+    RateLimited(Duration),
+    QuotaExceeded(Duration),
+    Unauthorized(&'static str),
+}
+
+struct ClientBucket {
+    key: String,
+    store: Arc<dyn LimitStore>,
+    capacity: f64,
+    per_sec: f64,
+    quota: u64,
+    quota_window: Duration,
+}
+
+// The client's bucket and quota, those of its API key or else of its address, for routes that
+// convert more than once per request, as `/live` sessions do, to charge for each conversion.
+// Clients without either aren't limited, nor is anyone while the store can't be reached.
+pub struct Limiter(Option<ClientBucket>);
+
+impl Limiter {
+    // The bucket and quota of `key`, for clients told apart by something other than their API key
+    // or address, as the chats a webhook relays are.
+    pub fn keyed(config: &RateLimitConfig, store: &Arc<dyn LimitStore>, key: String) -> Limiter {
+        if !config.enabled {
+            return Limiter(None);
+        }
+        Limiter(Some(ClientBucket {
+            key,
+            store: store.clone(),
+            capacity: config.burst,
+            per_sec: config.per_minute / 60.,
+            quota: config.quota,
+            quota_window: Duration::from_secs(config.quota_window),
+        }))
+    }
+
+    // Blocks on the store.
+    pub fn take(&self) -> Result<(), Error> {
+        let bucket = match &self.0 {
+            Some(bucket) => bucket,
+            None => return Ok(()),
+        };
+        let ClientBucket {
+            key,
+            store,
+            capacity,
+            per_sec,
+            quota,
+            quota_window,
+        } = bucket;
+        match store.take(key, *capacity, *per_sec, 1.) {
+            Ok(None) => {}
+            Ok(Some(wait)) => return Err(Error::RateLimited(wait)),
+            Err(err) => {
+                warn!("could not rate limit {}: {}", key, err);
+                return Ok(());
+            }
+        }
+        // only requests the bucket let through count towards the quota
+        if *quota == 0 {
+            return Ok(());
+        }
+        match store.count(&format!("quota:{}", key), 1, *quota_window) {
+            Ok((total, _)) if total <= *quota => Ok(()),
+            Ok((_, reset)) => Err(Error::QuotaExceeded(reset)),
+            Err(err) => {
+                warn!("could not count the quota of {}: {}", key, err);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Limiter {
+    type Error = Error;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let api_key = match req.guard::<ApiKey>().await {
+            Outcome::Success(ApiKey(name)) => name,
+            Outcome::Error((status, reason)) => {
+                req.local_cache(|| Refusal::Unauthorized(reason));
+                return Outcome::Error((status, Error::Unauthorized(reason)));
+            }
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        let rocket = req.rocket();
+        let (config, store) = match (
+            rocket.state::<Config>(),
+            rocket.state::<Arc<dyn LimitStore>>(),
+        ) {
+            (Some(config), Some(store)) if config.rate_limit.enabled => (&config.rate_limit, store),
+            _ => return Outcome::Success(Limiter(None)),
+        };
+        let ip = match config.trust_ip_header {
+            true => req.client_ip(),
+            false => req.remote().map(|remote| remote.ip()),
+        };
+        let key = match (api_key, ip) {
+            (Some(name), _) => format!("key:{}", name),
+            (None, Some(ip)) => format!("ip:{}", ip),
+            (None, None) => return Outcome::Success(Limiter(None)),
+        };
+        Outcome::Success(Limiter::keyed(config, store, key))
+    }
+}
+
+// Guards the routes that convert or fetch, checking the client's API key and then taking a token
+// from its bucket.
+pub struct RateLimit;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimit {
+    type Error = Error;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let limiter = match req.guard::<Limiter>().await {
+            Outcome::Success(limiter) => limiter,
+            Outcome::Error(err) => return Outcome::Error(err),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        match block_in_place(|| limiter.take()) {
+            Ok(()) => Outcome::Success(RateLimit),
+            Err(err) => {
+                let refusal = match err {
+                    Error::RateLimited(wait) => Refusal::RateLimited(wait),
+                    Error::QuotaExceeded(reset) => Refusal::QuotaExceeded(reset),
+                    _ => Refusal::None,
+                };
+                req.local_cache(|| refusal);
+                Outcome::Error((Status::TooManyRequests, err))
+            }
+        }
+    }
+}
+
+// What the request was refused with, when its API key was refused, it was rate limited or it was
+// over its quota.
+pub fn refusal(req: &Request<'_>) -> Option<Error> {
+    match *req.local_cache(|| Refusal::None) {
+        Refusal::None => None,
+        Refusal::RateLimited(wait) => Some(Error::RateLimited(wait)),
+        Refusal::QuotaExceeded(reset) => Some(Error::QuotaExceeded(reset)),
+        Refusal::Unauthorized(reason) => Some(Error::Unauthorized(reason)),
+    }
+}
+
+pub fn from_config(config: &RateLimitConfig) -> Result<Arc<dyn LimitStore>, String> {
+    // buckets are refilled in `burst / per_minute` minutes, which must be a length of time
+    if !(config.per_minute > 0. && config.per_minute.is_finite()) {
+        return Err(String::from("per_minute must be positive"));
+    }
+    if !(config.burst >= 1. && config.burst.is_finite()) {
+        return Err(String::from("burst must be at least 1"));
+    }
+    if config.quota > 0 && config.quota_window == 0 {
+        return Err(String::from("quota_window must be positive"));
+    }
+    match config.backend.as_str() {
+        "memory" => Ok(Arc::new(MemoryLimits::default())),
+        "redis" => Ok(Arc::new(RedisLimits::new(config)?)),
+        backend => Err(format!("unknown backend {:?}", backend)),
+    }
+}
+
+// Manages the configured `Arc<dyn LimitStore>`, refusing to launch with an invalid one. Redis is
+// only connected to once something is counted, so it needn't be up first.
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Rate limit store", |rocket| async {
+        let config = rocket
+            .state::<Config>()
+            .map(|config| config.rate_limit.clone())
+            .unwrap_or_default();
+        match from_config(&config) {
+            Ok(store) => Ok(rocket.manage(store)),
+            Err(err) => {
+                error!("invalid rate_limit config: {}", err);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn takes_up_to_the_capacity() {
+        let limits = MemoryLimits::default();
+        assert_eq!(limits.take("a", 2., 1., 1.).unwrap(), None);
+        assert_eq!(limits.take("a", 2., 1., 1.).unwrap(), None);
+        let wait = limits.take("a", 2., 1., 1.).unwrap().unwrap();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+        // other keys have buckets of their own
+        assert_eq!(limits.take("b", 2., 1., 1.).unwrap(), None);
+    }
+
+    #[test]
+    fn refused_takes_cost_nothing() {
+        let limits = MemoryLimits::default();
+        assert!(limits.take("a", 2., 1., 3.).unwrap().is_some());
+        assert_eq!(limits.take("a", 2., 1., 2.).unwrap(), None);
+    }
+
+    #[test]
+    fn counts_within_the_window() {
+        let limits = MemoryLimits::default();
+        let window = Duration::from_secs(60);
+        assert_eq!(limits.count("a", 1, window).unwrap().0, 1);
+        assert_eq!(limits.count("a", 2, window).unwrap().0, 3);
+        assert_eq!(limits.count("b", 1, window).unwrap().0, 1);
+        let (_, reset) = limits.count("a", 0, window).unwrap();
+        assert!(reset > Duration::ZERO && reset <= window);
+    }
+
+    #[test]
+    fn counters_reset_after_the_window() {
+        let limits = MemoryLimits::default();
+        assert_eq!(limits.count("a", 5, Duration::ZERO).unwrap().0, 5);
+        assert_eq!(limits.count("a", 1, Duration::ZERO).unwrap().0, 1);
+    }
+
+    #[test]
+    fn keyed_limiters_limit_each_key_alone() {
+        let config = RateLimitConfig {
+            enabled: true,
+            burst: 1.,
+            ..RateLimitConfig::default()
+        };
+        let store: Arc<dyn LimitStore> = Arc::new(MemoryLimits::default());
+        let limiter = |key: &str| Limiter::keyed(&config, &store, String::from(key));
+        assert!(limiter("telegram:1").take().is_ok());
+        assert!(matches!(
+            limiter("telegram:1").take(),
+            Err(Error::RateLimited(_))
+        ));
+        assert!(limiter("telegram:2").take().is_ok());
+        let disabled = RateLimitConfig::default();
+        assert!(
+            Limiter::keyed(&disabled, &store, String::from("telegram:1"))
+                .take()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn refuses_replies_starting_with_unknown_characters() {
+        for reply in ["é1\r\n", "?\r\n"] {
+            let err = read_reply(&mut reply.as_bytes()).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+        assert!(matches!(
+            read_reply(&mut ":7\r\n".as_bytes()),
+            Ok(Reply::Integer(7))
+        ));
+    }
+
+    // Answers every command with a bucket that never runs out, counting the connections made.
+    fn fake_redis() -> (String, Arc<Mutex<usize>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(Mutex::new(0));
+        let counted = connections.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+                *counted.lock().unwrap() += 1;
+                std::thread::spawn(move || loop {
+                    let mut line = String::new();
+                    if stream.read_line(&mut line).unwrap_or(0) == 0 {
+                        return;
+                    }
+                    // the arguments of the command, each its length and then its value
+                    let args: usize = line.trim()[1..].parse().unwrap();
+                    for _ in 0..args {
+                        let mut length = String::new();
+                        stream.read_line(&mut length).unwrap();
+                        let length: usize = length.trim()[1..].parse().unwrap();
+                        stream.read_exact(&mut vec![0; length + 2]).unwrap();
+                    }
+                    stream.get_mut().write_all(b"$1\r\n0\r\n").unwrap();
+                });
+            }
+        });
+        (url, connections)
+    }
+
+    #[test]
+    fn reuses_idle_connections() {
+        let (redis_url, connections) = fake_redis();
+        let limits = RedisLimits::new(&RateLimitConfig {
+            redis_url,
+            ..RateLimitConfig::default()
+        })
+        .unwrap();
+        for _ in 0..3 {
+            assert_eq!(limits.take("a", 2., 1., 1.).unwrap(), None);
+        }
+        assert_eq!(*connections.lock().unwrap(), 1);
+        assert_eq!(limits.idle.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn counts_only_requests_the_bucket_let_through_towards_the_quota() {
+        let config = RateLimitConfig {
+            enabled: true,
+            burst: 3.,
+            quota: 2,
+            ..RateLimitConfig::default()
+        };
+        let store: Arc<dyn LimitStore> = Arc::new(MemoryLimits::default());
+        let limiter = Limiter::keyed(&config, &store, String::from("ip:1"));
+        assert!(limiter.take().is_ok());
+        assert!(limiter.take().is_ok());
+        assert!(matches!(limiter.take(), Err(Error::QuotaExceeded(_))));
+        assert!(matches!(limiter.take(), Err(Error::RateLimited(_))));
+        let (total, _) = store
+            .count("quota:ip:1", 0, Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(total, 3);
+    }
+
+    #[test]
+    fn refuses_invalid_configs() {
+        for config in [
+            RateLimitConfig {
+                per_minute: 0.,
+                ..RateLimitConfig::default()
+            },
+            RateLimitConfig {
+                burst: 0.5,
+                ..RateLimitConfig::default()
+            },
+            RateLimitConfig {
+                quota: 1,
+                quota_window: 0,
+                ..RateLimitConfig::default()
+            },
+            RateLimitConfig {
+                backend: String::from("redis"),
+                redis_url: String::from("http://127.0.0.1:6379"),
+                ..RateLimitConfig::default()
+            },
+            RateLimitConfig {
+                backend: String::from("memcached"),
+                ..RateLimitConfig::default()
+            },
+        ] {
+            assert!(from_config(&config).is_err(), "{:?}", config);
+        }
+        assert!(from_config(&RateLimitConfig::default()).is_ok());
+    }
+}
@@ -0,0 +1,117 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{get, State};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::fetch::Fetchers;
+use crate::negotiate::Representation;
+use crate::permalink::{PermalinkConfig, Permalinks};
+use crate::pools::Pools;
+use crate::profile::Profile;
+use crate::query::resolve;
+use crate::ratelimit::RateLimit;
+use crate::storage::Memory;
+
+const SAMPLE_PNG: &[u8] = include_bytes!("../samples/selftest.png");
+const SAMPLE_WIDTH: usize = 16;
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct StageReport {
+    stage: &'static str,
+    ok: bool,
+    ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SelfTest {
+    ok: bool,
+    stages: Vec<StageReport>,
+}
+
+impl SelfTest {
+    // Runs one stage and records how it went, or nothing once an earlier stage has failed.
+    async fn run<T>(
+        &mut self,
+        stage: &'static str,
+        job: impl Future<Output = Result<T, Error>>,
+    ) -> Option<T> {
+        if !self.ok {
+            return None;
+        }
+        let t0 = Instant::now();
+        let result = job.await;
+        self.ok = result.is_ok();
+        self.stages.push(StageReport {
+            stage,
+            ok: result.is_ok(),
+            ms: t0.elapsed().as_secs_f64() * 1000.,
+            error: result.as_ref().err().map(|err| err.to_string()),
+        });
+        result.ok()
+    }
+}
+
+// A deep health check: converts a small embedded image through the same fetchers, pools and
+// pipeline requests use, with the default parameters, answering `503 Service Unavailable` when a
+// stage fails.
+#[get("/selftest")]
+pub async fn selftest(
+    _limit: RateLimit,
+    config: &State<Config>,
+    pools: &State<Pools>,
+    fetchers: &State<Fetchers>,
+) -> (Status, Json<SelfTest>) {
+    let mut report = SelfTest {
+        ok: true,
+        stages: Vec::new(),
+    };
+    let url = format!("data:image/png;base64,{}", STANDARD.encode(SAMPLE_PNG));
+    let bytes = report
+        .run("fetch", pools.download(fetchers.fetch(&url)))
+        .await;
+    let image = match bytes {
+        Some(bytes) => report.run("decode", pools.decode(bytes)).await,
+        None => None,
+    };
+    let overrides = Profile {
+        width: Some(SAMPLE_WIDTH),
+        ..Profile::default()
+    };
+    let conversion = report
+        .run("resolve", async {
+            resolve(config, None, &overrides, false)
+        })
+        .await;
+
+    if let (Some((bytes, image)), Some(conversion)) = (image, conversion) {
+        let (text_image, text_conversion) = (image.clone(), conversion.clone());
+        let convert = pools.convert(move || text_conversion.render_text(&text_image, "selftest"));
+        report.run("convert", async { convert.await? }).await;
+
+        // rendering a bitmap, without storing a permalink for it
+        let permalinks = Permalinks::new(&PermalinkConfig::default(), Arc::new(Memory::default()));
+        let render = pools.convert(move || {
+            let representation = Representation::Png;
+            conversion.render_negotiated(&bytes, &image, "selftest", representation, &permalinks)
+        });
+        report.run("render", async { render.await? }).await;
+    }
+
+    let status = if report.ok {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    (status, Json(report))
+}
@@ -0,0 +1,301 @@
+use hmac::{Hmac, Mac};
+use log::warn;
+use reqwest::Client;
+use rocket::data::{self, Data, FromData, ToByteUnit};
+use rocket::form::{Form, FromForm};
+use rocket::http::{RawStr, Status};
+use rocket::outcome::Outcome;
+use rocket::serde::json::{json, Json, Value};
+use rocket::serde::Deserialize;
+use rocket::{post, Request, State};
+use sha2::Sha256;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::convert::METRICS;
+use crate::fetch::Fetchers;
+use crate::generate::{
+    image_to_text, is_available_font, is_bundled_alphabet, load_alphabet, load_font, Params,
+};
+use crate::pools::Pools;
+use crate::ratelimit::RateLimit;
+use crate::stats::Stats;
+
+// Slack rejects replays older than five minutes, so we do the same.
+const MAX_REQUEST_AGE_SECS: u64 = 60 * 5;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct SlackConfig {
+    pub signing_secret: Option<String>,
+    pub width: usize,
+    pub max_width: usize,
+    pub font: String,
+    pub alphabet: String,
+}
+
+impl Default for SlackConfig {
+    fn default() -> SlackConfig {
+        SlackConfig {
+            signing_secret: None,
+            width: 60,
+            max_width: 80,
+            font: String::from("bitocra-13"),
+            alphabet: String::from("letters"),
+        }
+    }
+}
+
+#[derive(FromForm)]
+struct SlashCommandForm {
+    text: String,
+    response_url: String,
+}
+
+pub struct SlashCommand {
+    pub text: String,
+    // where the result is posted once it's ready
+    pub response_url: String,
+}
+
+fn verify_signature(secret: &str, timestamp: &str, body: &str, signature: &str) -> bool {
+    let signature = match signature.strip_prefix("v0=").map(hex::decode) {
+        Some(Ok(signature)) => signature,
+        _ => return false,
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(format!("v0:{}:{}", timestamp, body).as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn is_fresh(timestamp: &str) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    match timestamp.parse::<u64>() {
+        Ok(timestamp) => now.abs_diff(timestamp) <= MAX_REQUEST_AGE_SECS,
+        Err(_) => false,
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for SlashCommand {
+    type Error = &'static str;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let secret = match req
+            .rocket()
+            .state::<Config>()
+            .and_then(|config| config.slack.signing_secret.as_ref())
+        {
+            Some(secret) => secret,
+            None => return Outcome::Error((Status::NotFound, "slack integration is disabled")),
+        };
+
+        let headers = req.headers();
+        let (timestamp, signature) = match (
+            headers.get_one("X-Slack-Request-Timestamp"),
+            headers.get_one("X-Slack-Signature"),
+        ) {
+            (Some(timestamp), Some(signature)) => (timestamp, signature),
+            _ => return Outcome::Error((Status::Unauthorized, "missing slack signature")),
+        };
+        if !is_fresh(timestamp) {
+            return Outcome::Error((Status::Unauthorized, "stale slack request"));
+        }
+
+        let body = match data.open(16.kibibytes()).into_string().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            _ => return Outcome::Error((Status::PayloadTooLarge, "slack payload too large")),
+        };
+        if !verify_signature(secret, timestamp, &body, signature) {
+            return Outcome::Error((Status::Unauthorized, "invalid slack signature"));
+        }
+
+        match Form::<SlashCommandForm>::parse_encoded(RawStr::new(&body)) {
+            Ok(form) => Outcome::Success(SlashCommand {
+                text: form.text,
+                response_url: form.response_url,
+            }),
+            Err(_) => Outcome::Error((Status::BadRequest, "malformed slash command")),
+        }
+    }
+}
+
+// Slack message text treats `&`, `<` and `>` as markup, and a stray backtick can close the code
+// block early.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('`', "'")
+}
+
+fn ephemeral(text: &str) -> Json<Value> {
+    Json(json!({ "response_type": "ephemeral", "text": text }))
+}
+
+// Slack gives up on a command that isn't answered within three seconds, so it's acknowledged
+// right away and the result posted to the command's `response_url` once it's ready.
+async fn respond(response_url: &str, message: &Value) {
+    let response = Client::new()
+        .post(response_url)
+        .timeout(RESPONSE_TIMEOUT)
+        .header("Content-Type", "application/json")
+        .body(message.to_string())
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    if let Err(err) = response {
+        warn!("could not answer slack command: {}", err);
+    }
+}
+
+// `/ascii <url> [width=N] [font=NAME] [alphabet=NAME] [metric=NAME]`
+#[post("/integrations/slack", data = "<command>")]
+pub async fn slack_command(
+    _limit: RateLimit,
+    command: SlashCommand,
+    config: &State<Config>,
+    stats: &State<Stats>,
+    pools: &State<Pools>,
+    fetchers: &State<Fetchers>,
+) -> Json<Value> {
+    let config = config.slack.clone();
+    let mut words = command.text.split_whitespace();
+    let url = match words.next() {
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => url.to_owned(),
+        _ => {
+            return ephemeral(
+                "Usage: `/ascii <image url> [width=N] [font=NAME] [alphabet=NAME] [metric=NAME]`",
+            )
+        }
+    };
+
+    let mut width = config.width;
+    let mut font = config.font.clone();
+    let mut alphabet = config.alphabet.clone();
+    let mut metric = String::from("grad");
+    for option in words {
+        match option.split_once('=') {
+            Some(("width", value)) => match value.parse::<usize>() {
+                Ok(value) => width = value.clamp(1, config.max_width),
+                Err(_) => return ephemeral(&format!("Invalid width {:?}", value)),
+            },
+            Some(("font", value)) if is_available_font(value) => font = value.to_owned(),
+            Some(("alphabet", value)) if is_bundled_alphabet(value) => alphabet = value.to_owned(),
+            Some(("metric", value)) if METRICS.contains(&value) => metric = value.to_owned(),
+            Some((name @ ("font" | "alphabet" | "metric"), value)) => {
+                return ephemeral(&format!("Unknown {} {:?}", name, value))
+            }
+            _ => return ephemeral(&format!("Unknown option {:?}", option)),
+        }
+    }
+
+    let (pools, fetchers, stats) = (
+        pools.inner().clone(),
+        fetchers.inner().clone(),
+        stats.inner().clone(),
+    );
+    rocket::tokio::spawn(async move {
+        let labels = (font.clone(), alphabet.clone(), metric.clone());
+        let t0 = Instant::now();
+        let source = async {
+            let bytes = pools.download(fetchers.fetch(&url)).await?;
+            pools.decode(bytes).await
+        };
+        let result = match source.await {
+            Ok((_, image)) => {
+                pools
+                    .convert(move || {
+                        let args = Params {
+                            font: &font,
+                            alphabet: &alphabet,
+                            metric: &metric,
+                            no_color: true,
+                            ..Params::defaults_for(&url, width)
+                        };
+                        let alphabet = load_alphabet(&alphabet);
+                        let font = load_font(&font, &alphabet).map_err(|err| err.to_string())?;
+                        let missing = font.missing_glyphs(&alphabet);
+                        if !missing.is_empty() {
+                            return Err(format!(
+                                "the alphabet has characters without a glyph in the font: {}",
+                                missing.iter().collect::<String>()
+                            ));
+                        }
+                        Ok(image_to_text(&image, &font, &args))
+                    })
+                    .await
+            }
+            Err(err) => Ok(Err(err.to_string())),
+        };
+
+        let message = match result {
+            Ok(Ok(ascii)) => {
+                let (font, alphabet, metric) = labels;
+                stats.record(&font, &alphabet, &metric, "slack", t0.elapsed());
+                Json(json!({
+                    "response_type": "in_channel",
+                    "text": format!("```\n{}\n```", escape(&ascii)),
+                }))
+            }
+            Ok(Err(err)) => {
+                stats.record_failure();
+                ephemeral(&format!("Could not convert image: {}", err))
+            }
+            Err(err) => {
+                stats.record_failure();
+                ephemeral(&format!("Could not convert image: {}", err))
+            }
+        };
+        respond(&command.response_url, &message).await;
+    });
+    ephemeral("Converting the image…")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature(secret: &str, timestamp: &str, body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("v0:{}:{}", timestamp, body).as_bytes());
+        format!("v0={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    #[test]
+    fn verifies_signatures_over_the_timestamp_and_body() {
+        let body = "text=https%3A%2F%2Fexample.com%2Fa.png&response_url=x";
+        let signed = signature("secret", "1700000000", body);
+        assert!(verify_signature("secret", "1700000000", body, &signed));
+        assert!(!verify_signature("other", "1700000000", body, &signed));
+        assert!(!verify_signature("secret", "1700000001", body, &signed));
+        assert!(!verify_signature("secret", "1700000000", "text=x", &signed));
+        assert!(!verify_signature(
+            "secret",
+            "1700000000",
+            body,
+            &signed[3..]
+        ));
+        assert!(!verify_signature("secret", "1700000000", body, "v0=zz"));
+    }
+
+    #[test]
+    fn refuses_stale_timestamps() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(is_fresh(&now.to_string()));
+        assert!(!is_fresh(&(now - MAX_REQUEST_AGE_SECS - 1).to_string()));
+        assert!(!is_fresh("yesterday"));
+    }
+
+    #[test]
+    fn escapes_markup_and_backticks() {
+        assert_eq!(escape("<a&b>`"), "&lt;a&amp;b&gt;'");
+    }
+}
@@ -0,0 +1,99 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+// Where a conversion's time goes, by stack of spans, each not counting the spans nested in it.
+#[derive(Debug, Default)]
+pub struct Folded {
+    stacks: BTreeMap<String, Duration>,
+}
+
+impl Folded {
+    // Adds `took` to `stack`, its spans' names joined by `;`.
+    pub fn add(&mut self, stack: &str, took: Duration) {
+        *self.stacks.entry(String::from(stack)).or_default() += took;
+    }
+
+    // One `stack microseconds` line per stack, the folded format flamegraph tools take.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (stack, took) in &self.stacks {
+            let _ = writeln!(text, "{} {}", stack, took.as_micros());
+        }
+        text
+    }
+}
+
+struct Frame {
+    name: &'static str,
+    started: Instant,
+    nested: Duration,
+}
+
+#[derive(Default)]
+struct Recording {
+    frames: Vec<Frame>,
+    folded: Folded,
+}
+
+thread_local! {
+    static RECORDING: RefCell<Option<Recording>> = const { RefCell::new(None) };
+}
+
+// Times the rest of the scope it's held for as the span `name`, nested in the spans around it.
+// Spans are only timed on a thread that's recording them, and cost next to nothing otherwise.
+pub struct Span(bool);
+
+pub fn span(name: &'static str) -> Span {
+    let recording = RECORDING.with(|recording| match recording.borrow_mut().as_mut() {
+        Some(recording) => {
+            recording.frames.push(Frame {
+                name,
+                started: Instant::now(),
+                nested: Duration::ZERO,
+            });
+            true
+        }
+        None => false,
+    });
+    Span(recording)
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if !self.0 {
+            return;
+        }
+        RECORDING.with(|recording| {
+            let mut recording = recording.borrow_mut();
+            let recording = match recording.as_mut() {
+                Some(recording) => recording,
+                None => return,
+            };
+            let frame = match recording.frames.pop() {
+                Some(frame) => frame,
+                None => return,
+            };
+            let took = frame.started.elapsed();
+            let mut stack: Vec<&str> = recording.frames.iter().map(|frame| frame.name).collect();
+            stack.push(frame.name);
+            recording
+                .folded
+                .add(&stack.join(";"), took.saturating_sub(frame.nested));
+            if let Some(parent) = recording.frames.last_mut() {
+                parent.nested += took;
+            }
+        });
+    }
+}
+
+// Runs `job`, recording the spans it goes through on this thread. Spans on the threads it spawns
+// count towards the spans they were spawned from.
+pub fn record<T>(job: impl FnOnce() -> T) -> (T, Folded) {
+    let outer = RECORDING.with(|recording| recording.replace(Some(Recording::default())));
+    let result = job();
+    let recording = RECORDING.with(|recording| recording.replace(outer));
+    let folded = recording.map(|recording| recording.folded);
+    (result, folded.unwrap_or_default())
+}
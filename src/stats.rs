@@ -0,0 +1,164 @@
+use log::warn;
+use rocket::fairing::AdHoc;
+use rocket::serde::json::{json, Json, Value};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{get, State};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::auth::AdminAuth;
+use crate::config::Config;
+use crate::storage::Storage;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct StatsConfig {
+    // counters are loaded from and saved back to this key of the storage across restarts when set
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+struct Counters {
+    conversions: u64,
+    failures: u64,
+    total_duration_ms: f64,
+    fonts: HashMap<String, u64>,
+    alphabets: HashMap<String, u64>,
+    metrics: HashMap<String, u64>,
+    formats: HashMap<String, u64>,
+    cache_hits: u64,
+    cache_misses: u64,
+    // requests by the name of their API key
+    api_keys: HashMap<String, u64>,
+}
+
+// Clones share the same counters.
+#[derive(Clone)]
+pub struct Stats {
+    counters: Arc<Mutex<Counters>>,
+    key: Option<String>,
+    storage: Arc<dyn Storage>,
+}
+
+impl Stats {
+    pub fn load(path: Option<PathBuf>, storage: Arc<dyn Storage>) -> Stats {
+        let key = path.map(|path| path.to_string_lossy().into_owned());
+        let counters = key
+            .as_ref()
+            .and_then(|key| storage.get(key).ok().flatten())
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default();
+        Stats {
+            counters: Arc::new(Mutex::new(counters)),
+            key,
+            storage,
+        }
+    }
+
+    pub fn record(&self, font: &str, alphabet: &str, metric: &str, format: &str, took: Duration) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.conversions += 1;
+        counters.total_duration_ms += took.as_secs_f64() * 1000.;
+        *counters.fonts.entry(font.to_owned()).or_default() += 1;
+        *counters.alphabets.entry(alphabet.to_owned()).or_default() += 1;
+        *counters.metrics.entry(metric.to_owned()).or_default() += 1;
+        *counters.formats.entry(format.to_owned()).or_default() += 1;
+    }
+
+    pub fn record_failure(&self) {
+        self.counters.lock().unwrap().failures += 1;
+    }
+
+    pub fn record_cache(&self, hit: bool) {
+        let mut counters = self.counters.lock().unwrap();
+        if hit {
+            counters.cache_hits += 1;
+        } else {
+            counters.cache_misses += 1;
+        }
+    }
+
+    pub fn record_key(&self, name: &str) {
+        *self
+            .counters
+            .lock()
+            .unwrap()
+            .api_keys
+            .entry(name.to_owned())
+            .or_default() += 1;
+    }
+
+    // Result cache hits and misses.
+    pub fn cache_lookups(&self) -> (u64, u64) {
+        let counters = self.counters.lock().unwrap();
+        (counters.cache_hits, counters.cache_misses)
+    }
+
+    pub fn save(&self) {
+        if let Some(key) = &self.key {
+            let json = serde_json::to_vec(&*self.counters.lock().unwrap()).unwrap();
+            if let Err(err) = self.storage.put(key, &json) {
+                warn!("could not save stats to {:?}: {}", key, err);
+            }
+        }
+    }
+
+    fn summary(&self) -> Value {
+        let counters = self.counters.lock().unwrap();
+        let average_duration_ms = if counters.conversions > 0 {
+            Some(counters.total_duration_ms / counters.conversions as f64)
+        } else {
+            None
+        };
+        let lookups = counters.cache_hits + counters.cache_misses;
+        let cache_hit_rate = if lookups > 0 {
+            Some(counters.cache_hits as f64 / lookups as f64)
+        } else {
+            None
+        };
+        json!({
+            "conversions": counters.conversions,
+            "failures": counters.failures,
+            "average_duration_ms": average_duration_ms,
+            "fonts": counters.fonts,
+            "alphabets": counters.alphabets,
+            "metrics": counters.metrics,
+            "formats": counters.formats,
+            "cache_hits": counters.cache_hits,
+            "cache_misses": counters.cache_misses,
+            "cache_hit_rate": cache_hit_rate,
+            "api_keys": counters.api_keys,
+        })
+    }
+}
+
+// Manages `Stats` (restoring persisted counters) and saves them again on shutdown. Must be attached
+// after the `Storage` fairing.
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Stats", |rocket| async {
+        let path = rocket
+            .state::<Config>()
+            .and_then(|config| config.stats.path.clone());
+        let storage = match rocket.state::<Arc<dyn Storage>>() {
+            Some(storage) => storage.clone(),
+            None => return rocket,
+        };
+        rocket
+            .manage(Stats::load(path, storage))
+            .attach(AdHoc::on_shutdown("Save stats", |rocket| {
+                Box::pin(async move {
+                    if let Some(stats) = rocket.state::<Stats>() {
+                        stats.save();
+                    }
+                })
+            }))
+    })
+}
+
+#[get("/stats")]
+pub fn stats(_auth: AdminAuth, stats: &State<Stats>) -> Json<Value> {
+    Json(stats.summary())
+}
@@ -0,0 +1,311 @@
+use hmac::{Hmac, Mac};
+use log::error;
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::{Method, StatusCode, Url};
+use rocket::fairing::AdHoc;
+use rocket::serde::Deserialize;
+use rocket::tokio::task::block_in_place;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind, Read};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+
+// Where results, counters and queues are kept, by key. Keys are relative paths such as
+// `permalinks/<id>.json`; backends are blocking, and only ever called off the async workers or for
+// small writes.
+pub trait Storage: Send + Sync {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+    fn put(&self, key: &str, value: &[u8]) -> io::Result<()>;
+    // Deleting a key that isn't there succeeds.
+    fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct StorageConfig {
+    // `disk`, `memory` or `s3`
+    pub backend: String,
+    // keys are resolved against this directory by the `disk` backend
+    pub dir: PathBuf,
+    pub s3: S3Config,
+}
+
+impl Default for StorageConfig {
+    fn default() -> StorageConfig {
+        StorageConfig {
+            backend: String::from("disk"),
+            dir: PathBuf::from("."),
+            s3: S3Config::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct S3Config {
+    pub bucket: String,
+    pub region: String,
+    // for S3-compatible services; defaults to AWS's endpoint for `region`
+    pub endpoint: Option<String>,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+impl Default for S3Config {
+    fn default() -> S3Config {
+        S3Config {
+            bucket: String::new(),
+            region: String::from("us-east-1"),
+            endpoint: None,
+            access_key_id: String::new(),
+            secret_access_key: String::new(),
+        }
+    }
+}
+
+pub struct Disk {
+    dir: PathBuf,
+}
+
+impl Storage for Disk {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.dir.join(key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        let path = self.dir.join(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, value)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.dir.join(key)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Kept for the life of the process only, for development and tests.
+#[derive(Default)]
+pub struct Memory {
+    values: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl Storage for Memory {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.values.lock().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        self.values
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        self.values.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+// Objects in an S3 bucket, addressed path-style and signed with AWS Signature Version 4.
+pub struct S3 {
+    config: S3Config,
+    endpoint: Url,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// `YYYYMMDD` and `YYYYMMDDTHHMMSSZ` for a unix time, as SigV4 wants them.
+fn amz_date(secs: u64) -> (String, String) {
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+    // civil date from days since the epoch, after Howard Hinnant's `civil_from_days`
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let time = format!("{:02}{:02}{:02}", secs / 3600, secs / 60 % 60, secs % 60);
+    (date.clone(), format!("{}T{}Z", date, time))
+}
+
+fn uri_encode(path: &str) -> String {
+    path.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl S3 {
+    pub fn new(config: S3Config) -> Result<S3, String> {
+        let endpoint = config
+            .endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", config.region));
+        let endpoint = Url::parse(&endpoint).map_err(|err| format!("invalid endpoint: {}", err))?;
+        if config.bucket.is_empty() {
+            return Err(String::from("no bucket set"));
+        }
+        Ok(S3 { config, endpoint })
+    }
+
+    fn request(&self, method: Method, key: &str, body: &[u8]) -> RequestBuilder {
+        let path = uri_encode(&format!(
+            "/{}/{}",
+            self.config.bucket,
+            key.trim_start_matches('/')
+        ));
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_owned(),
+        };
+
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let (date, timestamp) = amz_date(secs);
+        let payload_hash = hex::encode(Sha256::digest(body));
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, host, payload_hash, timestamp, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = format!("AWS4{}", self.config.secret_access_key);
+        let key = hmac(key.as_bytes(), &date);
+        let key = hmac(&key, &self.config.region);
+        let key = hmac(&key, "s3");
+        let key = hmac(&key, "aws4_request");
+        let signature = hex::encode(hmac(&key, &string_to_sign));
+
+        Client::new()
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", timestamp)
+            .header(
+                "Authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.config.access_key_id, scope, signed_headers, signature
+                ),
+            )
+            .body(body.to_vec())
+    }
+}
+
+fn io_error(err: reqwest::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+impl S3 {
+    // Like `get`, reading no more than the first `limit` bytes of the object.
+    pub fn get_prefix(&self, key: &str, limit: u64) -> io::Result<Option<Vec<u8>>> {
+        block_in_place(|| {
+            let response = self
+                .request(Method::GET, key, &[])
+                .send()
+                .map_err(io_error)?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let response = response.error_for_status().map_err(io_error)?;
+            let mut bytes = Vec::new();
+            response.take(limit).read_to_end(&mut bytes)?;
+            Ok(Some(bytes))
+        })
+    }
+}
+
+impl Storage for S3 {
+    // The blocking client can't run on an async worker as is, so its calls are moved off it.
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        self.get_prefix(key, u64::MAX)
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        block_in_place(|| {
+            self.request(Method::PUT, key, value)
+                .send()
+                .and_then(|response| response.error_for_status())
+                .map_err(io_error)?;
+            Ok(())
+        })
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        block_in_place(|| {
+            self.request(Method::DELETE, key, &[])
+                .send()
+                .and_then(|response| response.error_for_status())
+                .map_err(io_error)?;
+            Ok(())
+        })
+    }
+}
+
+pub fn from_config(config: &StorageConfig) -> Result<Arc<dyn Storage>, String> {
+    match config.backend.as_str() {
+        "disk" => Ok(Arc::new(Disk {
+            dir: config.dir.clone(),
+        })),
+        "memory" => Ok(Arc::new(Memory::default())),
+        "s3" => Ok(Arc::new(S3::new(config.s3.clone())?)),
+        backend => Err(format!("unknown backend {:?}", backend)),
+    }
+}
+
+// Manages the configured `Arc<dyn Storage>`, refusing to launch with an invalid one. Must be
+// attached after the `Config` fairing and before anything that stores through it.
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Storage", |rocket| async {
+        let config = rocket
+            .state::<Config>()
+            .map(|config| config.storage.clone())
+            .unwrap_or_default();
+        match from_config(&config) {
+            Ok(storage) => Ok(rocket.manage(storage)),
+            Err(err) => {
+                error!("invalid storage config: {}", err);
+                Err(rocket)
+            }
+        }
+    })
+}
@@ -0,0 +1,276 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rocket::http::Status;
+use rocket::serde::json::{Json, Value};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{post, State};
+use serde_json::Map;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::alphabets::Alphabets;
+use crate::config::Config;
+use crate::error::Error;
+use crate::fetch::Fetchers;
+use crate::logging::RequestId;
+use crate::metadata::Quality;
+use crate::negotiate::Representation;
+use crate::permalink::{PermalinkConfig, Permalinks};
+use crate::pools::Pools;
+use crate::profile::Profile;
+use crate::query::{resolve, Conversion};
+use crate::ratelimit::RateLimit;
+use crate::stats::Stats;
+use crate::storage::Memory;
+
+// The parameters a sweep can vary, named as in the JSON body of `POST /convert`.
+pub const SWEEPABLE: [&str; 22] = [
+    "width",
+    "height",
+    "font",
+    "alphabet",
+    "charset",
+    "metric",
+    "no_color",
+    "brightness_offset",
+    "contrast",
+    "gamma",
+    "noise_scale",
+    "no_edge_detection",
+    "cvd",
+    "blank_char",
+    "mode",
+    "color_mode",
+    "color_tolerance",
+    "themed_background",
+    "light_background",
+    "invert",
+    "dither",
+    "aspect",
+];
+
+// The most previews one sweep renders, each a conversion of its own.
+const MAX_PREVIEWS: usize = 36;
+
+// Previews are this wide when the base request doesn't say, so a grid of them stays small.
+const PREVIEW_WIDTH: usize = 40;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Axis {
+    param: String,
+    values: Vec<Value>,
+}
+
+// A base request as for the JSON body of `POST /convert`, along with the one or two parameters to
+// vary over it: the first gives the rows of the grid of previews, the second its columns.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SweepRequest {
+    image: Option<String>,
+    url: Option<String>,
+    profile: Option<String>,
+    sweep: Vec<Axis>,
+    #[serde(flatten)]
+    options: Map<String, Value>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Preview {
+    // the values of the swept parameters, in the order of the axes
+    values: Vec<Value>,
+    text: String,
+    // only measured in the glyph matching modes
+    quality: Option<Quality>,
+    ms: f64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Sweep {
+    sweep: Vec<Axis>,
+    previews: Vec<Vec<Preview>>,
+}
+
+// Every combination of the axes' values, row by row.
+fn combinations(axes: &[Axis]) -> Vec<Vec<Vec<Value>>> {
+    let columns = match axes.get(1) {
+        Some(axis) => axis
+            .values
+            .iter()
+            .map(|value| vec![value.clone()])
+            .collect(),
+        None => vec![Vec::new()],
+    };
+    axes[0]
+        .values
+        .iter()
+        .map(|row| {
+            columns
+                .iter()
+                .map(|column| [vec![row.clone()], column.clone()].concat())
+                .collect()
+        })
+        .collect()
+}
+
+// Resolves the base options with `values` of the axes' parameters in place, as the conversion of
+// one preview.
+fn resolve_preview(
+    config: &Config,
+    alphabets: &Alphabets,
+    request: &SweepRequest,
+    names: &[&'static str],
+    values: &[Value],
+) -> Result<Conversion, Error> {
+    for (&name, value) in names.iter().zip(values) {
+        let option = Value::Object(Map::from_iter([(String::from(name), value.clone())]));
+        if serde_json::from_value::<Profile>(option).is_err() {
+            return Err(Error::InvalidValue(name, format!("can't parse {}", value)));
+        }
+    }
+    let mut options = request.options.clone();
+    if !options.contains_key("width") && !names.contains(&"width") {
+        options.insert(String::from("width"), Value::from(PREVIEW_WIDTH));
+    }
+    for (&name, value) in names.iter().zip(values) {
+        options.insert(String::from(name), value.clone());
+        // a charset replaces the alphabet and the other way around, rather than clashing with it
+        match name {
+            "charset" => options.remove("alphabet"),
+            "alphabet" => options.remove("charset"),
+            _ => None,
+        };
+    }
+    let overrides: Profile = serde_json::from_value(Value::Object(options))
+        .map_err(|_| Error::InvalidParameter("sweep"))?;
+    let overrides = alphabets.expand(overrides);
+    resolve(config, request.profile.as_deref(), &overrides, true)
+}
+
+// Converts the same source once for every combination of one or two parameters' values, returning
+// the grid of small text previews with their match quality, for tuning parameters side by side.
+// Every combination is validated before anything is downloaded.
+#[post("/sweep", format = "json", data = "<request>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn sweep(
+    _limit: RateLimit,
+    request_id: RequestId,
+    request: Json<SweepRequest>,
+    config: &State<Config>,
+    stats: &State<Stats>,
+    pools: &State<Pools>,
+    fetchers: &State<Fetchers>,
+    alphabets: &State<Arc<Alphabets>>,
+) -> Result<Json<Sweep>, Error> {
+    let request = request.into_inner();
+    if request.sweep.is_empty() || request.sweep.len() > 2 {
+        return Err(Error::InvalidValue(
+            "sweep",
+            String::from("must vary one or two parameters"),
+        ));
+    }
+    if serde_json::from_value::<Profile>(Value::Object(request.options.clone())).is_err() {
+        return Err(Status::UnprocessableEntity.into());
+    }
+    let mut names = Vec::new();
+    for axis in &request.sweep {
+        let name = SWEEPABLE
+            .iter()
+            .find(|&&name| name == axis.param)
+            .ok_or_else(|| {
+                let reason = format!("can't vary {:?}, only {}", axis.param, SWEEPABLE.join(", "));
+                Error::InvalidValue("sweep", reason)
+            })?;
+        if names.contains(name) {
+            let reason = format!("varies {:?} twice", axis.param);
+            return Err(Error::InvalidValue("sweep", reason));
+        }
+        names.push(*name);
+    }
+    let count: usize = request.sweep.iter().map(|axis| axis.values.len()).product();
+    if count == 0 || count > MAX_PREVIEWS {
+        let reason = format!("must have between 1 and {} combinations", MAX_PREVIEWS);
+        return Err(Error::InvalidValue("sweep", reason));
+    }
+    let grid = combinations(&request.sweep)
+        .into_iter()
+        .map(|row| {
+            row.into_iter()
+                .map(|values| {
+                    let mut conversion =
+                        resolve_preview(config, alphabets, &request, &names, &values)?;
+                    conversion.request_id = Some(request_id.0.clone());
+                    Ok((values, conversion))
+                })
+                .collect::<Result<Vec<_>, Error>>()
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let bytes = match (&request.image, &request.url) {
+        (Some(image), None) => STANDARD
+            .decode(image)
+            .map_err(|_| Error::InvalidParameter("image"))?,
+        (None, Some(url)) => pools.download(fetchers.fetch(url)).await?,
+        _ => return Err(Error::InvalidParameter("image")),
+    };
+    if bytes.len() as u64 > config.conversion_limits.max_source_bytes {
+        return Err(Error::SourceTooLarge(
+            config.conversion_limits.max_source_bytes,
+        ));
+    }
+    let (bytes, image) = pools.decode(bytes).await?;
+    let (bytes, image) = (Arc::new(bytes), Arc::new(image));
+    let source = request.url.unwrap_or_else(|| String::from("upload"));
+    // previews aren't stored behind permalinks
+    let permalinks = Permalinks::new(&PermalinkConfig::default(), Arc::new(Memory::default()));
+
+    let mut previews = Vec::new();
+    for row in grid {
+        let mut row_previews = Vec::new();
+        for (values, conversion) in row {
+            let labels = (
+                conversion.font.clone(),
+                conversion.alphabet.clone(),
+                conversion.metric.clone(),
+            );
+            let (bytes, image) = (bytes.clone(), image.clone());
+            let (source, permalinks) = (source.clone(), permalinks.clone());
+            let t0 = Instant::now();
+            let result = pools
+                .convert(move || {
+                    let representation = Representation::Text;
+                    conversion.render_negotiated(
+                        &bytes,
+                        &image,
+                        &source,
+                        representation,
+                        &permalinks,
+                    )
+                })
+                .await
+                .and_then(|result| result);
+            let ((_, text), _, _, quality) = match result {
+                Ok(rendered) => rendered,
+                Err(err) => {
+                    stats.record_failure();
+                    return Err(err);
+                }
+            };
+            let (font, alphabet, metric) = labels;
+            stats.record(&font, &alphabet, &metric, "sweep", t0.elapsed());
+            row_previews.push(Preview {
+                values,
+                text: String::from_utf8(text).unwrap(),
+                quality,
+                ms: t0.elapsed().as_secs_f64() * 1000.,
+            });
+        }
+        previews.push(row_previews);
+    }
+    Ok(Json(Sweep {
+        sweep: request.sweep,
+        previews,
+    }))
+}
@@ -0,0 +1,343 @@
+use image::{DynamicImage, GenericImageView};
+use reqwest::blocking::Client;
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::json::{Json, Value};
+use rocket::serde::Deserialize;
+use rocket::tokio::task::block_in_place;
+use rocket::{post, State};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::config::Config;
+use crate::fetch::Fetchers;
+use crate::font::Font;
+use crate::generate::{image_to_text, load_alphabet, load_font, Params};
+use crate::pools::Pools;
+use crate::ratelimit::{LimitStore, Limiter};
+use crate::stats::Stats;
+
+const API_URL: &str = "https://api.telegram.org";
+// Telegram's limit on message length, minus the `<pre></pre>` wrapper.
+const MAX_MESSAGE_CHARS: usize = 4096 - 11;
+// Photos arrive in several sizes; the smallest one at least this wide is plenty for chat widths.
+const MIN_PHOTO_WIDTH: u32 = 320;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct TelegramConfig {
+    pub token: Option<String>,
+    pub secret_token: Option<String>,
+    pub max_width: usize,
+    pub font: String,
+    pub alphabet: String,
+}
+
+impl Default for TelegramConfig {
+    fn default() -> TelegramConfig {
+        TelegramConfig {
+            token: None,
+            secret_token: None,
+            max_width: 40,
+            font: String::from("bitocra-13"),
+            alphabet: String::from("letters"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Update {
+    message: Option<Message>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Message {
+    chat: Chat,
+    // missing from messages posted to channels
+    from: Option<User>,
+    text: Option<String>,
+    photo: Option<Vec<PhotoSize>>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Chat {
+    id: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct User {
+    id: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct PhotoSize {
+    file_id: String,
+    width: u32,
+}
+
+// Telegram echoes the secret given to `setWebhook` in this header on every update.
+pub struct TelegramWebhook;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TelegramWebhook {
+    type Error = &'static str;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        // without a secret anyone could post updates, so the webhook is only enabled with both
+        let secret = match req.rocket().state::<Config>() {
+            Some(config) if config.telegram.token.is_some() => {
+                config.telegram.secret_token.as_ref()
+            }
+            _ => None,
+        };
+        let secret = match secret {
+            Some(secret) => secret,
+            None => return Outcome::Error((Status::NotFound, "telegram integration is disabled")),
+        };
+        let header = req.headers().get_one("X-Telegram-Bot-Api-Secret-Token");
+        if header == Some(secret.as_str()) {
+            Outcome::Success(TelegramWebhook)
+        } else {
+            Outcome::Error((Status::Unauthorized, "invalid telegram secret token"))
+        }
+    }
+}
+
+// Only ever used from blocking worker threads, where the blocking client is safe to create and drop.
+#[derive(Clone)]
+struct Bot {
+    token: String,
+}
+
+impl Bot {
+    fn call(&self, method: &str, form: &[(&str, &str)]) -> Result<Value, String> {
+        let response: Value = Client::new()
+            .post(format!("{}/bot{}/{}", API_URL, self.token, method))
+            .form(form)
+            .send()
+            .and_then(|response| response.text())
+            // the URL has the bot's token in it
+            .map_err(|err| err.without_url().to_string())
+            .and_then(|body| serde_json::from_str(&body).map_err(|err| err.to_string()))?;
+        if response["ok"].as_bool() == Some(true) {
+            Ok(response["result"].clone())
+        } else {
+            Err(response["description"]
+                .as_str()
+                .unwrap_or("telegram api error")
+                .to_owned())
+        }
+    }
+
+    fn file_url(&self, file_id: &str) -> Result<String, String> {
+        let file = self.call("getFile", &[("file_id", file_id)])?;
+        match file["file_path"].as_str() {
+            Some(path) => Ok(format!("{}/file/bot{}/{}", API_URL, self.token, path)),
+            None => Err(String::from("telegram returned no file path")),
+        }
+    }
+
+    fn send_message(&self, chat_id: i64, text: &str, html: bool) -> Result<(), String> {
+        let chat_id = chat_id.to_string();
+        let mut form = vec![("chat_id", chat_id.as_str()), ("text", text)];
+        if html {
+            form.push(("parse_mode", "HTML"));
+        }
+        self.call("sendMessage", &form).map(|_| ())
+    }
+}
+
+// Picks the widest output that still fits in a single message once rows and newlines are counted.
+fn fit_width(image: &DynamicImage, font: &Font, max_width: usize) -> usize {
+    let (width, height) = image.dimensions();
+    let ratio = (height as f64 / width as f64) * (font.width as f64 / font.height as f64);
+    (1..=max_width)
+        .rev()
+        .find(|&width| {
+            let rows = (width as f64 * ratio).round() as usize;
+            rows * (width + 1) <= MAX_MESSAGE_CHARS
+        })
+        .unwrap_or(1)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// Resolves the URL of the image a message refers to, replying directly to messages that don't
+// carry one.
+fn image_url(bot: &Bot, message: &Message) -> Result<Option<String>, String> {
+    let image_url = match (&message.photo, &message.text) {
+        (Some(photos), _) => {
+            let photo = photos
+                .iter()
+                .find(|photo| photo.width >= MIN_PHOTO_WIDTH)
+                .or_else(|| photos.last());
+            match photo {
+                Some(photo) => bot.file_url(&photo.file_id)?,
+                None => return Ok(None),
+            }
+        }
+        (None, Some(text)) if text.starts_with("http://") || text.starts_with("https://") => {
+            text.trim().to_owned()
+        }
+        _ => {
+            return bot
+                .send_message(
+                    message.chat.id,
+                    "Send me a photo or an image URL and I'll reply with ASCII art.",
+                    false,
+                )
+                .map(|_| None)
+        }
+    };
+
+    Ok(Some(image_url))
+}
+
+// Tells the chat why its image wasn't converted.
+async fn reply_error(pools: &Pools, bot: Bot, chat_id: i64, text: String) -> Result<bool, String> {
+    pools
+        .fetch(move || bot.send_message(chat_id, &text, false))
+        .await
+        .map_err(|err| err.to_string())?
+        .map(|_| false)
+}
+
+// Returns whether an image was actually converted.
+async fn handle_message(
+    config: TelegramConfig,
+    bot: Bot,
+    pools: &Pools,
+    fetchers: &Fetchers,
+    message: Message,
+) -> Result<bool, String> {
+    let chat_id = message.chat.id;
+    let fetch_bot = bot.clone();
+    let image_url = match pools
+        .fetch(move || image_url(&fetch_bot, &message))
+        .await
+        .map_err(|err| err.to_string())??
+    {
+        Some(image_url) => image_url,
+        None => return Ok(false),
+    };
+    let source = async {
+        let bytes = pools.download(fetchers.fetch(&image_url)).await?;
+        pools.decode(bytes).await
+    };
+    let image = match source.await {
+        Ok((_, image)) => image,
+        Err(err) => {
+            return reply_error(pools, bot, chat_id, err.to_string()).await;
+        }
+    };
+
+    let ascii = pools
+        .convert(move || {
+            let alphabet = load_alphabet(&config.alphabet);
+            let font = load_font(&config.font, &alphabet)?;
+            let args = Params {
+                font: &config.font,
+                alphabet: &config.alphabet,
+                no_color: true,
+                ..Params::defaults_for(&image_url, fit_width(&image, &font, config.max_width))
+            };
+            Ok(image_to_text(&image, &font, &args))
+        })
+        .await
+        .and_then(|ascii| ascii);
+    let ascii = match ascii {
+        Ok(ascii) => ascii,
+        Err(err) => {
+            return reply_error(pools, bot, chat_id, err.to_string()).await;
+        }
+    };
+
+    pools
+        .fetch(move || {
+            bot.send_message(
+                chat_id,
+                &format!("<pre>{}</pre>", escape_html(&ascii)),
+                true,
+            )
+        })
+        .await
+        .map_err(|err| err.to_string())?
+        .map(|_| true)
+}
+
+// Always acknowledges the update: Telegram redelivers anything that isn't answered with a 2xx, and
+// a failing conversion shouldn't be retried forever.
+#[post("/integrations/telegram", format = "json", data = "<update>")]
+pub async fn telegram_webhook(
+    _webhook: TelegramWebhook,
+    update: Json<Update>,
+    config: &State<Config>,
+    limits: &State<Arc<dyn LimitStore>>,
+    stats: &State<Stats>,
+    pools: &State<Pools>,
+    fetchers: &State<Fetchers>,
+) -> Status {
+    let message = match update.into_inner().message {
+        Some(message) => message,
+        None => return Status::Ok,
+    };
+    // every update comes from Telegram's servers, so senders are told apart by who they are, and
+    // those refused are dropped quietly rather than each answered through Telegram's own limits
+    let sender = message
+        .from
+        .as_ref()
+        .map_or(message.chat.id, |user| user.id);
+    let limiter = Limiter::keyed(&config.rate_limit, limits, format!("telegram:{}", sender));
+    if block_in_place(|| limiter.take()).is_err() {
+        return Status::Ok;
+    }
+    let config = config.telegram.clone();
+    let labels = (config.font.clone(), config.alphabet.clone());
+    let bot = Bot {
+        token: config.token.clone().unwrap(),
+    };
+    let t0 = Instant::now();
+    match handle_message(config, bot, pools, fetchers, message).await {
+        Ok(true) => stats.record(&labels.0, &labels.1, "grad", "telegram", t0.elapsed()),
+        Ok(false) => {}
+        Err(err) => {
+            stats.record_failure();
+            log::warn!("telegram update failed: {}", err)
+        }
+    }
+    Status::Ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    #[test]
+    fn fits_the_widest_art_in_a_message() {
+        let font = load_font("bitocra-13", &load_alphabet("letters")).unwrap();
+        let wide = DynamicImage::ImageRgb8(RgbImage::new(400, 100));
+        assert_eq!(fit_width(&wide, &font, 40), 40);
+        let tall = DynamicImage::ImageRgb8(RgbImage::new(100, 4000));
+        let width = fit_width(&tall, &font, 40);
+        let ratio = 40. * (font.width as f64 / font.height as f64);
+        let rows = |width: usize| (width as f64 * ratio).round() as usize;
+        assert!(rows(width) * (width + 1) <= MAX_MESSAGE_CHARS);
+        assert!(rows(width + 1) * (width + 2) > MAX_MESSAGE_CHARS);
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(escape_html("<b>&</b>"), "&lt;b&gt;&amp;&lt;/b&gt;");
+    }
+}
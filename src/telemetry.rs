@@ -0,0 +1,173 @@
+use rocket::fairing::AdHoc;
+use rocket::http::ContentType;
+use rocket::{get, State};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::auth::AdminAuth;
+use crate::stats::Stats;
+
+// Upper bounds of the histograms' buckets, in seconds and in bytes; each also has a `+Inf` one.
+const SECONDS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30.,
+];
+const BYTES: &[f64] = &[
+    256., 1024., 4096., 16384., 65536., 262144., 1048576., 4194304., 16777216.,
+];
+const MAX_BUCKETS: usize = 16;
+
+// The routes whose responses are conversions, whose sizes are observed.
+const CONVERTING_ROUTES: [&str; 8] = [
+    "get_image_url",
+    "convert",
+    "convert_json",
+    "badge",
+    "text_banner",
+    "qr",
+    "embed_js",
+    "permalink",
+];
+
+struct Histogram {
+    bounds: &'static [f64],
+    // observations at or under each bound, not counting those under the bounds before it
+    counts: [u64; MAX_BUCKETS],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    const fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            counts: [0; MAX_BUCKETS],
+            sum: 0.,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|&bound| value <= bound) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    // As Prometheus's text format has it, with cumulative buckets.
+    fn write(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(
+            out,
+            "{}_sum {}\n{}_count {}",
+            name, self.sum, name, self.count
+        );
+    }
+}
+
+// What the server spends its time and bytes on, since it launched. Kept globally rather than as
+// managed state, since frames are timed deep inside conversions that don't see the `Rocket`.
+struct Telemetry {
+    // by route name and status
+    requests: BTreeMap<(String, u16), u64>,
+    download_seconds: Histogram,
+    frame_seconds: Histogram,
+    output_bytes: Histogram,
+}
+
+static TELEMETRY: Mutex<Telemetry> = Mutex::new(Telemetry {
+    requests: BTreeMap::new(),
+    download_seconds: Histogram::new(SECONDS),
+    frame_seconds: Histogram::new(SECONDS),
+    output_bytes: Histogram::new(BYTES),
+});
+
+pub fn observe_download(took: Duration) {
+    let mut telemetry = TELEMETRY.lock().unwrap();
+    telemetry.download_seconds.observe(took.as_secs_f64());
+}
+
+// Observes `frames` converted in `took` as that many frames of the average time.
+pub fn observe_frames(took: Duration, frames: usize) {
+    let mut telemetry = TELEMETRY.lock().unwrap();
+    let per_frame = took.as_secs_f64() / frames.max(1) as f64;
+    for _ in 0..frames {
+        telemetry.frame_seconds.observe(per_frame);
+    }
+}
+
+// Counts every response by route and status, and observes the sizes of conversions.
+pub fn stage() -> AdHoc {
+    AdHoc::on_response("Telemetry", |req, res| {
+        Box::pin(async move {
+            let route = req
+                .route()
+                .and_then(|route| route.name.as_deref())
+                .unwrap_or("unmatched");
+            let status = res.status();
+            let mut telemetry = TELEMETRY.lock().unwrap();
+            *telemetry
+                .requests
+                .entry((String::from(route), status.code))
+                .or_default() += 1;
+            if status.class().is_success() && CONVERTING_ROUTES.contains(&route) {
+                if let Some(size) = res.body().preset_size() {
+                    telemetry.output_bytes.observe(size as f64);
+                }
+            }
+        })
+    })
+}
+
+// The counters and histograms in Prometheus's text exposition format, for it to scrape.
+#[get("/metrics")]
+pub fn metrics(_auth: AdminAuth, stats: &State<Stats>) -> (ContentType, String) {
+    let mut out = String::new();
+    {
+        let telemetry = TELEMETRY.lock().unwrap();
+        out.push_str("# HELP image_to_ascii_requests_total Responses by route and status.\n");
+        out.push_str("# TYPE image_to_ascii_requests_total counter\n");
+        for ((route, status), count) in &telemetry.requests {
+            let _ = writeln!(
+                out,
+                "image_to_ascii_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                route, status, count
+            );
+        }
+        telemetry.download_seconds.write(
+            &mut out,
+            "image_to_ascii_download_seconds",
+            "Time taken to download sources.",
+        );
+        telemetry.frame_seconds.write(
+            &mut out,
+            "image_to_ascii_frame_conversion_seconds",
+            "Time taken to convert each frame to a grid.",
+        );
+        telemetry.output_bytes.write(
+            &mut out,
+            "image_to_ascii_output_bytes",
+            "Sizes of converted responses.",
+        );
+    }
+    let (hits, misses) = stats.cache_lookups();
+    out.push_str("# HELP image_to_ascii_cache_lookups_total Result cache lookups by result.\n");
+    out.push_str("# TYPE image_to_ascii_cache_lookups_total counter\n");
+    for (result, count) in [("hit", hits), ("miss", misses)] {
+        let _ = writeln!(
+            out,
+            "image_to_ascii_cache_lookups_total{{result=\"{}\"}} {}",
+            result, count
+        );
+    }
+    let content_type = ContentType::new("text", "plain").with_params(("version", "0.0.4"));
+    (content_type, out)
+}
@@ -0,0 +1,254 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, Rgb, RgbImage};
+use rocket::serde::Serialize;
+
+use crate::convert::dominant_colors;
+use crate::font::Font;
+use crate::generate::{output_colors, use_color, Params};
+use crate::metadata::hex_color;
+
+// The most colors a palette holds, so a color index fits in a byte.
+const MAX_PALETTE: usize = 256;
+
+const MAGIC: &[u8; 4] = b"TMAP";
+const VERSION: u8 = 1;
+// Bits of the binary header's flags byte, set when every cell has a color index, and a background
+// color index after it.
+const HAS_COLORS: u8 = 1;
+const HAS_BACKGROUNDS: u8 = 2;
+// Set when the compact cells' tile indices take two bytes, there being more than 256 tiles.
+const WIDE_INDICES: u8 = 4;
+
+const CELLS_MAGIC: &[u8; 4] = b"CELL";
+
+// A grid as indices into a table of tiles, its characters, and when colored into a palette, for
+// game engines and LED matrix firmware that would rather not parse text.
+pub struct Tilemap {
+    columns: usize,
+    rows: usize,
+    // the font's characters for the alphabet in order, so indices stay the same across images,
+    // followed by any others the grid has such as a blank character
+    tiles: Vec<char>,
+    // row by row from the top left, as are the color indices
+    indices: Vec<usize>,
+    palette: Vec<[u8; 3]>,
+    colors: Option<Vec<usize>>,
+    // only for the block modes, which color both halves of their cells
+    backgrounds: Option<Vec<usize>>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TilemapJson {
+    columns: usize,
+    rows: usize,
+    tiles: Vec<String>,
+    indices: Vec<Vec<usize>>,
+    palette: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    colors: Option<Vec<Vec<usize>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backgrounds: Option<Vec<Vec<usize>>>,
+}
+
+// The font's characters followed by the grid's others, and the index of every cell's into them.
+fn tiles_of(char_rows: &[Vec<char>], font: &Font) -> (Vec<char>, Vec<usize>) {
+    let mut tiles: Vec<char> = font.chars.iter().map(|c| c.value).collect();
+    let mut indices = Vec::new();
+    for &c in char_rows.iter().flatten() {
+        let index = match tiles.iter().position(|&tile| tile == c) {
+            Some(index) => index,
+            None => {
+                tiles.push(c);
+                tiles.len() - 1
+            }
+        };
+        indices.push(index);
+    }
+    (tiles, indices)
+}
+
+// The color of every cell, sampled from `image` as the HTML output does.
+fn cell_colors(image: &DynamicImage, columns: usize, rows: usize) -> Vec<[u8; 3]> {
+    image
+        .resize_exact(columns as u32, rows as u32, FilterType::Nearest)
+        .to_rgb8()
+        .pixels()
+        .map(|pixel| pixel.0)
+        .collect()
+}
+
+// The distinct colors of `colors` in order of appearance, or when there are too many, the most
+// common of them.
+fn palette_of(colors: &[[u8; 3]]) -> Vec<[u8; 3]> {
+    let mut palette = Vec::new();
+    for color in colors {
+        if !palette.contains(color) {
+            palette.push(*color);
+            if palette.len() > MAX_PALETTE {
+                // square, so the thumbnail they're counted on keeps as many of them as it can
+                let side = (colors.len() as f64).sqrt().ceil() as u32;
+                let swatch = RgbImage::from_fn(side, side, |x, y| {
+                    Rgb(colors[(y * side + x) as usize % colors.len()])
+                });
+                return dominant_colors(&DynamicImage::ImageRgb8(swatch), MAX_PALETTE);
+            }
+        }
+    }
+    palette
+}
+
+fn nearest(palette: &[[u8; 3]], color: &[u8; 3]) -> usize {
+    let distance = |other: &[u8; 3]| -> i32 {
+        other
+            .iter()
+            .zip(color)
+            .map(|(&a, &b)| (a as i32 - b as i32).pow(2))
+            .sum()
+    };
+    (0..palette.len())
+        .min_by_key(|&i| distance(&palette[i]))
+        .unwrap_or(0)
+}
+
+impl Tilemap {
+    pub fn new(char_rows: &[Vec<char>], font: &Font, image: &DynamicImage, args: &Params) -> Self {
+        let (columns, rows) = (char_rows[0].len(), char_rows.len());
+        let (tiles, indices) = tiles_of(char_rows, font);
+        let (mut palette, mut colors, mut backgrounds) = (Vec::new(), None, None);
+        if use_color(args) {
+            let (foreground, background) = output_colors(char_rows, image, args);
+            let foreground = cell_colors(&foreground, columns, rows);
+            let background = background.map(|image| cell_colors(&image, columns, rows));
+            let all = [foreground.clone(), background.clone().unwrap_or_default()].concat();
+            palette = palette_of(&all);
+            let index = |colors: Vec<[u8; 3]>| {
+                colors
+                    .iter()
+                    .map(|color| nearest(&palette, color))
+                    .collect()
+            };
+            colors = Some(index(foreground));
+            backgrounds = background.map(index);
+        }
+        Tilemap {
+            columns,
+            rows,
+            tiles,
+            indices,
+            palette,
+            colors,
+            backgrounds,
+        }
+    }
+
+    pub fn to_json(&self) -> Vec<u8> {
+        let rows = |cells: &[usize]| -> Vec<Vec<usize>> {
+            cells.chunks(self.columns).map(|row| row.to_vec()).collect()
+        };
+        let json = TilemapJson {
+            columns: self.columns,
+            rows: self.rows,
+            tiles: self.tiles.iter().map(|c| c.to_string()).collect(),
+            indices: rows(&self.indices),
+            palette: self.palette.iter().map(hex_color).collect(),
+            colors: self.colors.as_deref().map(rows),
+            backgrounds: self.backgrounds.as_deref().map(rows),
+        };
+        serde_json::to_vec(&json).unwrap()
+    }
+
+    // The magic `TMAP`, a version byte and a flags byte, then little-endian u16 columns and rows,
+    // the u16 count of tiles and each as a u32 code point, the u16 count of palette colors and each
+    // as three RGB bytes, and last every cell: a u16 tile index, then a color index byte and a
+    // background one when the flags say.
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.colors.is_some() {
+            flags |= HAS_COLORS;
+        }
+        if self.backgrounds.is_some() {
+            flags |= HAS_BACKGROUNDS;
+        }
+        let mut bytes = [&MAGIC[..], &[VERSION, flags][..]].concat();
+        let push_u16 =
+            |bytes: &mut Vec<u8>, n: usize| bytes.extend_from_slice(&(n as u16).to_le_bytes());
+        push_u16(&mut bytes, self.columns);
+        push_u16(&mut bytes, self.rows);
+        push_u16(&mut bytes, self.tiles.len());
+        for &tile in &self.tiles {
+            bytes.extend_from_slice(&(tile as u32).to_le_bytes());
+        }
+        push_u16(&mut bytes, self.palette.len());
+        for color in &self.palette {
+            bytes.extend_from_slice(color);
+        }
+        for (i, &index) in self.indices.iter().enumerate() {
+            push_u16(&mut bytes, index);
+            if let Some(colors) = &self.colors {
+                bytes.push(colors[i] as u8);
+            }
+            if let Some(backgrounds) = &self.backgrounds {
+                bytes.push(backgrounds[i] as u8);
+            }
+        }
+        bytes
+    }
+}
+
+// 5 bits of red, 6 of green and 5 of blue, as small displays take them.
+fn rgb565([r, g, b]: [u8; 3]) -> u16 {
+    ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3)
+}
+
+// The grid at its most compact, for clients on slow links: the magic `CELL`, a version byte and a
+// flags byte, little-endian u16 columns and rows, the u16 count of tiles and the u16 length of
+// their UTF-8, then every cell as a tile index byte, two when the flags say, followed by its
+// color and then its background color as RGB565 when the flags say.
+pub fn compact_cells(
+    char_rows: &[Vec<char>],
+    font: &Font,
+    image: &DynamicImage,
+    args: &Params,
+) -> Vec<u8> {
+    let (columns, rows) = (char_rows[0].len(), char_rows.len());
+    let (tiles, indices) = tiles_of(char_rows, font);
+    let (colors, backgrounds) = match use_color(args) {
+        true => {
+            let (foreground, background) = output_colors(char_rows, image, args);
+            (
+                Some(cell_colors(&foreground, columns, rows)),
+                background.map(|image| cell_colors(&image, columns, rows)),
+            )
+        }
+        false => (None, None),
+    };
+    let wide = tiles.len() > 256;
+    let mut flags = 0;
+    for (set, flag) in [
+        (colors.is_some(), HAS_COLORS),
+        (backgrounds.is_some(), HAS_BACKGROUNDS),
+        (wide, WIDE_INDICES),
+    ] {
+        if set {
+            flags |= flag;
+        }
+    }
+    let tiles: String = tiles.into_iter().collect();
+    let mut bytes = [&CELLS_MAGIC[..], &[VERSION, flags][..]].concat();
+    for n in [columns, rows, tiles.chars().count(), tiles.len()] {
+        bytes.extend_from_slice(&(n as u16).to_le_bytes());
+    }
+    bytes.extend_from_slice(tiles.as_bytes());
+    for (i, &index) in indices.iter().enumerate() {
+        if wide {
+            bytes.extend_from_slice(&(index as u16).to_le_bytes());
+        } else {
+            bytes.push(index as u8);
+        }
+        for colors in [&colors, &backgrounds].into_iter().flatten() {
+            bytes.extend_from_slice(&rgb565(colors[i]).to_le_bytes());
+        }
+    }
+    bytes
+}
@@ -0,0 +1,469 @@
+use std::collections::HashMap;
+
+// A reader for TrueType fonts, `.ttf` and `.otf` files with TrueType outlines alike, that's just
+// enough to draw glyphs at a pixel size. Hinting is ignored, as glyphs are matched by their
+// coverage rather than read.
+pub struct TrueType<'a> {
+    tables: HashMap<[u8; 4], &'a [u8]>,
+    cmap: Cmap<'a>,
+    units_per_em: f32,
+    long_loca: bool,
+    num_glyphs: u16,
+    ascender: i16,
+    descender: i16,
+    num_h_metrics: u16,
+}
+
+enum Cmap<'a> {
+    // segments mapping the Basic Multilingual Plane
+    Format4(&'a [u8]),
+    // groups mapping every plane
+    Format12(&'a [u8]),
+}
+
+// A glyph outline's contours as closed polylines, in font units with y pointing up.
+type Contours = Vec<Vec<(f32, f32)>>;
+
+// A straight piece of an outline, from one point to the next, in pixels.
+type Edge = ((f32, f32), (f32, f32));
+
+// Quadratic curves are drawn as this many line segments.
+const CURVE_STEPS: usize = 8;
+// Rows are sampled this many times over, columns exactly.
+const SUBSAMPLES: usize = 4;
+// How deeply composite glyphs may nest.
+const MAX_DEPTH: usize = 8;
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8], String> {
+    data.get(offset..offset + len)
+        .ok_or_else(|| String::from("truncated font"))
+}
+
+fn u8_at(data: &[u8], offset: usize) -> Result<u8, String> {
+    Ok(slice(data, offset, 1)?[0])
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, String> {
+    let bytes = slice(data, offset, 2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn i16_at(data: &[u8], offset: usize) -> Result<i16, String> {
+    Ok(u16_at(data, offset)? as i16)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, String> {
+    let bytes = slice(data, offset, 4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// A 2.14 fixed point number, as composite glyphs are scaled by.
+fn f2dot14_at(data: &[u8], offset: usize) -> Result<f32, String> {
+    Ok(i16_at(data, offset)? as f32 / 16384.)
+}
+
+// Whether `data` starts like a TrueType or OpenType file, as opposed to a BDF one.
+pub fn is_font(data: &[u8]) -> bool {
+    matches!(
+        data.get(..4),
+        Some(b"ttcf" | [0, 1, 0, 0] | b"true" | b"OTTO")
+    )
+}
+
+impl<'a> TrueType<'a> {
+    // Parses the first font of a `.ttf`, `.otf` or `.ttc` file.
+    pub fn parse(data: &'a [u8]) -> Result<TrueType<'a>, String> {
+        let start = match slice(data, 0, 4)? {
+            b"ttcf" => u32_at(data, 12)? as usize,
+            _ => 0,
+        };
+        match slice(data, start, 4)? {
+            [0, 1, 0, 0] | b"true" => {}
+            b"OTTO" => return Err(String::from("CFF outlines aren't supported")),
+            _ => return Err(String::from("not a TrueType font")),
+        }
+        let mut tables = HashMap::new();
+        for i in 0..u16_at(data, start + 4)? as usize {
+            let record = start + 12 + 16 * i;
+            let tag = slice(data, record, 4)?;
+            let offset = u32_at(data, record + 8)? as usize;
+            let len = u32_at(data, record + 12)? as usize;
+            tables.insert([tag[0], tag[1], tag[2], tag[3]], slice(data, offset, len)?);
+        }
+        let table = |tag: &[u8; 4]| {
+            tables
+                .get(tag)
+                .copied()
+                .ok_or_else(|| format!("no {} table", String::from_utf8_lossy(tag)))
+        };
+        let (head, maxp, hhea) = (table(b"head")?, table(b"maxp")?, table(b"hhea")?);
+        table(b"glyf")?;
+        table(b"loca")?;
+        table(b"hmtx")?;
+        let cmap = Self::find_cmap(table(b"cmap")?)?;
+        Ok(TrueType {
+            cmap,
+            units_per_em: u16_at(head, 18)?.max(1) as f32,
+            long_loca: i16_at(head, 50)? != 0,
+            num_glyphs: u16_at(maxp, 4)?,
+            ascender: i16_at(hhea, 4)?,
+            descender: i16_at(hhea, 6)?,
+            num_h_metrics: u16_at(hhea, 34)?.max(1),
+            tables,
+        })
+    }
+
+    // The Unicode subtable, preferring the one covering every plane.
+    fn find_cmap(cmap: &'a [u8]) -> Result<Cmap<'a>, String> {
+        let mut found = None;
+        for i in 0..u16_at(cmap, 2)? as usize {
+            let record = 4 + 8 * i;
+            let platform = u16_at(cmap, record)?;
+            let encoding = u16_at(cmap, record + 2)?;
+            let offset = u32_at(cmap, record + 4)? as usize;
+            if !matches!((platform, encoding), (0, _) | (3, 1) | (3, 10)) {
+                continue;
+            }
+            match u16_at(cmap, offset)? {
+                12 => {
+                    let len = u32_at(cmap, offset + 4)? as usize;
+                    return Ok(Cmap::Format12(slice(cmap, offset, len)?));
+                }
+                4 => {
+                    let len = u16_at(cmap, offset + 2)? as usize;
+                    found = Some(Cmap::Format4(slice(cmap, offset, len)?));
+                }
+                _ => {}
+            }
+        }
+        found.ok_or_else(|| String::from("no Unicode cmap subtable"))
+    }
+
+    fn table(&self, tag: &[u8; 4]) -> &'a [u8] {
+        self.tables[tag]
+    }
+
+    // The glyph drawing `c`, if the font has one.
+    pub fn glyph_index(&self, c: char) -> Option<u16> {
+        let glyph = match self.cmap {
+            Cmap::Format4(subtable) => Self::format4_index(subtable, c as u32).ok()?,
+            Cmap::Format12(subtable) => Self::format12_index(subtable, c as u32).ok()?,
+        };
+        (glyph != 0 && glyph < self.num_glyphs).then_some(glyph)
+    }
+
+    fn format4_index(subtable: &[u8], c: u32) -> Result<u16, String> {
+        if c > 0xffff {
+            return Ok(0);
+        }
+        let seg_count_x2 = u16_at(subtable, 6)? as usize;
+        let (ends, starts) = (14, 16 + seg_count_x2);
+        let (deltas, range_offsets) = (starts + seg_count_x2, starts + 2 * seg_count_x2);
+        for segment in (0..seg_count_x2).step_by(2) {
+            if u16_at(subtable, ends + segment)? as u32 >= c {
+                let start = u16_at(subtable, starts + segment)? as u32;
+                if start > c {
+                    return Ok(0);
+                }
+                let delta = u16_at(subtable, deltas + segment)?;
+                let range_offset = u16_at(subtable, range_offsets + segment)? as usize;
+                if range_offset == 0 {
+                    return Ok((c as u16).wrapping_add(delta));
+                }
+                let offset = range_offsets + segment + range_offset + 2 * (c - start) as usize;
+                return match u16_at(subtable, offset)? {
+                    0 => Ok(0),
+                    glyph => Ok(glyph.wrapping_add(delta)),
+                };
+            }
+        }
+        Ok(0)
+    }
+
+    fn format12_index(subtable: &[u8], c: u32) -> Result<u16, String> {
+        for group in 0..u32_at(subtable, 12)? as usize {
+            let group = 16 + 12 * group;
+            let (start, end) = (u32_at(subtable, group)?, u32_at(subtable, group + 4)?);
+            if (start..=end).contains(&c) {
+                return Ok((u32_at(subtable, group + 8)? + c - start) as u16);
+            }
+        }
+        Ok(0)
+    }
+
+    // How far the pen moves after drawing `glyph`, in font units.
+    fn advance(&self, glyph: u16) -> f32 {
+        let metric = glyph.min(self.num_h_metrics - 1) as usize;
+        u16_at(self.table(b"hmtx"), 4 * metric).unwrap_or(0) as f32
+    }
+
+    fn glyph_data(&self, glyph: u16) -> Result<&'a [u8], String> {
+        let loca = self.table(b"loca");
+        let glyph = glyph as usize;
+        let (start, end) = if self.long_loca {
+            (
+                u32_at(loca, 4 * glyph)? as usize,
+                u32_at(loca, 4 * glyph + 4)? as usize,
+            )
+        } else {
+            (
+                2 * u16_at(loca, 2 * glyph)? as usize,
+                2 * u16_at(loca, 2 * glyph + 2)? as usize,
+            )
+        };
+        slice(self.table(b"glyf"), start, end.saturating_sub(start))
+    }
+
+    fn outline(&self, glyph: u16, depth: usize) -> Result<Contours, String> {
+        let data = self.glyph_data(glyph)?;
+        // blank glyphs such as the space have no outline at all
+        if data.is_empty() {
+            return Ok(Vec::new());
+        }
+        let contours = i16_at(data, 0)?;
+        if contours >= 0 {
+            Self::simple_outline(data, contours as usize)
+        } else if depth < MAX_DEPTH {
+            self.composite_outline(data, depth)
+        } else {
+            Err(String::from("composite glyphs nest too deeply"))
+        }
+    }
+
+    fn simple_outline(data: &[u8], contours: usize) -> Result<Contours, String> {
+        let mut ends = Vec::with_capacity(contours);
+        for i in 0..contours {
+            ends.push(u16_at(data, 10 + 2 * i)? as usize);
+        }
+        let points = ends.last().map_or(0, |end| end + 1);
+        let instructions = u16_at(data, 10 + 2 * contours)? as usize;
+        let mut offset = 12 + 2 * contours + instructions;
+
+        let mut flags = Vec::with_capacity(points);
+        while flags.len() < points {
+            let flag = u8_at(data, offset)?;
+            offset += 1;
+            let repeat = if flag & 8 != 0 {
+                offset += 1;
+                u8_at(data, offset - 1)? as usize
+            } else {
+                0
+            };
+            flags.extend(std::iter::repeat_n(flag, repeat + 1));
+        }
+        flags.truncate(points);
+
+        // x coordinates, then y, each a short or long delta from the previous point
+        let mut coordinates = [Vec::with_capacity(points), Vec::with_capacity(points)];
+        for (axis, (short, same)) in [(2, 16), (4, 32)].into_iter().enumerate() {
+            let mut value = 0i32;
+            for &flag in &flags {
+                if flag & short != 0 {
+                    let delta = u8_at(data, offset)? as i32;
+                    offset += 1;
+                    value += if flag & same != 0 { delta } else { -delta };
+                } else if flag & same == 0 {
+                    value += i16_at(data, offset)? as i32;
+                    offset += 2;
+                }
+                coordinates[axis].push(value as f32);
+            }
+        }
+
+        let mut outline = Vec::with_capacity(contours);
+        let mut start = 0;
+        for end in ends {
+            let contour: Vec<(f32, f32, bool)> = (start..=end.min(points.saturating_sub(1)))
+                .map(|i| (coordinates[0][i], coordinates[1][i], flags[i] & 1 != 0))
+                .collect();
+            outline.push(flatten(&contour));
+            start = end + 1;
+        }
+        Ok(outline)
+    }
+
+    fn composite_outline(&self, data: &[u8], depth: usize) -> Result<Contours, String> {
+        let mut outline = Vec::new();
+        let mut offset = 10;
+        loop {
+            let flags = u16_at(data, offset)?;
+            let glyph = u16_at(data, offset + 2)?;
+            offset += 4;
+            // offsets rather than points to match, which are rare enough to be placed unmoved
+            let (dx, dy) = if flags & 1 != 0 {
+                offset += 4;
+                (
+                    i16_at(data, offset - 4)? as f32,
+                    i16_at(data, offset - 2)? as f32,
+                )
+            } else {
+                offset += 2;
+                let (dx, dy) = (
+                    u8_at(data, offset - 2)? as i8,
+                    u8_at(data, offset - 1)? as i8,
+                );
+                (dx as f32, dy as f32)
+            };
+            let (dx, dy) = if flags & 2 != 0 { (dx, dy) } else { (0., 0.) };
+            let (a, b, c, d) = if flags & 8 != 0 {
+                offset += 2;
+                let scale = f2dot14_at(data, offset - 2)?;
+                (scale, 0., 0., scale)
+            } else if flags & 0x40 != 0 {
+                offset += 4;
+                (
+                    f2dot14_at(data, offset - 4)?,
+                    0.,
+                    0.,
+                    f2dot14_at(data, offset - 2)?,
+                )
+            } else if flags & 0x80 != 0 {
+                offset += 8;
+                (
+                    f2dot14_at(data, offset - 8)?,
+                    f2dot14_at(data, offset - 6)?,
+                    f2dot14_at(data, offset - 4)?,
+                    f2dot14_at(data, offset - 2)?,
+                )
+            } else {
+                (1., 0., 0., 1.)
+            };
+            for contour in self.outline(glyph, depth + 1)? {
+                let contour = contour
+                    .into_iter()
+                    .map(|(x, y)| (a * x + c * y + dx, b * x + d * y + dy))
+                    .collect();
+                outline.push(contour);
+            }
+            if flags & 0x20 == 0 {
+                return Ok(outline);
+            }
+        }
+    }
+
+    // How many pixels wide and tall every glyph of `glyphs` fits in at `size` pixels per em.
+    pub fn cell(&self, glyphs: &[u16], size: f32) -> (usize, usize) {
+        let scale = size / self.units_per_em;
+        let advance = glyphs
+            .iter()
+            .map(|&glyph| self.advance(glyph))
+            .fold(0., f32::max);
+        let width = (advance * scale).round().max(1.) as usize;
+        let height = ((self.ascender as f32 - self.descender as f32) * scale).round();
+        (width, height.max(1.) as usize)
+    }
+
+    // The glyph's coverage of each pixel of a `width` by `height` cell at `size` pixels per em,
+    // from 0 to 1 row by row, its baseline at the font's ascender and its advance centered.
+    pub fn rasterize(
+        &self,
+        glyph: u16,
+        size: f32,
+        (width, height): (usize, usize),
+    ) -> Result<Vec<f32>, String> {
+        let scale = size / self.units_per_em;
+        let left = (width as f32 - self.advance(glyph) * scale) / 2.;
+        let top = self.ascender as f32 * scale;
+        let mut edges = Vec::new();
+        for contour in self.outline(glyph, 0)? {
+            let points: Vec<(f32, f32)> = contour
+                .iter()
+                .map(|&(x, y)| (left + x * scale, top - y * scale))
+                .collect();
+            for (i, &from) in points.iter().enumerate() {
+                edges.push((from, points[(i + 1) % points.len()]));
+            }
+        }
+        Ok(coverage(&edges, width, height))
+    }
+}
+
+// A contour of on- and off-curve points as a polyline, its quadratic curves made of straight steps.
+// Two off-curve points in a row have an implied on-curve one midway between them.
+fn flatten(points: &[(f32, f32, bool)]) -> Vec<(f32, f32)> {
+    let midway = |(x0, y0, _): (f32, f32, bool), (x1, y1, _): (f32, f32, bool)| {
+        ((x0 + x1) / 2., (y0 + y1) / 2., true)
+    };
+    let Some(first_on) = points.iter().position(|point| point.2) else {
+        return match points {
+            [] => Vec::new(),
+            _ => flatten(
+                &points
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(i, &point)| [point, midway(point, points[(i + 1) % points.len()])])
+                    .collect::<Vec<_>>(),
+            ),
+        };
+    };
+    let mut polyline = Vec::new();
+    let (mut x, mut y, _) = points[first_on];
+    polyline.push((x, y));
+    let mut control: Option<(f32, f32, bool)> = None;
+    for i in 1..=points.len() {
+        let point = points[(first_on + i) % points.len()];
+        let to = match (control, point.2) {
+            (None, true) => point,
+            (None, false) => {
+                control = Some(point);
+                continue;
+            }
+            (Some(_), true) => point,
+            (Some(previous), false) => midway(previous, point),
+        };
+        if let Some((cx, cy, _)) = control {
+            for step in 1..=CURVE_STEPS {
+                let t = step as f32 / CURVE_STEPS as f32;
+                let u = 1. - t;
+                polyline.push((
+                    u * u * x + 2. * u * t * cx + t * t * to.0,
+                    u * u * y + 2. * u * t * cy + t * t * to.1,
+                ));
+            }
+        } else {
+            polyline.push((to.0, to.1));
+        }
+        (x, y) = (to.0, to.1);
+        control = (!point.2).then_some(point);
+    }
+    polyline
+}
+
+// How much of each pixel the shape bounded by `edges` covers by the nonzero winding rule, row by
+// row. Each row is sampled along `SUBSAMPLES` lines, over which spans cover columns exactly.
+fn coverage(edges: &[Edge], width: usize, height: usize) -> Vec<f32> {
+    let mut bitmap = vec![0f32; width * height];
+    let mut crossings: Vec<(f32, i32)> = Vec::new();
+    for row in 0..height {
+        for sample in 0..SUBSAMPLES {
+            let y = row as f32 + (sample as f32 + 0.5) / SUBSAMPLES as f32;
+            crossings.clear();
+            for &((x0, y0), (x1, y1)) in edges {
+                if (y0 <= y) != (y1 <= y) {
+                    let x = x0 + (y - y0) / (y1 - y0) * (x1 - x0);
+                    crossings.push((x, if y1 > y0 { 1 } else { -1 }));
+                }
+            }
+            crossings.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let mut winding = 0;
+            for pair in crossings.windows(2) {
+                winding += pair[0].1;
+                if winding == 0 {
+                    continue;
+                }
+                let (from, to) = (pair[0].0.max(0.), pair[1].0.min(width as f32));
+                let mut x = from;
+                while x < to {
+                    let column = x.floor();
+                    let end = (column + 1.).min(to);
+                    bitmap[row * width + column as usize] += (end - x) / SUBSAMPLES as f32;
+                    x = end;
+                }
+            }
+        }
+    }
+    for value in &mut bitmap {
+        *value = value.min(1.);
+    }
+    bitmap
+}
@@ -0,0 +1,210 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rocket::form::Form;
+use rocket::serde::json::{Json, Value};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{post, FromForm, State};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::alphabets::Alphabets;
+use crate::callback::Callbacks;
+use crate::config::Config;
+use crate::echo::{source_metadata, WithParams};
+use crate::error::Error;
+use crate::fetch::Fetchers;
+use crate::logging::RequestId;
+use crate::metadata::SourceMetadata;
+use crate::negotiate::{Body, Representation};
+use crate::permalink::Permalinks;
+use crate::pools::Pools;
+use crate::profile::Profile;
+use crate::query::{resolve, ValidatedParams};
+use crate::ratelimit::RateLimit;
+use crate::stats::Stats;
+
+// Rocket caps the `image` field with its own `limits.bytes` and `limits.data-form` settings, which
+// default well below `conversion_limits.max_source_bytes`.
+#[derive(FromForm)]
+pub struct Upload<'r> {
+    image: &'r [u8],
+}
+
+// Same conversion parameters as the image URL route, in the query string, with the image itself
+// uploaded as the `image` field of a multipart form.
+#[post("/convert", format = "multipart/form-data", data = "<upload>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn convert(
+    _limit: RateLimit,
+    params: Result<ValidatedParams, Error>,
+    upload: Form<Upload<'_>>,
+    representation: Representation,
+    config: &State<Config>,
+    stats: &State<Stats>,
+    pools: &State<Pools>,
+    permalinks: &State<Permalinks>,
+) -> Result<WithParams<Body>, Error> {
+    let ValidatedParams { conversion, format } = params?;
+    let representation = representation.or_format(format.as_deref())?;
+    if upload.image.len() as u64 > config.conversion_limits.max_source_bytes {
+        return Err(Error::SourceTooLarge(
+            config.conversion_limits.max_source_bytes,
+        ));
+    }
+
+    let labels = (
+        conversion.font.clone(),
+        conversion.alphabet.clone(),
+        conversion.metric.clone(),
+    );
+    let bytes = upload.image.to_vec();
+    let permalinks = permalinks.inner().clone();
+    let t0 = Instant::now();
+    let result = async {
+        let (bytes, image) = pools.decode(bytes).await?;
+        pools
+            .convert(move || {
+                let (body, resolved, permalink, quality) = conversion.render_negotiated(
+                    &bytes,
+                    &image,
+                    "upload",
+                    representation,
+                    &permalinks,
+                )?;
+                let metadata = conversion.metadata.then(|| {
+                    source_metadata(&SourceMetadata::new(&bytes, &image).with_quality(quality))
+                });
+                Ok((body, resolved, metadata, permalink))
+            })
+            .await?
+    }
+    .await;
+
+    match result {
+        Ok((body, resolved, metadata, permalink)) => {
+            let (font, alphabet, metric) = labels;
+            let format = representation.name();
+            stats.record(&font, &alphabet, &metric, format, t0.elapsed());
+            Ok(WithParams::new(body, resolved)
+                .with_metadata(metadata)
+                .with_permalink(permalink))
+        }
+        Err(err) => {
+            stats.record_failure();
+            Err(err)
+        }
+    }
+}
+
+// The same conversion for server-to-server callers, with the image base64 encoded in a JSON body
+// alongside the conversion parameters, which take their JSON types rather than query strings.
+// Instead of the image, its `url` may be given, in any scheme the `Fetchers` accept.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct JsonUpload {
+    image: Option<String>,
+    url: Option<String>,
+    profile: Option<String>,
+    #[serde(default)]
+    metadata: bool,
+    // the result is also POSTed, signed, to this URL
+    callback_url: Option<String>,
+    #[serde(flatten)]
+    options: Profile,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct JsonConversion {
+    text: String,
+    params: Value,
+    metadata: Option<SourceMetadata>,
+    permalink: Option<String>,
+    callback: Option<String>,
+}
+
+// Rocket caps the whole body with `limits.json`, which the base64 encoding inflates by a third.
+#[post("/convert", format = "json", data = "<upload>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn convert_json(
+    _limit: RateLimit,
+    request_id: RequestId,
+    upload: Json<JsonUpload>,
+    config: &State<Config>,
+    stats: &State<Stats>,
+    pools: &State<Pools>,
+    fetchers: &State<Fetchers>,
+    permalinks: &State<Permalinks>,
+    callbacks: &State<Arc<Callbacks>>,
+    alphabets: &State<Arc<Alphabets>>,
+) -> Result<Json<JsonConversion>, Error> {
+    let upload = upload.into_inner();
+    if let Some(url) = &upload.callback_url {
+        callbacks.check(url)?;
+    }
+    let mut conversion = resolve(
+        config,
+        upload.profile.as_deref(),
+        &alphabets.expand(upload.options),
+        upload.metadata,
+    )?;
+    conversion.request_id = Some(request_id.0);
+    let bytes = match (upload.image, &upload.url) {
+        (Some(image), None) => STANDARD
+            .decode(image)
+            .map_err(|_| Error::InvalidParameter("image"))?,
+        (None, Some(url)) => pools.download(fetchers.fetch(url)).await?,
+        _ => return Err(Error::InvalidParameter("image")),
+    };
+    if bytes.len() as u64 > config.conversion_limits.max_source_bytes {
+        return Err(Error::SourceTooLarge(
+            config.conversion_limits.max_source_bytes,
+        ));
+    }
+
+    let labels = (
+        conversion.font.clone(),
+        conversion.alphabet.clone(),
+        conversion.metric.clone(),
+    );
+    let source = upload.url.unwrap_or_else(|| String::from("upload"));
+    let permalinks = permalinks.inner().clone();
+    let t0 = Instant::now();
+    let result = async {
+        let (bytes, image) = pools.decode(bytes).await?;
+        pools
+            .convert(move || {
+                let (text, _, permalink, quality) =
+                    conversion.render_permalinked(&image, &source, &permalinks)?;
+                let metadata = conversion
+                    .metadata
+                    .then(|| SourceMetadata::new(&bytes, &image).with_quality(quality));
+                let params = serde_json::to_value(conversion.params(&source)).unwrap();
+                Ok(JsonConversion {
+                    text,
+                    params,
+                    metadata,
+                    permalink,
+                    callback: None,
+                })
+            })
+            .await?
+    }
+    .await;
+
+    match result {
+        Ok(mut output) => {
+            let (font, alphabet, metric) = labels;
+            stats.record(&font, &alphabet, &metric, "json", t0.elapsed());
+            if let Some(url) = &upload.callback_url {
+                let body = serde_json::to_string(&output).unwrap();
+                output.callback = Some(callbacks.enqueue(url, body)?);
+            }
+            Ok(Json(output))
+        }
+        Err(err) => {
+            stats.record_failure();
+            Err(err)
+        }
+    }
+}
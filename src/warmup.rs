@@ -0,0 +1,75 @@
+use image::{DynamicImage, GrayImage, Luma};
+use log::{info, warn};
+use rocket::fairing::AdHoc;
+use rocket::serde::Deserialize;
+use rocket::tokio::task::spawn_blocking;
+use std::time::Instant;
+
+use crate::config::Config;
+use crate::convert::{get_converter, img_to_char_rows, MatchOptions, METRICS};
+use crate::generate::{load_alphabet, load_font, ALPHABETS, FONTS};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct WarmupConfig {
+    // parse every bundled font against every bundled alphabet before launching
+    pub fonts: bool,
+    // then convert a small gradient with every metric
+    pub metrics: bool,
+}
+
+impl Default for WarmupConfig {
+    fn default() -> WarmupConfig {
+        WarmupConfig {
+            fonts: true,
+            metrics: true,
+        }
+    }
+}
+
+fn warm_up(config: &WarmupConfig) {
+    if config.fonts {
+        let t0 = Instant::now();
+        for (font, _) in FONTS {
+            for (alphabet, _) in ALPHABETS {
+                let _ = load_font(font, &load_alphabet(alphabet));
+            }
+        }
+        let pairs = FONTS.len() * ALPHABETS.len();
+        info!("parsed {} fonts in {:?}", pairs, t0.elapsed());
+    }
+    if config.metrics {
+        let t0 = Instant::now();
+        let Ok(font) = load_font("bitocra-13", &load_alphabet("alphabet")) else {
+            return;
+        };
+        let gradient = DynamicImage::ImageLuma8(GrayImage::from_fn(64, 64, |x, y| {
+            Luma([((x + y) * 2) as u8])
+        }));
+        for metric in METRICS {
+            img_to_char_rows(
+                &font,
+                &gradient,
+                get_converter(metric),
+                8,
+                MatchOptions::default(),
+            );
+        }
+        info!("warmed up {} metrics in {:?}", METRICS.len(), t0.elapsed());
+    }
+}
+
+// Warms up fonts and metrics off the async workers before the server launches, so the first
+// requests run as fast as later ones. Must be attached after the `Config` fairing.
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Warm-up", |rocket| async {
+        let config = rocket
+            .state::<Config>()
+            .map(|config| config.warmup.clone())
+            .unwrap_or_default();
+        if let Err(err) = spawn_blocking(move || warm_up(&config)).await {
+            warn!("warm-up failed: {}", err);
+        }
+        rocket
+    })
+}